@@ -0,0 +1,99 @@
+//! The context of a line: the first lines of the syntax nodes enclosing it, like the module, the
+//! impl block and the function it lies in. Views show it at the top when those lines are
+//! scrolled out of sight.
+
+use crate::find_first_non_whitespace_char;
+use crate::syntax::{ContextQuery, Syntax};
+use crate::RopeSlice;
+
+/// The node kinds that make up the context of languages without a `context.scm` query.
+const CONTEXT_NODE_KINDS: &[&str] = &[
+    "class_declaration",
+    "class_definition",
+    "class_specifier",
+    "do_statement",
+    "for_statement",
+    "function_declaration",
+    "function_definition",
+    "function_item",
+    "if_statement",
+    "impl_item",
+    "method_declaration",
+    "method_definition",
+    "mod_item",
+    "module",
+    "namespace_definition",
+    "struct_specifier",
+    "switch_statement",
+    "trait_item",
+    "try_statement",
+    "while_statement",
+];
+
+/// The lines on which the nodes enclosing `line` start, outermost first. Nodes starting on
+/// `line` or after it aren't part of its context.
+pub fn context_lines(
+    syntax: &Syntax,
+    query: Option<&ContextQuery>,
+    text: RopeSlice,
+    line: usize,
+) -> Vec<usize> {
+    let pos =
+        text.line_to_char(line) + find_first_non_whitespace_char(text.line(line)).unwrap_or(0);
+    let byte = text.char_to_byte(pos);
+    let root = syntax.tree().root_node();
+
+    let query_nodes = query.map(|query| query.context_nodes(root, text, byte));
+    let mut node = root.descendant_for_byte_range(byte, byte);
+    let mut lines = Vec::new();
+    while let Some(current) = node {
+        let is_context = match &query_nodes {
+            Some(nodes) => nodes.contains(&current),
+            None => CONTEXT_NODE_KINDS.contains(&current.kind()),
+        };
+        let start = text.byte_to_line(current.start_byte());
+        if is_context && start < line && lines.last() != Some(&start) {
+            lines.push(start);
+        }
+        node = current.parent();
+    }
+
+    lines.reverse();
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::{get_language, HighlightConfiguration};
+    use crate::Rope;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_context_lines() {
+        let language = get_language(&crate::RUNTIME_DIR, "Rust").unwrap();
+        let config = HighlightConfiguration::new(language, "", "", "").unwrap();
+        let source = Rope::from_str(
+            "mod a {\n    impl B {\n        fn c() {\n            let d = 1;\n\n            loop {\n                d;\n            }\n        }\n    }\n}\n",
+        );
+        let syntax = Syntax::new(&source, Arc::new(config));
+        let text = source.slice(..);
+
+        // without a query the node kinds are used, loops aren't part of them
+        assert_eq!(context_lines(&syntax, None, text, 6), vec![0, 1, 2]);
+        assert_eq!(context_lines(&syntax, None, text, 2), vec![0, 1]);
+        assert_eq!(context_lines(&syntax, None, text, 0), Vec::<usize>::new());
+
+        let query = ContextQuery::new(
+            language,
+            &std::fs::read_to_string("../runtime/queries/rust/context.scm").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            context_lines(&syntax, Some(&query), text, 6),
+            vec![0, 1, 2, 5]
+        );
+        // an empty line still has the context of its enclosing nodes
+        assert_eq!(context_lines(&syntax, Some(&query), text, 4), vec![0, 1, 2]);
+    }
+}
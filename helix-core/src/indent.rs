@@ -454,18 +454,26 @@ where
                 language_id: "Rust".to_string(),
                 highlight_config: OnceCell::new(),
                 config: None,
+                settings: None,
                 //
                 injection_regex: None,
+                first_line_regex: None,
                 roots: vec![],
                 comment_token: None,
                 auto_format: false,
                 language_server: None,
+                formatter: None,
+                grammar: None,
                 indent: Some(IndentationConfiguration {
                     tab_width: 4,
                     unit: String::from("    "),
                 }),
                 indent_query: OnceCell::new(),
                 textobject_query: OnceCell::new(),
+                linked_editing_query: OnceCell::new(),
+                tags_query: OnceCell::new(),
+                context_query: OnceCell::new(),
+                rainbow_query: OnceCell::new(),
             }],
         });
 
@@ -0,0 +1,166 @@
+//! Inspecting syntax trees, mostly to write queries: the tree printed as an S-expression, and the
+//! captures of queries run against a document.
+
+use crate::syntax::{RopeProvider, Syntax};
+use crate::RopeSlice;
+use std::ops;
+use tree_sitter::{Node, Query, QueryCursor, QueryError};
+
+/// A line of the S-expression of a syntax tree, see [`tree_lines`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeLine {
+    /// How deep the node is in the tree, which is how far the line is indented.
+    pub depth: usize,
+    pub text: String,
+    /// The byte range of the node.
+    pub range: ops::Range<usize>,
+}
+
+/// Prints the named nodes under `root` as an S-expression, one node per line. The children of
+/// `root` are collapsed to a line, except for the one containing the `focus` byte, which keeps
+/// the output short for large documents.
+pub fn tree_lines(root: Node, focus: usize) -> Vec<TreeLine> {
+    let mut lines = Vec::new();
+    print_node(root, None, 0, focus, &mut lines);
+    lines
+}
+
+fn print_node(
+    node: Node,
+    field: Option<&str>,
+    depth: usize,
+    focus: usize,
+    lines: &mut Vec<TreeLine>,
+) {
+    let mut text = String::new();
+    if let Some(field) = field {
+        text.push_str(field);
+        text.push_str(": ");
+    }
+    text.push('(');
+    if node.is_missing() {
+        text.push_str("MISSING ");
+    }
+    text.push_str(node.kind());
+
+    let range = node.byte_range();
+    let collapsed = depth == 1 && !range.contains(&focus);
+    if collapsed && node.named_child_count() > 0 {
+        text.push_str(" …");
+    }
+    lines.push(TreeLine { depth, text, range });
+
+    if !collapsed {
+        for i in 0..node.child_count() {
+            let child = node.child(i).unwrap();
+            if child.is_named() {
+                let field = node.field_name_for_child(i as u32);
+                print_node(child, field, depth + 1, focus, lines);
+            }
+        }
+    }
+    lines.last_mut().unwrap().text.push(')');
+}
+
+/// A capture of a query, see [`query_captures`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryCapture {
+    pub name: String,
+    /// The kind of the captured node.
+    pub kind: &'static str,
+    /// The byte range of the captured node.
+    pub range: ops::Range<usize>,
+}
+
+/// Runs `query` against the syntax tree of the document, returning the first `limit` captures in
+/// document order.
+pub fn query_captures(
+    syntax: &Syntax,
+    query: &str,
+    text: RopeSlice,
+    limit: usize,
+) -> Result<Vec<QueryCapture>, QueryError> {
+    let query = Query::new(syntax.grammar(), query)?;
+    let mut cursor = QueryCursor::new();
+    let captures = cursor
+        .captures(&query, syntax.tree().root_node(), RopeProvider(text))
+        .take(limit)
+        .map(|(mat, index)| {
+            let capture = mat.captures[index];
+            QueryCapture {
+                name: query.capture_names()[capture.index as usize].clone(),
+                kind: capture.node.kind(),
+                range: capture.node.byte_range(),
+            }
+        })
+        .collect();
+    Ok(captures)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::{get_language, HighlightConfiguration};
+    use crate::Rope;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_tree_lines() {
+        let language = get_language(&crate::RUNTIME_DIR, "Rust").unwrap();
+        let config = HighlightConfiguration::new(language, "", "", "").unwrap();
+        let source = Rope::from_str("struct A;\nfn b(c: u8) {}\n");
+        let syntax = Syntax::new(&source, Arc::new(config));
+        let root = syntax.tree().root_node();
+
+        let lines = |focus| -> Vec<String> {
+            tree_lines(root, focus)
+                .into_iter()
+                .map(|line| format!("{}{}", "  ".repeat(line.depth), line.text))
+                .collect()
+        };
+        // only the item containing the focus is expanded
+        assert_eq!(
+            lines(source.to_string().find("c:").unwrap()),
+            [
+                "(source_file",
+                "  (struct_item …)",
+                "  (function_item",
+                "    name: (identifier)",
+                "    parameters: (parameters",
+                "      (parameter",
+                "        pattern: (identifier)",
+                "        type: (primitive_type)))",
+                "    body: (block)))",
+            ]
+        );
+        assert_eq!(
+            lines(0),
+            [
+                "(source_file",
+                "  (struct_item",
+                "    name: (type_identifier))",
+                "  (function_item …))",
+            ]
+        );
+
+        let captures = query_captures(
+            &syntax,
+            "(parameter pattern: (identifier) @name type: (_) @type)",
+            source.slice(..),
+            10,
+        )
+        .unwrap();
+        let names: Vec<_> = captures
+            .iter()
+            .map(|capture| (capture.name.as_str(), capture.kind, capture.range.clone()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("name", "identifier", 15..16),
+                ("type", "primitive_type", 18..20)
+            ]
+        );
+        assert!(query_captures(&syntax, "(not_a_node)", source.slice(..), 10).is_err());
+    }
+}
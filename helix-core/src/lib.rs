@@ -1,12 +1,15 @@
 pub mod auto_pairs;
 pub mod chars;
 pub mod comment;
+pub mod context;
 pub mod diagnostic;
 pub mod diff;
 pub mod graphemes;
 pub mod history;
 pub mod indent;
+pub mod inspect;
 pub mod line_ending;
+pub mod linked_editing;
 pub mod macros;
 pub mod match_brackets;
 pub mod movement;
@@ -14,6 +17,7 @@ pub mod numbers;
 pub mod object;
 pub mod path;
 mod position;
+pub mod rainbow;
 pub mod register;
 pub mod search;
 pub mod selection;
@@ -100,7 +104,8 @@ pub fn cache_dir() -> std::path::PathBuf {
     path
 }
 
-// right overrides left
+// right overrides left: tables are merged key by key, arrays of tables by their `name`, and
+// other values, arrays of strings included, are replaced
 pub fn merge_toml_values(left: toml::Value, right: toml::Value) -> toml::Value {
     use toml::Value;
 
@@ -109,7 +114,9 @@ pub fn merge_toml_values(left: toml::Value, right: toml::Value) -> toml::Value {
     }
 
     match (left, right) {
-        (Value::Array(mut left_items), Value::Array(right_items)) => {
+        (Value::Array(mut left_items), Value::Array(right_items))
+            if left_items.iter().chain(&right_items).all(Value::is_table) =>
+        {
             left_items.reserve(right_items.len());
             for rvalue in right_items {
                 let lvalue = get_name(&rvalue)
@@ -181,6 +188,43 @@ mod merge_toml_tests {
         // We didn't change comment-token so it should be same
         assert_eq!(nix.get("comment-token").unwrap().as_str().unwrap(), "#");
     }
+
+    #[test]
+    fn arrays_are_replaced() {
+        use toml::Value;
+
+        let base: Value = toml::from_str(
+            "
+            [[language]]
+            name = \"typescript\"
+            roots = [\"package.json\"]
+            language-server = { command = \"typescript-language-server\", args = [\"--stdio\"] }
+            ",
+        )
+        .unwrap();
+        let user: Value = toml::from_str(
+            "
+            [[language]]
+            name = \"typescript\"
+            roots = []
+            language-server = { args = [\"--stdio\", \"--log-level\", \"4\"] }
+            ",
+        )
+        .unwrap();
+
+        let merged = merge_toml_values(base, user);
+        let typescript = &merged["language"][0];
+        assert_eq!(merged["language"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            typescript["language-server"]["args"],
+            Value::Array(vec!["--stdio".into(), "--log-level".into(), "4".into()])
+        );
+        assert_eq!(
+            typescript["language-server"]["command"].as_str(),
+            Some("typescript-language-server")
+        );
+        assert_eq!(typescript["roots"], Value::Array(Vec::new()));
+    }
 }
 
 pub use etcetera::home_dir;
@@ -0,0 +1,186 @@
+//! Linked editing: ranges that hold the same text and are edited together, like the names of an
+//! opening and a closing tag.
+
+use crate::{Assoc, Change, Rope, Transaction};
+use regex::Regex;
+use std::borrow::Cow;
+
+/// Linked ranges reported by a language server, mapped through edits.
+#[derive(Debug, Clone)]
+pub struct LinkedRanges {
+    /// Char ranges, each one holding the same text.
+    pub ranges: Vec<(usize, usize)>,
+    /// Describes the valid contents of the ranges, edits making them invalid aren't mirrored.
+    pub word_pattern: Option<Regex>,
+}
+
+/// Whether `text` is a valid content of linked ranges: the whole of it has to match
+/// `word_pattern`, or without one only contain characters used in tag names.
+pub fn is_valid_content(text: &str, word_pattern: Option<&Regex>) -> bool {
+    match word_pattern {
+        Some(regex) => {
+            text.is_empty()
+                || matches!(regex.find(text), Some(m) if m.start() == 0 && m.end() == text.len())
+        }
+        None => text
+            .chars()
+            .all(|ch| ch.is_alphanumeric() || matches!(ch, '-' | '_' | ':' | '.')),
+    }
+}
+
+/// Mirrors the changes of `transaction` into the ranges linked to the one they lie in, returning
+/// a single transaction that edits all of them. Returns `None` if the changes don't all lie in
+/// one of the `ranges`, if the ranges don't hold the same text or if the edit would make the
+/// contents invalid.
+pub fn mirror(
+    transaction: &Transaction,
+    doc: &Rope,
+    ranges: &[(usize, usize)],
+    word_pattern: Option<&Regex>,
+) -> Option<Transaction> {
+    let changes: Vec<Change> = transaction.changes_iter().collect();
+    let (first, last) = (changes.first()?, changes.last()?);
+    let &(start, end) = ranges
+        .iter()
+        .find(|(start, end)| *start <= first.0 && last.1 <= *end)?;
+
+    let source = doc.slice(start..end);
+    if ranges
+        .iter()
+        .any(|&(other_start, other_end)| doc.slice(other_start..other_end) != source)
+    {
+        return None;
+    }
+
+    let changeset = transaction.changes();
+    let mut text = doc.clone();
+    if !transaction.apply(&mut text) {
+        return None;
+    }
+    let edited =
+        text.slice(changeset.map_pos(start, Assoc::Before)..changeset.map_pos(end, Assoc::After));
+    if !is_valid_content(&Cow::from(edited), word_pattern) {
+        return None;
+    }
+
+    // the same changes at the same offsets in the other ranges, on top of the edited text
+    let mut mirrored: Vec<Change> = ranges
+        .iter()
+        .filter(|&&range| range != (start, end))
+        .flat_map(|&(other_start, _)| {
+            changes.iter().map(move |(from, to, fragment)| {
+                (
+                    changeset.map_pos(from - start + other_start, Assoc::After),
+                    changeset.map_pos(to - start + other_start, Assoc::After),
+                    fragment.clone(),
+                )
+            })
+        })
+        .collect();
+    mirrored.sort_by_key(|(from, _, _)| *from);
+    let mirror = Transaction::change(&text, mirrored.into_iter());
+
+    let selection = transaction
+        .selection()
+        .map(|selection| selection.clone().map(mirror.changes()));
+    let transaction = transaction.clone().compose(mirror);
+    Some(match selection {
+        Some(selection) => transaction.with_selection(selection),
+        None => transaction,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Selection, Tendril};
+
+    #[test]
+    fn test_mirror() {
+        let doc = Rope::from("<div>a</div>");
+        let ranges = [(1, 4), (8, 11)];
+
+        // append to the opening tag name
+        let selection = Selection::point(4);
+        let transaction = Transaction::insert(&doc, &selection, Tendril::from_char('x'));
+        let transaction = mirror(&transaction, &doc, &ranges, None).unwrap();
+        let mut text = doc.clone();
+        assert!(transaction.apply(&mut text));
+        assert_eq!(text, "<divx>a</divx>");
+
+        // delete in the closing tag name
+        let transaction = Transaction::change(&doc, vec![(8, 9, None)].into_iter());
+        let transaction = mirror(&transaction, &doc, &ranges, None).unwrap();
+        let mut text = doc.clone();
+        assert!(transaction.apply(&mut text));
+        assert_eq!(text, "<iv>a</iv>");
+
+        // edits that don't belong in a tag name aren't mirrored
+        let transaction = Transaction::insert(&doc, &selection, Tendril::from_char(' '));
+        assert!(mirror(&transaction, &doc, &ranges, None).is_none());
+
+        // nor are edits outside of the ranges
+        let selection = Selection::point(5);
+        let transaction = Transaction::insert(&doc, &selection, Tendril::from_char('b'));
+        assert!(mirror(&transaction, &doc, &ranges, None).is_none());
+    }
+
+    #[test]
+    fn test_mirror_keeps_selection() {
+        let doc = Rope::from("<a></a>");
+        let ranges = [(1, 2), (5, 6)];
+
+        // the selection after the edit is shifted by the mirrored insertions before it
+        let transaction = Transaction::change(&doc, vec![(6, 6, Some("b".into()))].into_iter())
+            .with_selection(Selection::point(7));
+        let transaction = mirror(&transaction, &doc, &ranges, None).unwrap();
+        let mut text = doc.clone();
+        assert!(transaction.apply(&mut text));
+        assert_eq!(text, "<ab></ab>");
+        assert_eq!(transaction.selection(), Some(&Selection::point(8)));
+    }
+
+    #[test]
+    fn test_tag_ranges() {
+        use crate::syntax::{get_language, HighlightConfiguration, LinkedEditingQuery};
+        use crate::Syntax;
+        use std::sync::Arc;
+        use tree_sitter::Query;
+
+        // svelte inherits the html query
+        let language = get_language(&crate::RUNTIME_DIR, "Svelte").unwrap();
+        let config = HighlightConfiguration::new(language, "", "", "").unwrap();
+        let query = Query::new(
+            language,
+            &std::fs::read_to_string("../runtime/queries/html/linked-editing.scm").unwrap(),
+        )
+        .unwrap();
+        let query = LinkedEditingQuery { query };
+
+        let doc = Rope::from("<ul><li>a</li></ul>\n");
+        let syntax = Syntax::new(&doc, Arc::new(config));
+        let root = syntax.tree().root_node();
+
+        // inside and at the end of a tag name
+        assert_eq!(
+            query.linked_ranges(root, doc.slice(..), 5),
+            Some(vec![(5, 7), (11, 13)])
+        );
+        assert_eq!(
+            query.linked_ranges(root, doc.slice(..), 17),
+            Some(vec![(1, 3), (16, 18)])
+        );
+        // in the text
+        assert_eq!(query.linked_ranges(root, doc.slice(..), 8), None);
+    }
+
+    #[test]
+    fn test_word_pattern() {
+        let regex = Regex::new(r"[a-z]+").unwrap();
+        assert!(is_valid_content("div", Some(&regex)));
+        assert!(is_valid_content("", Some(&regex)));
+        assert!(!is_valid_content("div x", Some(&regex)));
+        assert!(is_valid_content("my-element", None));
+        assert!(!is_valid_content("div>", None));
+    }
+}
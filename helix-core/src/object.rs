@@ -1,7 +1,5 @@
 use crate::{Range, RopeSlice, Selection, Syntax};
 
-// TODO: to contract_selection we'd need to store the previous ranges before expand.
-// Maybe just contract to the first child node?
 pub fn expand_selection(syntax: &Syntax, text: RopeSlice, selection: &Selection) -> Selection {
     let tree = syntax.tree();
 
@@ -34,3 +32,117 @@ pub fn expand_selection(syntax: &Syntax, text: RopeSlice, selection: &Selection)
         }
     })
 }
+
+/// The ranges `range` can be expanded through: `range` itself followed by the syntax nodes around
+/// its cursor, from the innermost to the root.
+pub fn selection_range_chain(syntax: &Syntax, text: RopeSlice, range: Range) -> Vec<Range> {
+    let mut chain = vec![range];
+
+    let pos = text.char_to_byte(range.cursor(text));
+    let mut node = syntax
+        .tree()
+        .root_node()
+        .descendant_for_byte_range(pos, pos);
+    while let Some(current) = node {
+        let node_range = Range::new(
+            text.byte_to_char(current.start_byte()),
+            text.byte_to_char(current.end_byte()),
+        );
+        // parents that span the same text as their child don't add a step
+        if chain.last().map(|last| (last.from(), last.to()))
+            != Some((node_range.from(), node_range.to()))
+        {
+            chain.push(node_range);
+        }
+        node = current.parent();
+    }
+
+    chain
+}
+
+fn len(range: &Range) -> usize {
+    range.to() - range.from()
+}
+
+fn contains(range: &Range, other: &Range) -> bool {
+    range.from() <= other.from() && other.to() <= range.to()
+}
+
+/// Keeps the direction of `range` for `to`.
+fn with_direction(range: Range, to: Range) -> Range {
+    if range.head < range.anchor {
+        Range::new(to.to(), to.from())
+    } else {
+        Range::new(to.from(), to.to())
+    }
+}
+
+/// Expands `range` to the smallest range of `chain` that contains it.
+pub fn expand_range(chain: &[Range], range: Range) -> Range {
+    chain
+        .iter()
+        .filter(|r| contains(r, &range) && len(r) > len(&range))
+        .min_by_key(|r| len(r))
+        .map_or(range, |r| with_direction(range, *r))
+}
+
+/// Shrinks `range` to the largest range of `chain` inside it.
+pub fn shrink_range(chain: &[Range], range: Range) -> Range {
+    chain
+        .iter()
+        .filter(|r| contains(&range, r) && len(r) < len(&range))
+        .max_by_key(|r| len(r))
+        .map_or(range, |r| with_direction(range, *r))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_selection_range_chain() {
+        use crate::syntax::{get_language, HighlightConfiguration};
+        use crate::Rope;
+        use std::sync::Arc;
+
+        let language = get_language(&crate::RUNTIME_DIR, "Rust").unwrap();
+        let config = HighlightConfiguration::new(language, "", "", "").unwrap();
+        let text = Rope::from_str("fn main() { foo(a, b); }\n");
+        let syntax = Syntax::new(&text, Arc::new(config));
+
+        // the cursor on `b`
+        let chain = selection_range_chain(&syntax, text.slice(..), Range::new(19, 20));
+        let range = expand_range(&chain, Range::new(19, 20));
+        assert_eq!(range, Range::new(15, 21)); // `(a, b)`
+        let range = expand_range(&chain, range);
+        assert_eq!(range, Range::new(12, 21)); // `foo(a, b)`
+        assert_eq!(shrink_range(&chain, range), Range::new(15, 21));
+    }
+
+    #[test]
+    fn test_expand_and_shrink_range() {
+        // e.g. `b` in `f(a, b)`: the argument, the argument list, the call
+        let chain = [
+            Range::new(5, 6),
+            Range::new(5, 6),
+            Range::new(1, 7),
+            Range::new(0, 7),
+        ];
+
+        let range = Range::new(5, 6);
+        let range = expand_range(&chain, range);
+        assert_eq!(range, Range::new(1, 7));
+        let range = expand_range(&chain, range);
+        assert_eq!(range, Range::new(0, 7));
+        assert_eq!(expand_range(&chain, range), range);
+
+        let range = shrink_range(&chain, range);
+        assert_eq!(range, Range::new(1, 7));
+        let range = shrink_range(&chain, range);
+        assert_eq!(range, Range::new(5, 6));
+        assert_eq!(shrink_range(&chain, range), range);
+
+        // the direction is kept
+        assert_eq!(expand_range(&chain, Range::new(6, 5)), Range::new(7, 1));
+    }
+}
@@ -0,0 +1,114 @@
+//! Rainbow brackets: brackets coloured by how deeply they're nested.
+//!
+//! By default the brackets are the `(`, `[` and `{` tokens of the syntax tree and their closing
+//! counterparts, and the nodes containing them are the scopes that nest. The `rainbows.scm` query
+//! of a language can add scopes and brackets by capturing them as `@rainbow.scope` and
+//! `@rainbow.bracket`, like the `<` and `>` of type parameters or the tags of HTML elements.
+
+use crate::syntax::{RainbowQuery, Syntax};
+use crate::RopeSlice;
+use std::collections::HashSet;
+use std::ops;
+use tree_sitter::Node;
+
+const BRACKETS: &[&str] = &["(", ")", "[", "]", "{", "}"];
+const OPEN_BRACKETS: &[&str] = &["(", "[", "{"];
+
+/// The brackets that intersect the byte range `range`, in document order, with their char range
+/// and their depth: the number of scopes they're nested in, not counting their own.
+pub fn rainbow_brackets(
+    syntax: &Syntax,
+    query: Option<&RainbowQuery>,
+    text: RopeSlice,
+    range: ops::Range<usize>,
+) -> Vec<(usize, ops::Range<usize>)> {
+    let root = syntax.tree().root_node();
+    let (scopes, brackets) = match query {
+        Some(query) => query.captures(root, text, range.clone()),
+        None => (HashSet::new(), HashSet::new()),
+    };
+
+    let is_bracket = |node: &Node| {
+        brackets.contains(&node.id()) || (!node.is_named() && BRACKETS.contains(&node.kind()))
+    };
+    let is_scope = |node: &Node| {
+        if scopes.contains(&node.id()) {
+            return true;
+        }
+        let mut cursor = node.walk();
+        let is_scope = node
+            .children(&mut cursor)
+            .any(|child| !child.is_named() && OPEN_BRACKETS.contains(&child.kind()));
+        is_scope
+    };
+
+    let mut result = Vec::new();
+    // nodes to visit along with the number of scopes around them
+    let mut stack = vec![(root, 0usize)];
+    let mut cursor = root.walk();
+    while let Some((node, depth)) = stack.pop() {
+        if is_bracket(&node) {
+            let start = text.byte_to_char(node.start_byte());
+            let end = text.byte_to_char(node.end_byte());
+            result.push((depth.saturating_sub(1), start..end));
+        }
+
+        let depth = if is_scope(&node) { depth + 1 } else { depth };
+        let children: Vec<_> = node
+            .children(&mut cursor)
+            .filter(|child| child.end_byte() > range.start && child.start_byte() < range.end)
+            .collect();
+        // visit the children in order
+        stack.extend(children.into_iter().rev().map(|child| (child, depth)));
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syntax::{get_language, HighlightConfiguration};
+    use crate::Rope;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rainbow_brackets() {
+        let language = get_language(&crate::RUNTIME_DIR, "Rust").unwrap();
+        let config = HighlightConfiguration::new(language, "", "", "").unwrap();
+        let source = Rope::from_str("fn a() { b(c[0], Vec::<u8>::new()); }\n");
+        let syntax = Syntax::new(&source, Arc::new(config));
+        let text = source.slice(..);
+        let brackets = |query, range| {
+            rainbow_brackets(&syntax, query, text, range)
+                .into_iter()
+                .map(|(depth, range)| (depth, text.slice(range).to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let depths = |brackets: &str| {
+            brackets
+                .split(' ')
+                .map(|bracket| {
+                    let (depth, bracket) = bracket.split_at(1);
+                    (depth.parse().unwrap(), bracket.to_string())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            brackets(None, 0..source.len_bytes()),
+            depths("0( 0) 0{ 1( 2[ 2] 2( 2) 1) 0}")
+        );
+        // only the brackets in the range are returned, but their depth is the same
+        assert_eq!(brackets(None, 11..16), depths("2[ 2]"));
+
+        let query = RainbowQuery::new(
+            language,
+            &std::fs::read_to_string("../runtime/queries/rust/rainbows.scm").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            brackets(Some(&query), 0..source.len_bytes()),
+            depths("0( 0) 0{ 1( 2[ 2] 2< 2> 2( 2) 1) 0}")
+        );
+    }
+}
@@ -8,6 +8,7 @@ use crate::{
 pub use helix_syntax::get_language;
 
 use arc_swap::ArcSwap;
+use globset::{GlobBuilder, GlobMatcher};
 
 use std::{
     borrow::Cow,
@@ -15,7 +16,10 @@ use std::{
     collections::{HashMap, HashSet},
     fmt,
     path::Path,
-    sync::Arc,
+    sync::{
+        mpsc::{Receiver, TryRecvError},
+        Arc, Mutex,
+    },
 };
 
 use once_cell::sync::{Lazy, OnceCell};
@@ -51,15 +55,20 @@ pub struct Configuration {
 pub struct LanguageConfiguration {
     #[serde(rename = "name")]
     pub language_id: String,
-    pub scope: String,           // source.rust
-    pub file_types: Vec<String>, // filename ends_with? <Gemfile, rb, etc>
+    pub scope: String, // source.rust
+    /// File names, extensions (which may be compound, like `blade.php`) and glob patterns
+    /// (like `Dockerfile.*` or `.github/workflows/*.yml`) of the language.
+    pub file_types: Vec<String>,
     #[serde(default)]
     pub shebangs: Vec<String>, // interpreter(s) associated with language
-    pub roots: Vec<String>,      // these indicate project roots <.git, Cargo.toml>
+    pub roots: Vec<String>, // these indicate project roots <.git, Cargo.toml>
     pub comment_token: Option<String>,
 
     #[serde(default, skip_serializing, deserialize_with = "deserialize_lsp_config")]
     pub config: Option<serde_json::Value>,
+    /// Settings the language server pulls with `workspace/configuration`, by section.
+    #[serde(default, skip_serializing, deserialize_with = "deserialize_lsp_config")]
+    pub settings: Option<serde_json::Value>,
 
     #[serde(default)]
     pub auto_format: bool,
@@ -67,20 +76,34 @@ pub struct LanguageConfiguration {
     // content_regex
     #[serde(default, skip_serializing, deserialize_with = "deserialize_regex")]
     pub injection_regex: Option<Regex>,
-    // first_line_regex
-    //
+    /// Detects the language from the first line of files with an unknown file type.
+    #[serde(default, skip_serializing, deserialize_with = "deserialize_regex")]
+    pub first_line_regex: Option<Regex>,
     #[serde(skip)]
     pub(crate) highlight_config: OnceCell<Option<Arc<HighlightConfiguration>>>,
-    // tags_config OnceCell<> https://github.com/tree-sitter/tree-sitter/pull/583
     #[serde(skip_serializing_if = "Option::is_none")]
     pub language_server: Option<LanguageServerConfiguration>,
+    /// An external formatter the document is piped through, preferred over the language server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatter: Option<FormatterConfiguration>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub indent: Option<IndentationConfiguration>,
+    /// Where `hx --grammar build` builds the tree-sitter grammar from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grammar: Option<GrammarConfiguration>,
 
     #[serde(skip)]
     pub(crate) indent_query: OnceCell<Option<IndentQuery>>,
     #[serde(skip)]
     pub(crate) textobject_query: OnceCell<Option<TextObjectQuery>>,
+    #[serde(skip)]
+    pub(crate) linked_editing_query: OnceCell<Option<LinkedEditingQuery>>,
+    #[serde(skip)]
+    pub(crate) tags_query: OnceCell<Option<TagsQuery>>,
+    #[serde(skip)]
+    pub(crate) context_query: OnceCell<Option<ContextQuery>>,
+    #[serde(skip)]
+    pub(crate) rainbow_query: OnceCell<Option<RainbowQuery>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -90,6 +113,42 @@ pub struct LanguageServerConfiguration {
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub args: Vec<String>,
+    /// Time in seconds to wait for a response to a request.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    20
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FormatterConfiguration {
+    pub command: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Time in seconds after which the formatter process is killed.
+    #[serde(default = "default_formatter_timeout")]
+    pub timeout: u64,
+}
+
+fn default_formatter_timeout() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GrammarConfiguration {
+    /// A directory with the grammar sources, or a git repository if `rev` is set.
+    pub source: String,
+    /// The git revision to build.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    /// The directory of the grammar inside `source`, the one containing `src/parser.c`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subpath: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -136,6 +195,232 @@ impl TextObjectQuery {
     }
 }
 
+#[derive(Debug)]
+pub struct LinkedEditingQuery {
+    pub query: Query,
+}
+
+impl LinkedEditingQuery {
+    /// The char ranges edited together with the one at `pos`: the `@linked` captures of the
+    /// match that has one touching `pos`, e.g. the names of an opening and a closing tag.
+    pub fn linked_ranges(
+        &self,
+        node: Node,
+        slice: RopeSlice,
+        pos: usize,
+    ) -> Option<Vec<(usize, usize)>> {
+        let capture_idx = self.query.capture_index_for_name("linked")?;
+        let byte = slice.char_to_byte(pos);
+
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(byte.saturating_sub(1)..byte + 1);
+        let mut matches = cursor.matches(&self.query, node, RopeProvider(slice));
+
+        let linked = matches.find(|mat| {
+            mat.captures.iter().any(|capture| {
+                capture.index == capture_idx
+                    && capture.node.start_byte() <= byte
+                    && byte <= capture.node.end_byte()
+            })
+        })?;
+
+        let ranges = linked
+            .captures
+            .iter()
+            .filter(|capture| capture.index == capture_idx)
+            .map(|capture| {
+                (
+                    slice.byte_to_char(capture.node.start_byte()),
+                    slice.byte_to_char(capture.node.end_byte()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        if ranges.len() > 1 {
+            Some(ranges)
+        } else {
+            None
+        }
+    }
+}
+
+/// The `context.scm` query of a language, which captures the nodes that are part of the context
+/// of the lines within them as `@context`, see [`crate::context`].
+#[derive(Debug)]
+pub struct ContextQuery {
+    pub query: Query,
+}
+
+impl ContextQuery {
+    pub fn new(language: Grammar, source: &str) -> Result<Self, QueryError> {
+        Ok(Self {
+            query: Query::new(language, source)?,
+        })
+    }
+
+    /// The `@context` nodes below `node` that contain `byte`.
+    pub fn context_nodes<'tree>(
+        &self,
+        node: Node<'tree>,
+        slice: RopeSlice<'tree>,
+        byte: usize,
+    ) -> Vec<Node<'tree>> {
+        let capture_idx = match self.query.capture_index_for_name("context") {
+            Some(idx) => idx,
+            None => return Vec::new(),
+        };
+
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(byte..byte + 1);
+        cursor
+            .captures(&self.query, node, RopeProvider(slice))
+            .filter_map(|(mat, idx)| {
+                let capture = mat.captures[idx];
+                let node = capture.node;
+                if capture.index == capture_idx
+                    && node.start_byte() <= byte
+                    && byte < node.end_byte()
+                {
+                    Some(node)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// The `rainbows.scm` query of a language, see [`crate::rainbow`].
+#[derive(Debug)]
+pub struct RainbowQuery {
+    pub query: Query,
+}
+
+impl RainbowQuery {
+    pub fn new(language: Grammar, source: &str) -> Result<Self, QueryError> {
+        Ok(Self {
+            query: Query::new(language, source)?,
+        })
+    }
+
+    /// The ids of the `@rainbow.scope` and the `@rainbow.bracket` nodes below `node` that
+    /// intersect the byte range `range`.
+    pub fn captures(
+        &self,
+        node: Node,
+        slice: RopeSlice,
+        range: std::ops::Range<usize>,
+    ) -> (HashSet<usize>, HashSet<usize>) {
+        let scope_idx = self.query.capture_index_for_name("rainbow.scope");
+        let bracket_idx = self.query.capture_index_for_name("rainbow.bracket");
+
+        let mut scopes = HashSet::new();
+        let mut brackets = HashSet::new();
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(range);
+        for (mat, idx) in cursor.captures(&self.query, node, RopeProvider(slice)) {
+            let capture = mat.captures[idx];
+            if Some(capture.index) == scope_idx {
+                scopes.insert(capture.node.id());
+            } else if Some(capture.index) == bracket_idx {
+                brackets.insert(capture.node.id());
+            }
+        }
+        (scopes, brackets)
+    }
+}
+
+/// A definition found by a [`TagsQuery`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub name: String,
+    /// What is defined, e.g. `function` for a `@definition.function` capture.
+    pub kind: String,
+    /// The char range of the name.
+    pub range: std::ops::Range<usize>,
+    /// The line and the char offset within the line of the start and the end of the name.
+    pub start: crate::Position,
+    pub end: crate::Position,
+}
+
+/// The `tags.scm` query of a language, which captures the names of definitions with `@name` in
+/// patterns captured as `@definition.<kind>`, following the tree-sitter tags format.
+#[derive(Debug)]
+pub struct TagsQuery {
+    pub query: Query,
+    language: Grammar,
+}
+
+impl TagsQuery {
+    /// The definitions in the tree below `node`, in document order.
+    pub fn definitions(&self, node: Node, slice: RopeSlice) -> Vec<Tag> {
+        let name_idx = match self.query.capture_index_for_name("name") {
+            Some(idx) => idx,
+            None => return Vec::new(),
+        };
+
+        let mut cursor = QueryCursor::new();
+        let mut tags: Vec<_> = cursor
+            .matches(&self.query, node, RopeProvider(slice))
+            .filter_map(|mat| {
+                let kind = mat.captures.iter().find_map(|capture| {
+                    let capture_name = &self.query.capture_names()[capture.index as usize];
+                    capture_name.strip_prefix("definition.")
+                })?;
+                let name = mat
+                    .captures
+                    .iter()
+                    .find(|capture| capture.index == name_idx)?
+                    .node;
+
+                let start = slice.byte_to_char(name.start_byte());
+                let end = slice.byte_to_char(name.end_byte());
+                let position = |pos: usize| {
+                    let line = slice.char_to_line(pos);
+                    crate::Position::new(line, pos - slice.line_to_char(line))
+                };
+                let tag = Tag {
+                    name: slice.slice(start..end).to_string(),
+                    kind: kind.to_string(),
+                    range: start..end,
+                    start: position(start),
+                    end: position(end),
+                };
+                Some((mat.pattern_index, tag))
+            })
+            .collect();
+
+        // A name matched by several patterns is tagged by the first one, e.g. a function in an
+        // impl block is a method rather than a function.
+        tags.sort_by_key(|(pattern_index, tag)| (tag.range.start, *pattern_index));
+        tags.dedup_by(|(_, tag), (_, first)| tag.range == first.range);
+        tags.into_iter().map(|(_, tag)| tag).collect()
+    }
+
+    /// Parses `source` on its own and returns its definitions, for files that aren't open.
+    pub fn parse_definitions(&self, source: &Rope) -> Vec<Tag> {
+        let mut parser = Parser::new();
+        if parser.set_language(self.language).is_err() {
+            return Vec::new();
+        }
+        let tree = parser.parse_with(
+            &mut |byte, _| {
+                if byte <= source.len_bytes() {
+                    let (chunk, start_byte, _, _) = source.chunk_at_byte(byte);
+                    &chunk.as_bytes()[byte - start_byte..]
+                } else {
+                    &[]
+                }
+            },
+            None,
+        );
+        match tree {
+            Some(tree) => self.definitions(tree.root_node(), source.slice(..)),
+            None => Vec::new(),
+        }
+    }
+}
+
 fn load_runtime_file(language: &str, filename: &str) -> Result<String, std::io::Error> {
     let path = crate::RUNTIME_DIR
         .join("queries")
@@ -247,17 +532,89 @@ impl LanguageConfiguration {
             .as_ref()
     }
 
+    pub fn linked_editing_query(&self) -> Option<&LinkedEditingQuery> {
+        self.linked_editing_query
+            .get_or_init(|| -> Option<LinkedEditingQuery> {
+                let lang_name = self.language_id.to_ascii_lowercase();
+                let query_text = read_query(&lang_name, "linked-editing.scm");
+                let lang = self.highlight_config.get()?.as_ref()?.language;
+                let query = Query::new(lang, &query_text).ok()?;
+                Some(LinkedEditingQuery { query })
+            })
+            .as_ref()
+    }
+
+    pub fn context_query(&self) -> Option<&ContextQuery> {
+        self.context_query
+            .get_or_init(|| -> Option<ContextQuery> {
+                let lang_name = self.language_id.to_ascii_lowercase();
+                let query_text = read_query(&lang_name, "context.scm");
+                if query_text.is_empty() {
+                    return None;
+                }
+                let lang = self.highlight_config.get()?.as_ref()?.language;
+                ContextQuery::new(lang, &query_text)
+                    .map_err(|e| log::error!("invalid context.scm for {}: {}", lang_name, e))
+                    .ok()
+            })
+            .as_ref()
+    }
+
+    pub fn rainbow_query(&self) -> Option<&RainbowQuery> {
+        self.rainbow_query
+            .get_or_init(|| -> Option<RainbowQuery> {
+                let lang_name = self.language_id.to_ascii_lowercase();
+                let query_text = read_query(&lang_name, "rainbows.scm");
+                if query_text.is_empty() {
+                    return None;
+                }
+                let lang = self.highlight_config.get()?.as_ref()?.language;
+                RainbowQuery::new(lang, &query_text)
+                    .map_err(|e| log::error!("invalid rainbows.scm for {}: {}", lang_name, e))
+                    .ok()
+            })
+            .as_ref()
+    }
+
+    pub fn tags_query(&self) -> Option<&TagsQuery> {
+        self.tags_query
+            .get_or_init(|| -> Option<TagsQuery> {
+                let lang_name = self.language_id.to_ascii_lowercase();
+                let query_text = read_query(&lang_name, "tags.scm");
+                if query_text.is_empty() {
+                    return None;
+                }
+                // unlike the other queries this one is also used for files that aren't open, so
+                // the grammar may not be loaded yet
+                let language = get_language(&crate::RUNTIME_DIR, &self.language_id)
+                    .map_err(|e| log::info!("{}", e))
+                    .ok()?;
+                let query = Query::new(language, &query_text)
+                    .map_err(|e| log::error!("invalid tags.scm for {}: {}", lang_name, e))
+                    .ok()?;
+                Some(TagsQuery { query, language })
+            })
+            .as_ref()
+    }
+
     pub fn scope(&self) -> &str {
         &self.scope
     }
 }
 
+/// Whether a `file-types` entry is a glob pattern rather than a file name or an extension.
+fn is_glob(file_type: &str) -> bool {
+    file_type.contains(['*', '?', '[', '{'])
+}
+
 #[derive(Debug)]
 pub struct Loader {
     // highlight_names ?
     language_configs: Vec<Arc<LanguageConfiguration>>,
     language_config_ids_by_file_type: HashMap<String, usize>, // Vec<usize>
     language_config_ids_by_shebang: HashMap<String, usize>,
+    /// Glob patterns of file types, the most specific (longest) first.
+    language_config_globs: Vec<(String, GlobMatcher, usize)>,
 }
 
 impl Loader {
@@ -266,6 +623,7 @@ impl Loader {
             language_configs: Vec::new(),
             language_config_ids_by_file_type: HashMap::new(),
             language_config_ids_by_shebang: HashMap::new(),
+            language_config_globs: Vec::new(),
         };
 
         for config in config.language {
@@ -273,6 +631,28 @@ impl Loader {
             let language_id = loader.language_configs.len();
 
             for file_type in &config.file_types {
+                if is_glob(file_type) {
+                    // patterns with a directory match the end of the path, others the file name
+                    let pattern = if file_type.contains('/') && !file_type.starts_with('/') {
+                        format!("**/{}", file_type)
+                    } else {
+                        file_type.clone()
+                    };
+                    match GlobBuilder::new(&pattern).literal_separator(true).build() {
+                        Ok(glob) => loader.language_config_globs.push((
+                            file_type.clone(),
+                            glob.compile_matcher(),
+                            language_id,
+                        )),
+                        Err(err) => log::error!(
+                            "Invalid file type `{}` of {}: {}",
+                            file_type,
+                            config.language_id,
+                            err
+                        ),
+                    }
+                    continue;
+                }
                 // entry().or_insert(Vec::new).push(language_id);
                 loader
                     .language_config_ids_by_file_type
@@ -287,25 +667,68 @@ impl Loader {
             loader.language_configs.push(Arc::new(config));
         }
 
+        // stable, so the patterns declared later still come first among equally long ones
+        loader.language_config_globs.reverse();
         loader
+            .language_config_globs
+            .sort_by_key(|(pattern, _, _)| std::cmp::Reverse(pattern.len()));
+
+        loader
+    }
+
+    /// Detects the language of a document, trying in order:
+    ///
+    /// 1. a vim or emacs modeline in the first or last 5 lines,
+    /// 2. the exact file name,
+    /// 3. the glob patterns, the longest first,
+    /// 4. the extension, the longest compound one first,
+    /// 5. the shebang,
+    /// 6. the `first-line-regex` of the languages.
+    ///
+    /// When several languages declare the same file type, the one declared last wins, so the
+    /// languages of the user's `languages.toml` override the built-in ones.
+    pub fn language_config_for_document(
+        &self,
+        path: Option<&Path>,
+        source: &Rope,
+    ) -> Option<Arc<LanguageConfiguration>> {
+        self.language_config_for_modeline(source)
+            .or_else(|| path.and_then(|path| self.language_config_for_file_name(path)))
+            .or_else(|| self.language_config_for_shebang(source))
+            .or_else(|| self.language_config_for_first_line(source))
     }
 
     pub fn language_config_for_file_name(&self, path: &Path) -> Option<Arc<LanguageConfiguration>> {
+        let file_name = path.file_name().and_then(|n| n.to_str())?;
         // Find all the language configurations that match this file name
         // or a suffix of the file name.
-        let configuration_id = path
-            .file_name()
-            .and_then(|n| n.to_str())
-            .and_then(|file_name| self.language_config_ids_by_file_type.get(file_name))
+        let configuration_id = self
+            .language_config_ids_by_file_type
+            .get(file_name)
+            .or_else(|| {
+                self.language_config_globs
+                    .iter()
+                    .find(|(pattern, glob, _)| {
+                        if pattern.contains('/') {
+                            glob.is_match(path)
+                        } else {
+                            glob.is_match(file_name)
+                        }
+                    })
+                    .map(|(_, _, id)| id)
+            })
             .or_else(|| {
-                path.extension()
-                    .and_then(|extension| extension.to_str())
-                    .and_then(|extension| self.language_config_ids_by_file_type.get(extension))
+                // `blade.php` before `php` for `index.blade.php`
+                file_name
+                    .match_indices('.')
+                    .filter(|&(i, _)| i > 0)
+                    .find_map(|(i, _)| {
+                        self.language_config_ids_by_file_type
+                            .get(&file_name[i + 1..])
+                    })
             });
 
         configuration_id.and_then(|&id| self.language_configs.get(id).cloned())
-
-        // TODO: content_regex handling conflict resolution
     }
 
     pub fn language_config_for_shebang(&self, source: &Rope) -> Option<Arc<LanguageConfiguration>> {
@@ -319,6 +742,67 @@ impl Loader {
         configuration_id.and_then(|&id| self.language_configs.get(id).cloned())
     }
 
+    /// Finds the language named by a vim (`vim: set ft=python:`) or emacs (`-*- mode: python -*-`)
+    /// modeline in the first or last 5 lines of `source`.
+    pub fn language_config_for_modeline(
+        &self,
+        source: &Rope,
+    ) -> Option<Arc<LanguageConfiguration>> {
+        static MODELINE_REGEX: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(concat!(
+                r"(?:^|\s)(?:vim?|ex):.*?\b(?:ft|filetype|syn|syntax)=([^\s:]+)",
+                r"|-\*-(?:.*?\bmode:\s*([^\s;]+)[^\n]*?|\s*([^\s:;]+)\s*)-\*-",
+            ))
+            .unwrap()
+        });
+        const MODELINES: usize = 5;
+
+        let len_lines = source.len_lines();
+        let lines = (0..len_lines.min(MODELINES))
+            .chain(len_lines.saturating_sub(MODELINES).max(MODELINES)..len_lines);
+        lines
+            .filter_map(|line| {
+                let line = Cow::from(source.line(line));
+                let captures = MODELINE_REGEX.captures(&line)?;
+                let name = captures.iter().skip(1).flatten().next()?.as_str();
+                self.language_config_for_language_name(name).or_else(|| {
+                    let &id = self.language_config_ids_by_file_type.get(name)?;
+                    self.language_configs.get(id).cloned()
+                })
+            })
+            .next()
+    }
+
+    /// Finds the language whose `first-line-regex` matches the first line of `source`.
+    pub fn language_config_for_first_line(
+        &self,
+        source: &Rope,
+    ) -> Option<Arc<LanguageConfiguration>> {
+        let line = Cow::from(source.line(0));
+        self.language_configs
+            .iter()
+            .rev()
+            .find(|config| matches!(&config.first_line_regex, Some(regex) if regex.is_match(&line)))
+            .cloned()
+    }
+
+    /// The configurations of all the languages, in the order they were declared.
+    pub fn language_configs(&self) -> impl Iterator<Item = &Arc<LanguageConfiguration>> {
+        self.language_configs.iter()
+    }
+
+    /// Finds the language with the `name` from `languages.toml`, ignoring case.
+    pub fn language_config_for_language_name(
+        &self,
+        name: &str,
+    ) -> Option<Arc<LanguageConfiguration>> {
+        self.language_configs
+            .iter()
+            .rev()
+            .find(|config| config.language_id.eq_ignore_ascii_case(name))
+            .cloned()
+    }
+
     pub fn language_config_for_scope(&self, scope: &str) -> Option<Arc<LanguageConfiguration>> {
         self.language_configs
             .iter()
@@ -379,6 +863,134 @@ pub struct Syntax {
     config: Arc<HighlightConfiguration>,
 
     root_layer: LanguageLayer,
+    /// The locals of the root layer, resolved lazily for the current revision of the tree.
+    locals: OnceCell<Locals>,
+    /// A parse that took longer than [`PARSE_TIMEOUT_MICROS`] and goes on in the background.
+    /// The tree of the root layer is stale until it's done.
+    pending: Option<PendingParse>,
+    on_parsed: ParseCallback,
+}
+
+/// Called from the parsing thread once a background parse is over, see
+/// [`Syntax::set_parse_callback`]. It's shared with the thread, which may have been started
+/// before the callback was set.
+#[derive(Clone, Default)]
+struct ParseCallback(Arc<Mutex<Option<BoxedCallback>>>);
+
+type BoxedCallback = Box<dyn Fn() + Send + Sync>;
+
+impl fmt::Debug for ParseCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ParseCallback")
+    }
+}
+
+/// How far before the highlighted range definitions are looked for when highlighting references,
+/// within the top-level node containing the start of the range.
+const MAX_LOCALS_LOOKBEHIND: usize = 64 * 1024;
+
+/// How long parsing may block the caller. Longer parses are moved to a background thread, and
+/// the previous tree is used in the meantime.
+const PARSE_TIMEOUT_MICROS: u64 = 50_000;
+
+/// A language injected into the document, see [`Syntax::injection_layers`].
+#[derive(Debug)]
+pub struct InjectionLayer {
+    /// The name the injection gives the language.
+    pub language: String,
+    /// How many injections deep the layer is, 1 for the ones in the language of the document.
+    pub depth: usize,
+    pub tree: Tree,
+}
+
+impl InjectionLayer {
+    /// Parses the languages injected into `tree` of the language of `config`, and the ones
+    /// injected into those. The highlighter parses them on the fly instead, this is meant to
+    /// inspect them. It takes the tree by value so it can run on another thread.
+    pub fn parse_all(
+        config: Arc<HighlightConfiguration>,
+        tree: Tree,
+        source: RopeSlice,
+        injection_callback: impl Fn(&str) -> Option<Arc<HighlightConfiguration>>,
+    ) -> Vec<Self> {
+        let mut layers = Vec::new();
+        let mut parser = Parser::new();
+        let ranges = vec![tree.root_node().range()];
+        let mut queue = vec![(config, tree, ranges, 0)];
+
+        while let Some((config, tree, ranges, depth)) = queue.pop() {
+            // the language and content nodes of each injection
+            let mut injections = Vec::new();
+            let mut cursor = QueryCursor::new();
+            for mat in cursor.matches(&config.query, tree.root_node(), RopeProvider(source)) {
+                if mat.pattern_index >= config.locals_pattern_index {
+                    continue;
+                }
+                let (language, content_node, include_children) =
+                    injection_for_match(&config, &config.query, &mat, source);
+                if let (Some(language), Some(content_node)) = (language, content_node) {
+                    injections.push((language, vec![content_node], include_children));
+                }
+            }
+
+            let mut cursor = QueryCursor::new();
+            injections.extend(combined_injections(
+                &config,
+                tree.root_node(),
+                source,
+                &mut cursor,
+            ));
+
+            for (language, content_nodes, include_children) in injections {
+                let config = match injection_callback(&language) {
+                    Some(config) => config,
+                    None => continue,
+                };
+                let injected_ranges =
+                    HighlightIterLayer::intersect_ranges(&ranges, &content_nodes, include_children);
+                // an injection spanning all of its layer would be injected into itself forever
+                if injected_ranges.is_empty()
+                    || injected_ranges == ranges
+                    || parser.set_included_ranges(&injected_ranges).is_err()
+                    || parser.set_language(config.language).is_err()
+                {
+                    continue;
+                }
+                if let Some(tree) =
+                    parser.parse_with(&mut |byte, _| chunk_from_byte(source, byte), None)
+                {
+                    layers.push(Self {
+                        language: language.into_owned(),
+                        depth: depth + 1,
+                        tree: tree.clone(),
+                    });
+                    queue.push((config, tree, injected_ranges, depth + 1));
+                }
+            }
+        }
+
+        layers.sort_by_key(|layer| (layer.tree.root_node().start_byte(), layer.depth));
+        layers
+    }
+}
+
+#[derive(Debug)]
+struct PendingParse {
+    /// The text being parsed.
+    source: Rope,
+    /// The changes to the document since the parse started, which still have to be applied to
+    /// the resulting tree.
+    changes: ChangeSet,
+    tree: Receiver<Tree>,
+    cancelled: Arc<AtomicUsize>,
+    /// Set by the parsing thread once it's over, before it calls the [`ParseCallback`].
+    done: Arc<AtomicBool>,
+}
+
+impl Drop for PendingParse {
+    fn drop(&mut self) {
+        self.cancelled.store(1, Ordering::Relaxed);
+    }
 }
 
 fn byte_range_to_str(range: std::ops::Range<usize>, source: RopeSlice) -> Cow<str> {
@@ -387,6 +999,17 @@ fn byte_range_to_str(range: std::ops::Range<usize>, source: RopeSlice) -> Cow<st
     Cow::from(source.slice(start_char..end_char))
 }
 
+/// The text of `source` from `byte` to the end of its chunk, as tree-sitter reads it.
+fn chunk_from_byte<'a>(source: RopeSlice<'a>, byte: usize) -> &'a [u8] {
+    if byte <= source.len_bytes() {
+        let (chunk, start_byte, _, _) = source.chunk_at_byte(byte);
+        chunk[byte - start_byte..].as_bytes()
+    } else {
+        // out of range
+        &[]
+    }
+}
+
 impl Syntax {
     // buffer, grammar, config, grammars, sync_timeout?
     pub fn new(
@@ -402,13 +1025,17 @@ impl Syntax {
             // grammar,
             config,
             root_layer,
+            locals: OnceCell::new(),
+            pending: None,
+            on_parsed: ParseCallback::default(),
         };
 
         // update root layer
         PARSER.with(|ts_parser| {
+            let ts_parser = &mut ts_parser.borrow_mut();
             // TODO: handle the returned `Result` properly.
-            let _ = syntax.root_layer.parse(
-                &mut ts_parser.borrow_mut(),
+            let parsed = syntax.root_layer.parse(
+                ts_parser,
                 &syntax.config,
                 source,
                 0,
@@ -419,6 +1046,11 @@ impl Syntax {
                     end_point: Point::new(usize::MAX, usize::MAX),
                 }],
             );
+            if let Err(Error::Cancelled) = parsed {
+                // nothing is highlighted until the background parse is done
+                syntax.root_layer.tree = ts_parser.parser.parse("", None);
+                syntax.parse_in_background(source, None);
+            }
         });
         syntax
     }
@@ -429,7 +1061,17 @@ impl Syntax {
         source: &Rope,
         changeset: &ChangeSet,
     ) -> Result<(), Error> {
-        PARSER.with(|ts_parser| {
+        self.locals = OnceCell::new();
+
+        if let Some(pending) = &mut self.pending {
+            // keep the stale tree in line with the text, the document is reparsed once the
+            // background parse is done
+            pending.changes = mem::take(&mut pending.changes).compose(changeset.clone());
+            self.root_layer.edit(old_source, changeset);
+            return Ok(());
+        }
+
+        let updated = PARSER.with(|ts_parser| {
             self.root_layer.update(
                 &mut ts_parser.borrow_mut(),
                 &self.config,
@@ -437,11 +1079,116 @@ impl Syntax {
                 source,
                 changeset,
             )
-        })
+        });
+        match updated {
+            Err(Error::Cancelled) => {
+                // the edited tree is used until the background parse is done
+                let old_tree = self.root_layer.tree.clone();
+                self.parse_in_background(source, old_tree);
+                Ok(())
+            }
+            result => result,
+        }
 
         // TODO: deal with injections and update them too
     }
 
+    /// Parses `source` on another thread, reusing `old_tree` if it was edited to match it.
+    fn parse_in_background(&mut self, source: &Rope, old_tree: Option<Tree>) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let cancelled = Arc::new(AtomicUsize::new(0));
+        let language = self.config.language;
+        let text = source.clone();
+        let flag = cancelled.clone();
+        let done = Arc::new(AtomicBool::new(false));
+        let parse_done = done.clone();
+        let on_parsed = self.on_parsed.clone();
+
+        std::thread::spawn(move || {
+            let mut parser = Parser::new();
+            if parser.set_language(language).is_ok() {
+                // the flag is kept alive by the thread for the whole parse
+                unsafe { parser.set_cancellation_flag(Some(&flag)) };
+                let tree = parser.parse_with(
+                    &mut |byte, _| chunk_from_byte(text.slice(..), byte),
+                    old_tree.as_ref(),
+                );
+                if let Some(tree) = tree {
+                    let _ = tx.send(tree);
+                }
+            }
+            // a failed parse is noticed by the disconnected channel
+            drop(tx);
+            parse_done.store(true, Ordering::Release);
+            if let Some(on_parsed) = &*on_parsed.0.lock().unwrap() {
+                on_parsed();
+            }
+        });
+
+        self.pending = Some(PendingParse {
+            source: source.clone(),
+            changes: ChangeSet::new(source),
+            tree: rx,
+            cancelled,
+            done,
+        });
+    }
+
+    pub(crate) fn grammar(&self) -> Grammar {
+        self.config.language
+    }
+
+    /// Parses the languages injected into the document, see [`InjectionLayer::parse_all`].
+    pub fn injection_layers(
+        &self,
+        source: RopeSlice,
+        injection_callback: impl Fn(&str) -> Option<Arc<HighlightConfiguration>>,
+    ) -> Vec<InjectionLayer> {
+        InjectionLayer::parse_all(
+            self.config.clone(),
+            self.tree().clone(),
+            source,
+            injection_callback,
+        )
+    }
+
+    /// Sets a function that's called from the parsing thread whenever a background parse is
+    /// over, so that the result can be picked up with [`Syntax::poll`] right away. It's called
+    /// right away as well if the pending parse was over before there was a callback.
+    pub fn set_parse_callback(&mut self, callback: impl Fn() + Send + Sync + 'static) {
+        let mut on_parsed = self.on_parsed.0.lock().unwrap();
+        let on_parsed = on_parsed.insert(Box::new(callback));
+        // checked under the lock, so either the thread finds the callback or it's seen done here
+        if matches!(&self.pending, Some(pending) if pending.done.load(Ordering::Acquire)) {
+            on_parsed();
+        }
+    }
+
+    /// Whether the tree is stale because the document is being parsed in the background.
+    pub fn is_parsing(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Picks up the tree of a finished background parse, bringing it up to date with `source`.
+    /// Returns whether the tree changed.
+    pub fn poll(&mut self, source: &Rope) -> bool {
+        let tree = match self.pending.as_ref().map(|pending| pending.tree.try_recv()) {
+            Some(Ok(tree)) => tree,
+            Some(Err(TryRecvError::Disconnected)) => {
+                // the parse failed, the stale tree is kept until the next edit
+                self.pending = None;
+                return false;
+            }
+            Some(Err(TryRecvError::Empty)) | None => return false,
+        };
+        let pending = self.pending.take().unwrap();
+        self.root_layer.tree = Some(tree);
+        self.locals = OnceCell::new();
+        if let Err(err) = self.update(&pending.source, source, &pending.changes) {
+            log::error!("failed to update the syntax tree: {:?}", err);
+        }
+        true
+    }
     // fn buffer_changed -> call layer.update(range, new_text) on root layer and then all marker layers
 
     // call this on transaction.apply() -> buffer_changed(changes)
@@ -451,6 +1198,12 @@ impl Syntax {
     pub fn tree(&self) -> &Tree {
         self.root_layer.tree()
     }
+
+    /// The local variable definitions and references of the whole document.
+    pub fn locals(&self, source: RopeSlice) -> &Locals {
+        self.locals
+            .get_or_init(|| Locals::new(&self.config, self.tree(), source))
+    }
     //
     // <!--update_for_injection(grammar)-->
 
@@ -481,6 +1234,22 @@ impl Syntax {
         // if reusing cursors & no range this resets to whole range
         cursor_ref.set_byte_range(range.clone().unwrap_or(0..usize::MAX));
 
+        // When only part of the document is highlighted, the definitions before the range are
+        // never seen by the iterator. They are looked for from the start of the top-level node
+        // containing the range, like the function the view is in.
+        let mut locals = LocalResolver::new();
+        if let (Some(range), Some(_)) = (&range, config_ref.local_ref_capture_index) {
+            let root = tree_ref.root_node();
+            let mut cursor = root.walk();
+            let start = cursor
+                .goto_first_child_for_byte(range.start)
+                .map_or(range.start, |_| cursor.node().start_byte())
+                .max(range.start.saturating_sub(MAX_LOCALS_LOOKBEHIND));
+            if start < range.start {
+                locals.scan(config_ref, root, source, start..range.start, |_, _| ());
+            }
+        }
+
         let captures = cursor_ref
             .captures(query_ref, tree_ref.root_node(), RopeProvider(source))
             .peekable();
@@ -488,11 +1257,7 @@ impl Syntax {
         // manually craft the root layer based on the existing tree
         let layer = HighlightIterLayer {
             highlight_end_stack: Vec::new(),
-            scope_stack: vec![LocalScope {
-                inherits: false,
-                range: 0..usize::MAX,
-                local_defs: Vec::new(),
-            }],
+            locals,
             cursor,
             depth: 0,
             _tree: None,
@@ -573,23 +1338,21 @@ impl LanguageLayer {
                 .map_err(|_| Error::InvalidLanguage)?;
 
             // unsafe { syntax.parser.set_cancellation_flag(cancellation_flag) };
-            let tree = ts_parser
-                .parser
-                .parse_with(
-                    &mut |byte, _| {
-                        if byte <= source.len_bytes() {
-                            let (chunk, start_byte, _, _) = source.chunk_at_byte(byte);
-                            chunk[byte - start_byte..].as_bytes()
-                        } else {
-                            // out of range
-                            &[]
-                        }
-                    },
-                    self.tree.as_ref(),
-                )
-                .ok_or(Error::Cancelled)?;
-
-            self.tree = Some(tree)
+            ts_parser.parser.set_timeout_micros(PARSE_TIMEOUT_MICROS);
+            let tree = ts_parser.parser.parse_with(
+                &mut |byte, _| chunk_from_byte(source.slice(..), byte),
+                self.tree.as_ref(),
+            );
+            ts_parser.parser.set_timeout_micros(0);
+
+            match tree {
+                Some(tree) => self.tree = Some(tree),
+                None => {
+                    // don't resume the timed out parse next time
+                    ts_parser.parser.reset();
+                    return Err(Error::Cancelled);
+                }
+            }
         }
         Ok(())
     }
@@ -701,6 +1464,17 @@ impl LanguageLayer {
         edits
     }
 
+    /// Notifies the tree about the changes, without reparsing.
+    fn edit(&mut self, old_source: &Rope, changeset: &ChangeSet) {
+        let edits = Self::generate_edits(old_source.slice(..), changeset);
+
+        for edit in edits.iter().rev() {
+            // apply the edits in reverse. If we applied them in order then edit 1 would disrupt
+            // the positioning of edit 2
+            self.tree.as_mut().unwrap().edit(edit);
+        }
+    }
+
     fn update(
         &mut self,
         ts_parser: &mut TsParser,
@@ -713,14 +1487,7 @@ impl LanguageLayer {
             return Ok(());
         }
 
-        let edits = Self::generate_edits(old_source.slice(..), changeset);
-
-        // Notify the tree about all the changes
-        for edit in edits.iter().rev() {
-            // apply the edits in reverse. If we applied them in order then edit 1 would disrupt
-            // the positioning of edit 2
-            self.tree.as_mut().unwrap().edit(edit);
-        }
+        self.edit(old_source, changeset);
 
         self.parse(
             ts_parser,
@@ -772,7 +1539,7 @@ impl LanguageLayer {
 // For now cheat and just throw out non-root layers if they exist. This should still improve
 // parsing in majority of cases.
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::{iter, mem, ops, str, usize};
 use tree_sitter::{
     Language as Grammar, Node, Parser, Point, Query, QueryCaptures, QueryCursor, QueryError,
@@ -821,18 +1588,289 @@ pub struct HighlightConfiguration {
     local_ref_capture_index: Option<u32>,
 }
 
+/// A definition in a [`LocalScope`].
 #[derive(Debug)]
-struct LocalDef<'a> {
-    name: Cow<'a, str>,
+struct LocalDef {
     value_range: ops::Range<usize>,
     highlight: Option<Highlight>,
+    /// The index of the definition in the order they were found, see [`Locals`].
+    index: usize,
 }
 
 #[derive(Debug)]
 struct LocalScope<'a> {
     inherits: bool,
     range: ops::Range<usize>,
-    local_defs: Vec<LocalDef<'a>>,
+    /// The definitions by name, in order.
+    local_defs: HashMap<Cow<'a, str>, Vec<LocalDef>>,
+}
+
+/// What a capture of the locals patterns turned out to be, see [`LocalResolver::scan`].
+enum LocalCapture {
+    Definition,
+    /// A reference to the definition with this index.
+    Reference(usize),
+}
+
+/// The local scopes enclosing the current capture and their definitions, following the
+/// `locals.scm` query of the language. It resolves references for the highlighter and for
+/// [`Locals`].
+#[derive(Debug)]
+struct LocalResolver<'a> {
+    scope_stack: Vec<LocalScope<'a>>,
+    /// The scope, name and position of the last definition, which gets the highlight of its
+    /// node.
+    last_definition: Option<(usize, Cow<'a, str>, usize)>,
+    definitions: usize,
+    /// The scopes starting before this byte were pushed by [`LocalResolver::scan`] already.
+    scanned_until: usize,
+}
+
+impl<'a> LocalResolver<'a> {
+    fn new() -> Self {
+        Self {
+            scope_stack: vec![LocalScope {
+                inherits: false,
+                range: 0..usize::MAX,
+                local_defs: HashMap::new(),
+            }],
+            last_definition: None,
+            definitions: 0,
+            scanned_until: 0,
+        }
+    }
+
+    /// Pops the scopes that ended before `start`.
+    fn leave_scopes(&mut self, start: usize) {
+        while start > self.scope_stack.last().unwrap().range.end {
+            self.scope_stack.pop();
+        }
+    }
+
+    fn push_scope(
+        &mut self,
+        config: &HighlightConfiguration,
+        pattern_index: usize,
+        range: ops::Range<usize>,
+    ) {
+        if range.start < self.scanned_until {
+            return;
+        }
+        let mut scope = LocalScope {
+            inherits: true,
+            range,
+            local_defs: HashMap::new(),
+        };
+        for prop in config.query.property_settings(pattern_index) {
+            if let "local.scope-inherits" = prop.key.as_ref() {
+                scope.inherits = prop.value.as_ref().map_or(true, |r| r.as_ref() == "true");
+            }
+        }
+        self.scope_stack.push(scope);
+    }
+
+    /// Adds the definition of the name at `range` captured by `match_` to the innermost scope.
+    fn define(
+        &mut self,
+        config: &HighlightConfiguration,
+        match_: &QueryMatch,
+        range: ops::Range<usize>,
+        source: RopeSlice<'a>,
+    ) {
+        let mut value_range = 0..0;
+        for capture in match_.captures {
+            if Some(capture.index) == config.local_def_value_capture_index {
+                value_range = capture.node.byte_range();
+            }
+        }
+
+        let name = byte_range_to_str(range, source);
+        let scope = self.scope_stack.len() - 1;
+        let local_defs = self.scope_stack[scope]
+            .local_defs
+            .entry(name.clone())
+            .or_default();
+        local_defs.push(LocalDef {
+            value_range,
+            highlight: None,
+            index: self.definitions,
+        });
+        self.last_definition = Some((scope, name, local_defs.len() - 1));
+        self.definitions += 1;
+    }
+
+    /// Sets the highlight of the last definition.
+    fn set_definition_highlight(&mut self, highlight: Option<Highlight>) {
+        if let Some((scope, name, i)) = &self.last_definition {
+            if let Some(local_defs) = self
+                .scope_stack
+                .get_mut(*scope)
+                .and_then(|scope| scope.local_defs.get_mut(name))
+            {
+                local_defs[*i].highlight = highlight;
+            }
+        }
+    }
+
+    /// The definition a reference at `range` resolves to.
+    fn resolve(&self, range: &ops::Range<usize>, source: RopeSlice<'a>) -> Option<&LocalDef> {
+        let name = byte_range_to_str(range.clone(), source);
+        for scope in self.scope_stack.iter().rev() {
+            let definition = scope.local_defs.get(&name).and_then(|local_defs| {
+                local_defs
+                    .iter()
+                    .rev()
+                    .find(|definition| range.start >= definition.value_range.end)
+            });
+            if definition.is_some() {
+                return definition;
+            }
+            if !scope.inherits {
+                break;
+            }
+        }
+        None
+    }
+
+    /// Goes through the locals captures of `range` like the highlighter does, calling
+    /// `on_capture` for every definition and resolved reference. The scopes still open at the
+    /// end of the range are kept.
+    fn scan(
+        &mut self,
+        config: &HighlightConfiguration,
+        root: Node,
+        source: RopeSlice<'a>,
+        range: ops::Range<usize>,
+        mut on_capture: impl FnMut(ops::Range<usize>, LocalCapture),
+    ) {
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(range.clone());
+        let mut captures = cursor
+            .captures(&config.query, root, RopeProvider(source))
+            .peekable();
+
+        'main: while let Some((mut match_, capture_index)) = captures.next() {
+            let mut capture = match_.captures[capture_index];
+            if match_.pattern_index < config.locals_pattern_index {
+                match_.remove();
+                continue;
+            }
+
+            let range = capture.node.byte_range();
+            self.leave_scopes(range.start);
+
+            let mut definition = false;
+            while match_.pattern_index < config.highlights_pattern_index {
+                if Some(capture.index) == config.local_scope_capture_index {
+                    definition = false;
+                    self.push_scope(config, match_.pattern_index, range.clone());
+                } else if Some(capture.index) == config.local_def_capture_index {
+                    self.define(config, &match_, range.clone(), source);
+                    on_capture(range.clone(), LocalCapture::Definition);
+                    definition = true;
+                } else if Some(capture.index) == config.local_ref_capture_index && !definition {
+                    if let Some(definition) = self.resolve(&range, source) {
+                        on_capture(range.clone(), LocalCapture::Reference(definition.index));
+                    }
+                }
+
+                match captures.peek() {
+                    Some((next_match, next_capture_index))
+                        if next_match.captures[*next_capture_index].node == capture.node =>
+                    {
+                        capture = next_match.captures[*next_capture_index];
+                        match_ = captures.next().unwrap().0;
+                    }
+                    _ => continue 'main,
+                }
+            }
+
+            // the definition gets the first highlight that isn't disabled for local variables
+            if definition {
+                while config.non_local_variable_patterns[match_.pattern_index] {
+                    match captures.peek() {
+                        Some((next_match, next_capture_index))
+                            if next_match.captures[*next_capture_index].node == capture.node =>
+                        {
+                            capture = next_match.captures[*next_capture_index];
+                            match_ = captures.next().unwrap().0;
+                        }
+                        _ => continue 'main,
+                    }
+                }
+                let highlight = config.highlight_indices.load()[capture.index as usize];
+                self.set_definition_highlight(highlight);
+            }
+        }
+        self.scanned_until = range.end;
+    }
+}
+
+/// A local variable definition, see [`Locals`].
+#[derive(Debug)]
+pub struct LocalDefinition {
+    /// The byte range of the defined name.
+    pub range: ops::Range<usize>,
+}
+
+/// The local variable definitions of a syntax tree, and the references to them, as described
+/// by the `locals.scm` query of the language.
+#[derive(Debug, Default)]
+pub struct Locals {
+    /// Definitions in document order.
+    definitions: Vec<LocalDefinition>,
+    /// The byte range of each reference and the index of its definition, in document order.
+    references: Vec<(ops::Range<usize>, usize)>,
+}
+
+impl Locals {
+    /// Resolves the locals of `tree` with the same scoping rules the highlighter uses.
+    fn new(config: &HighlightConfiguration, tree: &Tree, source: RopeSlice) -> Self {
+        let mut locals = Self::default();
+        if config.local_ref_capture_index.is_none() {
+            return locals;
+        }
+
+        let mut resolver = LocalResolver::new();
+        let range = 0..source.len_bytes();
+        resolver.scan(
+            config,
+            tree.root_node(),
+            source,
+            range,
+            |range, capture| match capture {
+                LocalCapture::Definition => locals.definitions.push(LocalDefinition { range }),
+                LocalCapture::Reference(index) => locals.references.push((range, index)),
+            },
+        );
+        locals
+    }
+
+    pub fn definitions(&self) -> &[LocalDefinition] {
+        &self.definitions
+    }
+
+    /// The index of the definition at `byte`, or of the definition referenced at `byte`.
+    pub fn definition_at(&self, byte: usize) -> Option<usize> {
+        let contains = |range: &ops::Range<usize>| range.start <= byte && byte < range.end;
+        self.definitions
+            .iter()
+            .position(|definition| contains(&definition.range))
+            .or_else(|| {
+                self.references
+                    .iter()
+                    .find(|(range, _)| contains(range))
+                    .map(|&(_, index)| index)
+            })
+    }
+
+    /// The byte ranges of all references to the definition at `index`.
+    pub fn references(&self, index: usize) -> impl Iterator<Item = ops::Range<usize>> + '_ {
+        self.references
+            .iter()
+            .filter(move |(_, definition)| *definition == index)
+            .map(|(range, _)| range.clone())
+    }
 }
 
 #[derive(Debug)]
@@ -851,7 +1889,7 @@ where
 }
 
 // Adapter to convert rope chunks to bytes
-struct ChunksBytes<'a> {
+pub(crate) struct ChunksBytes<'a> {
     chunks: ropey::iter::Chunks<'a>,
 }
 impl<'a> Iterator for ChunksBytes<'a> {
@@ -861,7 +1899,7 @@ impl<'a> Iterator for ChunksBytes<'a> {
     }
 }
 
-struct RopeProvider<'a>(RopeSlice<'a>);
+pub(crate) struct RopeProvider<'a>(pub(crate) RopeSlice<'a>);
 impl<'a> TextProvider<'a> for RopeProvider<'a> {
     type I = ChunksBytes<'a>;
 
@@ -881,7 +1919,7 @@ struct HighlightIterLayer<'a> {
     captures: iter::Peekable<QueryCaptures<'a, 'a, RopeProvider<'a>>>,
     config: &'a HighlightConfiguration,
     highlight_end_stack: Vec<usize>,
-    scope_stack: Vec<LocalScope<'a>>,
+    locals: LocalResolver<'a>,
     ranges: Vec<Range>,
     depth: usize,
 }
@@ -1105,49 +2143,14 @@ impl<'a> HighlightIterLayer<'a> {
                     let mut cursor = highlighter.cursors.pop().unwrap_or_else(QueryCursor::new);
 
                     // Process combined injections.
-                    if let Some(combined_injections_query) = &config.combined_injections_query {
-                        let mut injections_by_pattern_index = vec![
-                            (None, Vec::new(), false);
-                            combined_injections_query
-                                .pattern_count()
-                        ];
-                        let matches = cursor.matches(
-                            combined_injections_query,
-                            tree.root_node(),
-                            RopeProvider(source),
-                        );
-                        for mat in matches {
-                            let entry = &mut injections_by_pattern_index[mat.pattern_index];
-                            let (language_name, content_node, include_children) =
-                                injection_for_match(
-                                    config,
-                                    combined_injections_query,
-                                    &mat,
-                                    source,
-                                );
-                            if language_name.is_some() {
-                                entry.0 = language_name;
-                            }
-                            if let Some(content_node) = content_node {
-                                entry.1.push(content_node);
-                            }
-                            entry.2 = include_children;
-                        }
-                        for (lang_name, content_nodes, includes_children) in
-                            injections_by_pattern_index
-                        {
-                            if let (Some(lang_name), false) = (lang_name, content_nodes.is_empty())
-                            {
-                                if let Some(next_config) = (injection_callback)(&lang_name) {
-                                    let ranges = Self::intersect_ranges(
-                                        &ranges,
-                                        &content_nodes,
-                                        includes_children,
-                                    );
-                                    if !ranges.is_empty() {
-                                        queue.push((next_config, depth + 1, ranges));
-                                    }
-                                }
+                    for (lang_name, content_nodes, includes_children) in
+                        combined_injections(config, tree.root_node(), source, &mut cursor)
+                    {
+                        if let Some(next_config) = (injection_callback)(&lang_name) {
+                            let ranges =
+                                Self::intersect_ranges(&ranges, &content_nodes, includes_children);
+                            if !ranges.is_empty() {
+                                queue.push((next_config, depth + 1, ranges));
                             }
                         }
                     }
@@ -1166,11 +2169,7 @@ impl<'a> HighlightIterLayer<'a> {
 
                     result.push(HighlightIterLayer {
                         highlight_end_stack: Vec::new(),
-                        scope_stack: vec![LocalScope {
-                            inherits: false,
-                            range: 0..usize::MAX,
-                            local_defs: Vec::new(),
-                        }],
+                        locals: LocalResolver::new(),
                         cursor,
                         depth,
                         _tree: Some(tree),
@@ -1498,74 +2497,36 @@ where
             }
 
             // Remove from the local scope stack any local scopes that have already ended.
-            while range.start > layer.scope_stack.last().unwrap().range.end {
-                layer.scope_stack.pop();
-            }
+            layer.locals.leave_scopes(range.start);
 
             // If this capture is for tracking local variables, then process the
             // local variable info.
             let mut reference_highlight = None;
-            let mut definition_highlight = None;
+            let mut definition = false;
             while match_.pattern_index < layer.config.highlights_pattern_index {
                 // If the node represents a local scope, push a new local scope onto
                 // the scope stack.
                 if Some(capture.index) == layer.config.local_scope_capture_index {
-                    definition_highlight = None;
-                    let mut scope = LocalScope {
-                        inherits: true,
-                        range: range.clone(),
-                        local_defs: Vec::new(),
-                    };
-                    for prop in layer.config.query.property_settings(match_.pattern_index) {
-                        if let "local.scope-inherits" = prop.key.as_ref() {
-                            scope.inherits =
-                                prop.value.as_ref().map_or(true, |r| r.as_ref() == "true");
-                        }
-                    }
-                    layer.scope_stack.push(scope);
+                    definition = false;
+                    layer
+                        .locals
+                        .push_scope(layer.config, match_.pattern_index, range.clone());
                 }
                 // If the node represents a definition, add a new definition to the
                 // local scope at the top of the scope stack.
                 else if Some(capture.index) == layer.config.local_def_capture_index {
                     reference_highlight = None;
-                    let scope = layer.scope_stack.last_mut().unwrap();
-
-                    let mut value_range = 0..0;
-                    for capture in match_.captures {
-                        if Some(capture.index) == layer.config.local_def_value_capture_index {
-                            value_range = capture.node.byte_range();
-                        }
-                    }
-
-                    let name = byte_range_to_str(range.clone(), self.source);
-                    scope.local_defs.push(LocalDef {
-                        name,
-                        value_range,
-                        highlight: None,
-                    });
-                    definition_highlight = scope.local_defs.last_mut().map(|s| &mut s.highlight);
+                    layer
+                        .locals
+                        .define(layer.config, &match_, range.clone(), self.source);
+                    definition = true;
                 }
                 // If the node represents a reference, then try to find the corresponding
                 // definition in the scope stack.
-                else if Some(capture.index) == layer.config.local_ref_capture_index
-                    && definition_highlight.is_none()
+                else if Some(capture.index) == layer.config.local_ref_capture_index && !definition
                 {
-                    definition_highlight = None;
-                    let name = byte_range_to_str(range.clone(), self.source);
-                    for scope in layer.scope_stack.iter().rev() {
-                        if let Some(highlight) = scope.local_defs.iter().rev().find_map(|def| {
-                            if def.name == name && range.start >= def.value_range.end {
-                                Some(def.highlight)
-                            } else {
-                                None
-                            }
-                        }) {
-                            reference_highlight = highlight;
-                            break;
-                        }
-                        if !scope.inherits {
-                            break;
-                        }
+                    if let Some(definition) = layer.locals.resolve(&range, self.source) {
+                        reference_highlight = definition.highlight;
                     }
                 }
 
@@ -1595,7 +2556,7 @@ where
 
             // If the current node was found to be a local variable, then skip over any
             // highlighting patterns that are disabled for local variables.
-            if definition_highlight.is_some() || reference_highlight.is_some() {
+            if definition || reference_highlight.is_some() {
                 while layer.config.non_local_variable_patterns[match_.pattern_index] {
                     if let Some((next_match, next_capture_index)) = layer.captures.peek() {
                         let next_capture = next_match.captures[*next_capture_index];
@@ -1629,8 +2590,8 @@ where
 
             // If this node represents a local definition, then store the current
             // highlight value on the local scope entry representing this node.
-            if let Some(definition_highlight) = definition_highlight {
-                *definition_highlight = current_highlight;
+            if definition {
+                layer.locals.set_definition_highlight(current_highlight);
             }
 
             // Emit a scope start event and push the node's end position to the stack.
@@ -1646,6 +2607,47 @@ where
     }
 }
 
+/// Collects the combined injections of a layer, where the content nodes of all the matches of a
+/// pattern are parsed as one document. Returns the language, the content nodes and whether their
+/// children are included for each pattern that matched.
+fn combined_injections<'a>(
+    config: &'a HighlightConfiguration,
+    root: Node<'a>,
+    source: RopeSlice<'a>,
+    cursor: &'a mut QueryCursor,
+) -> Vec<(Cow<'a, str>, Vec<Node<'a>>, bool)> {
+    let query = match &config.combined_injections_query {
+        Some(query) => query,
+        None => return Vec::new(),
+    };
+
+    let mut injections_by_pattern_index = vec![(None, Vec::new(), false); query.pattern_count()];
+    for mat in cursor.matches(query, root, RopeProvider(source)) {
+        let entry = &mut injections_by_pattern_index[mat.pattern_index];
+        let (language_name, content_node, include_children) =
+            injection_for_match(config, query, &mat, source);
+        if language_name.is_some() {
+            entry.0 = language_name;
+        }
+        if let Some(content_node) = content_node {
+            entry.1.push(content_node);
+        }
+        entry.2 = include_children;
+    }
+
+    injections_by_pattern_index
+        .into_iter()
+        .filter_map(|(language_name, content_nodes, include_children)| {
+            match (language_name, content_nodes.is_empty()) {
+                (Some(language_name), false) => {
+                    Some((language_name, content_nodes, include_children))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
 fn injection_for_match<'a>(
     config: &HighlightConfiguration,
     query: &'a Query,
@@ -1869,11 +2871,11 @@ mod test {
         let config = HighlightConfiguration::new(
             language,
             &std::fs::read_to_string(
-                "../helix-syntax/languages/tree-sitter-rust/queries/highlights.scm",
+                "../runtime/grammars/sources/tree-sitter-rust/queries/highlights.scm",
             )
             .unwrap(),
             &std::fs::read_to_string(
-                "../helix-syntax/languages/tree-sitter-rust/queries/injections.scm",
+                "../runtime/grammars/sources/tree-sitter-rust/queries/injections.scm",
             )
             .unwrap(),
             "", // locals.scm
@@ -1905,6 +2907,172 @@ mod test {
         assert_eq!(struct_node.kind(), "struct_item");
     }
 
+    #[test]
+    fn test_locals() {
+        let highlight_names: Vec<String> = ["variable", "variable.parameter"]
+            .iter()
+            .cloned()
+            .map(String::from)
+            .collect();
+
+        let language = get_language(&crate::RUNTIME_DIR, "Rust").unwrap();
+        let config = HighlightConfiguration::new(
+            language,
+            &std::fs::read_to_string("../runtime/queries/rust/highlights.scm").unwrap(),
+            "",
+            &std::fs::read_to_string("../runtime/queries/rust/locals.scm").unwrap(),
+        )
+        .unwrap();
+        config.configure(&highlight_names);
+
+        let source = Rope::from_str(
+            "fn add(first: u32) -> u32 {\n    let second = 1;\n    first + second\n}\n",
+        );
+        let syntax = Syntax::new(&source, Arc::new(config));
+        let byte = |needle: &str| source.to_string().rfind(needle).unwrap();
+
+        // The parameter is resolved from its use, and the other way around.
+        let locals = syntax.locals(source.slice(..));
+        let first = locals.definition_at(byte("first")).unwrap();
+        assert_eq!(locals.definitions()[first].range, 7..12);
+        assert_eq!(locals.definition_at(7), Some(first));
+        assert_eq!(
+            locals.references(first).collect::<Vec<_>>(),
+            vec![byte("first")..byte("first") + 5]
+        );
+        assert_eq!(locals.definition_at(byte("add")), None);
+
+        // References keep the highlight of a definition outside of the highlighted range.
+        let start = source.line_to_byte(2);
+        let end = source.line_to_byte(3);
+        let highlights: Vec<_> = syntax
+            .highlight_iter(source.slice(..), Some(start..end), None, |_| None)
+            .map(Result::unwrap)
+            .collect();
+        let parameter = highlights.iter().position(|event| {
+            matches!(event, HighlightEvent::Source { start, .. } if *start == byte("first"))
+        });
+        assert!(matches!(
+            parameter.map(|i| highlights[i - 1]),
+            Some(HighlightEvent::HighlightStart(Highlight(1)))
+        ));
+    }
+
+    #[test]
+    fn test_tags() {
+        let language = get_language(&crate::RUNTIME_DIR, "Rust").unwrap();
+        let query = Query::new(
+            language,
+            &std::fs::read_to_string("../runtime/queries/rust/tags.scm").unwrap(),
+        )
+        .unwrap();
+        let tags_query = TagsQuery { query, language };
+
+        let source = Rope::from_str(
+            "struct Point {}\nimpl Point {\n    fn new() -> Self {\n        Point {}\n    }\n}\nfn main() {\n    Point::new();\n}\n",
+        );
+        let tags: Vec<_> = tags_query
+            .parse_definitions(&source)
+            .into_iter()
+            .map(|tag| (tag.name, tag.kind, tag.start))
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                ("Point".into(), "struct".into(), crate::Position::new(0, 7)),
+                ("new".into(), "method".into(), crate::Position::new(2, 7)),
+                ("main".into(), "function".into(), crate::Position::new(6, 3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_background_parse() {
+        let language = get_language(&crate::RUNTIME_DIR, "Rust").unwrap();
+        let config = Arc::new(HighlightConfiguration::new(language, "", "", "").unwrap());
+
+        let mut source = Rope::from("fn one() {}\n");
+        let mut syntax = Syntax::new(&source, config.clone());
+        let (parsed_tx, parsed) = std::sync::mpsc::channel();
+        syntax.set_parse_callback(move || parsed_tx.send(()).unwrap());
+        // pretend the first parse timed out
+        syntax.parse_in_background(&source, None);
+        assert!(syntax.is_parsing());
+
+        // edits made while parsing are applied to the tree once it's done
+        for (pos, text) in [(12, "fn two() {}\n"), (0, "struct Zero;\n")] {
+            let old_source = source.clone();
+            let transaction =
+                Transaction::change(&source, std::iter::once((pos, pos, Some(text.into()))));
+            transaction.apply(&mut source);
+            syntax
+                .update(&old_source, &source, transaction.changes())
+                .unwrap();
+        }
+
+        parsed
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap();
+        assert!(syntax.poll(&source));
+        assert!(!syntax.is_parsing());
+        assert_eq!(
+            syntax.tree().root_node().to_sexp(),
+            Syntax::new(&source, config).tree().root_node().to_sexp()
+        );
+    }
+
+    #[test]
+    fn test_parse_callback_set_after_parse() {
+        let language = get_language(&crate::RUNTIME_DIR, "Rust").unwrap();
+        let config = Arc::new(HighlightConfiguration::new(language, "", "", "").unwrap());
+
+        let source = Rope::from("fn one() {}\n");
+        let mut syntax = Syntax::new(&source, config);
+        syntax.parse_in_background(&source, None);
+        while !syntax
+            .pending
+            .as_ref()
+            .unwrap()
+            .done
+            .load(Ordering::Acquire)
+        {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        // the parse is over already, the callback is called right away
+        let (parsed_tx, parsed) = std::sync::mpsc::channel();
+        syntax.set_parse_callback(move || parsed_tx.send(()).unwrap());
+        assert!(parsed.try_recv().is_ok());
+        assert!(syntax.poll(&source));
+    }
+
+    #[test]
+    fn test_injection_layers() {
+        let language = get_language(&crate::RUNTIME_DIR, "Rust").unwrap();
+        let injections = r#"((line_comment) @injection.content
+            (#set! injection.language "rust"))"#;
+        let config = Arc::new(HighlightConfiguration::new(language, "", injections, "").unwrap());
+
+        let source = Rope::from_str("fn a() {}\n// fn b() {}\n");
+        let syntax = Syntax::new(&source, config.clone());
+        let layers = syntax.injection_layers(source.slice(..), |language| {
+            if language == "rust" {
+                Some(config.clone())
+            } else {
+                None
+            }
+        });
+        // the comment isn't injected into itself again
+        let layers: Vec<_> = layers
+            .iter()
+            .map(|layer| {
+                let root = layer.tree.root_node();
+                (layer.language.as_str(), layer.depth, root.byte_range())
+            })
+            .collect();
+        assert_eq!(layers, [("rust", 1, 10..22)]);
+    }
+
     #[test]
     fn test_input_edits() {
         use tree_sitter::InputEdit;
@@ -1969,4 +3137,103 @@ mod test {
         let results = load_runtime_file("rust", "does-not-exist");
         assert!(results.is_err());
     }
+
+    #[test]
+    fn test_language_detection() {
+        let config: Configuration = toml::from_str(
+            r#"
+            [[language]]
+            name = "php"
+            scope = "source.php"
+            file-types = ["php"]
+            roots = []
+
+            [[language]]
+            name = "blade"
+            scope = "source.blade"
+            file-types = ["blade.php"]
+            roots = []
+
+            [[language]]
+            name = "dockerfile"
+            scope = "source.dockerfile"
+            file-types = ["Dockerfile", "Dockerfile.*"]
+            roots = []
+
+            [[language]]
+            name = "yaml"
+            scope = "source.yaml"
+            file-types = ["yml", "yaml"]
+            roots = []
+
+            [[language]]
+            name = "github-actions"
+            scope = "source.yaml.actions"
+            file-types = [".github/workflows/*.yml"]
+            roots = []
+
+            [[language]]
+            name = "xml"
+            scope = "text.xml"
+            file-types = ["xml"]
+            first-line-regex = "^<\\?xml"
+            roots = []
+
+            [[language]]
+            name = "python"
+            scope = "source.python"
+            file-types = ["py"]
+            shebangs = ["python"]
+            roots = []
+            "#,
+        )
+        .unwrap();
+        let loader = Loader::new(config);
+        let detect = |path: &str, text: &str| {
+            loader
+                .language_config_for_document(Some(Path::new(path)), &Rope::from(text))
+                .map(|config| config.language_id.clone())
+        };
+
+        assert_eq!(detect("index.php", "").as_deref(), Some("php"));
+        assert_eq!(detect("index.blade.php", "").as_deref(), Some("blade"));
+        assert_eq!(detect("Dockerfile", "").as_deref(), Some("dockerfile"));
+        assert_eq!(detect("Dockerfile.dev", "").as_deref(), Some("dockerfile"));
+        assert_eq!(detect("ci.yml", "").as_deref(), Some("yaml"));
+        assert_eq!(
+            detect("/repo/.github/workflows/ci.yml", "").as_deref(),
+            Some("github-actions")
+        );
+        assert_eq!(
+            detect("/repo/workflows/ci.yml", "").as_deref(),
+            Some("yaml")
+        );
+        assert_eq!(
+            detect("layout", "<?xml version=\"1.0\"?>").as_deref(),
+            Some("xml")
+        );
+        assert_eq!(
+            detect("script", "#!/usr/bin/env python\n").as_deref(),
+            Some("python")
+        );
+        assert_eq!(detect("notes", "plain text").as_deref(), None);
+
+        // modelines take precedence over the file name
+        assert_eq!(
+            detect("build.txt", "# vim: set ft=python :\n").as_deref(),
+            Some("python")
+        );
+        assert_eq!(
+            detect("ci.yml", "# -*- mode: Python -*-\n").as_deref(),
+            Some("python")
+        );
+        assert_eq!(
+            detect("conf", "a\nb\nc\nd\ne\nf\ng\n# -*- py -*-\n").as_deref(),
+            Some("python")
+        );
+        assert_eq!(
+            detect("ci.yml", "# -*- coding: utf-8 -*-").as_deref(),
+            Some("yaml")
+        );
+    }
 }
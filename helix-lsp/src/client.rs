@@ -1,17 +1,22 @@
 use crate::{
+    pull_diagnostics::{
+        self, DiagnosticOptions, DocumentDiagnosticReport, PreviousResultId,
+        WorkspaceDiagnosticReport,
+    },
     transport::{Payload, Transport},
-    Call, Error, OffsetEncoding, Result,
+    Call, Error, MessageLog, OffsetEncoding, Result,
 };
 
 use helix_core::{find_root, ChangeSet, Rope};
 use jsonrpc_core as jsonrpc;
 use lsp_types as lsp;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::future::Future;
 use std::process::Stdio;
 use std::sync::{
     atomic::{AtomicU64, Ordering},
-    Arc,
+    Arc, Mutex,
 };
 use tokio::{
     io::{BufReader, BufWriter},
@@ -25,14 +30,36 @@ use tokio::{
 #[derive(Debug)]
 pub struct Client {
     id: usize,
+    name: String,
     _process: Child,
     server_tx: UnboundedSender<Payload>,
     request_counter: AtomicU64,
     pub(crate) capabilities: OnceCell<lsp::ServerCapabilities>,
+    /// The `diagnosticProvider` capability, which `lsp::ServerCapabilities` doesn't know about.
+    diagnostic_provider: OnceCell<DiagnosticOptions>,
     offset_encoding: OffsetEncoding,
     config: Option<Value>,
+    req_timeout: u64,
+    /// The last request of each method in [`SUPERSEDED_REQUESTS`].
+    latest_requests: Mutex<HashMap<&'static str, jsonrpc::Id>>,
+}
+
+/// `initialize` with untyped params and result, to use capabilities `lsp_types` doesn't support.
+enum RawInitialize {}
+
+impl lsp::request::Request for RawInitialize {
+    type Params = Value;
+    type Result = Value;
+    const METHOD: &'static str = <lsp::request::Initialize as lsp::request::Request>::METHOD;
 }
 
+/// Requests that are only useful for the latest position, a new one cancels the previous one.
+const SUPERSEDED_REQUESTS: &[&str] = &[
+    <lsp::request::Completion as lsp::request::Request>::METHOD,
+    <lsp::request::HoverRequest as lsp::request::Request>::METHOD,
+    <lsp::request::SignatureHelpRequest as lsp::request::Request>::METHOD,
+];
+
 impl Client {
     #[allow(clippy::type_complexity)]
     pub fn start(
@@ -40,6 +67,8 @@ impl Client {
         args: &[String],
         config: Option<Value>,
         id: usize,
+        req_timeout: u64,
+        message_log: Arc<MessageLog>,
     ) -> Result<(Self, UnboundedReceiver<(usize, Call)>, Arc<Notify>)> {
         let process = Command::new(cmd)
             .args(args)
@@ -58,16 +87,20 @@ impl Client {
         let stderr = BufReader::new(process.stderr.take().expect("Failed to open stderr"));
 
         let (server_rx, server_tx, initialize_notify) =
-            Transport::start(reader, writer, stderr, id);
+            Transport::start(reader, writer, stderr, id, cmd.to_string(), message_log);
 
         let client = Self {
             id,
+            name: cmd.to_string(),
             _process: process,
             server_tx,
             request_counter: AtomicU64::new(0),
             capabilities: OnceCell::new(),
+            diagnostic_provider: OnceCell::new(),
             offset_encoding: OffsetEncoding::Utf8,
             config,
+            req_timeout,
+            latest_requests: Mutex::new(HashMap::new()),
         };
 
         Ok((client, server_rx, initialize_notify))
@@ -77,6 +110,11 @@ impl Client {
         self.id
     }
 
+    /// The name of the server, the command it was started with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     fn next_request_id(&self) -> jsonrpc::Id {
         let id = self.request_counter.fetch_add(1, Ordering::Relaxed);
         jsonrpc::Id::Num(id)
@@ -103,6 +141,11 @@ impl Client {
             .expect("language server not yet initialized!")
     }
 
+    /// The pull diagnostics options of the server, if it supports them.
+    pub fn diagnostic_provider(&self) -> Option<&DiagnosticOptions> {
+        self.diagnostic_provider.get()
+    }
+
     pub fn offset_encoding(&self) -> OffsetEncoding {
         self.offset_encoding
     }
@@ -129,34 +172,59 @@ impl Client {
     {
         let server_tx = self.server_tx.clone();
         let id = self.next_request_id();
+        let timeout_secs = self.req_timeout;
+
+        // queue the request right away rather than when the future is first polled, so that
+        // requests reach the server in the order they were made
+        let rx = serde_json::to_value(params)
+            .map_err(Error::from)
+            .and_then(|params| {
+                let request = jsonrpc::MethodCall {
+                    jsonrpc: Some(jsonrpc::Version::V2),
+                    id: id.clone(),
+                    method: R::METHOD.to_string(),
+                    params: Self::value_into_params(params),
+                };
+
+                let (tx, rx) = channel::<Result<Value>>(1);
+
+                server_tx
+                    .send(Payload::Request {
+                        chan: tx,
+                        value: request,
+                    })
+                    .map_err(|e| Error::Other(e.into()))?;
+
+                Ok(rx)
+            });
+
+        if rx.is_ok() && SUPERSEDED_REQUESTS.contains(&R::METHOD) {
+            let previous = self
+                .latest_requests
+                .lock()
+                .unwrap()
+                .insert(R::METHOD, id.clone());
+            if let Some(previous) = previous {
+                // a no-op if the previous request was already answered
+                let _ = server_tx.send(Payload::Cancel(previous));
+            }
+        }
 
         async move {
             use std::time::Duration;
             use tokio::time::timeout;
 
-            let params = serde_json::to_value(params)?;
-
-            let request = jsonrpc::MethodCall {
-                jsonrpc: Some(jsonrpc::Version::V2),
-                id,
-                method: R::METHOD.to_string(),
-                params: Self::value_into_params(params),
-            };
+            let mut rx = rx?;
 
-            let (tx, mut rx) = channel::<Result<Value>>(1);
-
-            server_tx
-                .send(Payload::Request {
-                    chan: tx,
-                    value: request,
-                })
-                .map_err(|e| Error::Other(e.into()))?;
-
-            // TODO: specifiable timeout, delay other calls until initialize success
-            timeout(Duration::from_secs(20), rx.recv())
-                .await
-                .map_err(|_| Error::Timeout)? // return Timeout
-                .ok_or(Error::StreamClosed)?
+            // TODO: delay other calls until initialize success
+            match timeout(Duration::from_secs(timeout_secs), rx.recv()).await {
+                Ok(response) => response.ok_or(Error::StreamClosed)?,
+                Err(_) => {
+                    // let the server know it can stop working on the request
+                    let _ = server_tx.send(Payload::Cancel(id));
+                    Err(Error::Timeout)
+                }
+            }
         }
     }
 
@@ -259,10 +327,23 @@ impl Client {
                     }),
                     rename: Some(lsp::RenameClientCapabilities {
                         dynamic_registration: Some(false),
-                        prepare_support: Some(false),
+                        prepare_support: Some(true),
                         prepare_support_default_behavior: None,
                         honors_change_annotations: Some(false),
                     }),
+                    code_lens: Some(lsp::CodeLensClientCapabilities {
+                        dynamic_registration: Some(false),
+                    }),
+                    document_link: Some(lsp::DocumentLinkClientCapabilities {
+                        dynamic_registration: Some(false),
+                        tooltip_support: Some(false),
+                    }),
+                    selection_range: Some(lsp::SelectionRangeClientCapabilities {
+                        dynamic_registration: Some(false),
+                    }),
+                    linked_editing_range: Some(lsp::LinkedEditingRangeClientCapabilities {
+                        dynamic_registration: Some(false),
+                    }),
                     code_action: Some(lsp::CodeActionClientCapabilities {
                         code_action_literal_support: Some(lsp::CodeActionLiteralSupport {
                             code_action_kind: lsp::CodeActionKindLiteralSupport {
@@ -287,6 +368,25 @@ impl Client {
                 }),
                 window: Some(lsp::WindowClientCapabilities {
                     work_done_progress: Some(true),
+                    show_message: Some(lsp::ShowMessageRequestClientCapabilities {
+                        message_action_item: Some(lsp::MessageActionItemCapabilities {
+                            additional_properties_support: Some(true),
+                        }),
+                    }),
+                    show_document: Some(lsp::ShowDocumentClientCapabilities { support: true }),
+                }),
+                workspace: Some(lsp::WorkspaceClientCapabilities {
+                    configuration: Some(true),
+                    apply_edit: Some(true),
+                    execute_command: Some(lsp::ExecuteCommandClientCapabilities {
+                        dynamic_registration: Some(false),
+                    }),
+                    did_change_configuration: Some(lsp::DynamicRegistrationClientCapabilities {
+                        dynamic_registration: Some(false),
+                    }),
+                    did_change_watched_files: Some(lsp::DynamicRegistrationClientCapabilities {
+                        dynamic_registration: Some(true),
+                    }),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -297,7 +397,25 @@ impl Client {
             locale: None, // TODO
         };
 
-        self.request::<lsp::request::Initialize>(params).await
+        let mut params = serde_json::to_value(params)?;
+        if let Some(text_document) = params
+            .pointer_mut("/capabilities/textDocument")
+            .and_then(Value::as_object_mut)
+        {
+            text_document.insert(
+                "diagnostic".to_string(),
+                pull_diagnostics::client_capabilities(),
+            );
+        }
+
+        let response = self.call::<RawInitialize>(params).await?;
+        let diagnostic_provider = response
+            .pointer("/capabilities/diagnosticProvider")
+            .and_then(|options| serde_json::from_value(options.clone()).ok());
+        if let Some(diagnostic_provider) = diagnostic_provider {
+            let _ = self.diagnostic_provider.set(diagnostic_provider);
+        }
+        Ok(serde_json::from_value(response)?)
     }
 
     pub async fn shutdown(&self) -> Result<()> {
@@ -323,6 +441,50 @@ impl Client {
         self.exit().await
     }
 
+    // -------------------------------------------------------------------------------------------
+    // Workspace
+    // -------------------------------------------------------------------------------------------
+
+    pub fn did_change_configuration(&self, settings: Value) -> impl Future<Output = Result<()>> {
+        self.notify::<lsp::notification::DidChangeConfiguration>(
+            lsp::DidChangeConfigurationParams { settings },
+        )
+    }
+
+    pub fn did_change_watched_files(
+        &self,
+        changes: Vec<lsp::FileEvent>,
+    ) -> impl Future<Output = Result<()>> {
+        self.notify::<lsp::notification::DidChangeWatchedFiles>(lsp::DidChangeWatchedFilesParams {
+            changes,
+        })
+    }
+
+    pub fn workspace_diagnostic(
+        &self,
+        previous_result_ids: Vec<PreviousResultId>,
+    ) -> Option<impl Future<Output = Result<WorkspaceDiagnosticReport>>> {
+        let options = self.diagnostic_provider()?;
+        if !options.workspace_diagnostics {
+            return None;
+        }
+
+        let params = pull_diagnostics::WorkspaceDiagnosticParams {
+            identifier: options.identifier.clone(),
+            previous_result_ids,
+            work_done_progress_params: lsp::WorkDoneProgressParams::default(),
+            partial_result_params: lsp::PartialResultParams::default(),
+        };
+
+        let request = self.call::<pull_diagnostics::WorkspaceDiagnosticRequest>(params);
+
+        Some(async move {
+            let json = request.await?;
+            let response = serde_json::from_value(json)?;
+            Ok(response)
+        })
+    }
+
     // -------------------------------------------------------------------------------------------
     // Text document
     // -------------------------------------------------------------------------------------------
@@ -593,6 +755,30 @@ impl Client {
         self.call::<lsp::request::HoverRequest>(params)
     }
 
+    pub fn text_document_diagnostic(
+        &self,
+        text_document: lsp::TextDocumentIdentifier,
+        previous_result_id: Option<String>,
+    ) -> Option<impl Future<Output = Result<DocumentDiagnosticReport>>> {
+        let options = self.diagnostic_provider()?;
+
+        let params = pull_diagnostics::DocumentDiagnosticParams {
+            text_document,
+            identifier: options.identifier.clone(),
+            previous_result_id,
+            work_done_progress_params: lsp::WorkDoneProgressParams::default(),
+            partial_result_params: lsp::PartialResultParams::default(),
+        };
+
+        let request = self.call::<pull_diagnostics::DocumentDiagnosticRequest>(params);
+
+        Some(async move {
+            let json = request.await?;
+            let response = serde_json::from_value(json)?;
+            Ok(response)
+        })
+    }
+
     // formatting
 
     pub fn text_document_formatting(
@@ -780,6 +966,163 @@ impl Client {
         self.call::<lsp::request::CodeActionRequest>(params)
     }
 
+    pub fn code_lens(
+        &self,
+        text_document: lsp::TextDocumentIdentifier,
+    ) -> Option<impl Future<Output = Result<Value>>> {
+        let capabilities = self.capabilities.get().unwrap();
+        capabilities.code_lens_provider.as_ref()?;
+
+        let params = lsp::CodeLensParams {
+            text_document,
+            work_done_progress_params: lsp::WorkDoneProgressParams::default(),
+            partial_result_params: lsp::PartialResultParams::default(),
+        };
+
+        Some(self.call::<lsp::request::CodeLensRequest>(params))
+    }
+
+    /// Fills in the command of a code lens, if the server resolves code lenses lazily.
+    pub fn code_lens_resolve(
+        &self,
+        code_lens: lsp::CodeLens,
+    ) -> Option<impl Future<Output = Result<Value>>> {
+        let capabilities = self.capabilities.get().unwrap();
+
+        match capabilities.code_lens_provider {
+            Some(lsp::CodeLensOptions {
+                resolve_provider: Some(true),
+            }) => (),
+            _ => return None,
+        }
+
+        Some(self.call::<lsp::request::CodeLensResolve>(code_lens))
+    }
+
+    pub fn document_link(
+        &self,
+        text_document: lsp::TextDocumentIdentifier,
+    ) -> Option<impl Future<Output = Result<Value>>> {
+        let capabilities = self.capabilities.get().unwrap();
+        capabilities.document_link_provider.as_ref()?;
+
+        let params = lsp::DocumentLinkParams {
+            text_document,
+            work_done_progress_params: lsp::WorkDoneProgressParams::default(),
+            partial_result_params: lsp::PartialResultParams::default(),
+        };
+
+        Some(self.call::<lsp::request::DocumentLinkRequest>(params))
+    }
+
+    /// Fills in the target of a document link, if the server resolves document links lazily.
+    pub fn document_link_resolve(
+        &self,
+        document_link: lsp::DocumentLink,
+    ) -> Option<impl Future<Output = Result<Value>>> {
+        let capabilities = self.capabilities.get().unwrap();
+
+        match capabilities.document_link_provider {
+            Some(lsp::DocumentLinkOptions {
+                resolve_provider: Some(true),
+                ..
+            }) => (),
+            _ => return None,
+        }
+
+        Some(self.call::<lsp::request::DocumentLinkResolve>(document_link))
+    }
+
+    pub fn selection_range(
+        &self,
+        text_document: lsp::TextDocumentIdentifier,
+        positions: Vec<lsp::Position>,
+    ) -> Option<impl Future<Output = Result<Value>>> {
+        let capabilities = self.capabilities.get().unwrap();
+
+        match capabilities.selection_range_provider {
+            Some(lsp::SelectionRangeProviderCapability::Simple(false)) | None => return None,
+            _ => (),
+        }
+
+        let params = lsp::SelectionRangeParams {
+            text_document,
+            positions,
+            work_done_progress_params: lsp::WorkDoneProgressParams::default(),
+            partial_result_params: lsp::PartialResultParams::default(),
+        };
+
+        Some(self.call::<lsp::request::SelectionRangeRequest>(params))
+    }
+
+    pub fn linked_editing_range(
+        &self,
+        text_document: lsp::TextDocumentIdentifier,
+        position: lsp::Position,
+    ) -> Option<impl Future<Output = Result<Value>>> {
+        let capabilities = self.capabilities.get().unwrap();
+
+        match capabilities.linked_editing_range_provider {
+            Some(lsp::LinkedEditingRangeServerCapabilities::Simple(false)) | None => return None,
+            _ => (),
+        }
+
+        let params = lsp::LinkedEditingRangeParams {
+            text_document_position_params: lsp::TextDocumentPositionParams {
+                text_document,
+                position,
+            },
+            work_done_progress_params: lsp::WorkDoneProgressParams::default(),
+        };
+
+        Some(self.call::<lsp::request::LinkedEditingRange>(params))
+    }
+
+    /// Runs a command on the server. Returns `None` if the server doesn't provide the command.
+    pub fn execute_command(
+        &self,
+        command: lsp::Command,
+    ) -> Option<impl Future<Output = Result<Value>>> {
+        let capabilities = self.capabilities.get().unwrap();
+
+        match &capabilities.execute_command_provider {
+            Some(options) if options.commands.contains(&command.command) => (),
+            _ => return None,
+        }
+
+        let params = lsp::ExecuteCommandParams {
+            command: command.command,
+            arguments: command.arguments.unwrap_or_default(),
+            work_done_progress_params: lsp::WorkDoneProgressParams::default(),
+        };
+
+        Some(self.call::<lsp::request::ExecuteCommand>(params))
+    }
+
+    pub fn prepare_rename(
+        &self,
+        text_document: lsp::TextDocumentIdentifier,
+        position: lsp::Position,
+    ) -> Option<impl Future<Output = Result<Value>>> {
+        let capabilities = self.capabilities.get().unwrap();
+
+        // `prepareRename` is only supported if the server registered rename options with it
+        match capabilities.rename_provider {
+            Some(lsp::OneOf::Right(lsp::RenameOptions {
+                prepare_provider: Some(true),
+                ..
+            })) => (),
+            _ => return None,
+        }
+
+        let params = lsp::TextDocumentPositionParams {
+            text_document,
+            position,
+        };
+
+        Some(self.call::<lsp::request::PrepareRenameRequest>(params))
+    }
+
     pub async fn rename_symbol(
         &self,
         text_document: lsp::TextDocumentIdentifier,
@@ -1,4 +1,6 @@
 mod client;
+pub mod message_log;
+pub mod pull_diagnostics;
 mod transport;
 
 pub use client::Client;
@@ -7,6 +9,7 @@ pub use jsonrpc::Call;
 pub use jsonrpc_core as jsonrpc;
 pub use lsp::{Position, Url};
 pub use lsp_types as lsp;
+pub use message_log::MessageLog;
 
 use futures_util::stream::select_all::SelectAll;
 use helix_core::syntax::LanguageConfiguration;
@@ -36,6 +39,10 @@ pub enum Error {
     IO(#[from] std::io::Error),
     #[error("request timed out")]
     Timeout,
+    #[error("request was cancelled")]
+    Cancelled,
+    #[error("content was modified before the request completed")]
+    ContentModified,
     #[error("server closed the stream")]
     StreamClosed,
     #[error("LSP not defined")]
@@ -44,6 +51,28 @@ pub enum Error {
     Other(#[from] anyhow::Error),
 }
 
+impl Error {
+    /// Converts an error response from the server, recognizing the LSP specific error codes.
+    pub fn from_rpc(error: jsonrpc::Error) -> Self {
+        match error.code.code() {
+            REQUEST_CANCELLED => Self::Cancelled,
+            CONTENT_MODIFIED => Self::ContentModified,
+            _ => Self::Rpc(error),
+        }
+    }
+
+    /// Whether the request was dropped because it was superseded or outdated rather than
+    /// failing, these aren't worth reporting to the user.
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, Self::Cancelled | Self::ContentModified)
+    }
+}
+
+/// The server cancelled the request, usually because the client asked it to.
+const REQUEST_CANCELLED: i64 = -32800;
+/// The document changed in a way that made the result of the request invalid.
+const CONTENT_MODIFIED: i64 = -32801;
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum OffsetEncoding {
     /// UTF-8 code units aka bytes
@@ -57,6 +86,7 @@ pub enum OffsetEncoding {
 pub mod util {
     use super::*;
     use helix_core::{Range, Rope, Transaction};
+    use serde_json::Value;
 
     /// Converts [`lsp::Position`] to a position in the document.
     ///
@@ -152,6 +182,64 @@ pub mod util {
         Some(Range::new(start, end))
     }
 
+    /// Converts the severity of a [`lsp::Diagnostic`], `None` if it's unknown.
+    pub fn lsp_severity_to_severity(
+        severity: lsp::DiagnosticSeverity,
+    ) -> Option<helix_core::diagnostic::Severity> {
+        use helix_core::diagnostic::Severity::*;
+        use lsp::DiagnosticSeverity;
+
+        match severity {
+            DiagnosticSeverity::ERROR => Some(Error),
+            DiagnosticSeverity::WARNING => Some(Warning),
+            DiagnosticSeverity::INFORMATION => Some(Info),
+            DiagnosticSeverity::HINT => Some(Hint),
+            _ => None,
+        }
+    }
+
+    /// Converts a [`lsp::Diagnostic`] to a diagnostic in the document.
+    ///
+    /// Returns `None` if the diagnostic range exceeds the document bounds.
+    pub fn lsp_diagnostic_to_diagnostic(
+        doc: &Rope,
+        diagnostic: &lsp::Diagnostic,
+        offset_encoding: OffsetEncoding,
+    ) -> Option<helix_core::Diagnostic> {
+        use helix_core::diagnostic::Range;
+
+        // TODO: convert inside server
+        let start =
+            if let Some(start) = lsp_pos_to_pos(doc, diagnostic.range.start, offset_encoding) {
+                start
+            } else {
+                log::warn!("lsp position out of bounds - {:?}", diagnostic);
+                return None;
+            };
+
+        let end = if let Some(end) = lsp_pos_to_pos(doc, diagnostic.range.end, offset_encoding) {
+            end
+        } else {
+            log::warn!("lsp position out of bounds - {:?}", diagnostic);
+            return None;
+        };
+
+        Some(helix_core::Diagnostic {
+            range: Range { start, end },
+            line: diagnostic.range.start.line as usize,
+            message: diagnostic.message.clone(),
+            severity: diagnostic.severity.and_then(|severity| {
+                let converted = lsp_severity_to_severity(severity);
+                if converted.is_none() {
+                    log::warn!("unknown lsp diagnostic severity - {:?}", severity);
+                }
+                converted
+            }),
+            // code
+            // source
+        })
+    }
+
     pub fn generate_transaction_from_edits(
         doc: &Rope,
         edits: Vec<lsp::TextEdit>,
@@ -183,6 +271,31 @@ pub mod util {
         )
     }
 
+    /// Looks up a `workspace/configuration` section in a settings tree.
+    ///
+    /// Sections are dotted paths (`yaml.schemas`) into nested tables, the whole tree is returned
+    /// when no section is requested and `null` when the section doesn't exist.
+    pub fn settings_section(settings: Option<&Value>, section: Option<&str>) -> Value {
+        let settings = match settings {
+            Some(settings) => settings,
+            None => return Value::Null,
+        };
+        let section = match section {
+            Some(section) if !section.is_empty() => section,
+            _ => return settings.clone(),
+        };
+
+        // a dotted key that is spelled out in full takes precedence
+        if let Some(value) = settings.get(section) {
+            return value.clone();
+        }
+        section
+            .split('.')
+            .try_fold(settings, |value, key| value.get(key))
+            .cloned()
+            .unwrap_or(Value::Null)
+    }
+
     /// The result of asking the language server to format the document. This can be turned into a
     /// `Transaction`, but the advantage of not doing that straight away is that this one is
     /// `Send` and `Sync`.
@@ -203,6 +316,12 @@ pub mod util {
 #[derive(Debug, PartialEq, Clone)]
 pub enum MethodCall {
     WorkDoneProgressCreate(lsp::WorkDoneProgressCreateParams),
+    WorkspaceConfiguration(lsp::ConfigurationParams),
+    RegisterCapability(lsp::RegistrationParams),
+    UnregisterCapability(lsp::UnregistrationParams),
+    ShowMessageRequest(lsp::ShowMessageRequestParams),
+    ShowDocument(lsp::ShowDocumentParams),
+    ApplyWorkspaceEdit(lsp::ApplyWorkspaceEditParams),
 }
 
 impl MethodCall {
@@ -215,6 +334,30 @@ impl MethodCall {
                     .expect("Failed to parse WorkDoneCreate params");
                 Self::WorkDoneProgressCreate(params)
             }
+            lsp::request::WorkspaceConfiguration::METHOD => {
+                let params: lsp::ConfigurationParams = params.parse().ok()?;
+                Self::WorkspaceConfiguration(params)
+            }
+            lsp::request::RegisterCapability::METHOD => {
+                let params: lsp::RegistrationParams = params.parse().ok()?;
+                Self::RegisterCapability(params)
+            }
+            lsp::request::UnregisterCapability::METHOD => {
+                let params: lsp::UnregistrationParams = params.parse().ok()?;
+                Self::UnregisterCapability(params)
+            }
+            lsp::request::ShowMessageRequest::METHOD => {
+                let params: lsp::ShowMessageRequestParams = params.parse().ok()?;
+                Self::ShowMessageRequest(params)
+            }
+            lsp::request::ShowDocument::METHOD => {
+                let params: lsp::ShowDocumentParams = params.parse().ok()?;
+                Self::ShowDocument(params)
+            }
+            lsp::request::ApplyWorkspaceEdit::METHOD => {
+                let params: lsp::ApplyWorkspaceEditParams = params.parse().ok()?;
+                Self::ApplyWorkspaceEdit(params)
+            }
             _ => {
                 log::warn!("unhandled lsp request: {}", method);
                 return None;
@@ -228,6 +371,8 @@ impl MethodCall {
 pub enum Notification {
     // we inject this notification to signal the LSP is ready
     Initialized,
+    // and this one to signal the LSP exited
+    Exit,
     PublishDiagnostics(lsp::PublishDiagnosticsParams),
     ShowMessage(lsp::ShowMessageParams),
     LogMessage(lsp::LogMessageParams),
@@ -240,6 +385,7 @@ impl Notification {
 
         let notification = match method {
             lsp::notification::Initialized::METHOD => Self::Initialized,
+            lsp::notification::Exit::METHOD => Self::Exit,
             lsp::notification::PublishDiagnostics::METHOD => {
                 let params: lsp::PublishDiagnosticsParams = params
                     .parse()
@@ -280,6 +426,8 @@ pub struct Registry {
 
     counter: AtomicUsize,
     pub incoming: SelectAll<UnboundedReceiverStream<(usize, Call)>>,
+    /// Traffic with all the language servers, see `:lsp-log`.
+    pub message_log: Arc<MessageLog>,
 }
 
 impl Default for Registry {
@@ -294,14 +442,15 @@ impl Registry {
             inner: HashMap::new(),
             counter: AtomicUsize::new(0),
             incoming: SelectAll::new(),
+            message_log: Arc::new(MessageLog::new()),
         }
     }
 
-    pub fn get_by_id(&self, id: usize) -> Option<&Client> {
+    pub fn get_by_id(&self, id: usize) -> Option<&Arc<Client>> {
         self.inner
             .values()
             .find(|(client_id, _)| client_id == &id)
-            .map(|(_, client)| client.as_ref())
+            .map(|(_, client)| client)
     }
 
     pub fn get(&mut self, language_config: &LanguageConfiguration) -> Result<Arc<Client>> {
@@ -320,14 +469,21 @@ impl Registry {
                     &config.args,
                     language_config.config.clone(),
                     id,
+                    config.timeout,
+                    self.message_log.clone(),
                 )?;
                 self.incoming.push(UnboundedReceiverStream::new(incoming));
+                // a server that fails to initialize is reported like one that exited, so that
+                // it's removed from the registry and restarted
+                let (exit_tx, exit_rx) = tokio::sync::mpsc::unbounded_channel();
+                self.incoming.push(UnboundedReceiverStream::new(exit_rx));
                 let client = Arc::new(client);
 
                 // Initialize the client asynchronously
                 let _client = client.clone();
                 tokio::spawn(async move {
                     use futures_util::TryFutureExt;
+                    use lsp::notification::Notification;
                     let value = _client
                         .capabilities
                         .get_or_try_init(|| {
@@ -337,7 +493,16 @@ impl Registry {
                         })
                         .await;
 
-                    value.expect("failed to initialize capabilities");
+                    if let Err(e) = value {
+                        log::error!("failed to initialize language server: {}", e);
+                        let exit = Call::Notification(jsonrpc::Notification {
+                            jsonrpc: None,
+                            method: lsp::notification::Exit::METHOD.to_string(),
+                            params: jsonrpc::Params::None,
+                        });
+                        let _ = exit_tx.send((id, exit));
+                        return;
+                    }
 
                     // next up, notify<initialized>
                     _client
@@ -354,6 +519,20 @@ impl Registry {
         }
     }
 
+    /// Returns the language scope the client with the given `id` was started for.
+    pub fn scope_by_id(&self, id: usize) -> Option<&str> {
+        self.inner
+            .iter()
+            .find(|(_, (client_id, _))| client_id == &id)
+            .map(|(scope, _)| scope.as_str())
+    }
+
+    /// Removes the client started for `scope` from the registry, so that the next call to
+    /// [`Registry::get`] spawns a new one.
+    pub fn remove(&mut self, scope: &str) -> Option<Arc<Client>> {
+        self.inner.remove(scope).map(|(_, client)| client)
+    }
+
     pub fn iter_clients(&self) -> impl Iterator<Item = &Arc<Client>> {
         self.inner.values().map(|(_, client)| client)
     }
@@ -439,8 +618,9 @@ impl LspProgressMap {
 
 #[cfg(test)]
 mod tests {
-    use super::{lsp, util::*, OffsetEncoding};
+    use super::{jsonrpc, lsp, util::*, MessageLog, OffsetEncoding};
     use helix_core::Rope;
+    use std::sync::Arc;
 
     #[test]
     fn converts_lsp_pos_to_pos() {
@@ -466,4 +646,133 @@ mod tests {
         test_case!("test\n\n\n\ncase", (4, 5) => None);
         test_case!("", (u32::MAX, u32::MAX) => None);
     }
+
+    #[test]
+    fn ignores_unknown_diagnostic_severities() {
+        let doc = Rope::from("test\n");
+        let diagnostic = |severity: u32| -> lsp::Diagnostic {
+            serde_json::from_value(serde_json::json!({
+                "range": { "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 4 } },
+                "severity": severity,
+                "message": "test",
+            }))
+            .unwrap()
+        };
+
+        let convert = |severity| {
+            lsp_diagnostic_to_diagnostic(&doc, &diagnostic(severity), OffsetEncoding::Utf8)
+                .unwrap()
+                .severity
+        };
+        assert_eq!(convert(1), Some(helix_core::diagnostic::Severity::Error));
+        assert_eq!(convert(5), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pending_requests_fail_when_server_exits() {
+        use super::{Client, Error, Notification};
+
+        // a "server" that exits right away without answering anything
+        let (client, mut incoming, _) =
+            Client::start("true", &[], None, 0, 20, Arc::new(MessageLog::new())).unwrap();
+
+        let err = client.initialize().await.unwrap_err();
+        assert!(matches!(err, Error::StreamClosed), "{:?}", err);
+
+        // the exit is forwarded to the editor as a notification
+        let (id, call) = incoming.recv().await.unwrap();
+        assert_eq!(id, 0);
+        match call {
+            jsonrpc::Call::Notification(jsonrpc::Notification { method, params, .. }) => {
+                assert_eq!(
+                    Notification::parse(&method, params),
+                    Some(Notification::Exit)
+                );
+            }
+            call => panic!("unexpected call {:?}", call),
+        }
+    }
+
+    #[test]
+    fn looks_up_settings_sections() {
+        let settings = serde_json::json!({
+            "yaml": { "schemas": { "kubernetes": "*.yaml" }, "format": { "enable": true } },
+            "python.analysis": { "typeCheckingMode": "strict" },
+        });
+
+        assert_eq!(settings_section(Some(&settings), None), settings);
+        assert_eq!(
+            settings_section(Some(&settings), Some("yaml.format.enable")),
+            serde_json::json!(true)
+        );
+        assert_eq!(
+            settings_section(Some(&settings), Some("yaml.schemas")),
+            serde_json::json!({ "kubernetes": "*.yaml" })
+        );
+        assert_eq!(
+            settings_section(Some(&settings), Some("python.analysis")),
+            serde_json::json!({ "typeCheckingMode": "strict" })
+        );
+        assert_eq!(
+            settings_section(Some(&settings), Some("yaml.missing")),
+            serde_json::Value::Null
+        );
+        assert_eq!(
+            settings_section(None, Some("yaml")),
+            serde_json::Value::Null
+        );
+    }
+
+    #[test]
+    fn recognizes_lsp_error_codes() {
+        use super::Error;
+        use jsonrpc::{Error as RpcError, ErrorCode};
+
+        let error = |code| RpcError {
+            code: ErrorCode::ServerError(code),
+            message: String::new(),
+            data: None,
+        };
+        assert!(matches!(Error::from_rpc(error(-32800)), Error::Cancelled));
+        assert!(matches!(
+            Error::from_rpc(error(-32801)),
+            Error::ContentModified
+        ));
+        assert!(matches!(Error::from_rpc(error(-32000)), Error::Rpc(_)));
+        assert!(matches!(
+            Error::from_rpc(RpcError::method_not_found()),
+            Error::Rpc(_)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn superseded_requests_are_cancelled() {
+        use super::{Client, Error};
+
+        // `cat` echoes requests back instead of answering them
+        let (client, _incoming, _) =
+            Client::start("cat", &[], None, 0, 20, Arc::new(MessageLog::new())).unwrap();
+        let doc = lsp::TextDocumentIdentifier::new(lsp::Url::parse("file:///test.rs").unwrap());
+
+        let first = client.completion(doc.clone(), lsp::Position::new(0, 0), None);
+        let second = client.completion(doc, lsp::Position::new(0, 1), None);
+        drop(second);
+
+        let err = first.await.unwrap_err();
+        assert!(matches!(err, Error::Cancelled), "{:?}", err);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn requests_time_out() {
+        use super::{Client, Error};
+
+        let (client, _incoming, _) =
+            Client::start("cat", &[], None, 0, 0, Arc::new(MessageLog::new())).unwrap();
+
+        let err = client.initialize().await.unwrap_err();
+        assert!(matches!(err, Error::Timeout), "{:?}", err);
+    }
 }
@@ -0,0 +1,261 @@
+//! A bounded, in-memory record of the traffic with the language servers, used to debug their
+//! behaviour from inside the editor. Nothing is recorded unless it's enabled with
+//! `lsp.message-log`.
+
+use crate::{jsonrpc, lsp};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Older entries are discarded once the log holds this many.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Payloads are cut after this many bytes, as they include the whole text of opened documents.
+const MAX_PAYLOAD_LEN: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageKind {
+    /// A JSON-RPC message sent to the server.
+    Outgoing,
+    /// A JSON-RPC message received from the server.
+    Incoming,
+    /// A `window/logMessage` notification.
+    Log(lsp::MessageType),
+    /// A line the server printed to stderr.
+    Stderr,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageLogEntry {
+    pub time: SystemTime,
+    /// Id of the client the message was exchanged with.
+    pub server_id: usize,
+    /// Name of the server, the command it was started with.
+    pub server: String,
+    pub kind: MessageKind,
+    /// The method of the message. For responses this is the method of the request.
+    pub method: Option<String>,
+    pub id: Option<jsonrpc::Id>,
+    /// For responses, the time elapsed since the request was sent.
+    pub elapsed: Option<Duration>,
+    pub payload: String,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: VecDeque<MessageLogEntry>,
+    /// Method and start time of requests in flight, by server, request id and whether the
+    /// request was sent by the client.
+    requests: HashMap<(usize, jsonrpc::Id, bool), (String, Instant)>,
+}
+
+#[derive(Debug, Default)]
+pub struct MessageLog {
+    enabled: AtomicBool,
+    inner: Mutex<Inner>,
+}
+
+impl MessageLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts or stops recording messages. The recorded ones are dropped when stopping.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            let mut inner = self.inner.lock().unwrap();
+            inner.entries.clear();
+            inner.requests.clear();
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Records a JSON-RPC message. Requests carry both a `method` and an `id`, responses only
+    /// an `id` and notifications only a `method`.
+    pub fn message(
+        &self,
+        server_id: usize,
+        server: &str,
+        kind: MessageKind,
+        method: Option<&str>,
+        id: Option<&jsonrpc::Id>,
+        payload: &str,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+
+        let outgoing = kind == MessageKind::Outgoing;
+        let mut method = method.map(ToOwned::to_owned);
+        let mut elapsed = None;
+        if let Some(id) = id {
+            match &method {
+                // a request, remember it so the response can be matched to it
+                Some(method) => {
+                    let key = (server_id, id.clone(), outgoing);
+                    inner.requests.insert(key, (method.clone(), Instant::now()));
+                }
+                // a response, to a request that was sent in the other direction
+                None => {
+                    let key = (server_id, id.clone(), !outgoing);
+                    if let Some((request_method, start)) = inner.requests.remove(&key) {
+                        method = Some(request_method);
+                        elapsed = Some(start.elapsed());
+                    }
+                }
+            }
+        }
+
+        if inner.entries.len() == MAX_ENTRIES {
+            inner.entries.pop_front();
+        }
+        inner.entries.push_back(MessageLogEntry {
+            time: SystemTime::now(),
+            server_id,
+            server: server.to_owned(),
+            kind,
+            method,
+            id: id.cloned(),
+            elapsed,
+            payload: truncate(payload),
+        });
+    }
+
+    /// Records a `window/logMessage` notification.
+    pub fn log_message(&self, server_id: usize, server: &str, params: &lsp::LogMessageParams) {
+        use lsp::notification::{LogMessage, Notification};
+        self.message(
+            server_id,
+            server,
+            MessageKind::Log(params.typ),
+            Some(LogMessage::METHOD),
+            None,
+            &params.message,
+        );
+    }
+
+    /// Records a line of the server's stderr output.
+    pub fn stderr(&self, server_id: usize, server: &str, line: &str) {
+        self.message(server_id, server, MessageKind::Stderr, None, None, line);
+    }
+
+    /// Returns a snapshot of the entries, oldest first.
+    pub fn entries(&self) -> Vec<MessageLogEntry> {
+        self.inner.lock().unwrap().entries.iter().cloned().collect()
+    }
+
+    /// Forgets a request sent to the server that won't be answered, as it was cancelled or timed
+    /// out.
+    pub fn cancel(&self, server_id: usize, id: &jsonrpc::Id) {
+        let key = (server_id, id.clone(), true);
+        self.inner.lock().unwrap().requests.remove(&key);
+    }
+
+    /// Forgets the requests in flight with a server that exited.
+    pub fn remove_server(&self, server_id: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.requests.retain(|(id, _, _), _| *id != server_id);
+    }
+}
+
+fn truncate(payload: &str) -> String {
+    if payload.len() <= MAX_PAYLOAD_LEN {
+        return payload.to_owned();
+    }
+    let mut end = MAX_PAYLOAD_LEN;
+    while !payload.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}… ({} bytes)", &payload[..end], payload.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn responses_are_matched_to_requests() {
+        let log = MessageLog::new();
+        log.set_enabled(true);
+        let id = jsonrpc::Id::Num(1);
+
+        log.message(
+            0,
+            "server",
+            MessageKind::Outgoing,
+            Some("textDocument/hover"),
+            Some(&id),
+            "{}",
+        );
+        log.message(0, "server", MessageKind::Incoming, None, Some(&id), "{}");
+        log.message(
+            0,
+            "server",
+            MessageKind::Incoming,
+            Some("textDocument/publishDiagnostics"),
+            None,
+            "{}",
+        );
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].method.as_deref(), Some("textDocument/hover"));
+        assert!(entries[1].elapsed.is_some());
+        assert!(entries[2].elapsed.is_none());
+        assert!(log.inner.lock().unwrap().requests.is_empty());
+    }
+
+    #[test]
+    fn log_is_bounded() {
+        let log = MessageLog::new();
+        log.set_enabled(true);
+        for i in 0..MAX_ENTRIES + 10 {
+            log.stderr(0, "server", &i.to_string());
+        }
+
+        let entries = log.entries();
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries[0].payload, "10");
+    }
+
+    #[test]
+    fn nothing_is_recorded_unless_enabled() {
+        let log = MessageLog::new();
+        log.stderr(0, "server", "ignored");
+        assert!(log.entries().is_empty());
+
+        log.set_enabled(true);
+        log.stderr(0, "server", &"x".repeat(MAX_PAYLOAD_LEN + 1));
+        let entries = log.entries();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0]
+            .payload
+            .ends_with(&format!("… ({} bytes)", MAX_PAYLOAD_LEN + 1)));
+
+        log.set_enabled(false);
+        assert!(log.entries().is_empty());
+    }
+
+    #[test]
+    fn cancelled_requests_are_forgotten() {
+        let log = MessageLog::new();
+        log.set_enabled(true);
+        for id in 0..3 {
+            let id = jsonrpc::Id::Num(id);
+            let method = Some("textDocument/hover");
+            log.message(0, "server", MessageKind::Outgoing, method, Some(&id), "{}");
+            log.message(1, "other", MessageKind::Outgoing, method, Some(&id), "{}");
+        }
+
+        log.cancel(0, &jsonrpc::Id::Num(0));
+        assert_eq!(log.inner.lock().unwrap().requests.len(), 5);
+        log.remove_server(1);
+        assert_eq!(log.inner.lock().unwrap().requests.len(), 2);
+    }
+}
@@ -0,0 +1,179 @@
+//! Pull diagnostics (`textDocument/diagnostic` and `workspace/diagnostic`) were added in LSP 3.17
+//! and aren't part of `lsp_types` yet.
+
+use crate::lsp;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// The server's `diagnosticProvider` capability.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+    /// Whether changes in one document can affect the diagnostics of others.
+    #[serde(default)]
+    pub inter_file_dependencies: bool,
+    #[serde(default)]
+    pub workspace_diagnostics: bool,
+}
+
+/// The `textDocument.diagnostic` client capability.
+pub fn client_capabilities() -> Value {
+    json!({
+        "dynamicRegistration": false,
+        "relatedDocumentSupport": true,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentDiagnosticParams {
+    pub text_document: lsp::TextDocumentIdentifier,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+    /// The `resultId` of the previous report, so the server can answer that nothing changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_result_id: Option<String>,
+    #[serde(flatten)]
+    pub work_done_progress_params: lsp::WorkDoneProgressParams,
+    #[serde(flatten)]
+    pub partial_result_params: lsp::PartialResultParams,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum DocumentDiagnosticReport {
+    Full(FullDocumentDiagnosticReport),
+    Unchanged(UnchangedDocumentDiagnosticReport),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FullDocumentDiagnosticReport {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result_id: Option<String>,
+    pub items: Vec<lsp::Diagnostic>,
+    /// Diagnostics of other documents that changed along with this one.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub related_documents: HashMap<lsp::Url, DocumentDiagnosticReport>,
+}
+
+/// The diagnostics didn't change since the report with the same `resultId`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnchangedDocumentDiagnosticReport {
+    pub result_id: String,
+}
+
+impl DocumentDiagnosticReport {
+    pub fn result_id(&self) -> Option<&str> {
+        match self {
+            Self::Full(report) => report.result_id.as_deref(),
+            Self::Unchanged(report) => Some(&report.result_id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviousResultId {
+    pub uri: lsp::Url,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceDiagnosticParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+    pub previous_result_ids: Vec<PreviousResultId>,
+    #[serde(flatten)]
+    pub work_done_progress_params: lsp::WorkDoneProgressParams,
+    #[serde(flatten)]
+    pub partial_result_params: lsp::PartialResultParams,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WorkspaceDiagnosticReport {
+    pub items: Vec<WorkspaceDocumentDiagnosticReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WorkspaceDocumentDiagnosticReport {
+    pub uri: lsp::Url,
+    /// The version of the document the diagnostics were computed for, `null` if it isn't open.
+    #[serde(default)]
+    pub version: Option<i32>,
+    #[serde(flatten)]
+    pub report: DocumentDiagnosticReport,
+}
+
+#[derive(Debug)]
+pub enum DocumentDiagnosticRequest {}
+
+impl lsp::request::Request for DocumentDiagnosticRequest {
+    type Params = DocumentDiagnosticParams;
+    type Result = DocumentDiagnosticReport;
+    const METHOD: &'static str = "textDocument/diagnostic";
+}
+
+#[derive(Debug)]
+pub enum WorkspaceDiagnosticRequest {}
+
+impl lsp::request::Request for WorkspaceDiagnosticRequest {
+    type Params = WorkspaceDiagnosticParams;
+    type Result = WorkspaceDiagnosticReport;
+    const METHOD: &'static str = "workspace/diagnostic";
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_reports() {
+        let report: DocumentDiagnosticReport = serde_json::from_value(json!({
+            "kind": "full",
+            "resultId": "1",
+            "items": [{
+                "range": {
+                    "start": { "line": 0, "character": 0 },
+                    "end": { "line": 0, "character": 1 },
+                },
+                "message": "unused variable",
+            }],
+            "relatedDocuments": {
+                "file:///b.rs": { "kind": "unchanged", "resultId": "2" },
+            },
+        }))
+        .unwrap();
+        match &report {
+            DocumentDiagnosticReport::Full(report) => {
+                assert_eq!(report.items.len(), 1);
+                assert_eq!(report.related_documents.len(), 1);
+            }
+            report => panic!("unexpected report {:?}", report),
+        }
+        assert_eq!(report.result_id(), Some("1"));
+
+        let report: WorkspaceDiagnosticReport = serde_json::from_value(json!({
+            "items": [
+                { "uri": "file:///a.rs", "version": 3, "kind": "unchanged", "resultId": "3" },
+                { "uri": "file:///b.rs", "version": null, "kind": "full", "items": [] },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(report.items[0].version, Some(3));
+        assert_eq!(report.items[0].report.result_id(), Some("3"));
+        assert_eq!(
+            report.items[1].report,
+            DocumentDiagnosticReport::Full(FullDocumentDiagnosticReport {
+                result_id: None,
+                items: Vec::new(),
+                related_documents: HashMap::new(),
+            })
+        );
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{Error, Result};
+use crate::{
+    message_log::{MessageKind, MessageLog},
+    Error, Result,
+};
 use anyhow::Context;
 use jsonrpc_core as jsonrpc;
 use log::{error, info};
@@ -23,6 +26,8 @@ pub enum Payload {
     },
     Notification(jsonrpc::Notification),
     Response(jsonrpc::Output),
+    /// Fails a pending request with [`Error::Cancelled`] and sends `$/cancelRequest` for it.
+    Cancel(jsonrpc::Id),
 }
 
 /// A type representing all possible values sent from the server to the client.
@@ -39,6 +44,9 @@ enum ServerMessage {
 #[derive(Debug)]
 pub struct Transport {
     id: usize,
+    /// Name of the server, used in the message log.
+    name: String,
+    message_log: Arc<MessageLog>,
     pending_requests: Mutex<HashMap<jsonrpc::Id, Sender<Result<Value>>>>,
 }
 
@@ -48,6 +56,8 @@ impl Transport {
         server_stdin: BufWriter<ChildStdin>,
         server_stderr: BufReader<ChildStderr>,
         id: usize,
+        name: String,
+        message_log: Arc<MessageLog>,
     ) -> (
         UnboundedReceiver<(usize, jsonrpc::Call)>,
         UnboundedSender<Payload>,
@@ -59,6 +69,8 @@ impl Transport {
 
         let transport = Self {
             id,
+            name,
+            message_log,
             pending_requests: Mutex::new(HashMap::default()),
         };
 
@@ -82,6 +94,7 @@ impl Transport {
     }
 
     async fn recv_server_message(
+        &self,
         reader: &mut (impl AsyncBufRead + Unpin + Send),
         buffer: &mut String,
     ) -> Result<ServerMessage> {
@@ -128,12 +141,26 @@ impl Transport {
         info!("<- {}", msg);
 
         // try parsing as output (server response) or call (server request)
-        let output: serde_json::Result<ServerMessage> = serde_json::from_str(msg);
+        let output: ServerMessage = serde_json::from_str(msg)?;
+
+        let (method, id) = match &output {
+            ServerMessage::Output(output) => (None, Some(output.id())),
+            ServerMessage::Call(jsonrpc::Call::MethodCall(call)) => {
+                (Some(call.method.as_str()), Some(&call.id))
+            }
+            ServerMessage::Call(jsonrpc::Call::Notification(notification)) => {
+                (Some(notification.method.as_str()), None)
+            }
+            ServerMessage::Call(jsonrpc::Call::Invalid { id }) => (None, Some(id)),
+        };
+        self.message_log
+            .message(self.id, &self.name, MessageKind::Incoming, method, id, msg);
 
-        Ok(output?)
+        Ok(output)
     }
 
     async fn recv_server_error(
+        &self,
         err: &mut (impl AsyncBufRead + Unpin + Send),
         buffer: &mut String,
     ) -> Result<()> {
@@ -142,6 +169,8 @@ impl Transport {
             return Err(Error::StreamClosed);
         };
         error!("err <- {:?}", buffer);
+        self.message_log
+            .stderr(self.id, &self.name, buffer.trim_end());
 
         Ok(())
     }
@@ -152,18 +181,74 @@ impl Transport {
         payload: Payload,
     ) -> Result<()> {
         //TODO: reuse string
-        let json = match payload {
+        let (json, request_id) = match payload {
             Payload::Request { chan, value } => {
                 self.pending_requests
                     .lock()
                     .await
                     .insert(value.id.clone(), chan);
-                serde_json::to_string(&value)?
+                let json = serde_json::to_string(&value)?;
+                self.log_outgoing(Some(&value.method), Some(&value.id), &json);
+                (json, Some(value.id))
+            }
+            Payload::Notification(value) => {
+                let json = serde_json::to_string(&value)?;
+                self.log_outgoing(Some(&value.method), None, &json);
+                (json, None)
+            }
+            Payload::Response(output) => {
+                let json = serde_json::to_string(&output)?;
+                self.log_outgoing(None, Some(output.id()), &json);
+                (json, None)
+            }
+            Payload::Cancel(id) => {
+                self.message_log.cancel(self.id, &id);
+                let tx = self.pending_requests.lock().await.remove(&id);
+                // the request was already answered, or timed out and got cancelled
+                let tx = match tx {
+                    Some(tx) => tx,
+                    None => return Ok(()),
+                };
+                let _ = tx.send(Err(Error::Cancelled)).await;
+
+                use lsp_types::notification::{Cancel, Notification};
+                let mut params = serde_json::Map::new();
+                params.insert("id".to_string(), serde_json::to_value(&id)?);
+                let notification = jsonrpc::Notification {
+                    jsonrpc: Some(jsonrpc::Version::V2),
+                    method: Cancel::METHOD.to_string(),
+                    params: jsonrpc::Params::Map(params),
+                };
+                let json = serde_json::to_string(&notification)?;
+                self.log_outgoing(Some(&notification.method), None, &json);
+                (json, None)
             }
-            Payload::Notification(value) => serde_json::to_string(&value)?,
-            Payload::Response(error) => serde_json::to_string(&error)?,
         };
-        self.send_string_to_server(server_stdin, json).await
+        let result = self.send_string_to_server(server_stdin, json).await;
+
+        // the request will never be answered, fail it right away instead of letting it time out
+        if let (Err(_), Some(id)) = (&result, request_id) {
+            if let Some(tx) = self.pending_requests.lock().await.remove(&id) {
+                let _ = tx.send(Err(Error::StreamClosed)).await;
+            }
+        }
+        result
+    }
+
+    fn log_outgoing(&self, method: Option<&str>, id: Option<&jsonrpc::Id>, json: &str) {
+        self.message_log
+            .message(self.id, &self.name, MessageKind::Outgoing, method, id, json);
+    }
+
+    /// Fails all requests that are still waiting for a response, used once the server exited.
+    async fn close_pending_requests(&self) {
+        self.message_log.remove_server(self.id);
+        let pending_requests = std::mem::take(&mut *self.pending_requests.lock().await);
+        for (id, tx) in pending_requests {
+            if tx.send(Err(Error::StreamClosed)).await.is_err() {
+                error!("Could not close request on a closed channel (id={:?})", id);
+            }
+        }
     }
 
     async fn send_string_to_server(
@@ -211,16 +296,18 @@ impl Transport {
             }
             jsonrpc::Output::Failure(jsonrpc::Failure { id, error, .. }) => {
                 error!("<- {}", error);
-                (id, Err(error.into()))
+                (id, Err(Error::from_rpc(error)))
             }
         };
 
-        let tx = self
-            .pending_requests
-            .lock()
-            .await
-            .remove(&id)
-            .expect("pending_request with id not found!");
+        let tx = match self.pending_requests.lock().await.remove(&id) {
+            Some(tx) => tx,
+            None => {
+                // the request was cancelled, the server answered anyway
+                info!("discarding response to cancelled request (id={:?})", id);
+                return Ok(());
+            }
+        };
 
         match tx.send(result).await {
             Ok(_) => (),
@@ -240,7 +327,10 @@ impl Transport {
     ) {
         let mut recv_buffer = String::new();
         loop {
-            match Self::recv_server_message(&mut server_stdout, &mut recv_buffer).await {
+            match transport
+                .recv_server_message(&mut server_stdout, &mut recv_buffer)
+                .await
+            {
                 Ok(msg) => {
                     match transport.process_server_message(&client_tx, msg).await {
                         Ok(_) => {}
@@ -256,12 +346,33 @@ impl Transport {
                 }
             }
         }
+
+        // the server exited or closed its stdout, so no more responses will arrive
+        transport.close_pending_requests().await;
+
+        use lsp_types::notification::Notification;
+        // Hack: inject an exit notification so the client can clean up and restart the server
+        let notification =
+            ServerMessage::Call(jsonrpc::Call::Notification(jsonrpc::Notification {
+                jsonrpc: None,
+                method: lsp_types::notification::Exit::METHOD.to_string(),
+                params: jsonrpc::Params::None,
+            }));
+        if let Err(err) = transport
+            .process_server_message(&client_tx, notification)
+            .await
+        {
+            error!("err: <- {:?}", err);
+        }
     }
 
-    async fn err(_transport: Arc<Self>, mut server_stderr: BufReader<ChildStderr>) {
+    async fn err(transport: Arc<Self>, mut server_stderr: BufReader<ChildStderr>) {
         let mut recv_buffer = String::new();
         loop {
-            match Self::recv_server_error(&mut server_stderr, &mut recv_buffer).await {
+            match transport
+                .recv_server_error(&mut server_stderr, &mut recv_buffer)
+                .await
+            {
                 Ok(_) => {}
                 Err(err) => {
                     error!("err: <- {:?}", err);
@@ -344,6 +455,19 @@ impl Transport {
                                 continue;
                             }
 
+                            // drop requests that were cancelled before they could be sent
+                            if let Payload::Cancel(id) = &msg {
+                                let position = pending_messages.iter().position(|msg| {
+                                    matches!(msg, Payload::Request { value, .. } if &value.id == id)
+                                });
+                                if let Some(Payload::Request { chan, .. }) =
+                                    position.map(|position| pending_messages.remove(position))
+                                {
+                                    let _ = chan.send(Err(Error::Cancelled)).await;
+                                }
+                                continue;
+                            }
+
                             log::info!("Language server not initialized, delaying request");
                             pending_messages.push(msg);
                         } else {
@@ -0,0 +1,223 @@
+//! Compiling tree-sitter grammars into dynamic libraries loaded by [`get_language`].
+//!
+//! [`get_language`]: crate::get_language
+
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use crate::DYLIB_EXTENSION;
+
+/// The path of the library built for the grammar `name` in `out_dir`.
+pub fn library_path(out_dir: &Path, name: &str) -> PathBuf {
+    let mut library_path = out_dir.join(name.to_ascii_lowercase());
+    library_path.set_extension(DYLIB_EXTENSION);
+    library_path
+}
+
+fn scanner_path(src_path: &Path) -> Option<PathBuf> {
+    let mut scanner_path = src_path.join("scanner.c");
+    if scanner_path.exists() {
+        return Some(scanner_path);
+    }
+    scanner_path.set_extension("cc");
+    if scanner_path.exists() {
+        Some(scanner_path)
+    } else {
+        None
+    }
+}
+
+/// Whether the library of the grammar `name` is missing or older than its sources in
+/// `grammar_dir`, the directory containing `src/parser.c`.
+pub fn needs_recompile(grammar_dir: &Path, out_dir: &Path, name: &str) -> Result<bool> {
+    let src_path = grammar_dir.join("src");
+    let library_path = library_path(out_dir, name);
+    if !library_path.exists() {
+        return Ok(true);
+    }
+    let lib_mtime = mtime(&library_path)?;
+    if mtime(&src_path.join("parser.c"))? > lib_mtime {
+        return Ok(true);
+    }
+    if let Some(scanner_path) = scanner_path(&src_path) {
+        if mtime(&scanner_path)? > lib_mtime {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn mtime(path: &Path) -> Result<SystemTime> {
+    Ok(fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .modified()?)
+}
+
+/// Compiles the grammar `name` from `grammar_dir`, the directory containing `src/parser.c`, into
+/// `out_dir`. Returns `false` if the library is already up to date.
+pub fn build_grammar(name: &str, grammar_dir: &Path, out_dir: &Path) -> Result<bool> {
+    let src_path = grammar_dir.join("src");
+    let header_path = &src_path;
+    let parser_path = src_path.join("parser.c");
+    if !parser_path.exists() {
+        return Err(anyhow!("{} doesn't exist", parser_path.display()));
+    }
+    let scanner_path = scanner_path(&src_path);
+
+    if !needs_recompile(grammar_dir, out_dir, name)
+        .with_context(|| "Failed to compare source and binary timestamps")?
+    {
+        return Ok(false);
+    }
+
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create {}", out_dir.display()))?;
+    let library_path = library_path(out_dir, name);
+
+    let mut config = cc::Build::new();
+    config
+        .cpp(true)
+        .opt_level(2)
+        .cargo_metadata(false)
+        .host(env!("BUILD_TARGET"))
+        .target(env!("BUILD_TARGET"));
+    let compiler = config.get_compiler();
+    let mut command = Command::new(compiler.path());
+    command.current_dir(&src_path);
+    for (key, value) in compiler.env() {
+        command.env(key, value);
+    }
+
+    if cfg!(windows) {
+        command
+            .args(["/nologo", "/LD", "/I"])
+            .arg(header_path)
+            .arg("/Od")
+            .arg("/utf-8");
+        if let Some(scanner_path) = scanner_path.as_ref() {
+            command.arg(scanner_path);
+        }
+
+        command
+            .arg(parser_path)
+            .arg("/link")
+            .arg(format!("/out:{}", library_path.to_str().unwrap()));
+    } else {
+        command
+            .arg("-shared")
+            .arg("-fPIC")
+            .arg("-fno-exceptions")
+            .arg("-g")
+            .arg("-I")
+            .arg(header_path)
+            .arg("-o")
+            .arg(&library_path)
+            .arg("-O2");
+        if let Some(scanner_path) = scanner_path.as_ref() {
+            if scanner_path.extension() == Some("c".as_ref()) {
+                command.arg("-xc").arg("-std=c99").arg(scanner_path);
+            } else {
+                command.arg(scanner_path);
+            }
+        }
+        command.arg("-xc").arg(parser_path);
+        if cfg!(all(unix, not(target_os = "macos"))) {
+            command.arg("-Wl,-z,relro,-z,now");
+        }
+    }
+
+    let output = command
+        .output()
+        .with_context(|| "Failed to execute C compiler")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Parser compilation failed.\nStdout: {}\nStderr: {}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(true)
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .with_context(|| "Failed to execute git")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Checks out `rev` of the git repository `source` into `checkout`, cloning it first if needed.
+/// Nothing is touched when `rev` is already checked out, so the sources keep their timestamps.
+pub fn checkout(source: &str, rev: &str, checkout: &Path) -> Result<()> {
+    if !checkout.join(".git").exists() {
+        let parent = checkout.parent().unwrap_or(checkout);
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+        let status = Command::new("git")
+            .args(["clone", "--quiet", "--no-checkout", source])
+            .arg(checkout)
+            .status()
+            .with_context(|| "Failed to execute git")?;
+        if !status.success() {
+            return Err(anyhow!("git clone {} failed", source));
+        }
+    }
+
+    let commit = format!("{}^{{commit}}", rev);
+    let wanted = match git(checkout, &["rev-parse", "--verify", "--quiet", &commit]) {
+        Ok(wanted) => wanted,
+        Err(_) => {
+            // the revision may be newer than the clone
+            git(checkout, &["fetch", "--quiet", "origin"])?;
+            git(checkout, &["rev-parse", "--verify", "--quiet", &commit])
+                .map_err(|_| anyhow!("revision {} not found in {}", rev, source))?
+        }
+    };
+
+    // a fresh `--no-checkout` clone has a HEAD but no files yet
+    let checked_out = git(checkout, &["rev-parse", "HEAD"]).ok() == Some(wanted.clone())
+        && matches!(git(checkout, &["ls-files"]), Ok(files) if !files.is_empty());
+    if !checked_out {
+        git(
+            checkout,
+            &["checkout", "--quiet", "--force", "--detach", &wanted],
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_grammar() {
+        let grammar_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../runtime/grammars/sources/tree-sitter-tsq");
+        let runtime = std::env::temp_dir().join(format!("helix-grammars-{}", std::process::id()));
+        let out_dir = runtime.join("grammars");
+
+        assert!(build_grammar("tsq", &grammar_dir, &out_dir).unwrap());
+        assert!(crate::get_language(&runtime, "tsq").is_ok());
+        // the library is newer than the sources now
+        assert!(!needs_recompile(&grammar_dir, &out_dir, "tsq").unwrap());
+        assert!(!build_grammar("tsq", &grammar_dir, &out_dir).unwrap());
+
+        fs::remove_dir_all(&runtime).unwrap();
+    }
+}
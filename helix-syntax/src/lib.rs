@@ -2,20 +2,20 @@ use anyhow::{Context, Result};
 use libloading::{Library, Symbol};
 use tree_sitter::Language;
 
+pub mod build;
+
 fn replace_dashes_with_underscores(name: &str) -> String {
     name.replace('-', "_")
 }
 #[cfg(unix)]
-const DYLIB_EXTENSION: &str = "so";
+pub(crate) const DYLIB_EXTENSION: &str = "so";
 
 #[cfg(windows)]
-const DYLIB_EXTENSION: &str = "dll";
+pub(crate) const DYLIB_EXTENSION: &str = "dll";
 
 pub fn get_language(runtime_path: &std::path::Path, name: &str) -> Result<Language> {
     let name = name.to_ascii_lowercase();
-    let mut library_path = runtime_path.join("grammars").join(&name);
-    // TODO: duplicated under build
-    library_path.set_extension(DYLIB_EXTENSION);
+    let library_path = build::library_path(&runtime_path.join("grammars"), &name);
 
     let library = unsafe { Library::new(&library_path) }
         .with_context(|| format!("Error opening dynamic library {:?}", &library_path))?;
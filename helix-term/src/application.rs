@@ -1,18 +1,30 @@
-use helix_core::{merge_toml_values, syntax};
-use helix_lsp::{lsp, util::lsp_pos_to_pos, LspProgressMap};
-use helix_view::{theme, Editor};
-
-use crate::{args::Args, compositor::Compositor, config::Config, job::Jobs, ui};
+use helix_core::syntax;
+use helix_lsp::{lsp, LspProgressMap, OffsetEncoding};
+use helix_view::{editor::ConfigEvent, theme, Document, DocumentId, Editor};
+
+use crate::{
+    args::Args,
+    compositor::Compositor,
+    config::Config,
+    job::{self, Jobs},
+    ui,
+};
 
 use log::{error, warn};
+use serde::de::DeserializeOwned;
 
 use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
     io::{stdin, stdout, Write},
+    path::{Path, PathBuf},
     sync::Arc,
     time::{Duration, Instant},
 };
 
-use anyhow::Error;
+use anyhow::{Context, Error};
+use futures_util::Stream;
 
 use crossterm::{
     event::{DisableMouseCapture, EnableMouseCapture, Event, EventStream},
@@ -27,16 +39,86 @@ use {
 #[cfg(windows)]
 type Signals = futures_util::stream::Empty<()>;
 
+/// The answer to a request from a language server, replying `null` if it's dropped unanswered.
+struct PendingReply {
+    language_server: Arc<helix_lsp::Client>,
+    id: Option<helix_lsp::jsonrpc::Id>,
+}
+
+impl PendingReply {
+    fn reply(&mut self, result: serde_json::Value) {
+        if let Some(id) = self.id.take() {
+            tokio::spawn(self.language_server.reply(id, Ok(result)));
+        }
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        self.reply(serde_json::Value::Null);
+    }
+}
+
+/// Watches the files `:config-reload` reads, to reload them when they change.
+fn watch_config_files(editor: &mut Editor) {
+    let config_dir = helix_core::config_dir();
+    let runtime_dir = helix_core::runtime_dir();
+    editor
+        .file_watcher
+        .watch_file(&config_dir.join("config.toml"));
+    for path in crate::config::lang_config_paths() {
+        editor.file_watcher.watch_file(&path);
+    }
+    for dir in [
+        config_dir.join("themes"),
+        runtime_dir.join("themes"),
+        runtime_dir.join("queries"),
+    ] {
+        if dir.is_dir() {
+            editor.file_watcher.watch_dir(&dir);
+        }
+    }
+}
+
+/// Indexes the definitions in the workspace at `root` in the background, for symbol navigation
+/// without a language server.
+fn build_tag_index(editor: &Editor, root: PathBuf) {
+    let tag_index = editor.tag_index.clone();
+    let loader = editor.syn_loader.clone();
+    let config = editor.config.clone();
+    tokio::task::spawn_blocking(move || {
+        let files = ui::workspace_files(&root, &config);
+        tag_index.build(&loader, files.into_iter().map(|(path, _)| path));
+    });
+}
+
+/// Whether changes to `path` should reload the configuration.
+fn is_config_file(path: &Path) -> bool {
+    let config_dir = helix_core::config_dir();
+    let runtime_dir = helix_core::runtime_dir();
+    let is_theme =
+        |dir: PathBuf| path.starts_with(dir) && path.extension() == Some("toml".as_ref());
+
+    path == config_dir.join("config.toml")
+        || crate::config::lang_config_paths().iter().any(|p| p == path)
+        || is_theme(config_dir.join("themes"))
+        || is_theme(runtime_dir.join("themes"))
+        || path.starts_with(runtime_dir.join("queries"))
+}
+
+/// How many times a language server that keeps exiting is restarted before giving up.
+const MAX_LANGUAGE_SERVER_RESTARTS: u32 = 5;
+
+/// How long a restarted language server has to stay up before its restarts are forgotten.
+const LANGUAGE_SERVER_STABLE_AFTER: Duration = Duration::from_secs(30);
+
 pub struct Application {
-    compositor: Compositor,
-    editor: Editor,
+    pub compositor: Compositor,
+    pub editor: Editor,
 
     // TODO should be separate to take only part of the config
     config: Config,
 
-    // Currently never read from.  Remove the `allow(dead_code)` when
-    // that changes.
-    #[allow(dead_code)]
     theme_loader: Arc<theme::Loader>,
 
     // Currently never read from.  Remove the `allow(dead_code)` when
@@ -47,10 +129,15 @@ pub struct Application {
     signals: Signals,
     jobs: Jobs,
     lsp_progress: LspProgressMap,
+    last_render: Instant,
 }
 
 impl Application {
-    pub fn new(args: Args, mut config: Config) -> Result<Self, Error> {
+    pub fn new(
+        args: Args,
+        mut config: Config,
+        syn_loader_conf: syntax::Configuration,
+    ) -> Result<Self, Error> {
         use helix_view::editor::Action;
         let mut compositor = Compositor::new()?;
         let size = compositor.size();
@@ -60,44 +147,18 @@ impl Application {
         let theme_loader =
             std::sync::Arc::new(theme::Loader::new(&conf_dir, &helix_core::runtime_dir()));
 
-        // load default and user config, and merge both
-        let builtin_err_msg =
-            "Could not parse built-in languages.toml, something must be very wrong";
-        let def_lang_conf: toml::Value =
-            toml::from_slice(include_bytes!("../../languages.toml")).expect(builtin_err_msg);
-        let def_syn_loader_conf: helix_core::syntax::Configuration =
-            def_lang_conf.clone().try_into().expect(builtin_err_msg);
-        let user_lang_conf = std::fs::read(conf_dir.join("languages.toml"))
-            .ok()
-            .map(|raw| toml::from_slice(&raw));
-        let lang_conf = match user_lang_conf {
-            Some(Ok(value)) => Ok(merge_toml_values(def_lang_conf, value)),
-            Some(err @ Err(_)) => err,
-            None => Ok(def_lang_conf),
-        };
-
-        let theme = if let Some(theme) = &config.theme {
+        let (theme, theme_name) = if let Some(theme) = &config.theme {
             match theme_loader.load(theme) {
-                Ok(theme) => theme,
+                Ok(loaded) => (loaded, Some(theme.clone())),
                 Err(e) => {
                     log::warn!("failed to load theme `{}` - {}", theme, e);
-                    theme_loader.default()
+                    (theme_loader.default(), None)
                 }
             }
         } else {
-            theme_loader.default()
+            (theme_loader.default(), None)
         };
 
-        let syn_loader_conf: helix_core::syntax::Configuration = lang_conf
-            .and_then(|conf| conf.try_into())
-            .unwrap_or_else(|err| {
-                eprintln!("Bad language config: {}", err);
-                eprintln!("Press <ENTER> to continue with default language config");
-                use std::io::Read;
-                // This waits for an enter press.
-                let _ = std::io::stdin().read(&mut []);
-                def_syn_loader_conf
-            });
         let syn_loader = std::sync::Arc::new(syntax::Loader::new(syn_loader_conf));
 
         let mut editor = Editor::new(
@@ -149,6 +210,29 @@ impl Application {
         }
 
         editor.set_theme(theme);
+        editor.theme_name = theme_name;
+        editor
+            .language_servers
+            .message_log
+            .set_enabled(config.lsp.message_log);
+
+        let workspace_lang_config = crate::config::workspace_lang_config_path();
+        if workspace_lang_config.exists()
+            && !crate::config::lang_config_paths().contains(&workspace_lang_config)
+        {
+            editor.set_status(format!(
+                "Not loading {} of an untrusted workspace, see :trust-workspace",
+                workspace_lang_config.display()
+            ));
+        }
+
+        if let Some(root) = helix_core::find_root(None) {
+            editor.file_watcher.watch_root(&root);
+            build_tag_index(&editor, root);
+        }
+        if editor.config.auto_reload_config {
+            watch_config_files(&mut editor);
+        }
 
         #[cfg(windows)]
         let signals = futures_util::stream::empty();
@@ -167,6 +251,7 @@ impl Application {
             signals,
             jobs: Jobs::new(),
             lsp_progress: LspProgressMap::new(),
+            last_render: Instant::now(),
         };
 
         Ok(app)
@@ -186,17 +271,27 @@ impl Application {
         compositor.render(&mut cx);
     }
 
-    pub async fn event_loop(&mut self) {
-        let mut reader = EventStream::new();
-        let mut last_render = Instant::now();
-        let deadline = Duration::from_secs(1) / 60;
-
+    pub async fn event_loop<S>(&mut self, input_stream: &mut S)
+    where
+        S: Stream<Item = crossterm::Result<Event>> + Unpin,
+    {
         self.render();
+        self.last_render = Instant::now();
+
+        while self.event_loop_until_idle(input_stream).await {}
+    }
+
+    /// Handles events until the editor goes idle. Returns `false` once the editor should close.
+    pub async fn event_loop_until_idle<S>(&mut self, input_stream: &mut S) -> bool
+    where
+        S: Stream<Item = crossterm::Result<Event>> + Unpin,
+    {
+        let deadline = Duration::from_secs(1) / 60;
 
         loop {
             if self.editor.should_close() {
                 self.jobs.finish();
-                break;
+                return false;
             }
 
             use futures_util::StreamExt;
@@ -204,7 +299,7 @@ impl Application {
             tokio::select! {
                 biased;
 
-                event = reader.next() => {
+                event = input_stream.next() => {
                     self.handle_terminal_events(event)
                 }
                 Some(signal) = self.signals.next() => {
@@ -214,9 +309,9 @@ impl Application {
                     self.handle_language_server_message(call, id).await;
                     // limit render calls for fast language server messages
                     let last = self.editor.language_servers.incoming.is_empty();
-                    if last || last_render.elapsed() > deadline {
+                    if last || self.last_render.elapsed() > deadline {
                         self.render();
-                        last_render = Instant::now();
+                        self.last_render = Instant::now();
                     }
                 }
                 Some(callback) = self.jobs.futures.next() => {
@@ -227,15 +322,130 @@ impl Application {
                     self.jobs.handle_callback(&mut self.editor, &mut self.compositor, callback);
                     self.render();
                 }
+                Some(events) = self.editor.file_watcher.next() => {
+                    let reload = self.editor.config.auto_reload_config
+                        && events.iter().any(|event| is_config_file(&event.path));
+                    for (path, encoding) in self.editor.handle_file_events(events) {
+                        self.reload_changed_document(path, encoding);
+                    }
+                    if reload {
+                        self.refresh_config();
+                    }
+                    self.render();
+                }
+                Some(event) = self.editor.config_events.1.recv() => {
+                    self.handle_config_event(event);
+                    self.render();
+                }
+                _ = self.editor.syntax_parsed.notified() => {
+                    if self.editor.poll_syntax() {
+                        self.render();
+                    }
+                }
                 _ = &mut self.editor.idle_timer => {
                     // idle timeout
                     self.editor.clear_idle_timer();
                     self.handle_idle_timeout();
+                    return true;
                 }
             }
         }
     }
 
+    /// Reads an open document that changed on disk off the main loop, then reloads it.
+    fn reload_changed_document(&mut self, path: PathBuf, encoding: &'static encoding_rs::Encoding) {
+        self.jobs.callback(async move {
+            let read_path = path.clone();
+            let text = tokio::task::spawn_blocking(move || {
+                let mut file = std::fs::File::open(&read_path)?;
+                let (text, _) = helix_view::document::from_reader(&mut file, Some(encoding))?;
+                Ok(text)
+            })
+            .await?;
+            let call: job::Callback = Box::new(move |editor: &mut Editor, _| {
+                editor.document_changed_on_disk(&path, text);
+            });
+            Ok(call)
+        });
+    }
+
+    fn handle_config_event(&mut self, event: ConfigEvent) {
+        match event {
+            ConfigEvent::Refresh => self.refresh_config(),
+            ConfigEvent::RefreshLanguages => self.refresh_languages(),
+        }
+    }
+
+    /// Reloads the language configuration, reporting the outcome in the status line.
+    fn refresh_languages(&mut self) {
+        match crate::config::lang_config() {
+            Ok(syn_loader_conf) => {
+                self.set_languages(syn_loader_conf);
+                self.editor
+                    .set_status("Reloaded language settings".to_string());
+            }
+            Err(err) => self
+                .editor
+                .set_error(format!("Failed to reload languages.toml: {}", err)),
+        }
+    }
+
+    /// Replaces the language configuration, sending the new settings to the running language
+    /// servers and setting the languages of the open documents again.
+    fn set_languages(&mut self, syn_loader_conf: syntax::Configuration) {
+        self.syn_loader = Arc::new(syntax::Loader::new(syn_loader_conf));
+        self.editor.set_syn_loader(self.syn_loader.clone());
+        self.editor.refresh_languages();
+    }
+
+    /// Reloads the configuration, reporting the outcome in the status line. Nothing changes if
+    /// any of the files is invalid.
+    fn refresh_config(&mut self) {
+        match self.reload_config() {
+            Ok(()) => self.editor.set_status("Reloaded configuration".to_string()),
+            Err(err) => self
+                .editor
+                .set_error(format!("Failed to reload configuration: {}", err)),
+        }
+    }
+
+    fn reload_config(&mut self) -> Result<(), Error> {
+        let mut config = Config::load()?;
+        let syn_loader_conf = crate::config::lang_config()?;
+        // a theme set with `:theme` is kept unless the one in config.toml changed
+        let theme_name = if config.theme != self.config.theme {
+            config.theme.clone()
+        } else {
+            self.editor.theme_name.clone()
+        };
+        let theme = match &theme_name {
+            Some(name) => self
+                .theme_loader
+                .load(name)
+                .with_context(|| format!("failed loading theme `{}`", name))?,
+            None => self.theme_loader.default(),
+        };
+
+        if let Some(editor_view) = self.compositor.find::<ui::EditorView>() {
+            editor_view.set_keymaps(std::mem::take(&mut config.keys));
+        }
+        self.editor.config = config.editor.clone();
+        self.editor.set_theme(theme);
+        self.editor.theme_name = theme_name;
+        self.editor
+            .language_servers
+            .message_log
+            .set_enabled(config.lsp.message_log);
+        // parses the documents again with the new theme's scopes
+        self.set_languages(syn_loader_conf);
+        if self.editor.config.auto_reload_config {
+            watch_config_files(&mut self.editor);
+        }
+        self.config = config;
+
+        Ok(())
+    }
+
     #[cfg(windows)]
     // no signal handling available on windows
     pub async fn handle_signals(&mut self, _signal: ()) {}
@@ -265,6 +475,11 @@ impl Application {
         use crate::commands::{insert::idle_completion, Context};
         use helix_view::document::Mode;
 
+        self.pull_diagnostics();
+        self.request_code_lenses();
+        self.request_document_links();
+        self.request_linked_editing_ranges();
+
         if doc!(self.editor).mode != Mode::Insert || !self.config.editor.auto_completion {
             return;
         }
@@ -289,6 +504,380 @@ impl Application {
         self.render();
     }
 
+    /// Requests items like code lenses for the visible documents that changed since they were last
+    /// requested, and resolves them. They're stored with `store` unless the document changed in the
+    /// meantime, in which case they're requested again for the new version.
+    fn request_document_items<T, R, F>(
+        &mut self,
+        requests: fn(&mut Editor) -> &mut HashMap<DocumentId, (usize, i32)>,
+        request: fn(&helix_lsp::Client, lsp::TextDocumentIdentifier) -> Option<R>,
+        resolve: fn(Arc<helix_lsp::Client>, T) -> F,
+        store: fn(&mut Document, Vec<T>, OffsetEncoding),
+    ) where
+        T: DeserializeOwned + Send + 'static,
+        R: Future<Output = helix_lsp::Result<serde_json::Value>> + Send + 'static,
+        F: Future<Output = T> + Send + 'static,
+    {
+        let mut doc_ids: Vec<_> = self.editor.tree.views().map(|(view, _)| view.doc).collect();
+        doc_ids.sort_unstable();
+        doc_ids.dedup();
+
+        for doc_id in doc_ids {
+            let doc = match self.editor.documents.get(&doc_id) {
+                Some(doc) => doc,
+                None => continue,
+            };
+            let language_server = match doc
+                .language_server()
+                .and_then(|server| self.editor.language_servers.get_by_id(server.id()))
+            {
+                Some(language_server) => language_server.clone(),
+                None => continue,
+            };
+            let identifier = doc.identifier();
+            let version = doc.version();
+
+            let requests = requests(&mut self.editor);
+            let state = (language_server.id(), version);
+            if requests.get(&doc_id) == Some(&state) {
+                continue;
+            }
+            let request = match request(&language_server, identifier) {
+                Some(request) => request,
+                None => continue,
+            };
+            requests.insert(doc_id, state);
+
+            let offset_encoding = language_server.offset_encoding();
+            self.jobs.callback(async move {
+                let items: Option<Vec<T>> = serde_json::from_value(request.await?)?;
+                let items = futures_util::future::join_all(
+                    items
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|item| resolve(language_server.clone(), item)),
+                )
+                .await;
+
+                let call: job::Callback =
+                    Box::new(move |editor: &mut Editor, _compositor: &mut Compositor| {
+                        match editor.documents.get_mut(&doc_id) {
+                            Some(doc) if doc.version() == version => {
+                                store(doc, items, offset_encoding)
+                            }
+                            // outdated, the items are requested again for the new version
+                            _ => (),
+                        }
+                    });
+                Ok(call)
+            });
+        }
+    }
+
+    /// Requests code lenses for documents that changed since they were last requested, resolving
+    /// them before they're shown.
+    fn request_code_lenses(&mut self) {
+        use helix_view::document::CodeLens;
+
+        async fn resolve(
+            language_server: Arc<helix_lsp::Client>,
+            lens: lsp::CodeLens,
+        ) -> lsp::CodeLens {
+            if lens.command.is_some() {
+                return lens;
+            }
+            let request = match language_server.code_lens_resolve(lens.clone()) {
+                Some(request) => request,
+                None => return lens,
+            };
+            match request.await.map(serde_json::from_value) {
+                Ok(Ok(resolved)) => resolved,
+                _ => lens,
+            }
+        }
+
+        fn store(doc: &mut Document, lenses: Vec<lsp::CodeLens>, offset_encoding: OffsetEncoding) {
+            let lenses = lenses
+                .into_iter()
+                .filter_map(|lens| {
+                    let pos = helix_lsp::util::lsp_pos_to_pos(
+                        doc.text(),
+                        lens.range.start,
+                        offset_encoding,
+                    )?;
+                    Some(CodeLens {
+                        pos,
+                        command: lens.command?,
+                    })
+                })
+                .collect();
+            doc.set_code_lenses(lenses);
+        }
+
+        self.request_document_items(
+            |editor| &mut editor.code_lens_requests,
+            helix_lsp::Client::code_lens,
+            resolve,
+            store,
+        );
+    }
+
+    /// Requests document links for documents that changed since they were last requested. Links
+    /// without a target are resolved, the ones that still have no target are dropped.
+    fn request_document_links(&mut self) {
+        use helix_view::document::DocumentLink;
+
+        async fn resolve(
+            language_server: Arc<helix_lsp::Client>,
+            link: lsp::DocumentLink,
+        ) -> lsp::DocumentLink {
+            if link.target.is_some() {
+                return link;
+            }
+            let request = match language_server.document_link_resolve(link.clone()) {
+                Some(request) => request,
+                None => return link,
+            };
+            match request.await.map(serde_json::from_value) {
+                Ok(Ok(resolved)) => resolved,
+                _ => link,
+            }
+        }
+
+        fn store(
+            doc: &mut Document,
+            links: Vec<lsp::DocumentLink>,
+            offset_encoding: OffsetEncoding,
+        ) {
+            let links = links
+                .into_iter()
+                .filter_map(|link| {
+                    let text = doc.text();
+                    let start =
+                        helix_lsp::util::lsp_pos_to_pos(text, link.range.start, offset_encoding)?;
+                    let end =
+                        helix_lsp::util::lsp_pos_to_pos(text, link.range.end, offset_encoding)?;
+                    Some(DocumentLink {
+                        start,
+                        end,
+                        target: link.target?,
+                    })
+                })
+                .collect();
+            doc.set_document_links(links);
+        }
+
+        self.request_document_items(
+            |editor| &mut editor.document_link_requests,
+            helix_lsp::Client::document_link,
+            resolve,
+            store,
+        );
+    }
+
+    /// Forgets how often the language server was restarted once it stays up for a while after
+    /// initializing, so that a later crash gets as many restarts again.
+    fn forget_restarts(&mut self, server_id: usize) {
+        use helix_view::editor::LanguageServerStatus;
+
+        let scope = match self.editor.language_servers.scope_by_id(server_id) {
+            Some(scope) => scope.to_owned(),
+            None => return,
+        };
+        let attempt = match self.editor.language_server_status.get(&scope) {
+            Some(LanguageServerStatus::Restarted(attempt)) => *attempt,
+            _ => return,
+        };
+
+        self.jobs.callback(async move {
+            tokio::time::sleep(LANGUAGE_SERVER_STABLE_AFTER).await;
+            let call: job::Callback =
+                Box::new(move |editor: &mut Editor, _compositor: &mut Compositor| {
+                    // the server may have exited or been restarted meanwhile
+                    let status = editor.language_server_status.get(&scope);
+                    if editor.language_servers.scope_by_id(server_id) == Some(scope.as_str())
+                        && status == Some(&LanguageServerStatus::Restarted(attempt))
+                    {
+                        editor.language_server_status.remove(&scope);
+                    }
+                });
+            Ok(call)
+        });
+    }
+
+    /// Requests the ranges linked to the cursor of the focused document, so they're ready once
+    /// the user starts typing.
+    fn request_linked_editing_ranges(&mut self) {
+        use helix_core::{linked_editing::LinkedRanges, regex::Regex};
+
+        let (view, doc) = current_ref!(self.editor);
+        let language_server = match doc.language_server() {
+            Some(language_server) => language_server,
+            None => return,
+        };
+
+        let pos = doc
+            .selection(view.id)
+            .primary()
+            .cursor(doc.text().slice(..));
+        let state = (doc.version(), pos);
+        if self.editor.linked_editing_requests.get(&doc.id()) == Some(&state) {
+            return;
+        }
+
+        let offset_encoding = language_server.offset_encoding();
+        let position = helix_lsp::util::pos_to_lsp_pos(doc.text(), pos, offset_encoding);
+        let request = match language_server.linked_editing_range(doc.identifier(), position) {
+            Some(request) => request,
+            None => return,
+        };
+        let doc_id = doc.id();
+        self.editor.linked_editing_requests.insert(doc_id, state);
+
+        let version = doc.version();
+        self.jobs.callback(async move {
+            let response: Option<lsp::LinkedEditingRanges> =
+                serde_json::from_value(request.await?)?;
+
+            let call: job::Callback =
+                Box::new(move |editor: &mut Editor, _compositor: &mut Compositor| {
+                    let doc = match editor.documents.get_mut(&doc_id) {
+                        Some(doc) if doc.version() == version => doc,
+                        _ => return,
+                    };
+                    let linked_ranges = response.and_then(|response| {
+                        let ranges = response
+                            .ranges
+                            .into_iter()
+                            .map(|range| {
+                                let range = helix_lsp::util::lsp_range_to_range(
+                                    doc.text(),
+                                    range,
+                                    offset_encoding,
+                                )?;
+                                Some((range.from(), range.to()))
+                            })
+                            .collect::<Option<Vec<_>>>()?;
+                        // the pattern is a JavaScript regex, ignore the ones we can't parse
+                        let word_pattern = response
+                            .word_pattern
+                            .and_then(|pattern| Regex::new(&pattern).ok());
+                        Some(LinkedRanges {
+                            ranges,
+                            word_pattern,
+                        })
+                    });
+                    doc.set_linked_ranges(linked_ranges);
+                });
+            Ok(call)
+        });
+    }
+
+    /// Requests diagnostics from the language servers that only offer pulling them, for the
+    /// documents that changed or were saved since they were last requested.
+    fn pull_diagnostics(&mut self) {
+        use helix_lsp::pull_diagnostics::PreviousResultId;
+
+        let editor = &mut self.editor;
+        let pulls = &mut editor.diagnostic_pulls;
+
+        for doc in editor.documents.values() {
+            let language_server = match doc.language_server() {
+                Some(language_server) if language_server.diagnostic_provider().is_some() => {
+                    language_server
+                }
+                _ => continue,
+            };
+            let server_id = language_server.id();
+
+            let state = (doc.version(), doc.is_modified());
+            let previous = pulls.documents.insert(doc.id(), state);
+            if previous == Some(state) {
+                continue;
+            }
+            // saving can change the diagnostics of other files as well
+            if matches!(previous, Some((_, true))) && !doc.is_modified() {
+                pulls.workspace_pending.insert(server_id);
+            }
+
+            let uri = match doc.url() {
+                Some(uri) => uri,
+                None => continue,
+            };
+            let previous_result_id = pulls.result_ids.get(&(server_id, uri.clone())).cloned();
+            let request = match language_server
+                .text_document_diagnostic(doc.identifier(), previous_result_id)
+            {
+                Some(request) => request,
+                None => continue,
+            };
+            self.jobs.callback(async move {
+                let report = request.await?;
+                let call: job::Callback =
+                    Box::new(move |editor: &mut Editor, _compositor: &mut Compositor| {
+                        editor.apply_diagnostic_report(server_id, uri, report);
+                    });
+                Ok(call)
+            });
+        }
+
+        let pending: Vec<_> = pulls.workspace_pending.drain().collect();
+        for server_id in pending {
+            // ask again once the request in flight is answered
+            if pulls.workspace_in_flight.contains(&server_id) {
+                pulls.workspace_pending.insert(server_id);
+                continue;
+            }
+            let language_server = match editor.language_servers.get_by_id(server_id) {
+                Some(language_server) => language_server,
+                None => continue,
+            };
+            let previous_result_ids = pulls
+                .result_ids
+                .iter()
+                .filter(|((id, _), _)| *id == server_id)
+                .map(|((_, uri), value)| PreviousResultId {
+                    uri: uri.clone(),
+                    value: value.clone(),
+                })
+                .collect();
+            let request = match language_server.workspace_diagnostic(previous_result_ids) {
+                Some(request) => request,
+                None => continue,
+            };
+            pulls.workspace_in_flight.insert(server_id);
+
+            self.jobs.callback(async move {
+                let report = request.await;
+                let call: job::Callback =
+                    Box::new(move |editor: &mut Editor, _compositor: &mut Compositor| {
+                        editor
+                            .diagnostic_pulls
+                            .workspace_in_flight
+                            .remove(&server_id);
+                        let report = match report {
+                            Ok(report) => report,
+                            Err(err) => {
+                                log::warn!("failed to pull workspace diagnostics: {}", err);
+                                return;
+                            }
+                        };
+                        for item in report.items {
+                            // skip reports for an older version of an open document
+                            let outdated = editor.documents().any(|doc| {
+                                doc.url().as_ref() == Some(&item.uri)
+                                    && matches!(item.version, Some(version) if version != doc.version())
+                            });
+                            if !outdated {
+                                editor.apply_diagnostic_report(server_id, item.uri, item.report);
+                            }
+                        }
+                    });
+                Ok(call)
+            });
+        }
+    }
+
     pub fn handle_terminal_events(&mut self, event: Option<Result<Event, crossterm::ErrorKind>>) {
         let mut cx = crate::compositor::Context {
             editor: &mut self.editor,
@@ -358,76 +947,97 @@ impl Application {
                                 language_id,
                             ));
                         }
+
+                        // some servers only read their settings once they're pushed to them
+                        if let Some(settings) = self.editor.language_server_settings(server_id) {
+                            tokio::spawn(language_server.did_change_configuration(settings));
+                        }
+
+                        self.editor
+                            .diagnostic_pulls
+                            .workspace_pending
+                            .insert(server_id);
+                        self.pull_diagnostics();
+                        self.request_code_lenses();
+                        self.request_document_links();
+                        self.forget_restarts(server_id);
                     }
-                    Notification::PublishDiagnostics(params) => {
-                        let path = params.uri.to_file_path().unwrap();
-                        let doc = self.editor.document_by_path_mut(&path);
-
-                        if let Some(doc) = doc {
-                            let text = doc.text();
-
-                            let diagnostics = params
-                                .diagnostics
-                                .into_iter()
-                                .filter_map(|diagnostic| {
-                                    use helix_core::{
-                                        diagnostic::{Range, Severity::*},
-                                        Diagnostic,
-                                    };
-                                    use lsp::DiagnosticSeverity;
-
-                                    let language_server = doc.language_server().unwrap();
-
-                                    // TODO: convert inside server
-                                    let start = if let Some(start) = lsp_pos_to_pos(
-                                        text,
-                                        diagnostic.range.start,
-                                        language_server.offset_encoding(),
-                                    ) {
-                                        start
-                                    } else {
-                                        log::warn!("lsp position out of bounds - {:?}", diagnostic);
-                                        return None;
-                                    };
-
-                                    let end = if let Some(end) = lsp_pos_to_pos(
-                                        text,
-                                        diagnostic.range.end,
-                                        language_server.offset_encoding(),
-                                    ) {
-                                        end
-                                    } else {
-                                        log::warn!("lsp position out of bounds - {:?}", diagnostic);
-                                        return None;
-                                    };
-
-                                    Some(Diagnostic {
-                                        range: Range { start, end },
-                                        line: diagnostic.range.start.line as usize,
-                                        message: diagnostic.message,
-                                        severity: diagnostic.severity.map(
-                                            |severity| match severity {
-                                                DiagnosticSeverity::ERROR => Error,
-                                                DiagnosticSeverity::WARNING => Warning,
-                                                DiagnosticSeverity::INFORMATION => Info,
-                                                DiagnosticSeverity::HINT => Hint,
-                                                severity => unimplemented!("{:?}", severity),
-                                            },
-                                        ),
-                                        // code
-                                        // source
-                                    })
-                                })
-                                .collect();
-
-                            doc.set_diagnostics(diagnostics);
+                    Notification::Exit => {
+                        use helix_view::editor::LanguageServerStatus;
+
+                        // servers that were stopped or restarted on purpose are already gone
+                        // from the registry, only unexpected exits are handled here
+                        let scope = match self.editor.language_servers.scope_by_id(server_id) {
+                            Some(scope) => scope.to_owned(),
+                            None => return,
+                        };
+                        self.editor.remove_language_server(&scope);
+
+                        let attempt = match self.editor.language_server_status.get(&scope) {
+                            Some(
+                                LanguageServerStatus::Restarting(attempt)
+                                | LanguageServerStatus::Restarted(attempt),
+                            ) => attempt + 1,
+                            _ => 1,
+                        };
+
+                        if attempt > MAX_LANGUAGE_SERVER_RESTARTS {
+                            self.editor
+                                .language_server_status
+                                .insert(scope.clone(), LanguageServerStatus::Crashed);
+                            self.editor.set_error(format!(
+                                "Language server for {} keeps exiting, use :lsp-restart to start it again",
+                                scope
+                            ));
+                            return;
                         }
+
+                        self.editor
+                            .language_server_status
+                            .insert(scope.clone(), LanguageServerStatus::Restarting(attempt));
+                        self.editor
+                            .set_error(format!("Language server for {} exited", scope));
+
+                        // back off exponentially: 0.5s, 1s, 2s, ...
+                        let delay = Duration::from_millis(500 << (attempt - 1));
+                        self.jobs.callback(async move {
+                            tokio::time::sleep(delay).await;
+                            let call: job::Callback = Box::new(
+                                move |editor: &mut Editor, _compositor: &mut Compositor| {
+                                    // the server may have been stopped or restarted meanwhile
+                                    let status = editor.language_server_status.get(&scope);
+                                    if status != Some(&LanguageServerStatus::Restarting(attempt)) {
+                                        return;
+                                    }
+                                    editor.restart_language_server(&scope);
+                                    editor
+                                        .language_server_status
+                                        .insert(scope, LanguageServerStatus::Restarted(attempt));
+                                },
+                            );
+                            Ok(call)
+                        });
+                    }
+                    Notification::PublishDiagnostics(params) => {
+                        self.editor.set_language_server_diagnostics(
+                            server_id,
+                            params.uri,
+                            params.diagnostics,
+                        );
                     }
                     Notification::ShowMessage(params) => {
                         log::warn!("unhandled window/showMessage: {:?}", params);
                     }
                     Notification::LogMessage(params) => {
                         log::info!("window/logMessage: {:?}", params);
+                        let language_servers = &self.editor.language_servers;
+                        if let Some(client) = language_servers.get_by_id(server_id) {
+                            language_servers.message_log.log_message(
+                                server_id,
+                                client.name(),
+                                &params,
+                            );
+                        }
                     }
                     Notification::ProgressMessage(params)
                         if !self
@@ -521,7 +1131,7 @@ impl Application {
                 method, params, id, ..
             }) => {
                 let language_server = match self.editor.language_servers.get_by_id(server_id) {
-                    Some(language_server) => language_server,
+                    Some(language_server) => language_server.clone(),
                     None => {
                         warn!("can't find language server with id `{}`", server_id);
                         return;
@@ -559,12 +1169,172 @@ impl Application {
                         }
                         tokio::spawn(language_server.reply(id, Ok(serde_json::Value::Null)));
                     }
+                    MethodCall::RegisterCapability(params) => {
+                        use lsp::notification::{DidChangeWatchedFiles, Notification};
+
+                        for registration in params.registrations {
+                            if registration.method != DidChangeWatchedFiles::METHOD {
+                                continue;
+                            }
+                            let options: Option<lsp::DidChangeWatchedFilesRegistrationOptions> =
+                                registration
+                                    .register_options
+                                    .and_then(|options| serde_json::from_value(options).ok());
+                            if let Some(options) = options {
+                                self.editor.file_watcher.register_lsp_watchers(
+                                    server_id,
+                                    registration.id,
+                                    options.watchers,
+                                );
+                            }
+                        }
+                        tokio::spawn(language_server.reply(id, Ok(serde_json::Value::Null)));
+                    }
+                    MethodCall::UnregisterCapability(params) => {
+                        for unregistration in params.unregisterations {
+                            self.editor
+                                .file_watcher
+                                .unregister_lsp_watchers(server_id, &unregistration.id);
+                        }
+                        tokio::spawn(language_server.reply(id, Ok(serde_json::Value::Null)));
+                    }
+                    MethodCall::WorkspaceConfiguration(params) => {
+                        let settings = self.editor.language_server_settings(server_id);
+                        let result = params
+                            .items
+                            .iter()
+                            .map(|item| {
+                                helix_lsp::util::settings_section(
+                                    settings.as_ref(),
+                                    item.section.as_deref(),
+                                )
+                            })
+                            .collect();
+                        tokio::spawn(
+                            language_server.reply(id, Ok(serde_json::Value::Array(result))),
+                        );
+                    }
+                    MethodCall::ShowMessageRequest(params) => {
+                        if params.typ == lsp::MessageType::ERROR {
+                            self.editor.set_error(params.message);
+                        } else {
+                            self.editor.set_status(params.message);
+                        }
+
+                        let actions = params.actions.unwrap_or_default();
+                        if actions.is_empty() {
+                            tokio::spawn(language_server.reply(id, Ok(serde_json::Value::Null)));
+                            return;
+                        }
+
+                        // closing the menu without choosing an action answers `null`
+                        let reply = RefCell::new(PendingReply {
+                            language_server,
+                            id: Some(id),
+                        });
+                        let mut menu = ui::Menu::new(
+                            actions,
+                            move |_editor, action: Option<&lsp::MessageActionItem>, event| {
+                                if let (ui::PromptEvent::Validate, Some(action)) = (event, action) {
+                                    let action = serde_json::to_value(action)
+                                        .unwrap_or(serde_json::Value::Null);
+                                    reply.borrow_mut().reply(action);
+                                }
+                            },
+                        );
+                        menu.move_down();
+                        self.compositor.push(Box::new(ui::Popup::new(menu)));
+                    }
+                    MethodCall::ApplyWorkspaceEdit(params) => {
+                        let offset_encoding = language_server.offset_encoding();
+                        let summary = crate::commands::apply_workspace_edit(
+                            &mut self.editor,
+                            offset_encoding,
+                            &params.edit,
+                        );
+                        let result = lsp::ApplyWorkspaceEditResponse {
+                            applied: summary.failed == 0,
+                            failure_reason: None,
+                            failed_change: None,
+                        };
+                        tokio::spawn(language_server.reply(
+                            id,
+                            Ok(serde_json::to_value(result).unwrap_or(serde_json::Value::Null)),
+                        ));
+                    }
+                    MethodCall::ShowDocument(params) => {
+                        let offset_encoding = language_server.offset_encoding();
+                        let success = self.show_document(params, offset_encoding);
+                        let result = lsp::ShowDocumentResult { success };
+                        tokio::spawn(language_server.reply(
+                            id,
+                            Ok(serde_json::to_value(result).unwrap_or(serde_json::Value::Null)),
+                        ));
+                    }
                 }
             }
             e => unreachable!("{:?}", e),
         }
     }
 
+    /// Opens the document a language server asked to show, returning whether it was shown.
+    fn show_document(
+        &mut self,
+        params: lsp::ShowDocumentParams,
+        offset_encoding: helix_lsp::OffsetEncoding,
+    ) -> bool {
+        use helix_core::Selection;
+        use helix_view::{current, editor::Action, view};
+
+        if params.external.unwrap_or(false) {
+            self.editor.set_error(format!(
+                "Opening {} in an external program is not supported",
+                params.uri
+            ));
+            return false;
+        }
+
+        let path = match params.uri.to_file_path() {
+            Ok(path) => path,
+            Err(_) => {
+                self.editor
+                    .set_error(format!("Unable to show non-file URI {}", params.uri));
+                return false;
+            }
+        };
+
+        let take_focus = params.take_focus.unwrap_or(false);
+        let focus = view!(self.editor).id;
+        let action = if take_focus {
+            Action::Replace
+        } else {
+            Action::VerticalSplit
+        };
+
+        if let Err(err) = self.editor.open(path.clone(), action) {
+            self.editor
+                .set_error(format!("Failed to open {}: {}", path.display(), err));
+            return false;
+        }
+
+        if let Some(range) = params.selection {
+            let (view, doc) = current!(self.editor);
+            if let Some(range) =
+                helix_lsp::util::lsp_range_to_range(doc.text(), range, offset_encoding)
+            {
+                doc.set_selection(view.id, Selection::single(range.anchor, range.head));
+                let view_id = view.id;
+                self.editor.ensure_cursor_in_view(view_id);
+            }
+        }
+
+        if !take_focus {
+            self.editor.tree.focus = focus;
+        }
+
+        true
+    }
+
     async fn claim_term(&mut self) -> Result<(), Error> {
         terminal::enable_raw_mode()?;
         let mut stdout = stdout();
@@ -602,7 +1372,8 @@ impl Application {
             hook(info);
         }));
 
-        self.event_loop().await;
+        let mut reader = EventStream::new();
+        self.event_loop(&mut reader).await;
 
         if self.editor.close_language_servers(None).await.is_err() {
             log::error!("Timed out waiting for language servers to shutdown");
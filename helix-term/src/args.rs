@@ -7,9 +7,17 @@ pub struct Args {
     pub display_version: bool,
     pub load_tutor: bool,
     pub verbosity: u64,
+    pub grammar: Option<GrammarCommand>,
     pub files: Vec<PathBuf>,
 }
 
+/// What `hx --grammar` does with the grammars declared in `languages.toml`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrammarCommand {
+    Build,
+    List,
+}
+
 impl Args {
     pub fn parse_args() -> Result<Args> {
         let mut args = Args::default();
@@ -18,12 +26,25 @@ impl Args {
 
         iter.next(); // skip the program, we don't care about that
 
-        for arg in &mut iter {
+        while let Some(arg) = iter.next() {
             match arg.as_str() {
                 "--" => break, // stop parsing at this point treat the remaining as files
                 "--version" => args.display_version = true,
                 "--help" => args.display_help = true,
                 "--tutor" => args.load_tutor = true,
+                "--grammar" => {
+                    args.grammar = match iter.next().map(|arg| arg.as_str()) {
+                        Some("build") => Some(GrammarCommand::Build),
+                        Some("list") => Some(GrammarCommand::List),
+                        Some(arg) => {
+                            return Err(Error::msg(format!(
+                                "unexpected --grammar action: {}, expected build or list",
+                                arg
+                            )))
+                        }
+                        None => return Err(Error::msg("--grammar needs an action: build or list")),
+                    }
+                }
                 arg if arg.starts_with("--") => {
                     return Err(Error::msg(format!(
                         "unexpected double dash argument: {}",
@@ -9,14 +9,16 @@ use helix_core::{
     numbers::NumberIncrementor,
     object, pos_at_coords,
     regex::{self, Regex, RegexBuilder},
-    search, selection, surround, textobject,
+    search, selection, surround,
+    syntax::Tag,
+    textobject,
     unicode::width::UnicodeWidthChar,
     LineEnding, Position, Range, Rope, RopeGraphemes, RopeSlice, Selection, SmallVec, Tendril,
     Transaction,
 };
 use helix_view::{
     clipboard::ClipboardType,
-    document::{Mode, SCRATCH_BUFFER_NAME},
+    document::{FormatterError, Formatting, Mode, SCRATCH_BUFFER_NAME},
     editor::{Action, Motion},
     input::KeyEvent,
     keyboard::KeyCode,
@@ -45,6 +47,7 @@ use std::{fmt, future::Future};
 
 use std::{
     borrow::Cow,
+    collections::{BTreeMap, HashSet},
     path::{Path, PathBuf},
 };
 
@@ -241,9 +244,12 @@ impl Command {
         command_mode, "Enter command mode",
         file_picker, "Open file picker",
         code_action, "Perform code action",
+        code_lens, "Run a code lens on the cursor line",
         buffer_picker, "Open buffer picker",
         symbol_picker, "Open symbol picker",
         workspace_symbol_picker, "Open workspace symbol picker",
+        diagnostics_picker, "Open diagnostic picker",
+        workspace_diagnostics_picker, "Open workspace diagnostic picker",
         last_picker, "Open last picker",
         prepend_to_line, "Insert at start of line",
         append_to_line, "Insert at end of line",
@@ -257,9 +263,11 @@ impl Command {
         add_newline_below, "Add newline below",
         goto_type_definition, "Goto type definition",
         goto_implementation, "Goto implementation",
+        goto_local_definition, "Goto local definition",
+        select_local_references, "Select local definition and references",
         goto_file_start, "Goto file start/line",
         goto_file_end, "Goto file end",
-        goto_file, "Goto files in the selection",
+        goto_file, "Goto files or links in the selection",
         goto_file_hsplit, "Goto files in the selection in horizontal splits",
         goto_file_vsplit, "Goto files in the selection in vertical splits",
         goto_reference, "Goto references",
@@ -329,6 +337,7 @@ impl Command {
         rotate_selection_contents_forward, "Rotate selection contents forward",
         rotate_selection_contents_backward, "Rotate selections contents backward",
         expand_selection, "Expand selection to parent syntax node",
+        shrink_selection, "Shrink selection to previously expanded syntax node",
         jump_forward, "Jump forward on jumplist",
         jump_backward, "Jump backward on jumplist",
         jump_view_right, "Jump to the split to the right",
@@ -743,16 +752,21 @@ fn goto_window(cx: &mut Context, align: Align) {
     // a height of 6 with padding of 3 on each side will keep shifting the view back and forth
     // as we type
     let scrolloff = cx.editor.config.scrolloff.min(height.saturating_sub(1) / 2);
+    // the first lines are covered by the sticky context
+    let first_line = view.offset.row
+        + view
+            .sticky_context(doc, cx.editor.config.sticky_context.lines())
+            .len();
 
     let last_line = view.last_line(doc);
 
     let line = match align {
-        Align::Top => (view.offset.row + scrolloff + count),
+        Align::Top => (first_line + scrolloff + count),
         Align::Center => (view.offset.row + ((last_line - view.offset.row) / 2)),
         Align::Bottom => last_line.saturating_sub(scrolloff + count),
     }
     .min(last_line.saturating_sub(scrolloff))
-    .max(view.offset.row + scrolloff);
+    .max(first_line + scrolloff);
 
     let pos = doc.text().line_to_char(line);
 
@@ -857,12 +871,18 @@ fn goto_file_impl(cx: &mut Context, action: Action) {
     let (view, doc) = current_ref!(cx.editor);
     let text = doc.text();
     let selections = doc.selection(view.id);
-    let mut paths: Vec<_> = selections
-        .iter()
-        .map(|r| text.slice(r.from()..r.to()).to_string())
-        .collect();
+
+    // links reported by the language server take precedence over the selected text
+    let mut links = Vec::new();
+    let mut paths = Vec::new();
+    for range in selections.iter() {
+        match doc.document_link_at(range.cursor(text.slice(..))) {
+            Some(link) => links.push(link.target.clone()),
+            None => paths.push(text.slice(range.from()..range.to()).to_string()),
+        }
+    }
     let primary = selections.primary();
-    if selections.len() == 1 && primary.to() - primary.from() == 1 {
+    if links.is_empty() && selections.len() == 1 && primary.to() - primary.from() == 1 {
         let current_word = movement::move_next_long_word_start(
             text.slice(..),
             movement::move_prev_long_word_start(text.slice(..), primary, 1),
@@ -874,6 +894,9 @@ fn goto_file_impl(cx: &mut Context, action: Action) {
                 .to_string(),
         );
     }
+    for link in links {
+        open_url(cx.editor, link, action);
+    }
     for sel in paths {
         let p = sel.trim();
         if !p.is_empty() {
@@ -884,6 +907,46 @@ fn goto_file_impl(cx: &mut Context, action: Action) {
     }
 }
 
+/// Opens the target of a link: files are opened in the editor, other URLs with the system's
+/// default handler.
+fn open_url(editor: &mut Editor, url: helix_lsp::Url, action: Action) {
+    use std::process::{Command, Stdio};
+
+    if url.scheme() == "file" {
+        match url.to_file_path() {
+            Ok(path) => {
+                if let Err(e) = editor.open(path, action) {
+                    editor.set_error(format!("Open file failed: {:?}", e));
+                }
+            }
+            Err(_) => editor.set_error(format!("Invalid file URL: {}", url)),
+        }
+        return;
+    }
+
+    #[cfg(target_os = "macos")]
+    let (program, args): (_, &[&str]) = ("open", &[]);
+    #[cfg(windows)]
+    let (program, args): (_, &[&str]) = ("cmd", &["/C", "start", ""]);
+    #[cfg(not(any(target_os = "macos", windows)))]
+    let (program, args): (_, &[&str]) = ("xdg-open", &[]);
+
+    let child = Command::new(program)
+        .args(args)
+        .arg(url.as_str())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    match child {
+        // reap the process once the handler exits
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => editor.set_error(format!("Failed to open {}: {}", url, e)),
+    }
+}
+
 fn extend_word_impl<F>(cx: &mut Context, extend_fn: F)
 where
     F: Fn(RopeSlice, Range, usize) -> Range,
@@ -1196,11 +1259,16 @@ pub fn scroll(cx: &mut Context, offset: usize, direction: Direction) {
 
     // recalculate last line
     let last_line = view.last_line(doc);
+    // the first lines are covered by the sticky context
+    let first_line = view.offset.row
+        + view
+            .sticky_context(doc, cx.editor.config.sticky_context.lines())
+            .len();
 
     // clamp into viewport
     let line = cursor
         .row
-        .max(view.offset.row + scrolloff)
+        .max(first_line + scrolloff)
         .min(last_line.saturating_sub(scrolloff));
 
     let head = pos_at_coords(text, Position::new(line, cursor.col), true); // this func will properly truncate to line end
@@ -1370,6 +1438,7 @@ fn split_selection_on_newline(cx: &mut Context) {
     doc.set_selection(view.id, selection);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn search_impl(
     doc: &mut Document,
     view: &mut View,
@@ -1378,6 +1447,7 @@ fn search_impl(
     movement: Movement,
     direction: Direction,
     scrolloff: usize,
+    context_lines: usize,
 ) {
     let text = doc.text().slice(..);
     let selection = doc.selection(view.id);
@@ -1436,8 +1506,8 @@ fn search_impl(
         };
 
         doc.set_selection(view.id, selection);
-        if view.is_cursor_in_view(doc, 0) {
-            view.ensure_cursor_in_view(doc, scrolloff);
+        if view.is_cursor_in_view(doc, 0, context_lines) {
+            view.ensure_cursor_in_view(doc, scrolloff, context_lines);
         } else {
             align_view(doc, view, Align::Center)
         }
@@ -1465,6 +1535,7 @@ fn rsearch(cx: &mut Context) {
 fn searcher(cx: &mut Context, direction: Direction) {
     let reg = cx.register.unwrap_or('/');
     let scrolloff = cx.editor.config.scrolloff;
+    let context_lines = cx.editor.config.sticky_context.lines();
 
     let (_, doc) = current!(cx.editor);
 
@@ -1498,6 +1569,7 @@ fn searcher(cx: &mut Context, direction: Direction) {
                 Movement::Move,
                 direction,
                 scrolloff,
+                context_lines,
             );
         },
     );
@@ -1507,6 +1579,7 @@ fn searcher(cx: &mut Context, direction: Direction) {
 
 fn search_next_or_prev_impl(cx: &mut Context, movement: Movement, direction: Direction) {
     let scrolloff = cx.editor.config.scrolloff;
+    let context_lines = cx.editor.config.sticky_context.lines();
     let (view, doc) = current!(cx.editor);
     let registers = &cx.editor.registers;
     if let Some(query) = registers.read('/') {
@@ -1521,7 +1594,16 @@ fn search_next_or_prev_impl(cx: &mut Context, movement: Movement, direction: Dir
             .case_insensitive(case_insensitive)
             .build()
         {
-            search_impl(doc, view, &contents, &regex, movement, direction, scrolloff);
+            search_impl(
+                doc,
+                view,
+                &contents,
+                &regex,
+                movement,
+                direction,
+                scrolloff,
+                context_lines,
+            );
         } else {
             // get around warning `mutable_borrow_reservation_conflict`
             // which will be a hard error in the future
@@ -1789,7 +1871,7 @@ fn delete_selection_insert_mode(doc: &mut Document, view: &View, selection: &Sel
     let transaction = Transaction::change_by_selection(doc.text(), selection, |range| {
         (range.from(), range.to(), None)
     });
-    doc.apply(&transaction, view_id);
+    doc.apply_linked(&transaction, view_id);
 }
 
 fn delete_selection(cx: &mut Context) {
@@ -1879,7 +1961,7 @@ mod cmd {
     use super::*;
     use std::collections::HashMap;
 
-    use helix_view::editor::Action;
+    use helix_view::editor::{Action, ConfigEvent};
     use ui::completers::{self, Completer};
 
     #[derive(Clone)]
@@ -2294,6 +2376,28 @@ mod cmd {
         cx.editor.set_theme_from_name(theme)
     }
 
+    fn set_language(
+        cx: &mut compositor::Context,
+        args: &[&str],
+        _event: PromptEvent,
+    ) -> anyhow::Result<()> {
+        let name = args.first().context("language not provided")?;
+        let language_config = if *name == "auto" {
+            None
+        } else {
+            let language_config = cx
+                .editor
+                .syn_loader
+                .language_config_for_language_name(name)
+                .with_context(|| format!("unknown language `{}`", name))?;
+            Some(language_config)
+        };
+
+        let doc_id = doc!(cx.editor).id();
+        cx.editor.set_doc_language(doc_id, language_config);
+        Ok(())
+    }
+
     fn yank_main_selection_to_clipboard(
         cx: &mut compositor::Context,
         _args: &[&str],
@@ -2490,6 +2594,50 @@ mod cmd {
         Ok(())
     }
 
+    fn tree_sitter_subtree(
+        cx: &mut compositor::Context,
+        _args: &[&str],
+        _event: PromptEvent,
+    ) -> anyhow::Result<()> {
+        let call: job::Callback = Box::new(|_editor: &mut Editor, compositor: &mut Compositor| {
+            let shown = compositor.remove::<ui::SyntaxTree>().is_none();
+            if shown {
+                compositor.push(Box::new(ui::SyntaxTree::new()));
+            }
+            if let Some(editor_view) = compositor.find::<ui::EditorView>() {
+                editor_view.set_side_panel(shown);
+            }
+        });
+        cx.jobs.callback(async move { Ok(call) });
+        Ok(())
+    }
+
+    fn tree_sitter_query(
+        cx: &mut compositor::Context,
+        args: &[&str],
+        _event: PromptEvent,
+    ) -> anyhow::Result<()> {
+        let query = if args.is_empty() {
+            None
+        } else {
+            Some(args.join(" "))
+        };
+
+        let call: job::Callback = Box::new(|_editor: &mut Editor, compositor: &mut Compositor| {
+            if compositor.find::<ui::SyntaxTree>().is_none() {
+                compositor.push(Box::new(ui::SyntaxTree::new()));
+            }
+            if let Some(panel) = compositor.find::<ui::SyntaxTree>() {
+                panel.set_query(query);
+            }
+            if let Some(editor_view) = compositor.find::<ui::EditorView>() {
+                editor_view.set_side_panel(true);
+            }
+        });
+        cx.jobs.callback(async move { Ok(call) });
+        Ok(())
+    }
+
     fn vsplit(
         cx: &mut compositor::Context,
         args: &[&str],
@@ -2522,6 +2670,171 @@ mod cmd {
         Ok(())
     }
 
+    /// Returns the language scope of the current document if it has a language server configured.
+    fn language_server_scope(editor: &Editor) -> anyhow::Result<String> {
+        let doc = doc!(editor);
+        doc.language_config()
+            .filter(|config| config.language_server.is_some())
+            .map(|config| config.scope.clone())
+            .context("no language server configured for the current document")
+    }
+
+    fn lsp_restart(
+        cx: &mut compositor::Context,
+        _args: &[&str],
+        _event: PromptEvent,
+    ) -> anyhow::Result<()> {
+        let scope = language_server_scope(cx.editor)?;
+        cx.editor.restart_language_server(&scope);
+        Ok(())
+    }
+
+    fn lsp_stop(
+        cx: &mut compositor::Context,
+        _args: &[&str],
+        _event: PromptEvent,
+    ) -> anyhow::Result<()> {
+        let scope = language_server_scope(cx.editor)?;
+        cx.editor.stop_language_server(&scope);
+        Ok(())
+    }
+
+    fn lsp_reload_settings(
+        cx: &mut compositor::Context,
+        _args: &[&str],
+        _event: PromptEvent,
+    ) -> anyhow::Result<()> {
+        cx.editor
+            .config_events
+            .0
+            .send(ConfigEvent::RefreshLanguages)
+            .map_err(|_| anyhow!("the editor isn't running"))
+    }
+
+    fn config_reload(
+        cx: &mut compositor::Context,
+        _args: &[&str],
+        _event: PromptEvent,
+    ) -> anyhow::Result<()> {
+        cx.editor
+            .config_events
+            .0
+            .send(ConfigEvent::Refresh)
+            .map_err(|_| anyhow!("the editor isn't running"))
+    }
+
+    fn trust_workspace(
+        cx: &mut compositor::Context,
+        _args: &[&str],
+        _event: PromptEvent,
+    ) -> anyhow::Result<()> {
+        crate::config::trust_workspace()?;
+        config_reload(cx, &[], PromptEvent::Validate)
+    }
+
+    fn format_message_log_entry(entry: &helix_lsp::message_log::MessageLogEntry) -> String {
+        use helix_lsp::message_log::MessageKind;
+        use std::fmt::Write;
+
+        let time = chrono::DateTime::<chrono::Local>::from(entry.time);
+        let mut line = format!("{} {}", time.format("%H:%M:%S%.3f"), entry.server);
+        let direction = match entry.kind {
+            MessageKind::Outgoing => "->",
+            MessageKind::Incoming => "<-",
+            MessageKind::Log(lsp::MessageType::ERROR) => "log[error]",
+            MessageKind::Log(lsp::MessageType::WARNING) => "log[warning]",
+            MessageKind::Log(lsp::MessageType::INFO) => "log[info]",
+            MessageKind::Log(_) => "log",
+            MessageKind::Stderr => "stderr",
+        };
+        let _ = write!(line, " {}", direction);
+        if let Some(method) = &entry.method {
+            let _ = write!(line, " {}", method);
+        }
+        match &entry.id {
+            Some(helix_lsp::jsonrpc::Id::Num(id)) => {
+                let _ = write!(line, " #{}", id);
+            }
+            Some(helix_lsp::jsonrpc::Id::Str(id)) => {
+                let _ = write!(line, " #{}", id);
+            }
+            _ => (),
+        }
+        if let Some(elapsed) = entry.elapsed {
+            let _ = write!(line, " ({}ms)", elapsed.as_millis());
+        }
+        // keep every entry on a single line
+        for payload_line in entry.payload.lines() {
+            line.push(' ');
+            line.push_str(payload_line);
+        }
+        line.push('\n');
+        line
+    }
+
+    /// Opens the traffic with the language servers in a scratch buffer, optionally filtered by
+    /// server name and method.
+    fn lsp_log(
+        cx: &mut compositor::Context,
+        args: &[&str],
+        _event: PromptEvent,
+    ) -> anyhow::Result<()> {
+        let server = args.first().copied();
+        let method = args.get(1).copied();
+
+        let message_log = &cx.editor.language_servers.message_log;
+        if !message_log.is_enabled() {
+            bail!("The messages aren't recorded, set `lsp.message-log = true` in config.toml");
+        }
+        let mut text = String::new();
+        for entry in message_log.entries() {
+            if matches!(server, Some(server) if !entry.server.contains(server)) {
+                continue;
+            }
+            if let Some(method) = method {
+                match &entry.method {
+                    Some(entry_method) if entry_method.contains(method) => (),
+                    _ => continue,
+                }
+            }
+            text.push_str(&format_message_log_entry(&entry));
+        }
+
+        let doc = Document::from(Rope::from(text), None);
+        cx.editor.new_file_from_document(Action::Replace, doc);
+        // scroll to the most recent messages
+        let (view, doc) = current!(cx.editor);
+        let end = doc.text().len_chars();
+        doc.set_selection(view.id, Selection::point(end));
+        align_view(doc, view, Align::Bottom);
+        Ok(())
+    }
+
+    fn workspace_diagnostics(
+        cx: &mut compositor::Context,
+        args: &[&str],
+        _event: PromptEvent,
+    ) -> anyhow::Result<()> {
+        let min_severity = match args.first().copied() {
+            None | Some("all") => None,
+            Some("error") => Some(lsp::DiagnosticSeverity::ERROR),
+            Some("warning") => Some(lsp::DiagnosticSeverity::WARNING),
+            Some("info") => Some(lsp::DiagnosticSeverity::INFORMATION),
+            Some("hint") => Some(lsp::DiagnosticSeverity::HINT),
+            Some(severity) => bail!("invalid severity `{}`", severity),
+        };
+        let source = args.get(1).map(|source| source.to_string());
+
+        let call: job::Callback =
+            Box::new(move |editor: &mut Editor, compositor: &mut Compositor| {
+                let picker =
+                    diagnostics_picker_impl(&editor.diagnostics, min_severity, source.as_deref());
+                compositor.push(Box::new(picker));
+            });
+        cx.jobs.callback(async move { Ok(call) });
+        Ok(())
+    }
+
     fn tutor(
         cx: &mut compositor::Context,
         _args: &[&str],
@@ -2549,7 +2862,7 @@ mod cmd {
 
         let (view, doc) = current!(cx.editor);
 
-        view.ensure_cursor_in_view(doc, line);
+        view.ensure_cursor_in_view(doc, line, cx.editor.config.sticky_context.lines());
 
         Ok(())
     }
@@ -2702,6 +3015,13 @@ mod cmd {
             fun: theme,
             completer: Some(completers::theme),
         },
+        TypableCommand {
+            name: "set-language",
+            aliases: &["lang"],
+            doc: "Set the language of the current document, overriding the detected one (:set-language <name>), or detect it again (:set-language auto).",
+            fun: set_language,
+            completer: Some(completers::language),
+        },
         TypableCommand {
             name: "clipboard-yank",
             aliases: &[],
@@ -2814,6 +3134,20 @@ mod cmd {
             fun: tree_sitter_scopes,
             completer: None,
         },
+        TypableCommand {
+            name: "tree-sitter-subtree",
+            aliases: &[],
+            doc: "Toggle a panel with the syntax tree around the cursor, including injected languages.",
+            fun: tree_sitter_subtree,
+            completer: None,
+        },
+        TypableCommand {
+            name: "tree-sitter-query",
+            aliases: &[],
+            doc: "List the captures of a query against the current document in the syntax tree panel. Clears the query without arguments.",
+            fun: tree_sitter_query,
+            completer: None,
+        },
         TypableCommand {
             name: "vsplit",
             aliases: &["vs"],
@@ -2828,6 +3162,55 @@ mod cmd {
             fun: hsplit,
             completer: Some(completers::filename),
         },
+        TypableCommand {
+            name: "lsp-restart",
+            aliases: &[],
+            doc: "Restart the language server of the current document and reopen its documents.",
+            fun: lsp_restart,
+            completer: None,
+        },
+        TypableCommand {
+            name: "lsp-stop",
+            aliases: &[],
+            doc: "Stop the language server of the current document until it's restarted.",
+            fun: lsp_stop,
+            completer: None,
+        },
+        TypableCommand {
+            name: "lsp-reload-settings",
+            aliases: &[],
+            doc: "Reload languages.toml, setting the languages of the open documents again and sending the new settings to the running language servers.",
+            fun: lsp_reload_settings,
+            completer: None,
+        },
+        TypableCommand {
+            name: "config-reload",
+            aliases: &[],
+            doc: "Reload config.toml, languages.toml, the theme and the queries, parsing the open documents again.",
+            fun: config_reload,
+            completer: None,
+        },
+        TypableCommand {
+            name: "trust-workspace",
+            aliases: &[],
+            doc: "Trust the current workspace, loading its .helix/languages.toml which can set the commands run as language servers and formatters.",
+            fun: trust_workspace,
+            completer: None,
+        },
+        TypableCommand {
+            name: "lsp-log",
+            aliases: &[],
+            doc: "Open the messages exchanged with the language servers in a scratch buffer. Optionally filter by server and method.",
+            fun: lsp_log,
+            completer: None,
+        },
+        TypableCommand {
+            name: "workspace-diagnostics",
+            aliases: &[],
+            doc: "Open a picker of the diagnostics in the workspace, optionally limited to a minimum severity (error, warning, info, hint) and a source (:workspace-diagnostics error rustc).",
+            fun: workspace_diagnostics,
+            completer: None,
+        },
         TypableCommand {
             name: "tutor",
             aliases: &[],
@@ -2856,10 +3239,12 @@ mod cmd {
 }
 
 fn command_mode(cx: &mut Context) {
+    // the languages are completed from the ones the editor knows
+    let syn_loader = cx.editor.syn_loader.clone();
     let mut prompt = Prompt::new(
         ":".into(),
         Some(':'),
-        |input: &str| {
+        move |input: &str| {
             // we use .this over split_whitespace() because we care about empty segments
             let parts = input.split(' ').collect::<Vec<&str>>();
 
@@ -2880,7 +3265,7 @@ fn command_mode(cx: &mut Context) {
                     ..
                 }) = cmd::COMMANDS.get(parts[0])
                 {
-                    completer(part)
+                    completer(&syn_loader, part)
                         .into_iter()
                         .map(|(range, file)| {
                             // offset ranges to input
@@ -3010,6 +3395,45 @@ fn buffer_picker(cx: &mut Context) {
     cx.push_layer(Box::new(picker));
 }
 
+/// Converts a definition found by the `tags.scm` query of a language to a symbol. Its columns
+/// are char offsets, which is what `OffsetEncoding::Utf8` stands for.
+fn tag_to_symbol(path: &Path, tag: &Tag) -> Option<lsp::SymbolInformation> {
+    let uri = lsp::Url::from_file_path(path).ok()?;
+    let range = lsp::Range::new(
+        lsp::Position::new(tag.start.row as u32, tag.start.col as u32),
+        lsp::Position::new(tag.end.row as u32, tag.end.col as u32),
+    );
+    let kind = match tag.kind.as_str() {
+        "class" => lsp::SymbolKind::CLASS,
+        "constant" => lsp::SymbolKind::CONSTANT,
+        "enum" => lsp::SymbolKind::ENUM,
+        "function" | "macro" => lsp::SymbolKind::FUNCTION,
+        "interface" => lsp::SymbolKind::INTERFACE,
+        "method" => lsp::SymbolKind::METHOD,
+        "module" => lsp::SymbolKind::MODULE,
+        "struct" => lsp::SymbolKind::STRUCT,
+        "type" => lsp::SymbolKind::TYPE_PARAMETER,
+        _ => lsp::SymbolKind::VARIABLE,
+    };
+
+    #[allow(deprecated)]
+    Some(lsp::SymbolInformation {
+        name: tag.name.clone(),
+        kind,
+        tags: None,
+        deprecated: None,
+        location: lsp::Location::new(uri, range),
+        container_name: None,
+    })
+}
+
+/// The definitions in the current document found with the `tags.scm` query of its language.
+fn document_tags(doc: &Document) -> Option<Vec<Tag>> {
+    let tags_query = doc.language_config()?.tags_query()?;
+    let syntax = doc.syntax()?;
+    Some(tags_query.definitions(syntax.tree().root_node(), doc.text().slice(..)))
+}
+
 fn symbol_picker(cx: &mut Context) {
     fn nested_to_flat(
         list: &mut Vec<lsp::SymbolInformation>,
@@ -3033,7 +3457,25 @@ fn symbol_picker(cx: &mut Context) {
 
     let language_server = match doc.language_server() {
         Some(language_server) => language_server,
-        None => return,
+        None => {
+            // fall back to the definitions tree-sitter finds
+            let symbols = match (doc.path(), document_tags(doc)) {
+                (Some(path), Some(tags)) => tags
+                    .iter()
+                    .filter_map(|tag| tag_to_symbol(path, tag))
+                    .collect(),
+                _ => {
+                    cx.editor
+                        .set_error("No language server or tags query for this document".into());
+                    return;
+                }
+            };
+            cx.push_layer(Box::new(document_symbol_picker(
+                symbols,
+                OffsetEncoding::Utf8,
+            )));
+            return;
+        }
     };
     let offset_encoding = language_server.offset_encoding();
 
@@ -3059,98 +3501,278 @@ fn symbol_picker(cx: &mut Context) {
                     }
                 };
 
-                let mut picker = FilePicker::new(
-                    symbols,
-                    |symbol| (&symbol.name).into(),
-                    move |editor: &mut Editor, symbol, _action| {
-                        push_jump(editor);
-                        let (view, doc) = current!(editor);
-
-                        if let Some(range) =
-                            lsp_range_to_range(doc.text(), symbol.location.range, offset_encoding)
-                        {
-                            // we flip the range so that the cursor sits on the start of the symbol
-                            // (for example start of the function).
-                            doc.set_selection(view.id, Selection::single(range.head, range.anchor));
-                            align_view(doc, view, Align::Center);
-                        }
-                    },
-                    move |_editor, symbol| {
-                        let path = symbol.location.uri.to_file_path().unwrap();
-                        let line = Some((
-                            symbol.location.range.start.line as usize,
-                            symbol.location.range.end.line as usize,
-                        ));
-                        Some((path, line))
-                    },
-                );
-                picker.truncate_start = false;
+                let picker = document_symbol_picker(symbols, offset_encoding);
                 compositor.push(Box::new(picker))
             }
         },
     )
 }
 
+fn document_symbol_picker(
+    symbols: Vec<lsp::SymbolInformation>,
+    offset_encoding: OffsetEncoding,
+) -> FilePicker<lsp::SymbolInformation> {
+    let mut picker = FilePicker::new(
+        symbols,
+        |symbol| (&symbol.name).into(),
+        move |editor: &mut Editor, symbol, _action| {
+            push_jump(editor);
+            let (view, doc) = current!(editor);
+
+            if let Some(range) =
+                lsp_range_to_range(doc.text(), symbol.location.range, offset_encoding)
+            {
+                // we flip the range so that the cursor sits on the start of the symbol
+                // (for example start of the function).
+                doc.set_selection(view.id, Selection::single(range.head, range.anchor));
+                align_view(doc, view, Align::Center);
+            }
+        },
+        move |_editor, symbol| {
+            let path = symbol.location.uri.to_file_path().unwrap();
+            let line = Some((
+                symbol.location.range.start.line as usize,
+                symbol.location.range.end.line as usize,
+            ));
+            Some((path, line))
+        },
+    );
+    picker.truncate_start = false;
+    picker
+}
+
 fn workspace_symbol_picker(cx: &mut Context) {
     let (_, doc) = current!(cx.editor);
+    let current_path = doc.path().cloned();
 
     let language_server = match doc.language_server() {
         Some(language_server) => language_server,
-        None => return,
+        None => {
+            // fall back to the definitions tree-sitter finds, with the ones of the current
+            // document up to date with its unsaved changes
+            let mut symbols: Vec<_> = match (&current_path, document_tags(doc)) {
+                (Some(path), Some(tags)) => tags
+                    .iter()
+                    .filter_map(|tag| tag_to_symbol(path, tag))
+                    .collect(),
+                _ => Vec::new(),
+            };
+            symbols.extend(
+                cx.editor
+                    .tag_index
+                    .all_definitions()
+                    .iter()
+                    .filter(|(path, _)| Some(path) != current_path.as_ref())
+                    .filter_map(|(path, tag)| tag_to_symbol(path, tag)),
+            );
+            if symbols.is_empty() && !cx.editor.tag_index.is_built() {
+                cx.editor.set_error(
+                    "No language server, and the workspace is still being indexed".into(),
+                );
+                return;
+            }
+            cx.push_layer(Box::new(workspace_symbol_picker_for(
+                symbols,
+                current_path,
+                OffsetEncoding::Utf8,
+            )));
+            return;
+        }
     };
     let offset_encoding = language_server.offset_encoding();
 
     let future = language_server.workspace_symbols("".to_string());
 
-    let current_path = doc_mut!(cx.editor).path().cloned();
     cx.callback(
         future,
         move |_editor: &mut Editor,
               compositor: &mut Compositor,
               response: Option<Vec<lsp::SymbolInformation>>| {
             if let Some(symbols) = response {
-                let mut picker = FilePicker::new(
-                    symbols,
-                    move |symbol| {
-                        let path = symbol.location.uri.to_file_path().unwrap();
-                        if current_path.as_ref().map(|p| p == &path).unwrap_or(false) {
-                            (&symbol.name).into()
-                        } else {
-                            let relative_path = helix_core::path::get_relative_path(path.as_path())
-                                .to_str()
-                                .unwrap()
-                                .to_owned();
-                            format!("{} ({})", &symbol.name, relative_path).into()
-                        }
-                    },
-                    move |editor: &mut Editor, symbol, action| {
-                        let path = symbol.location.uri.to_file_path().unwrap();
-                        editor.open(path, action).expect("editor.open failed");
-                        let (view, doc) = current!(editor);
-
-                        if let Some(range) =
-                            lsp_range_to_range(doc.text(), symbol.location.range, offset_encoding)
-                        {
-                            // we flip the range so that the cursor sits on the start of the symbol
-                            // (for example start of the function).
-                            doc.set_selection(view.id, Selection::single(range.head, range.anchor));
-                            align_view(doc, view, Align::Center);
-                        }
-                    },
-                    move |_editor, symbol| {
-                        let path = symbol.location.uri.to_file_path().unwrap();
-                        let line = Some((
-                            symbol.location.range.start.line as usize,
-                            symbol.location.range.end.line as usize,
-                        ));
-                        Some((path, line))
-                    },
-                );
-                picker.truncate_start = false;
+                let picker = workspace_symbol_picker_for(symbols, current_path, offset_encoding);
                 compositor.push(Box::new(picker))
             }
         },
-    )
+    )
+}
+
+fn workspace_symbol_picker_for(
+    symbols: Vec<lsp::SymbolInformation>,
+    current_path: Option<PathBuf>,
+    offset_encoding: OffsetEncoding,
+) -> FilePicker<lsp::SymbolInformation> {
+    let mut picker = FilePicker::new(
+        symbols,
+        move |symbol| {
+            let path = symbol.location.uri.to_file_path().unwrap();
+            if current_path.as_ref().map(|p| p == &path).unwrap_or(false) {
+                (&symbol.name).into()
+            } else {
+                let relative_path = helix_core::path::get_relative_path(path.as_path())
+                    .to_str()
+                    .unwrap()
+                    .to_owned();
+                format!("{} ({})", &symbol.name, relative_path).into()
+            }
+        },
+        move |editor: &mut Editor, symbol, action| {
+            let path = symbol.location.uri.to_file_path().unwrap();
+            editor.open(path, action).expect("editor.open failed");
+            let (view, doc) = current!(editor);
+
+            if let Some(range) =
+                lsp_range_to_range(doc.text(), symbol.location.range, offset_encoding)
+            {
+                // we flip the range so that the cursor sits on the start of the symbol
+                // (for example start of the function).
+                doc.set_selection(view.id, Selection::single(range.head, range.anchor));
+                align_view(doc, view, Align::Center);
+            }
+        },
+        move |_editor, symbol| {
+            let path = symbol.location.uri.to_file_path().unwrap();
+            let line = Some((
+                symbol.location.range.start.line as usize,
+                symbol.location.range.end.line as usize,
+            ));
+            Some((path, line))
+        },
+    );
+    picker.truncate_start = false;
+    picker
+}
+
+/// A diagnostic shown in the diagnostics pickers.
+struct PickerDiagnostic {
+    url: lsp::Url,
+    diagnostic: lsp::Diagnostic,
+    server_id: usize,
+    label: String,
+}
+
+fn diagnostic_severity_name(severity: Option<lsp::DiagnosticSeverity>) -> &'static str {
+    match severity {
+        Some(lsp::DiagnosticSeverity::ERROR) | None => "error",
+        Some(lsp::DiagnosticSeverity::WARNING) => "warning",
+        Some(lsp::DiagnosticSeverity::INFORMATION) => "info",
+        Some(lsp::DiagnosticSeverity::HINT) => "hint",
+        Some(_) => "unknown",
+    }
+}
+
+/// Builds a picker over `diagnostics`, keeping the ones that are at least as severe as
+/// `min_severity` and, if given, come from `source`. Entries are labeled with their severity and
+/// source so they can be narrowed down further by fuzzy matching.
+fn diagnostics_picker_impl(
+    diagnostics: &BTreeMap<lsp::Url, Vec<(lsp::Diagnostic, usize)>>,
+    min_severity: Option<lsp::DiagnosticSeverity>,
+    source: Option<&str>,
+) -> FilePicker<PickerDiagnostic> {
+    let mut items = Vec::new();
+    for (url, diagnostics) in diagnostics {
+        let path = match url.to_file_path() {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        let relative_path = helix_core::path::get_relative_path(&path);
+
+        for (diagnostic, server_id) in diagnostics {
+            // lower severity values are more severe, a missing severity is treated as an error
+            let severity = diagnostic
+                .severity
+                .unwrap_or(lsp::DiagnosticSeverity::ERROR);
+            if matches!(min_severity, Some(min_severity) if severity > min_severity) {
+                continue;
+            }
+            if source.is_some() && diagnostic.source.as_deref() != source {
+                continue;
+            }
+
+            let label = format!(
+                "{} {}: {} ({}:{})",
+                diagnostic_severity_name(diagnostic.severity),
+                diagnostic.source.as_deref().unwrap_or("unknown"),
+                diagnostic.message.lines().next().unwrap_or_default(),
+                relative_path.display(),
+                diagnostic.range.start.line + 1,
+            );
+            items.push(PickerDiagnostic {
+                url: url.clone(),
+                diagnostic: diagnostic.clone(),
+                server_id: *server_id,
+                label,
+            });
+        }
+    }
+
+    // errors first, then by location
+    items.sort_by_key(|item| {
+        (
+            item.diagnostic
+                .severity
+                .unwrap_or(lsp::DiagnosticSeverity::ERROR),
+            item.url.clone(),
+            item.diagnostic.range.start,
+        )
+    });
+
+    let mut picker = FilePicker::new(
+        items,
+        |item| item.label.as_str().into(),
+        move |editor: &mut Editor, item, action| {
+            let path = item.url.to_file_path().unwrap();
+            push_jump(editor);
+            if let Err(e) = editor.open(path, action) {
+                editor.set_error(format!("Failed to open file '{}': {}", item.url, e));
+                return;
+            }
+
+            let offset_encoding = match editor.language_servers.get_by_id(item.server_id) {
+                Some(language_server) => language_server.offset_encoding(),
+                None => return,
+            };
+            let (view, doc) = current!(editor);
+            if let Some(range) =
+                lsp_range_to_range(doc.text(), item.diagnostic.range, offset_encoding)
+            {
+                // we flip the range so that the cursor sits on the start of the diagnostic
+                doc.set_selection(view.id, Selection::single(range.head, range.anchor));
+                align_view(doc, view, Align::Center);
+            }
+        },
+        move |_editor, item| {
+            let path = item.url.to_file_path().ok()?;
+            let line = Some((
+                item.diagnostic.range.start.line as usize,
+                item.diagnostic.range.end.line as usize,
+            ));
+            Some((path, line))
+        },
+    );
+    picker.truncate_start = false;
+    picker
+}
+
+fn diagnostics_picker(cx: &mut Context) {
+    let (_, doc) = current!(cx.editor);
+    let url = match doc.url() {
+        Some(url) => url,
+        None => return,
+    };
+
+    let diagnostics = cx
+        .editor
+        .diagnostics
+        .get_key_value(&url)
+        .map(|(url, diagnostics)| std::iter::once((url.clone(), diagnostics.clone())).collect())
+        .unwrap_or_default();
+    let picker = diagnostics_picker_impl(&diagnostics, None, None);
+    cx.push_layer(Box::new(picker));
+}
+
+fn workspace_diagnostics_picker(cx: &mut Context) {
+    let picker = diagnostics_picker_impl(&cx.editor.diagnostics, None, None);
+    cx.push_layer(Box::new(picker));
 }
 
 pub fn code_action(cx: &mut Context) {
@@ -3188,12 +3810,16 @@ pub fn code_action(cx: &mut Context) {
                     move |editor, code_action, _action| match code_action {
                         lsp::CodeActionOrCommand::Command(command) => {
                             log::debug!("code action command: {:?}", command);
-                            editor.set_error(String::from("Handling code action command is not implemented yet, see https://github.com/helix-editor/helix/issues/183"));
+                            execute_lsp_command(editor, command.clone());
                         }
                         lsp::CodeActionOrCommand::CodeAction(code_action) => {
                             log::debug!("code action: {:?}", code_action);
                             if let Some(ref workspace_edit) = code_action.edit {
-                                apply_workspace_edit(editor, offset_encoding, workspace_edit)
+                                apply_workspace_edit(editor, offset_encoding, workspace_edit);
+                            }
+                            // the command is executed after the edit has been applied
+                            if let Some(ref command) = code_action.command {
+                                execute_lsp_command(editor, command.clone());
                             }
                         }
                     },
@@ -3204,6 +3830,84 @@ pub fn code_action(cx: &mut Context) {
     )
 }
 
+fn code_lens(cx: &mut Context) {
+    let (view, doc) = current_ref!(cx.editor);
+    let line = doc
+        .selection(view.id)
+        .primary()
+        .cursor_line(doc.text().slice(..));
+
+    let mut commands: Vec<lsp::Command> = doc
+        .code_lenses_at_line(line)
+        .iter()
+        .map(|lens| lens.command.clone())
+        .collect();
+
+    match commands.len() {
+        0 => cx.editor.set_error("No code lens on this line".to_string()),
+        1 => execute_lsp_command(cx.editor, commands.pop().unwrap()),
+        _ => {
+            let picker = Picker::new(
+                true,
+                commands,
+                |command| command.title.as_str().into(),
+                |editor, command, _action| execute_lsp_command(editor, command.clone()),
+            );
+            cx.push_layer(Box::new(picker));
+        }
+    }
+}
+
+/// Executes a command received from a code lens or a code action. Commands that by
+/// convention are implemented by the client are handled here, the others are sent to
+/// the language server with `workspace/executeCommand`.
+pub fn execute_lsp_command(editor: &mut Editor, command: lsp::Command) {
+    let doc = doc!(editor);
+    let language_server = match doc.language_server() {
+        Some(language_server) => language_server,
+        None => return,
+    };
+    let offset_encoding = language_server.offset_encoding();
+
+    // client-side commands
+    if command.command == "editor.action.showReferences" {
+        // arguments: [uri, position, locations]
+        let locations: Vec<lsp::Location> = command
+            .arguments
+            .as_ref()
+            .and_then(|arguments| arguments.get(2))
+            .and_then(|locations| serde_json::from_value(locations.clone()).ok())
+            .unwrap_or_default();
+        match locations.first() {
+            Some(location) => {
+                push_jump(editor);
+                jump_to(editor, location, offset_encoding, Action::Replace);
+                editor.set_status(format!("{} references", locations.len()));
+            }
+            None => editor.set_error("No references found".to_string()),
+        }
+        return;
+    }
+
+    let future = match language_server.execute_command(command.clone()) {
+        Some(future) => future,
+        None => {
+            editor.set_error(format!(
+                "Language server does not support the command '{}'",
+                command.command
+            ));
+            return;
+        }
+    };
+
+    editor.set_status(format!("Running '{}'", command.title));
+    tokio::spawn(async move {
+        if let Err(err) = future.await {
+            log::error!("failed to execute command '{}': {}", command.command, err);
+        }
+    });
+}
+
 pub fn apply_document_resource_op(op: &lsp::ResourceOp) -> std::io::Result<()> {
     use lsp::ResourceOp;
     use std::fs;
@@ -3257,18 +3961,100 @@ pub fn apply_document_resource_op(op: &lsp::ResourceOp) -> std::io::Result<()> {
     }
 }
 
-fn apply_workspace_edit(
+/// What a workspace edit changed, reported to the user after a rename.
+#[derive(Debug, Default)]
+pub struct WorkspaceEditSummary {
+    pub files: HashSet<PathBuf>,
+    pub edits: usize,
+    /// Files that weren't open and were edited on disk.
+    pub written: usize,
+    /// Files the edits couldn't be applied to.
+    pub failed: usize,
+}
+
+impl fmt::Display for WorkspaceEditSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        write!(
+            f,
+            "{} occurrence{} in {} file{}",
+            self.edits,
+            plural(self.edits),
+            self.files.len(),
+            plural(self.files.len())
+        )?;
+        if self.written > 0 {
+            write!(f, " ({} written to disk)", self.written)?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies `text_edits` directly to the file at `path`, without opening it.
+fn apply_edits_to_file(
+    path: &Path,
+    text_edits: Vec<lsp::TextEdit>,
+    offset_encoding: OffsetEncoding,
+) -> anyhow::Result<()> {
+    let mut text = Rope::from(std::fs::read_to_string(path)?);
+    let transaction =
+        helix_lsp::util::generate_transaction_from_edits(&text, text_edits, offset_encoding);
+    if !transaction.apply(&mut text) {
+        bail!("edits don't apply to the file contents");
+    }
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    text.write_to(&mut file)?;
+    file.into_inner()?;
+    Ok(())
+}
+
+pub fn apply_workspace_edit(
     editor: &mut Editor,
     offset_encoding: OffsetEncoding,
     workspace_edit: &lsp::WorkspaceEdit,
-) {
+) -> WorkspaceEditSummary {
+    use helix_view::editor::UnopenedFileEdits;
+
+    let mut summary = WorkspaceEditSummary::default();
+
     let mut apply_edits = |uri: &helix_lsp::Url, text_edits: Vec<lsp::TextEdit>| {
-        let path = uri
-            .to_file_path()
-            .expect("unable to convert URI to filepath");
+        let path = match uri.to_file_path() {
+            Ok(path) => helix_core::path::get_normalized_path(&path),
+            Err(_) => {
+                editor.set_error(format!("Unable to apply edits to non-file URI {}", uri));
+                summary.failed += 1;
+                return;
+            }
+        };
+        let edits = text_edits.len();
+
+        let is_open = helix_core::path::get_canonicalized_path(&path)
+            .map(|path| editor.document_by_path(path).is_some())
+            .unwrap_or(false);
+        if !is_open && editor.config.unopened_file_edits == UnopenedFileEdits::Write {
+            match apply_edits_to_file(&path, text_edits, offset_encoding) {
+                Ok(()) => {
+                    summary.written += 1;
+                    summary.edits += edits;
+                    summary.files.insert(path);
+                }
+                Err(err) => {
+                    editor.set_error(format!("Failed to edit {}: {}", path.display(), err));
+                    summary.failed += 1;
+                }
+            }
+            return;
+        }
 
         let current_view_id = view!(editor).id;
-        let doc_id = editor.open(path, Action::Load).unwrap();
+        let doc_id = match editor.open(path.clone(), Action::Load) {
+            Ok(doc_id) => doc_id,
+            Err(err) => {
+                editor.set_error(format!("Failed to open {}: {}", path.display(), err));
+                summary.failed += 1;
+                return;
+            }
+        };
         let doc = editor
             .document_mut(doc_id)
             .expect("Document for document_changes not found");
@@ -3294,6 +4080,9 @@ fn apply_workspace_edit(
         );
         doc.apply(&transaction, view_id);
         doc.append_changes_to_history(view_id);
+
+        summary.edits += edits;
+        summary.files.insert(path);
     };
 
     if let Some(ref changes) = workspace_edit.changes {
@@ -3302,7 +4091,7 @@ fn apply_workspace_edit(
             let text_edits = text_edits.to_vec();
             apply_edits(uri, text_edits);
         }
-        return;
+        return summary;
         // Not sure if it works properly, it'll be safer to just panic here to avoid breaking some parts of code on which code actions will be used
         // TODO: find some example that uses workspace changes, and test it
         // for (url, edits) in changes.iter() {
@@ -3360,6 +4149,8 @@ fn apply_workspace_edit(
             }
         }
     }
+
+    summary
 }
 
 fn last_picker(cx: &mut Context) {
@@ -3410,9 +4201,9 @@ async fn make_format_callback(
     doc_id: DocumentId,
     doc_version: i32,
     modified: Modified,
-    format: impl Future<Output = helix_lsp::util::LspFormatting> + Send + 'static,
+    format: impl Future<Output = Result<Formatting, FormatterError>> + Send + 'static,
 ) -> anyhow::Result<job::Callback> {
-    let format = format.await;
+    let format = format.await?;
     let call: job::Callback = Box::new(move |editor: &mut Editor, _compositor: &mut Compositor| {
         let view_id = view!(editor).id;
         if let Some(doc) = editor.document_mut(doc_id) {
@@ -3640,6 +4431,30 @@ fn exit_select_mode(cx: &mut Context) {
     }
 }
 
+fn jump_to(
+    editor: &mut Editor,
+    location: &lsp::Location,
+    offset_encoding: OffsetEncoding,
+    action: Action,
+) {
+    let path = location
+        .uri
+        .to_file_path()
+        .expect("unable to convert URI to filepath");
+    let _id = editor.open(path, action).expect("editor.open failed");
+    let (view, doc) = current!(editor);
+    let definition_pos = location.range.start;
+    // TODO: convert inside server
+    let new_pos = if let Some(new_pos) = lsp_pos_to_pos(doc.text(), definition_pos, offset_encoding)
+    {
+        new_pos
+    } else {
+        return;
+    };
+    doc.set_selection(view.id, Selection::point(new_pos));
+    align_view(doc, view, Align::Center);
+}
+
 fn goto_impl(
     editor: &mut Editor,
     compositor: &mut Compositor,
@@ -3648,30 +4463,6 @@ fn goto_impl(
 ) {
     push_jump(editor);
 
-    fn jump_to(
-        editor: &mut Editor,
-        location: &lsp::Location,
-        offset_encoding: OffsetEncoding,
-        action: Action,
-    ) {
-        let path = location
-            .uri
-            .to_file_path()
-            .expect("unable to convert URI to filepath");
-        let _id = editor.open(path, action).expect("editor.open failed");
-        let (view, doc) = current!(editor);
-        let definition_pos = location.range.start;
-        // TODO: convert inside server
-        let new_pos =
-            if let Some(new_pos) = lsp_pos_to_pos(doc.text(), definition_pos, offset_encoding) {
-                new_pos
-            } else {
-                return;
-            };
-        doc.set_selection(view.id, Selection::point(new_pos));
-        align_view(doc, view, Align::Center);
-    }
-
     let cwdir = std::env::current_dir().expect("couldn't determine current directory");
 
     match locations.as_slice() {
@@ -3721,11 +4512,55 @@ fn goto_impl(
     }
 }
 
+/// Goes to the definition of the local variable under the cursor, or else to the definitions
+/// named like the word under the cursor that tree-sitter finds in the current document and the
+/// rest of the workspace. Used when no language server is running.
+fn goto_tag_definition(cx: &mut Context) {
+    let (view, doc) = current_ref!(cx.editor);
+    if local_under_cursor(doc, view.id).is_some() {
+        goto_local_definition(cx);
+        return;
+    }
+
+    let text = doc.text().slice(..);
+    let range = doc.selection(view.id).primary();
+    let word = textobject::textobject_word(text, range, textobject::TextObject::Inside, 1, false);
+    let name = text.slice(word.from()..word.to()).to_string();
+    let current_path = doc.path().cloned();
+
+    let mut locations: Vec<_> = match (&current_path, document_tags(doc)) {
+        (Some(path), Some(tags)) => tags
+            .iter()
+            .filter(|tag| tag.name == name)
+            .filter_map(|tag| tag_to_symbol(path, tag))
+            .map(|symbol| symbol.location)
+            .collect(),
+        _ => Vec::new(),
+    };
+    locations.extend(
+        cx.editor
+            .tag_index
+            .definitions(&name)
+            .iter()
+            .filter(|(path, _)| Some(path) != current_path.as_ref())
+            .filter_map(|(path, tag)| tag_to_symbol(path, tag))
+            .map(|symbol| symbol.location),
+    );
+
+    let call: job::Callback = Box::new(move |editor: &mut Editor, compositor: &mut Compositor| {
+        goto_impl(editor, compositor, locations, OffsetEncoding::Utf8)
+    });
+    cx.jobs.callback(async move { Ok(call) });
+}
+
 fn goto_definition(cx: &mut Context) {
     let (view, doc) = current!(cx.editor);
     let language_server = match doc.language_server() {
         Some(language_server) => language_server,
-        None => return,
+        None => {
+            goto_tag_definition(cx);
+            return;
+        }
     };
 
     let offset_encoding = language_server.offset_encoding();
@@ -4117,7 +4952,7 @@ pub mod insert {
         // run through insert hooks, stopping on the first one that returns Some(t)
         for hook in hooks {
             if let Some(transaction) = hook(text, &selection, c) {
-                doc.apply(&transaction, view.id);
+                doc.apply_linked(&transaction, view.id);
                 break;
             }
         }
@@ -4277,7 +5112,7 @@ pub mod insert {
                     )
                 }
             });
-        doc.apply(&transaction, view.id);
+        doc.apply_linked(&transaction, view.id);
     }
 
     pub fn delete_char_forward(cx: &mut Context) {
@@ -4293,7 +5128,7 @@ pub mod insert {
                     None,
                 )
             });
-        doc.apply(&transaction, view.id);
+        doc.apply_linked(&transaction, view.id);
     }
 
     pub fn delete_word_backward(cx: &mut Context) {
@@ -5134,18 +5969,165 @@ fn rotate_selection_contents_backward(cx: &mut Context) {
 
 // tree sitter node selection
 
-fn expand_selection(cx: &mut Context) {
-    let motion = |editor: &mut Editor| {
-        let (view, doc) = current!(editor);
+/// The definition of the local variable under the primary cursor and the references to it,
+/// resolved with the `locals.scm` query of the language.
+fn local_under_cursor(doc: &Document, view_id: ViewId) -> Option<(Range, Vec<Range>)> {
+    let text = doc.text().slice(..);
+    let locals = doc.syntax()?.locals(text);
+    let cursor = doc.selection(view_id).primary().cursor(text);
+    let definition = locals.definition_at(text.char_to_byte(cursor))?;
 
-        if let Some(syntax) = doc.syntax() {
-            let text = doc.text().slice(..);
-            let selection = object::expand_selection(syntax, text, doc.selection(view.id));
-            doc.set_selection(view.id, selection);
+    let to_range = |range: std::ops::Range<usize>| {
+        Range::new(text.byte_to_char(range.start), text.byte_to_char(range.end))
+    };
+    Some((
+        to_range(locals.definitions()[definition].range.clone()),
+        locals.references(definition).map(to_range).collect(),
+    ))
+}
+
+fn goto_local_definition(cx: &mut Context) {
+    let (view, doc) = current_ref!(cx.editor);
+    let definition = match local_under_cursor(doc, view.id) {
+        Some((definition, _)) => definition,
+        None => {
+            cx.editor
+                .set_error("No local definition under the cursor".to_string());
+            return;
+        }
+    };
+
+    push_jump(cx.editor);
+    let (view, doc) = current!(cx.editor);
+    doc.set_selection(
+        view.id,
+        Selection::single(definition.anchor, definition.head),
+    );
+    align_view(doc, view, Align::Center);
+}
+
+/// Selects a local variable everywhere in its scope, e.g. to rename it with `c`.
+fn select_local_references(cx: &mut Context) {
+    let (view, doc) = current!(cx.editor);
+    let (definition, references) = match local_under_cursor(doc, view.id) {
+        Some(local) => local,
+        None => {
+            cx.editor
+                .set_error("No local definition under the cursor".to_string());
+            return;
         }
     };
-    motion(&mut cx.editor);
+
+    let text = doc.text().slice(..);
+    let cursor = doc.selection(view.id).primary().cursor(text);
+    let mut ranges: SmallVec<[Range; 1]> = references.into_iter().collect();
+    ranges.push(definition);
+    ranges.sort_by_key(Range::from);
+    let primary_index = ranges
+        .iter()
+        .position(|range| range.from() <= cursor && cursor < range.to())
+        .unwrap_or(0);
+    doc.set_selection(view.id, Selection::new(ranges, primary_index));
+}
+
+fn expand_selection(cx: &mut Context) {
+    select_range_chain(cx, object::expand_range)
+}
+
+fn shrink_selection(cx: &mut Context) {
+    select_range_chain(cx, object::shrink_range)
+}
+
+/// Moves each range of the selection along the chain of ranges around its cursor. The chains
+/// are computed by the language server if it supports `textDocument/selectionRange`, from the
+/// syntax tree otherwise, and cached until the text changes or the selection moves off them.
+fn select_range_chain(cx: &mut Context, step: fn(&[Range], Range) -> Range) {
+    let motion = move |editor: &mut Editor| {
+        let (view, doc) = current!(editor);
+        let chains = match (doc.selection_ranges(view.id), doc.syntax()) {
+            (Some(chains), _) => chains.to_vec(),
+            (None, Some(syntax)) => {
+                let text = doc.text().slice(..);
+                doc.selection(view.id)
+                    .iter()
+                    .map(|range| object::selection_range_chain(syntax, text, *range))
+                    .collect()
+            }
+            (None, None) => return,
+        };
+        step_range_chains(doc, view.id, chains, step);
+    };
     cx.editor.last_motion = Some(Motion(Box::new(motion)));
+
+    let (view, doc) = current!(cx.editor);
+    let language_server = match doc.language_server() {
+        Some(language_server) if doc.selection_ranges(view.id).is_none() => language_server,
+        _ => return motion(cx.editor),
+    };
+
+    let offset_encoding = language_server.offset_encoding();
+    let selection = doc.selection(view.id).clone();
+    let positions = selection
+        .iter()
+        .map(|range| {
+            let pos = range.cursor(doc.text().slice(..));
+            pos_to_lsp_pos(doc.text(), pos, offset_encoding)
+        })
+        .collect();
+    let future = match language_server.selection_range(doc.identifier(), positions) {
+        Some(future) => future,
+        None => return motion(cx.editor),
+    };
+
+    let doc_id = doc.id();
+    let view_id = view.id;
+    cx.callback(
+        future,
+        move |editor: &mut Editor,
+              _compositor: &mut Compositor,
+              response: Option<Vec<lsp::SelectionRange>>| {
+            let doc = match editor.documents.get_mut(&doc_id) {
+                Some(doc) if doc.selections().get(&view_id) == Some(&selection) => doc,
+                // the selection moved while waiting for the response
+                _ => return,
+            };
+
+            let mut chains = Vec::with_capacity(selection.len());
+            for (range, selection_range) in selection.iter().zip(response.unwrap_or_default()) {
+                let mut chain = vec![*range];
+                let mut next = Some(selection_range);
+                while let Some(selection_range) = next {
+                    if let Some(range) =
+                        lsp_range_to_range(doc.text(), selection_range.range, offset_encoding)
+                    {
+                        chain.push(range);
+                    }
+                    next = selection_range.parent.map(|parent| *parent);
+                }
+                chains.push(chain);
+            }
+            if chains.len() == selection.len() {
+                step_range_chains(doc, view_id, chains, step);
+            }
+        },
+    );
+}
+
+fn step_range_chains(
+    doc: &mut Document,
+    view_id: ViewId,
+    chains: Vec<Vec<Range>>,
+    step: fn(&[Range], Range) -> Range,
+) {
+    let selection = doc.selection(view_id);
+    let ranges = selection
+        .iter()
+        .zip(&chains)
+        .map(|(range, chain)| step(chain, *range))
+        .collect();
+    let selection = Selection::new(ranges, selection.primary_index());
+    doc.set_selection(view_id, selection);
+    doc.set_selection_ranges(view_id, chains);
 }
 
 fn match_brackets(cx: &mut Context) {
@@ -5631,7 +6613,11 @@ fn shell(cx: &mut Context, prompt: Cow<'static, str>, behavior: ShellBehavior) {
 
             // after replace cursor may be out of bounds, do this to
             // make sure cursor is in view and update scroll as well
-            view.ensure_cursor_in_view(doc, cx.editor.config.scrolloff);
+            view.ensure_cursor_in_view(
+                doc,
+                cx.editor.config.scrolloff,
+                cx.editor.config.sticky_context.lines(),
+            );
         },
     );
 
@@ -5678,40 +6664,105 @@ fn add_newline_impl(cx: &mut Context, open: Open) {
 }
 
 fn rename_symbol(cx: &mut Context) {
-    let prompt = Prompt::new(
-        "rename-to:".into(),
-        None,
-        |_input: &str| Vec::new(),
-        move |cx: &mut compositor::Context, input: &str, event: PromptEvent| {
-            if event != PromptEvent::Validate {
-                return;
-            }
+    fn create_rename_prompt(prefill: String) -> Box<Prompt> {
+        let mut prompt = Prompt::new(
+            "rename-to:".into(),
+            None,
+            |_input: &str| Vec::new(),
+            move |cx: &mut compositor::Context, input: &str, event: PromptEvent| {
+                if event != PromptEvent::Validate {
+                    return;
+                }
 
-            log::debug!("renaming to: {:?}", input);
+                log::debug!("renaming to: {:?}", input);
 
-            let (view, doc) = current!(cx.editor);
-            let language_server = match doc.language_server() {
-                Some(language_server) => language_server,
-                None => return,
-            };
+                let (view, doc) = current!(cx.editor);
+                let language_server = match doc.language_server() {
+                    Some(language_server) => language_server,
+                    None => return,
+                };
 
-            let offset_encoding = language_server.offset_encoding();
+                let offset_encoding = language_server.offset_encoding();
 
-            let pos = pos_to_lsp_pos(
-                doc.text(),
-                doc.selection(view.id)
-                    .primary()
-                    .cursor(doc.text().slice(..)),
-                offset_encoding,
-            );
+                let pos = pos_to_lsp_pos(
+                    doc.text(),
+                    doc.selection(view.id)
+                        .primary()
+                        .cursor(doc.text().slice(..)),
+                    offset_encoding,
+                );
+
+                let task = language_server.rename_symbol(doc.identifier(), pos, input.to_string());
+                let edits = match block_on(task) {
+                    Ok(edits) => edits,
+                    Err(err) => {
+                        cx.editor.set_error(format!("Rename failed: {}", err));
+                        return;
+                    }
+                };
+                log::debug!("Edits from LSP: {:?}", edits);
+                let summary = apply_workspace_edit(cx.editor, offset_encoding, &edits);
+                if summary.edits == 0 {
+                    cx.editor.set_error("Nothing to rename".to_string());
+                } else {
+                    cx.editor.set_status(format!("Renamed {}", summary));
+                }
+            },
+        );
+        prompt.insert_str(&prefill);
+        Box::new(prompt)
+    }
+
+    let (view, doc) = current_ref!(cx.editor);
+    let language_server = match doc.language_server() {
+        Some(language_server) => language_server,
+        None => {
+            cx.push_layer(create_rename_prompt(String::new()));
+            return;
+        }
+    };
+
+    let offset_encoding = language_server.offset_encoding();
+    let pos = pos_to_lsp_pos(
+        doc.text(),
+        doc.selection(view.id)
+            .primary()
+            .cursor(doc.text().slice(..)),
+        offset_encoding,
+    );
+
+    let future = match language_server.prepare_rename(doc.identifier(), pos) {
+        Some(future) => future,
+        None => {
+            cx.push_layer(create_rename_prompt(String::new()));
+            return;
+        }
+    };
 
-            let task = language_server.rename_symbol(doc.identifier(), pos, input.to_string());
-            let edits = block_on(task).unwrap_or_default();
-            log::debug!("Edits from LSP: {:?}", edits);
-            apply_workspace_edit(&mut cx.editor, offset_encoding, &edits);
+    cx.callback(
+        future,
+        move |editor: &mut Editor,
+              compositor: &mut Compositor,
+              response: Option<lsp::PrepareRenameResponse>| {
+            let prefill = match response {
+                Some(lsp::PrepareRenameResponse::Range(range)) => {
+                    let text = doc!(editor).text();
+                    lsp_range_to_range(text, range, offset_encoding)
+                        .map(|range| range.fragment(text.slice(..)).to_string())
+                        .unwrap_or_default()
+                }
+                Some(lsp::PrepareRenameResponse::RangeWithPlaceholder { placeholder, .. }) => {
+                    placeholder
+                }
+                Some(lsp::PrepareRenameResponse::DefaultBehavior { .. }) => String::new(),
+                None => {
+                    editor.set_error("No symbol to rename at the cursor".to_string());
+                    return;
+                }
+            };
+            compositor.push(create_rename_prompt(prefill));
         },
     );
-    cx.push_layer(Box::new(prompt));
 }
 
 /// Increment object under cursor by count.
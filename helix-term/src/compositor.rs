@@ -67,10 +67,21 @@ pub trait Component: Any + AnyComponent {
 }
 
 use anyhow::Error;
-use std::io::stdout;
-use tui::backend::{Backend, CrosstermBackend};
+use tui::backend::Backend;
+
+#[cfg(not(feature = "integration"))]
+use tui::backend::CrosstermBackend;
+
+#[cfg(not(feature = "integration"))]
 type Terminal = tui::terminal::Terminal<CrosstermBackend<std::io::Stdout>>;
 
+#[cfg(feature = "integration")]
+use tui::backend::TestBackend;
+
+/// Integration tests render into an in-memory buffer instead of the terminal.
+#[cfg(feature = "integration")]
+type Terminal = tui::terminal::Terminal<TestBackend>;
+
 pub struct Compositor {
     layers: Vec<Box<dyn Component>>,
     terminal: Terminal,
@@ -80,7 +91,11 @@ pub struct Compositor {
 
 impl Compositor {
     pub fn new() -> Result<Self, Error> {
-        let backend = CrosstermBackend::new(stdout());
+        #[cfg(not(feature = "integration"))]
+        let backend = CrosstermBackend::new(std::io::stdout());
+        #[cfg(feature = "integration")]
+        let backend = TestBackend::new(120, 150);
+
         let terminal = Terminal::new(backend)?;
         Ok(Self {
             layers: Vec::new(),
@@ -89,6 +104,12 @@ impl Compositor {
         })
     }
 
+    /// The last rendered frame.
+    #[cfg(feature = "integration")]
+    pub fn buffer(&self) -> &Surface {
+        self.terminal.backend().buffer()
+    }
+
     pub fn size(&self) -> Rect {
         self.terminal.size().expect("couldn't get terminal size")
     }
@@ -177,6 +198,16 @@ impl Compositor {
             .any(|component| component.type_name() == type_name)
     }
 
+    /// Removes the layer of type `T`, if there is one.
+    pub fn remove<T: 'static>(&mut self) -> Option<Box<dyn Component>> {
+        let type_name = std::any::type_name::<T>();
+        let index = self
+            .layers
+            .iter()
+            .position(|component| component.type_name() == type_name)?;
+        Some(self.layers.remove(index))
+    }
+
     pub fn find<T: 'static>(&mut self) -> Option<&mut T> {
         let type_name = std::any::type_name::<T>();
         self.layers
@@ -1,6 +1,10 @@
+use anyhow::{anyhow, bail, Context};
+use helix_core::merge_toml_values;
+use helix_core::syntax::{Configuration, LanguageConfiguration};
 use serde::Deserialize;
+use std::path::{Path, PathBuf};
 
-use crate::keymap::Keymaps;
+use crate::keymap::{merge_keys, Keymaps};
 
 #[derive(Debug, Default, Clone, PartialEq, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -18,6 +22,181 @@ pub struct Config {
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct LspConfig {
     pub display_messages: bool,
+    /// Record the messages exchanged with the language servers for `:lsp-log`.
+    #[serde(default)]
+    pub message_log: bool,
+}
+
+impl Config {
+    /// Loads `config.toml` from the config directory, merging its keys over the default
+    /// keymaps. The defaults are used if there is no such file.
+    pub fn load() -> anyhow::Result<Config> {
+        let path = helix_core::config_dir().join("config.toml");
+        match std::fs::read_to_string(&path) {
+            Ok(config) => toml::from_str(&config)
+                .map(merge_keys)
+                .map_err(|err| anyhow!("{}: {}", path.display(), err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(anyhow!("{}: {}", path.display(), err)),
+        }
+    }
+}
+
+/// The built-in `languages.toml`. Its grammar sources are in the runtime directory, so that they
+/// are found wherever the runtime files were copied.
+fn default_lang_config_value() -> toml::Value {
+    let mut default_lang_config: toml::Value =
+        toml::from_slice(include_bytes!("../../languages.toml"))
+            .expect("Could not parse built-in languages.toml, something must be very wrong");
+    resolve_grammar_sources(&mut default_lang_config, &helix_core::runtime_dir());
+    default_lang_config
+}
+
+/// The built-in language configuration, used when the user's one is invalid.
+pub fn default_lang_config() -> Configuration {
+    default_lang_config_value()
+        .try_into()
+        .expect("Could not parse built-in languages.toml, something must be very wrong")
+}
+
+/// The `languages.toml` files merged over the built-in one, in order: the user's one in the
+/// config directory and the `.helix/languages.toml` of the workspace if it's trusted, see
+/// [`workspace_lang_config_path`].
+pub fn lang_config_paths() -> Vec<PathBuf> {
+    let mut paths = vec![helix_core::config_dir().join("languages.toml")];
+    if is_workspace_trusted(&workspace_dir()) {
+        paths.push(workspace_lang_config_path());
+    }
+    paths
+}
+
+/// The root of the current workspace, holding its `.helix` directory.
+fn workspace_dir() -> PathBuf {
+    helix_core::find_root(None)
+        .or_else(|| std::env::current_dir().ok())
+        .unwrap_or_default()
+}
+
+/// The `languages.toml` of the current workspace. It can set the commands run as language
+/// servers and formatters, so it's only loaded once the workspace was trusted with
+/// `:trust-workspace`.
+pub fn workspace_lang_config_path() -> PathBuf {
+    workspace_dir().join(".helix").join("languages.toml")
+}
+
+/// The file listing the trusted workspaces, one directory per line.
+fn trusted_workspaces_path() -> PathBuf {
+    helix_core::config_dir().join("trusted_workspaces")
+}
+
+pub fn is_workspace_trusted(workspace: &Path) -> bool {
+    is_trusted(&trusted_workspaces_path(), workspace)
+}
+
+/// Trusts the current workspace, loading its `languages.toml` from now on.
+pub fn trust_workspace() -> std::io::Result<()> {
+    trust(&trusted_workspaces_path(), &workspace_dir())
+}
+
+fn is_trusted(trusted_workspaces: &Path, workspace: &Path) -> bool {
+    match std::fs::read_to_string(trusted_workspaces) {
+        Ok(trusted) => trusted.lines().any(|line| Path::new(line) == workspace),
+        Err(_) => false,
+    }
+}
+
+fn trust(trusted_workspaces: &Path, workspace: &Path) -> std::io::Result<()> {
+    use std::io::Write;
+
+    if is_trusted(trusted_workspaces, workspace) {
+        return Ok(());
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(trusted_workspaces)?;
+    writeln!(file, "{}", workspace.display())
+}
+
+/// Loads the built-in `languages.toml`, deep-merged by language name with the user's and the
+/// workspace's `languages.toml` if there are any, see [`lang_config_paths`]. Errors name the
+/// file and the language they come from.
+pub fn lang_config() -> anyhow::Result<Configuration> {
+    merge_lang_configs(default_lang_config_value(), &lang_config_paths())
+}
+
+fn merge_lang_configs(
+    mut lang_config: toml::Value,
+    paths: &[PathBuf],
+) -> anyhow::Result<Configuration> {
+    for path in paths {
+        let raw = match std::fs::read(path) {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => bail!("{}: {}", path.display(), err),
+        };
+        let mut value: toml::Value =
+            toml::from_slice(&raw).map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+        if let Some(dir) = path.parent() {
+            resolve_grammar_sources(&mut value, dir);
+        }
+        lang_config = merge_toml_values(lang_config, value.clone());
+
+        // check the languages of this file before the whole configuration, to point at them
+        check_languages(&lang_config, &value)
+            .map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+        Configuration::deserialize(lang_config.clone())
+            .map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+    }
+    Ok(Configuration::deserialize(lang_config)?)
+}
+
+/// Checks the merged configuration of every language declared in `file`.
+fn check_languages(lang_config: &toml::Value, file: &toml::Value) -> anyhow::Result<()> {
+    let languages = match file.get("language") {
+        Some(toml::Value::Array(languages)) => languages,
+        _ => return Ok(()),
+    };
+    for language in languages {
+        let name = language
+            .get("name")
+            .and_then(toml::Value::as_str)
+            .context("a [[language]] is missing its `name`")?;
+        let merged = lang_config
+            .get("language")
+            .and_then(toml::Value::as_array)
+            .and_then(|languages| {
+                languages.iter().find(|language| {
+                    language.get("name").and_then(toml::Value::as_str) == Some(name)
+                })
+            })
+            .unwrap_or(language);
+        LanguageConfiguration::deserialize(merged.clone())
+            .map_err(|err| anyhow!("language `{}`: {}", name, err))?;
+    }
+    Ok(())
+}
+
+/// Makes the relative `grammar.source` directories of a `languages.toml` relative to `dir`, the
+/// directory of the file declaring them. Git URLs are left as they are.
+fn resolve_grammar_sources(lang_config: &mut toml::Value, dir: &Path) {
+    let languages = match lang_config.get_mut("language") {
+        Some(toml::Value::Array(languages)) => languages,
+        _ => return,
+    };
+    for language in languages {
+        let source = match language
+            .get_mut("grammar")
+            .and_then(|g| g.get_mut("source"))
+        {
+            Some(toml::Value::String(source)) => source,
+            _ => continue,
+        };
+        if source.contains("://") || source.starts_with("git@") || Path::new(source).is_absolute() {
+            continue;
+        }
+        *source = dir.join(&source).to_string_lossy().into_owned();
+    }
 }
 
 #[test]
@@ -52,3 +231,154 @@ fn parsing_keymaps_config_file() {
         }
     );
 }
+
+#[test]
+fn resolving_grammar_sources() {
+    let mut lang_config: toml::Value = toml::from_str(
+        r#"
+            [[language]]
+            name = "rust"
+            grammar = { source = "grammars/tree-sitter-rust" }
+
+            [[language]]
+            name = "c"
+            grammar = { source = "https://github.com/tree-sitter/tree-sitter-c", rev = "main" }
+
+            [[language]]
+            name = "text"
+        "#,
+    )
+    .unwrap();
+    resolve_grammar_sources(&mut lang_config, Path::new("/config"));
+
+    let source = |i: usize| {
+        lang_config["language"][i]["grammar"]["source"]
+            .as_str()
+            .unwrap()
+    };
+    assert_eq!(
+        Path::new(source(0)),
+        Path::new("/config/grammars/tree-sitter-rust")
+    );
+    assert_eq!(source(1), "https://github.com/tree-sitter/tree-sitter-c");
+
+    // the built-in sources are found in the runtime directory
+    let rust = default_lang_config()
+        .language
+        .into_iter()
+        .find(|language| language.language_id == "rust")
+        .unwrap();
+    assert_eq!(
+        Path::new(&rust.grammar.unwrap().source),
+        helix_core::runtime_dir().join("grammars/sources/tree-sitter-rust")
+    );
+}
+
+#[test]
+fn merging_language_configs() {
+    let dir = std::env::temp_dir().join(format!("helix-lang-config-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join(".helix")).unwrap();
+    let user = dir.join("languages.toml");
+    let project = dir.join(".helix/languages.toml");
+    let paths = [user.clone(), project.clone()];
+
+    std::fs::write(
+        &user,
+        r#"
+            [[language]]
+            name = "rust"
+            auto-format = false
+            language-server = { command = "rust-analyzer", args = ["--verbose"] }
+
+            [[language]]
+            name = "typescript"
+            language-server = { args = ["--stdio", "--log-level", "4"] }
+
+            [[language]]
+            name = "mylang"
+            scope = "source.mylang"
+            file-types = ["my"]
+            roots = []
+        "#,
+    )
+    .unwrap();
+    std::fs::write(
+        &project,
+        r#"
+            [[language]]
+            name = "rust"
+            indent = { tab-width = 2, unit = "  " }
+        "#,
+    )
+    .unwrap();
+    let config = merge_lang_configs(default_lang_config_value(), &paths).unwrap();
+    let rust = config
+        .language
+        .iter()
+        .find(|language| language.language_id == "rust")
+        .unwrap();
+    // the keys of both files are merged over the built-in ones
+    assert!(!rust.auto_format);
+    assert_eq!(rust.language_server.as_ref().unwrap().args, ["--verbose"]);
+    assert_eq!(rust.indent.as_ref().unwrap().tab_width, 2);
+    assert_eq!(rust.comment_token.as_deref(), Some("//"));
+    // arrays are replaced rather than appended to
+    let typescript = config
+        .language
+        .iter()
+        .find(|language| language.language_id == "typescript")
+        .unwrap();
+    let typescript_server = typescript.language_server.as_ref().unwrap();
+    assert_eq!(typescript_server.command, "typescript-language-server");
+    assert_eq!(typescript_server.args, ["--stdio", "--log-level", "4"]);
+    assert!(config
+        .language
+        .iter()
+        .any(|language| language.language_id == "mylang"));
+
+    std::fs::write(
+        &project,
+        r#"
+            [[language]]
+            name = "rust"
+            indent = { tab-width = "two", unit = "  " }
+        "#,
+    )
+    .unwrap();
+    let err = merge_lang_configs(default_lang_config_value(), &paths)
+        .unwrap_err()
+        .to_string();
+    assert!(err.starts_with(&format!("{}: language `rust`: ", project.display())));
+    assert!(err.contains("tab-width"), "{}", err);
+
+    std::fs::write(&project, "[[language]]\nname = \"new\"\n").unwrap();
+    let err = merge_lang_configs(default_lang_config_value(), &paths)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("language `new`: missing field"), "{}", err);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn trusting_workspaces() {
+    let dir = std::env::temp_dir().join(format!("helix-trust-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let trusted_workspaces = dir.join("trusted_workspaces");
+    let workspace = Path::new("/home/user/project");
+
+    assert!(!is_trusted(&trusted_workspaces, workspace));
+    trust(&trusted_workspaces, workspace).unwrap();
+    trust(&trusted_workspaces, workspace).unwrap();
+    assert!(is_trusted(&trusted_workspaces, workspace));
+    assert!(!is_trusted(&trusted_workspaces, Path::new("/home/user")));
+    assert_eq!(
+        std::fs::read_to_string(&trusted_workspaces)
+            .unwrap()
+            .lines()
+            .count(),
+        1
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
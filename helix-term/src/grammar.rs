@@ -0,0 +1,134 @@
+//! `hx --grammar build|list`: building the tree-sitter grammars declared in `languages.toml`
+//! into the runtime directory.
+
+use anyhow::Result;
+use helix_core::syntax::{Configuration, GrammarConfiguration};
+use helix_syntax::build::{build_grammar, checkout, library_path, needs_recompile};
+use std::path::PathBuf;
+use std::sync::{mpsc::channel, Arc, Mutex};
+
+use crate::args::GrammarCommand;
+
+/// Runs `command`, returning the exit code.
+pub fn run(command: GrammarCommand, config: &Configuration) -> i32 {
+    match command {
+        GrammarCommand::Build => build_grammars(config),
+        GrammarCommand::List => {
+            list_grammars(config);
+            0
+        }
+    }
+}
+
+fn grammars(config: &Configuration) -> Vec<(String, GrammarConfiguration)> {
+    config
+        .language
+        .iter()
+        .filter_map(|language| {
+            let grammar = language.grammar.clone()?;
+            Some((language.language_id.to_ascii_lowercase(), grammar))
+        })
+        .collect()
+}
+
+fn out_dir() -> PathBuf {
+    helix_core::runtime_dir().join("grammars")
+}
+
+/// Where the sources of `grammar` are checked out if they come from a git repository.
+fn checkout_dir(name: &str) -> PathBuf {
+    helix_core::cache_dir().join("grammars").join(name)
+}
+
+/// The directory containing `src/parser.c`, checking out the sources first if `update` is set.
+fn grammar_dir(name: &str, grammar: &GrammarConfiguration, update: bool) -> Result<PathBuf> {
+    let dir = match &grammar.rev {
+        Some(rev) => {
+            let dir = checkout_dir(name);
+            if update {
+                checkout(&grammar.source, rev, &dir)?;
+            }
+            dir
+        }
+        None => PathBuf::from(&grammar.source),
+    };
+    Ok(match &grammar.subpath {
+        Some(subpath) => dir.join(subpath),
+        None => dir,
+    })
+}
+
+/// Builds the grammars that are missing or older than their sources, one per CPU at a time.
+fn build_grammars(config: &Configuration) -> i32 {
+    let out_dir = out_dir();
+    let queue = Arc::new(Mutex::new(grammars(config).into_iter()));
+    let (tx, rx) = channel();
+
+    let workers: Vec<_> = (0..num_cpus::get())
+        .map(|_| {
+            let queue = queue.clone();
+            let out_dir = out_dir.clone();
+            let tx = tx.clone();
+            std::thread::spawn(move || loop {
+                let next = queue.lock().unwrap().next();
+                let (name, grammar) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let result = grammar_dir(&name, &grammar, true)
+                    .and_then(|dir| build_grammar(&name, &dir, &out_dir));
+                let _ = tx.send((name, result));
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let mut failed = 0;
+    for (name, result) in rx {
+        match result {
+            Ok(true) => println!("{}: built", name),
+            Ok(false) => println!("{}: up to date", name),
+            Err(err) => {
+                failed += 1;
+                println!("{}: failed: {:#}", name, err);
+            }
+        }
+    }
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    if failed > 0 {
+        eprintln!("{} grammars failed to build", failed);
+        1
+    } else {
+        println!("Grammars are built in {}", out_dir.display());
+        0
+    }
+}
+
+fn list_grammars(config: &Configuration) {
+    let out_dir = out_dir();
+    for (name, grammar) in grammars(config) {
+        let status = match grammar_dir(&name, &grammar, false) {
+            Ok(_) if grammar.rev.is_some() && !checkout_dir(&name).exists() => "not fetched",
+            Ok(dir) if !dir.join("src/parser.c").exists() => "missing source",
+            Ok(dir) => match needs_recompile(&dir, &out_dir, &name) {
+                Ok(true) if library_path(&out_dir, &name).exists() => "outdated",
+                Ok(true) => "not built",
+                Ok(false) => "built",
+                Err(_) => "missing source",
+            },
+            Err(_) => "missing source",
+        };
+
+        let mut source = grammar.source.clone();
+        if let Some(rev) = &grammar.rev {
+            source = format!("{} @ {}", source, rev);
+        }
+        if let Some(subpath) = &grammar.subpath {
+            source = format!("{} ({})", source, subpath);
+        }
+        println!("{:<16} {:<15} {}", name, status, source);
+    }
+}
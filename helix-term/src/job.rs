@@ -72,7 +72,14 @@ impl Jobs {
                 call(editor, compositor);
             }
             Err(e) => {
-                editor.set_error(format!("Async job failed: {}", e));
+                // superseded or outdated requests aren't failures worth reporting
+                let cancelled =
+                    matches!(e.downcast_ref::<helix_lsp::Error>(), Some(e) if e.is_cancelled());
+                if cancelled {
+                    log::debug!("Async job cancelled: {}", e);
+                } else {
+                    editor.set_error(format!("Async job failed: {}", e));
+                }
             }
         }
     }
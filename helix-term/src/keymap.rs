@@ -520,6 +520,7 @@ impl Default for Keymaps {
                 "y" => goto_type_definition,
                 "r" => goto_reference,
                 "i" => goto_implementation,
+                "D" => goto_local_definition,
                 "t" => goto_window_top,
                 "m" => goto_window_middle,
                 "b" => goto_window_bottom,
@@ -554,6 +555,8 @@ impl Default for Keymaps {
             "%" => select_all,
             "x" => extend_line,
             "X" => extend_to_line_bounds,
+            "A-o" => expand_selection,
+            "A-i" => shrink_selection,
             // crop_to_whole_line
 
             "m" => { "Match"
@@ -647,7 +650,10 @@ impl Default for Keymaps {
                 "b" => buffer_picker,
                 "s" => symbol_picker,
                 "S" => workspace_symbol_picker,
+                "d" => diagnostics_picker,
+                "D" => workspace_diagnostics_picker,
                 "a" => code_action,
+                "l" => code_lens,
                 "'" => last_picker,
                 "w" => { "Window"
                     "C-w" | "w" => rotate_view,
@@ -670,6 +676,7 @@ impl Default for Keymaps {
                 "/" => global_search,
                 "k" => hover,
                 "r" => rename_symbol,
+                "h" => select_local_references,
             },
             "z" => { "View"
                 "z" | "c" => align_view_center,
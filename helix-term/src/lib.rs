@@ -6,6 +6,7 @@ pub mod args;
 pub mod commands;
 pub mod compositor;
 pub mod config;
+pub mod grammar;
 pub mod job;
 pub mod keymap;
 pub mod ui;
@@ -1,8 +1,7 @@
-use anyhow::{Context, Error, Result};
+use anyhow::{Context, Result};
 use helix_term::application::Application;
 use helix_term::args::Args;
 use helix_term::config::Config;
-use helix_term::keymap::merge_keys;
 use std::path::PathBuf;
 
 fn setup_logging(logpath: PathBuf, verbosity: u64) -> Result<()> {
@@ -54,6 +53,7 @@ async fn main_impl() -> Result<i32> {
 
 USAGE:
     hx [FLAGS] [files]...
+    hx --grammar <build|list>
 
 ARGS:
     <files>...    Sets the input file to use
@@ -61,6 +61,8 @@ ARGS:
 FLAGS:
     -h, --help       Prints help information
     --tutor          Loads the tutorial
+    --grammar build  Builds the tree-sitter grammars declared in languages.toml
+    --grammar list   Lists the grammars declared in languages.toml and whether they are built
     -v               Increases logging verbosity each use for up to 3 times
                      (default file: {})
     -V, --version    Prints version information
@@ -85,30 +87,40 @@ FLAGS:
         std::process::exit(0);
     }
 
+    if let Some(command) = args.grammar {
+        let syn_loader_conf = helix_term::config::lang_config()?;
+        return Ok(helix_term::grammar::run(command, &syn_loader_conf));
+    }
+
     let conf_dir = helix_core::config_dir();
     if !conf_dir.exists() {
         std::fs::create_dir_all(&conf_dir).ok();
     }
 
-    let config = match std::fs::read_to_string(conf_dir.join("config.toml")) {
-        Ok(config) => toml::from_str(&config)
-            .map(merge_keys)
-            .unwrap_or_else(|err| {
-                eprintln!("Bad config: {}", err);
-                eprintln!("Press <ENTER> to continue with default config");
-                use std::io::Read;
-                // This waits for an enter press.
-                let _ = std::io::stdin().read(&mut []);
-                Config::default()
-            }),
-        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Config::default(),
-        Err(err) => return Err(Error::new(err)),
-    };
+    let config = Config::load().unwrap_or_else(|err| {
+        eprintln!("Bad config: {}", err);
+        eprintln!("Press <ENTER> to continue with default config");
+        use std::io::Read;
+        // This waits for an enter press.
+        let _ = std::io::stdin().read(&mut []);
+        Config::default()
+    });
+
+    // load default and user config, and merge both
+    let syn_loader_conf = helix_term::config::lang_config().unwrap_or_else(|err| {
+        eprintln!("Bad language config: {}", err);
+        eprintln!("Press <ENTER> to continue with default language config");
+        use std::io::Read;
+        // This waits for an enter press.
+        let _ = std::io::stdin().read(&mut []);
+        helix_term::config::default_lang_config()
+    });
 
     setup_logging(logpath, args.verbosity).context("failed to initialize logging")?;
 
     // TODO: use the thread local executor to spawn the application task separately from the work pool
-    let mut app = Application::new(args, config).context("unable to create new application")?;
+    let mut app = Application::new(args, config, syn_loader_conf)
+        .context("unable to create new application")?;
 
     let exit_code = app.run().await?;
 
@@ -3,13 +3,14 @@ use crate::{
     compositor::{Component, Context, EventResult},
     key,
     keymap::{KeymapResult, KeymapResultKind, Keymaps},
-    ui::{Completion, ProgressSpinners},
+    ui::{Completion, ProgressSpinners, SyntaxTree},
 };
 
 use helix_core::{
-    coords_at_pos,
+    coords_at_pos, find_first_non_whitespace_char,
     graphemes::{ensure_grapheme_boundary_next, next_grapheme_boundary, prev_grapheme_boundary},
     movement::Direction,
+    rainbow,
     syntax::{self, HighlightEvent},
     unicode::segmentation::UnicodeSegmentation,
     unicode::width::UnicodeWidthStr,
@@ -35,6 +36,8 @@ pub struct EditorView {
     pub(crate) completion: Option<Completion>,
     spinners: ProgressSpinners,
     autoinfo: Option<Info>,
+    /// Whether the syntax tree panel is shown, the views are laid out on its left.
+    side_panel: bool,
 }
 
 impl Default for EditorView {
@@ -52,30 +55,52 @@ impl EditorView {
             completion: None,
             spinners: ProgressSpinners::default(),
             autoinfo: None,
+            side_panel: false,
         }
     }
 
+    /// Leaves the right of the screen to the syntax tree panel while it's `shown`.
+    pub fn set_side_panel(&mut self, shown: bool) {
+        self.side_panel = shown;
+    }
+
+    /// Replaces the keymaps, dropping any pending key sequence.
+    pub fn set_keymaps(&mut self, keymaps: Keymaps) {
+        self.keymaps = keymaps;
+        self.on_next_key = None;
+        self.autoinfo = None;
+    }
+
     pub fn spinners_mut(&mut self) -> &mut ProgressSpinners {
         &mut self.spinners
     }
 
-    #[allow(clippy::too_many_arguments)]
     pub fn render_view(
         &self,
+        editor: &Editor,
         doc: &Document,
         view: &View,
         viewport: Rect,
         surface: &mut Surface,
-        theme: &Theme,
         is_focused: bool,
-        loader: &syntax::Loader,
-        config: &helix_view::editor::Config,
     ) {
+        let theme = &editor.theme;
+        let loader = &editor.syn_loader;
+        let config = &editor.config;
         let inner = view.inner_area();
         let area = view.area;
 
         let highlights = Self::doc_syntax_highlights(doc, view.offset, inner.height, theme, loader);
+        let highlights: Box<dyn Iterator<Item = HighlightEvent>> = if config.rainbow_brackets {
+            Box::new(syntax::merge(
+                highlights,
+                Self::doc_rainbow_highlights(doc, view.offset, inner.height, theme),
+            ))
+        } else {
+            highlights
+        };
         let highlights = syntax::merge(highlights, Self::doc_diagnostics_highlights(doc, theme));
+        let highlights = syntax::merge(highlights, Self::doc_link_highlights(doc, theme));
         let highlights: Box<dyn Iterator<Item = HighlightEvent>> = if is_focused {
             Box::new(syntax::merge(
                 highlights,
@@ -92,6 +117,8 @@ impl EditorView {
             Self::render_focused_view_elements(view, doc, inner, theme, surface);
         }
 
+        Self::render_sticky_context(editor, doc, view, surface);
+
         // if we're not at the edge of the screen, draw a right border
         if viewport.right() != view.area.right() {
             let x = area.right();
@@ -111,7 +138,7 @@ impl EditorView {
             .area
             .clip_top(view.area.height.saturating_sub(1))
             .clip_bottom(1); // -1 from bottom to remove commandline
-        self.render_statusline(doc, view, statusline_area, surface, theme, is_focused);
+        self.render_statusline(editor, doc, view, statusline_area, surface, is_focused);
     }
 
     /// Get syntax highlights for a document in a view represented by the first line
@@ -184,6 +211,39 @@ impl EditorView {
         Box::new(highlights)
     }
 
+    /// Get highlight spans colouring the brackets in a view by their nesting depth, cycling
+    /// through the `ui.rainbow.N` scopes of the theme.
+    pub fn doc_rainbow_highlights(
+        doc: &Document,
+        offset: Position,
+        height: u16,
+        theme: &Theme,
+    ) -> Vec<(usize, std::ops::Range<usize>)> {
+        let mut scopes = Vec::new();
+        while let Some(scope) = theme.find_scope_index(&format!("ui.rainbow.{}", scopes.len())) {
+            scopes.push(scope);
+        }
+        let syntax = match doc.syntax() {
+            Some(syntax) if !scopes.is_empty() => syntax,
+            _ => return Vec::new(),
+        };
+
+        let text = doc.text().slice(..);
+        let last_line = std::cmp::min(
+            (offset.row + height as usize).saturating_sub(1),
+            text.len_lines().saturating_sub(1),
+        );
+        let range = text.line_to_byte(offset.row)..text.line_to_byte(last_line + 1);
+        let query = doc
+            .language_config()
+            .and_then(|config| config.rainbow_query());
+
+        rainbow::rainbow_brackets(syntax, query, text, range)
+            .into_iter()
+            .map(|(depth, range)| (scopes[depth % scopes.len()], range))
+            .collect()
+    }
+
     /// Get highlight spans for document diagnostics
     pub fn doc_diagnostics_highlights(
         doc: &Document,
@@ -208,6 +268,22 @@ impl EditorView {
             .collect()
     }
 
+    /// Get highlight spans for the links in a document, if the theme styles them.
+    pub fn doc_link_highlights(
+        doc: &Document,
+        theme: &Theme,
+    ) -> Vec<(usize, std::ops::Range<usize>)> {
+        let link_scope = match theme.find_scope_index("ui.link") {
+            Some(scope) => scope,
+            None => return Vec::new(),
+        };
+
+        doc.document_links()
+            .iter()
+            .map(|link| (link_scope, link.start..link.end))
+            .collect()
+    }
+
     /// Get highlight spans for selections in a document view.
     pub fn doc_selection_highlights(
         doc: &Document,
@@ -283,11 +359,14 @@ impl EditorView {
         let mut spans = Vec::new();
         let mut visual_x = 0u16;
         let mut line = 0u16;
+        let mut doc_line = offset.row;
         let tab_width = doc.tab_width();
         let tab = " ".repeat(tab_width);
 
         let text_style = theme.get("ui.text");
 
+        line += Self::render_virtual_lines(doc, doc_line, offset, viewport, line, surface, theme);
+
         'outer: for event in highlights {
             match event {
                 HighlightEvent::HighlightStart(span) => {
@@ -326,11 +405,19 @@ impl EditorView {
 
                             visual_x = 0;
                             line += 1;
+                            doc_line += 1;
 
                             // TODO: with proper iter this shouldn't be necessary
                             if line >= viewport.height {
                                 break 'outer;
                             }
+
+                            line += Self::render_virtual_lines(
+                                doc, doc_line, offset, viewport, line, surface, theme,
+                            );
+                            if line >= viewport.height {
+                                break 'outer;
+                            }
                         } else {
                             let grapheme = Cow::from(grapheme);
 
@@ -362,6 +449,106 @@ impl EditorView {
         }
     }
 
+    /// Render the virtual lines above `doc_line` starting at `row`, returns how many rows they
+    /// take up.
+    fn render_virtual_lines(
+        doc: &Document,
+        doc_line: usize,
+        offset: Position,
+        viewport: Rect,
+        row: u16,
+        surface: &mut Surface,
+        theme: &Theme,
+    ) -> u16 {
+        let code_lenses = doc.code_lenses_at_line(doc_line);
+        if code_lenses.is_empty() {
+            return 0;
+        }
+
+        // align the lenses with the indentation of their line
+        let text = doc.text().slice(..);
+        let indent = text
+            .line(doc_line)
+            .chars()
+            .take_while(|ch| *ch == ' ' || *ch == '\t')
+            .map(|ch| if ch == '\t' { doc.tab_width() } else { 1 })
+            .sum::<usize>()
+            .saturating_sub(offset.col);
+
+        let titles: Vec<_> = code_lenses
+            .iter()
+            .map(|lens| lens.command.title.as_str())
+            .collect();
+        let style = theme
+            .try_get("ui.code-lens")
+            .unwrap_or_else(|| theme.get("comment"));
+
+        if row < viewport.height && (indent as u16) < viewport.width {
+            surface.set_stringn(
+                viewport.x + indent as u16,
+                viewport.y + row,
+                titles.join(" | "),
+                (viewport.width as usize).saturating_sub(indent),
+                style,
+            );
+        }
+
+        doc.virtual_lines_above(doc_line) as u16
+    }
+
+    /// The lines shown at the top of `view` because the syntax nodes they start are scrolled
+    /// out of sight, outermost first. Empty unless `sticky-context` is enabled.
+    pub fn sticky_context(editor: &Editor, doc: &Document, view: &View) -> Vec<usize> {
+        view.sticky_context(doc, editor.config.sticky_context.lines())
+    }
+
+    pub fn render_sticky_context(
+        editor: &Editor,
+        doc: &Document,
+        view: &View,
+        surface: &mut Surface,
+    ) {
+        let lines = Self::sticky_context(editor, doc, view);
+        if lines.is_empty() {
+            return;
+        }
+
+        let theme = &editor.theme;
+        let style = theme.get("ui.background").patch(
+            theme
+                .try_get("ui.sticky-context")
+                .unwrap_or_else(|| theme.get("ui.statusline.inactive")),
+        );
+        let linenr = style.patch(theme.get("ui.linenr"));
+
+        let text = doc.text().slice(..);
+        let inner = view.inner_area();
+        let gutter_width = inner.x - view.area.x;
+        let tab = " ".repeat(doc.tab_width());
+
+        for (row, &line) in lines.iter().enumerate() {
+            let y = inner.y + row as u16;
+            surface.clear_with(
+                Rect::new(view.area.x, y, gutter_width + inner.width, 1),
+                style,
+            );
+
+            if gutter_width > 1 {
+                let number = format!("{:>1$} ", line + 1, gutter_width as usize - 1);
+                surface.set_stringn(view.area.x, y, number, gutter_width as usize, linenr);
+            }
+
+            let content: String = text
+                .line(line)
+                .chars()
+                .filter(|ch| !matches!(ch, '\n' | '\r'))
+                .skip(view.offset.col)
+                .collect();
+            let content = content.replace('\t', &tab);
+            surface.set_stringn(inner.x, y, content, inner.width as usize, style);
+        }
+    }
+
     /// Render brace match, etc (meant for the focused view only)
     pub fn render_focused_view_elements(
         view: &View,
@@ -428,19 +615,23 @@ impl EditorView {
         for (constructor, width) in view.gutters() {
             let gutter = constructor(doc, view, theme, config, is_focused, *width);
             text.reserve(*width); // ensure there's enough space for the gutter
-            for (i, line) in (view.offset.row..(last_line + 1)).enumerate() {
+            let mut row = 0;
+            for line in view.offset.row..(last_line + 1) {
+                // skip over virtual lines
+                row += doc.virtual_lines_above(line);
                 let selected = cursors.contains(&line);
 
                 if let Some(style) = gutter(line, selected, &mut text) {
                     surface.set_stringn(
                         viewport.x + offset,
-                        viewport.y + i as u16,
+                        viewport.y + row as u16,
                         &text,
                         *width,
                         style,
                     );
                 }
                 text.clear();
+                row += 1;
             }
             offset += *width as u16;
         }
@@ -501,17 +692,17 @@ impl EditorView {
         );
     }
 
-    #[allow(clippy::too_many_arguments)]
     pub fn render_statusline(
         &self,
+        editor: &Editor,
         doc: &Document,
         view: &View,
         viewport: Rect,
         surface: &mut Surface,
-        theme: &Theme,
         is_focused: bool,
     ) {
         use tui::text::{Span, Spans};
+        let theme = &editor.theme;
 
         //-------------------------------
         // Left side of the status line.
@@ -566,6 +757,31 @@ impl EditorView {
 
         // Compute the individual info strings and add them to `right_side_text`.
 
+        if doc.is_large_file() {
+            right_side_text.0.push(Span::styled(
+                " large file ",
+                base_style.patch(theme.get("warning")),
+            ));
+        }
+
+        // Language server status
+        use helix_view::editor::LanguageServerStatus;
+        let lsp_status = doc
+            .language()
+            .and_then(|scope| editor.language_server_status.get(scope))
+            .and_then(|status| match status {
+                LanguageServerStatus::Stopped => Some(("LSP stopped", "warning")),
+                LanguageServerStatus::Restarting(_) => Some(("LSP restarting", "warning")),
+                LanguageServerStatus::Crashed => Some(("LSP crashed", "error")),
+                LanguageServerStatus::Restarted(_) => None,
+            });
+        if let Some((status, scope)) = lsp_status {
+            right_side_text.0.push(Span::styled(
+                format!(" {} ", status),
+                base_style.patch(theme.get(scope)),
+            ));
+        }
+
         // Diagnostics
         let diags = doc.diagnostics().iter().fold((0, 0), |mut counts, diag| {
             use helix_core::diagnostic::Severity;
@@ -595,6 +811,24 @@ impl EditorView {
                 .push(Span::styled(format!(" {} ", count), base_style));
         }
 
+        // Workspace diagnostics, only shown when other files have some too
+        let workspace_diags = editor.workspace_diagnostic_counts();
+        if workspace_diags != diags && workspace_diags != (0, 0) {
+            let (warnings, errors) = workspace_diags;
+            right_side_text.0.push(Span::styled(" W ", base_style));
+            for (count, style) in [(warnings, warning_style), (errors, error_style)] {
+                if count == 0 {
+                    continue;
+                }
+                right_side_text
+                    .0
+                    .push(Span::styled("●", base_style.patch(style)));
+                right_side_text
+                    .0
+                    .push(Span::styled(format!(" {} ", count), base_style));
+            }
+        }
+
         // Selections
         let sels_count = doc.selection(view.id).len();
         right_side_text.0.push(Span::styled(
@@ -782,6 +1016,33 @@ impl EditorView {
             } => {
                 let editor = &mut cxt.editor;
 
+                // clicking a line of the sticky context jumps to it
+                let context_line = editor.tree.views().find_map(|(view, _focus)| {
+                    let inner = view.inner_area();
+                    if column < view.area.x || column >= inner.right() || row < inner.y {
+                        return None;
+                    }
+                    let doc = &editor.documents[&view.doc];
+                    let lines = Self::sticky_context(editor, doc, view);
+                    lines
+                        .get((row - inner.y) as usize)
+                        .map(|&line| (line, view.id))
+                });
+
+                if let Some((line, view_id)) = context_line {
+                    let view = editor.tree.get_mut(view_id);
+                    let doc = editor.documents.get_mut(&view.doc).unwrap();
+                    let text = doc.text().slice(..);
+                    let pos = text.line_to_char(line)
+                        + find_first_non_whitespace_char(text.line(line)).unwrap_or(0);
+
+                    view.jumps.push((doc.id(), doc.selection(view_id).clone()));
+                    doc.set_selection(view_id, Selection::point(pos));
+                    editor.tree.focus = view_id;
+                    editor.ensure_cursor_in_view(view_id);
+                    return EventResult::Consumed(None);
+                }
+
                 let result = editor.tree.views().find_map(|(view, _focus)| {
                     view.pos_at_screen_coords(&editor.documents[&view.doc], row, column)
                         .map(|pos| (pos, view.id))
@@ -1001,7 +1262,11 @@ impl Component for EditorView {
                 }
 
                 let (view, doc) = current!(cxt.editor);
-                view.ensure_cursor_in_view(doc, cxt.editor.config.scrolloff);
+                view.ensure_cursor_in_view(
+                    doc,
+                    cxt.editor.config.scrolloff,
+                    cxt.editor.config.sticky_context.lines(),
+                );
 
                 // mode transitions
                 match (mode, doc.mode()) {
@@ -1038,22 +1303,20 @@ impl Component for EditorView {
         // clear with background color
         surface.set_style(area, cx.editor.theme.get("ui.background"));
 
+        // the views are left of the syntax tree panel, unless it takes the whole width
+        let panel_width = SyntaxTree::width(area);
+        let views_area = if self.side_panel && panel_width < area.width {
+            area.clip_right(panel_width)
+        } else {
+            area
+        };
+
         // if the terminal size suddenly changed, we need to trigger a resize
-        cx.editor.resize(area.clip_bottom(1)); // -1 from bottom for commandline
+        cx.editor.resize(views_area.clip_bottom(1)); // -1 from bottom for commandline
 
         for (view, is_focused) in cx.editor.tree.views() {
             let doc = cx.editor.document(view.doc).unwrap();
-            let loader = &cx.editor.syn_loader;
-            self.render_view(
-                doc,
-                view,
-                area,
-                surface,
-                &cx.editor.theme,
-                is_focused,
-                loader,
-                &cx.editor.config,
-            );
+            self.render_view(cx.editor, doc, view, views_area, surface, is_focused);
         }
 
         if cx.editor.config.auto_info {
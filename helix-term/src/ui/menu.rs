@@ -10,6 +10,7 @@ pub use tui::widgets::{Cell, Row};
 use fuzzy_matcher::skim::SkimMatcherV2 as Matcher;
 use fuzzy_matcher::FuzzyMatcher;
 
+use helix_lsp::lsp;
 use helix_view::{graphics::Rect, Editor};
 use tui::layout::Constraint;
 
@@ -21,6 +22,25 @@ pub trait Item {
     fn row(&self) -> Row;
 }
 
+impl Item for lsp::MessageActionItem {
+    fn sort_text(&self) -> &str {
+        // keep the order the server sent
+        ""
+    }
+
+    fn filter_text(&self) -> &str {
+        &self.title
+    }
+
+    fn label(&self) -> &str {
+        &self.title
+    }
+
+    fn row(&self) -> Row<'_> {
+        Row::new(vec![Cell::from(self.title.as_str())])
+    }
+}
+
 pub struct Menu<T: Item> {
     options: Vec<T>,
 
@@ -82,8 +102,9 @@ impl<T: Item> Menu<T> {
                 }),
         );
         // matches.sort_unstable_by_key(|(_, score)| -score);
+        // stable, so options with the same sort text keep their order
         self.matches
-            .sort_unstable_by_key(|(index, _score)| self.options[*index].sort_text());
+            .sort_by_key(|(index, _score)| self.options[*index].sort_text());
 
         // reset cursor position
         self.cursor = None;
@@ -7,6 +7,7 @@ mod picker;
 mod popup;
 mod prompt;
 mod spinner;
+mod syntax_tree;
 mod text;
 
 pub use completion::Completion;
@@ -17,13 +18,14 @@ pub use picker::{FilePicker, Picker};
 pub use popup::Popup;
 pub use prompt::{Prompt, PromptEvent};
 pub use spinner::{ProgressSpinners, Spinner};
+pub use syntax_tree::SyntaxTree;
 pub use text::Text;
 
 use helix_core::regex::Regex;
 use helix_core::regex::RegexBuilder;
 use helix_view::{Document, Editor, View};
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub fn regex_prompt(
     cx: &mut crate::commands::Context,
@@ -83,7 +85,11 @@ pub fn regex_prompt(
 
                             fun(view, doc, regex, event);
 
-                            view.ensure_cursor_in_view(doc, cx.editor.config.scrolloff);
+                            view.ensure_cursor_in_view(
+                                doc,
+                                cx.editor.config.scrolloff,
+                                cx.editor.config.sticky_context.lines(),
+                            );
                         }
                         Err(_err) => (), // TODO: mark command line as error
                     }
@@ -93,13 +99,18 @@ pub fn regex_prompt(
     )
 }
 
-pub fn file_picker(root: PathBuf, config: &helix_view::editor::Config) -> FilePicker<PathBuf> {
+/// The files in the workspace at `root` as the file picker lists them, with the time they were
+/// last accessed.
+pub fn workspace_files(
+    root: &Path,
+    config: &helix_view::editor::Config,
+) -> Vec<(PathBuf, std::time::SystemTime)> {
     use ignore::{types::TypesBuilder, WalkBuilder};
     use std::time;
 
     // We want to exclude files that the editor can't handle yet
     let mut type_builder = TypesBuilder::new();
-    let mut walk_builder = WalkBuilder::new(&root);
+    let mut walk_builder = WalkBuilder::new(root);
     walk_builder
         .hidden(config.file_picker.hidden)
         .parents(config.file_picker.parents)
@@ -140,13 +151,16 @@ pub fn file_picker(root: PathBuf, config: &helix_view::editor::Config) -> FilePi
         Some((entry.into_path(), time))
     });
 
-    let mut files: Vec<_> = if root.join(".git").is_dir() {
+    if root.join(".git").is_dir() {
         files.collect()
     } else {
         const MAX: usize = 8192;
         files.take(MAX).collect()
-    };
+    }
+}
 
+pub fn file_picker(root: PathBuf, config: &helix_view::editor::Config) -> FilePicker<PathBuf> {
+    let mut files = workspace_files(&root, config);
     files.sort_by_key(|file| std::cmp::Reverse(file.1));
 
     let files = files.into_iter().map(|(path, _)| path).collect();
@@ -174,40 +188,51 @@ pub mod completers {
     use crate::ui::prompt::Completion;
     use fuzzy_matcher::skim::SkimMatcherV2 as Matcher;
     use fuzzy_matcher::FuzzyMatcher;
+    use helix_core::syntax;
     use helix_view::theme;
     use std::borrow::Cow;
     use std::cmp::Reverse;
 
-    pub type Completer = fn(&str) -> Vec<Completion>;
+    /// Completes the argument `input` of a command, knowing the languages of the editor.
+    pub type Completer = fn(&syntax::Loader, &str) -> Vec<Completion>;
 
-    pub fn theme(input: &str) -> Vec<Completion> {
+    pub fn theme(_syn_loader: &syntax::Loader, input: &str) -> Vec<Completion> {
         let mut names = theme::Loader::read_names(&helix_core::runtime_dir().join("themes"));
         names.extend(theme::Loader::read_names(
             &helix_core::config_dir().join("themes"),
         ));
         names.push("default".into());
 
-        let mut names: Vec<_> = names
-            .into_iter()
-            .map(|name| ((0..), Cow::from(name)))
+        fuzzy_match_names(names, input)
+    }
+
+    pub fn language(syn_loader: &syntax::Loader, input: &str) -> Vec<Completion> {
+        let mut names: Vec<String> = syn_loader
+            .language_configs()
+            .map(|config| config.language_id.clone())
             .collect();
+        // languages.toml files may declare the same language again
+        names.sort_unstable();
+        names.dedup();
+        names.push("auto".into());
 
+        fuzzy_match_names(names, input)
+    }
+
+    fn fuzzy_match_names(names: Vec<String>, input: &str) -> Vec<Completion> {
         let matcher = Matcher::default();
 
         let mut matches: Vec<_> = names
             .into_iter()
-            .filter_map(|(_range, name)| {
-                matcher.fuzzy_match(&name, input).map(|score| (name, score))
-            })
+            .map(Cow::from)
+            .filter_map(|name| matcher.fuzzy_match(&name, input).map(|score| (name, score)))
             .collect();
 
         matches.sort_unstable_by_key(|(_file, score)| Reverse(*score));
-        names = matches.into_iter().map(|(name, _)| ((0..), name)).collect();
-
-        names
+        matches.into_iter().map(|(name, _)| ((0..), name)).collect()
     }
 
-    pub fn filename(input: &str) -> Vec<Completion> {
+    pub fn filename(_syn_loader: &syntax::Loader, input: &str) -> Vec<Completion> {
         filename_impl(input, |entry| {
             let is_dir = entry.file_type().map_or(false, |entry| entry.is_dir());
 
@@ -219,7 +244,7 @@ pub mod completers {
         })
     }
 
-    pub fn directory(input: &str) -> Vec<Completion> {
+    pub fn directory(_syn_loader: &syntax::Loader, input: &str) -> Vec<Completion> {
         filename_impl(input, |entry| {
             let is_dir = entry.file_type().map_or(false, |entry| entry.is_dir());
 
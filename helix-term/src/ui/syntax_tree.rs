@@ -0,0 +1,278 @@
+use crate::compositor::{Component, Compositor, Context};
+use crate::job::{self, Jobs};
+use helix_core::inspect::{query_captures, tree_lines, QueryCapture, TreeLine};
+use helix_core::syntax::InjectionLayer;
+use helix_view::graphics::{Margin, Rect};
+use helix_view::{current_ref, Document, DocumentId, Editor};
+use tui::buffer::Buffer as Surface;
+use tui::widgets::{Block, Borders, Widget};
+
+/// The most captures of a query that are listed.
+const MAX_CAPTURES: usize = 1000;
+
+/// A panel on the right of the editor showing the syntax tree of the current document around the
+/// cursor, and the captures of a query. It doesn't handle any event, so it follows the cursor as
+/// the document is edited.
+#[derive(Default)]
+pub struct SyntaxTree {
+    query: Option<String>,
+    cache: Option<Snapshot>,
+    /// The injected languages of the last revision they were parsed for.
+    injections: Option<(Revision, Vec<InjectionLayer>)>,
+    /// Whether the injections are being parsed in the background.
+    parsing: bool,
+}
+
+/// A document, its version and language.
+type Revision = (DocumentId, i32, Option<String>);
+
+/// The captures of the query, kept for a revision of a document.
+struct Snapshot {
+    revision: Revision,
+    query: Option<String>,
+    captures: Option<Result<Vec<QueryCapture>, String>>,
+}
+
+impl SyntaxTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The width of the panel on the right of `viewport`, which the views leave to it.
+    pub fn width(viewport: Rect) -> u16 {
+        std::cmp::min(std::cmp::max(viewport.width / 3, 40), viewport.width)
+    }
+
+    /// Sets the query whose captures are listed, clearing it with `None`.
+    pub fn set_query(&mut self, query: Option<String>) {
+        self.query = query;
+    }
+
+    fn snapshot(&mut self, doc: &Document) -> &Snapshot {
+        let revision = revision(doc);
+        let query = &self.query;
+        let fresh = matches!(&self.cache, Some(snapshot) if snapshot.revision == revision
+            && &snapshot.query == query);
+
+        if !fresh {
+            let text = doc.text().slice(..);
+            let captures = match (doc.syntax(), query) {
+                (Some(syntax), Some(query)) => Some(
+                    query_captures(syntax, query, text, MAX_CAPTURES)
+                        .map_err(|err| err.to_string()),
+                ),
+                _ => None,
+            };
+            self.cache = Some(Snapshot {
+                revision,
+                query: query.clone(),
+                captures,
+            });
+        }
+        self.cache.as_ref().unwrap()
+    }
+
+    /// Parses the injected languages of the document in the background, unless they're up to
+    /// date or already being parsed. The last ones parsed are shown in the meantime.
+    fn parse_injections(&mut self, editor: &Editor, doc: &Document, jobs: &mut Jobs) {
+        let revision = revision(doc);
+        if self.parsing || matches!(&self.injections, Some((parsed, _)) if *parsed == revision) {
+            return;
+        }
+        let scopes = editor.theme.scopes().to_vec();
+        let (tree, config) = match (
+            doc.syntax(),
+            doc.language_config()
+                .and_then(|config| config.highlight_config(&scopes)),
+        ) {
+            (Some(syntax), Some(config)) => (syntax.tree().clone(), config),
+            _ => {
+                self.injections = Some((revision, Vec::new()));
+                return;
+            }
+        };
+
+        self.parsing = true;
+        let text = doc.text().clone();
+        let loader = editor.syn_loader.clone();
+        jobs.callback(async move {
+            let layers = tokio::task::spawn_blocking(move || {
+                InjectionLayer::parse_all(config, tree, text.slice(..), |language| {
+                    loader
+                        .language_configuration_for_injection_string(language)?
+                        .highlight_config(&scopes)
+                })
+            })
+            .await
+            // a panic of the parser was logged already, the panel shouldn't wait forever
+            .unwrap_or_default();
+
+            let call: job::Callback =
+                Box::new(move |_editor: &mut Editor, compositor: &mut Compositor| {
+                    if let Some(panel) = compositor.find::<SyntaxTree>() {
+                        panel.parsing = false;
+                        panel.injections = Some((revision, layers));
+                    }
+                });
+            Ok(call)
+        });
+    }
+}
+
+fn revision(doc: &Document) -> Revision {
+    (doc.id(), doc.version(), doc.language().map(String::from))
+}
+
+/// Appends the lines of a tree, remembering the deepest node containing `focus`. Nodes of
+/// injected languages are deeper than the ones they're injected into.
+fn push_tree(
+    lines: &mut Vec<String>,
+    focused: &mut Option<((usize, usize), usize)>,
+    tree: Vec<TreeLine>,
+    layer_depth: usize,
+    focus: usize,
+) {
+    for line in tree {
+        let depth = (layer_depth, line.depth);
+        if line.range.contains(&focus)
+            && !matches!(focused, Some((focused_depth, _)) if *focused_depth > depth)
+        {
+            *focused = Some((depth, lines.len()));
+        }
+        lines.push(format!("{}{}", "  ".repeat(line.depth), line.text));
+    }
+}
+
+impl Component for SyntaxTree {
+    fn render(&mut self, viewport: Rect, surface: &mut Surface, cx: &mut Context) {
+        let editor: &Editor = cx.editor;
+        let (view, doc) = current_ref!(editor);
+        self.parse_injections(editor, doc, cx.jobs);
+        let theme = &editor.theme;
+        let text_style = theme.get("ui.text");
+        let popup_style = text_style.patch(theme.get("ui.popup"));
+        let comment_style = text_style.patch(theme.get("comment"));
+        let selected_style = text_style.patch(theme.get("ui.selection"));
+
+        // on the right of the views, above the status line and the command line
+        let width = Self::width(viewport);
+        let area = Rect::new(
+            viewport.x + viewport.width - width,
+            viewport.y,
+            width,
+            viewport.height.saturating_sub(2),
+        );
+        surface.clear_with(area, popup_style);
+        let block = Block::default()
+            .title(" syntax tree ")
+            .borders(Borders::ALL)
+            .border_style(popup_style);
+        let inner = block.inner(area).inner(&Margin {
+            vertical: 0,
+            horizontal: 1,
+        });
+        block.render(area, surface);
+
+        let syntax = match doc.syntax() {
+            Some(syntax) => syntax,
+            None => {
+                surface.set_stringn(
+                    inner.x,
+                    inner.y,
+                    "no syntax tree",
+                    inner.width as usize,
+                    comment_style,
+                );
+                return;
+            }
+        };
+        let text = doc.text().slice(..);
+        let focus = text.char_to_byte(doc.selection(view.id).primary().cursor(text));
+
+        let mut lines = Vec::new();
+        let mut focused = None;
+        push_tree(
+            &mut lines,
+            &mut focused,
+            tree_lines(syntax.tree().root_node(), focus),
+            0,
+            focus,
+        );
+        // the headers of the injection layers, by line
+        let mut headers = Vec::new();
+        let injections = match &self.injections {
+            Some(((doc_id, ..), layers)) if *doc_id == doc.id() => layers.as_slice(),
+            _ => &[],
+        };
+        for layer in injections {
+            headers.push(lines.len());
+            lines.push(format!("; injection: {}", layer.language));
+            let tree = tree_lines(layer.tree.root_node(), focus);
+            push_tree(&mut lines, &mut focused, tree, layer.depth, focus);
+        }
+        let snapshot = self.snapshot(doc);
+
+        let mut query_lines = Vec::new();
+        if let (Some(query), Some(captures)) = (&snapshot.query, &snapshot.captures) {
+            query_lines.push((format!("; query: {}", query), comment_style));
+            match captures {
+                Ok(captures) if captures.is_empty() => {
+                    query_lines.push(("no captures".to_string(), comment_style));
+                }
+                Ok(captures) => {
+                    for capture in captures {
+                        let start = text.byte_to_char(capture.range.start);
+                        let line = text.char_to_line(start);
+                        let col = start - text.line_to_char(line);
+                        let end = text.byte_to_char(capture.range.end);
+                        let snippet = String::from(text.slice(start..end).lines().next().unwrap());
+                        let style = if capture.range.contains(&focus) {
+                            selected_style
+                        } else {
+                            text_style
+                        };
+                        query_lines.push((
+                            format!(
+                                "@{} ({}) {}:{} {}",
+                                capture.name,
+                                capture.kind,
+                                line + 1,
+                                col + 1,
+                                snippet.trim_end()
+                            ),
+                            style,
+                        ));
+                    }
+                }
+                Err(err) => query_lines.push((err.clone(), theme.get("error"))),
+            }
+        }
+
+        // the query takes up to half of the panel, the rest is the tree centered on the focus
+        let query_height = std::cmp::min(query_lines.len(), inner.height as usize / 2);
+        let tree_height = inner.height as usize - query_height;
+        let focused = focused.map(|(_, line)| line);
+        let offset = std::cmp::min(
+            focused.unwrap_or(0).saturating_sub(tree_height / 2),
+            lines.len().saturating_sub(tree_height),
+        );
+
+        for (i, line) in lines.iter().enumerate().skip(offset).take(tree_height) {
+            let y = inner.y + (i - offset) as u16;
+            let style = if Some(i) == focused {
+                surface.set_style(Rect::new(inner.x, y, inner.width, 1), selected_style);
+                selected_style
+            } else if headers.contains(&i) {
+                comment_style
+            } else {
+                text_style
+            };
+            surface.set_stringn(inner.x, y, line, inner.width as usize, style);
+        }
+
+        let y = inner.y + tree_height as u16;
+        for (i, (line, style)) in query_lines.iter().take(query_height).enumerate() {
+            surface.set_stringn(inner.x, y + i as u16, line, inner.width as usize, *style);
+        }
+    }
+}
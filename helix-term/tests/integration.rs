@@ -0,0 +1,12 @@
+//! End-to-end tests driving the `Application` headlessly against a mock language server.
+//!
+//! Run with `cargo integration-test`.
+
+#![cfg(feature = "integration")]
+
+mod test {
+    mod config;
+    mod helpers;
+    mod lsp;
+    mod ui;
+}
@@ -0,0 +1,151 @@
+//! A scriptable language server for the integration tests.
+//!
+//! Speaks JSON-RPC over stdio and answers according to a JSON script passed as the first
+//! argument:
+//!
+//! ```json
+//! {
+//!     "capabilities": { "renameProvider": true },
+//!     "responses": { "textDocument/rename": { "changes": { "$uri": [] } } },
+//!     "errors": { "textDocument/formatting": { "code": -32603, "message": "failed" } },
+//!     "notifications": {
+//!         "textDocument/didOpen": [
+//!             { "method": "textDocument/publishDiagnostics", "params": { "uri": "$uri", "diagnostics": [] } }
+//!         ]
+//!     },
+//!     "log": "/tmp/messages.jsonl"
+//! }
+//! ```
+//!
+//! `responses` maps request methods to their results, requests without one are answered with
+//! `null`. `errors` maps request methods to the errors they're answered with instead, including
+//! `initialize`. `notifications` maps incoming methods to the notifications sent back. `"$uri"` is
+//! replaced with the `textDocument.uri` of the message that triggered the reply. Every incoming
+//! message is appended to `log` if given.
+
+use serde_json::{json, Map, Value};
+use std::io::{self, BufRead, BufReader, Write};
+
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(len) = header.strip_prefix("Content-Length: ") {
+            content_length = len.parse::<usize>().ok();
+        }
+    }
+
+    let len = content_length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut content = vec![0; len];
+    reader.read_exact(&mut content)?;
+    Ok(Some(serde_json::from_slice(&content)?))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let content = message.to_string();
+    write!(
+        writer,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    )?;
+    writer.flush()
+}
+
+/// Replaces every `"$uri"` key and string in `value`.
+fn substitute(value: &Value, uri: &Value) -> Value {
+    match value {
+        Value::String(s) if s == "$uri" => uri.clone(),
+        Value::Array(values) => Value::Array(values.iter().map(|v| substitute(v, uri)).collect()),
+        Value::Object(map) => {
+            let mut substituted = Map::new();
+            for (key, value) in map {
+                let key = match (key.as_str(), uri) {
+                    ("$uri", Value::String(uri)) => uri.clone(),
+                    _ => key.clone(),
+                };
+                substituted.insert(key, substitute(value, uri));
+            }
+            Value::Object(substituted)
+        }
+        value => value.clone(),
+    }
+}
+
+fn main() -> io::Result<()> {
+    let script: Value = match std::env::args().nth(1) {
+        Some(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+        None => json!({}),
+    };
+    let mut log = match script["log"].as_str() {
+        Some(path) => Some(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        ),
+        None => None,
+    };
+
+    let stdin = io::stdin();
+    let mut reader = BufReader::new(stdin.lock());
+    let stdout = io::stdout();
+    let mut writer = stdout.lock();
+
+    while let Some(message) = read_message(&mut reader)? {
+        if let Some(log) = &mut log {
+            writeln!(log, "{}", message)?;
+        }
+
+        let method = message["method"].as_str().unwrap_or_default();
+        let uri = message["params"]["textDocument"]["uri"].clone();
+
+        // responses to our own requests
+        if method.is_empty() {
+            continue;
+        }
+
+        if let Some(id) = message.get("id") {
+            if let Some(error) = script["errors"].get(method) {
+                write_message(
+                    &mut writer,
+                    &json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+                )?;
+                continue;
+            }
+            let result = match method {
+                "initialize" => json!({
+                    "capabilities": script.get("capabilities").cloned().unwrap_or_else(|| json!({})),
+                    "serverInfo": { "name": "helix-mock-lsp" },
+                }),
+                method => substitute(&script["responses"][method], &uri),
+            };
+            write_message(
+                &mut writer,
+                &json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            )?;
+        }
+
+        if let Some(notifications) = script["notifications"][method].as_array() {
+            for notification in notifications {
+                let mut notification = substitute(notification, &uri);
+                notification["jsonrpc"] = json!("2.0");
+                write_message(&mut writer, &notification)?;
+            }
+        }
+
+        if method == "exit" {
+            break;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use helix_view::doc;
+use serde_json::json;
+
+use super::helpers::{screen, test_dir, TestApplication};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keeps_language_and_theme_on_reload() -> anyhow::Result<()> {
+    // the configuration is read from `$XDG_CONFIG_HOME/helix`
+    let config_home = test_dir("config-reload").join("config");
+    let languages = config_home.join("helix").join("languages.toml");
+    std::fs::create_dir_all(languages.parent().unwrap())?;
+    std::env::set_var("XDG_CONFIG_HOME", &config_home);
+    let write_languages = |comment_token: &str| {
+        let languages_toml = format!(
+            r#"
+            [[language]]
+            name = "mock"
+            scope = "source.mock"
+            file-types = ["mock"]
+            roots = []
+
+            [[language]]
+            name = "other"
+            scope = "source.other"
+            file-types = []
+            roots = []
+            comment-token = "{}"
+            "#,
+            comment_token
+        );
+        std::fs::write(&languages, languages_toml)
+    };
+    write_languages("#")?;
+
+    let mut test = TestApplication::new("config-reload", "foo\n", json!({}))?;
+    test.send_keys(":config<minus>reload<ret>")?;
+    test.run_until(|app| {
+        app.editor
+            .syn_loader
+            .language_config_for_language_name("other")
+            .is_some()
+    })
+    .await?;
+
+    // the languages are completed from the ones the editor loaded
+    test.send_keys(":lang<space>oth")?;
+    test.run_until(|app| screen(app).contains("other")).await?;
+    test.send_keys("<esc>")?;
+
+    test.send_keys(":lang<space>other<ret>:theme<space>bogster<ret>")?;
+    test.run_until(|app| {
+        doc!(app.editor).language() == Some("source.other")
+            && app.editor.theme_name.as_deref() == Some("bogster")
+    })
+    .await?;
+
+    write_languages("//")?;
+    test.send_keys(":config<minus>reload<ret>")?;
+    let comment_token = |app: &helix_term::application::Application| {
+        doc!(app.editor)
+            .language_config()
+            .and_then(|config| config.comment_token.clone())
+    };
+    test.run_until(|app| comment_token(app).as_deref() == Some("//"))
+        .await?;
+
+    // the document got the new configuration of the language it was set to
+    assert_eq!(doc!(test.app.editor).language(), Some("source.other"));
+    assert_eq!(test.app.editor.theme_name.as_deref(), Some("bogster"));
+
+    // reloading only the language settings refreshes the open documents as well
+    write_languages("--")?;
+    test.send_keys(":lsp<minus>reload<minus>settings<ret>")?;
+    test.run_until(|app| comment_token(app).as_deref() == Some("--"))
+        .await?;
+    assert_eq!(doc!(test.app.editor).language(), Some("source.other"));
+
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use crossterm::event::Event;
+use helix_core::syntax;
+use helix_term::{application::Application, args::Args, config::Config};
+use helix_view::{doc, input::KeyEvent};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+/// How long a test may wait for the editor to reach the expected state.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parses a key sequence in the notation of the default keymap: `<esc>`, `<C-x>` and `<space>`
+/// are single keys, every other character is typed as is.
+pub fn parse_keys(keys: &str) -> anyhow::Result<Vec<KeyEvent>> {
+    let mut events = Vec::new();
+    let mut chars = keys.chars();
+    while let Some(ch) = chars.next() {
+        let key = if ch == '<' {
+            let name: String = chars.by_ref().take_while(|&ch| ch != '>').collect();
+            name.parse()?
+        } else {
+            ch.to_string().parse()?
+        };
+        events.push(key);
+    }
+    Ok(events)
+}
+
+/// The directory holding the files of the test `name`.
+pub fn test_dir(name: &str) -> PathBuf {
+    std::env::temp_dir()
+        .join(format!("helix-integration-{}", std::process::id()))
+        .join(name)
+}
+
+/// A headless editor with a single document open, backed by the mock language server.
+pub struct TestApplication {
+    pub app: Application,
+    dir: PathBuf,
+    input_tx: UnboundedSender<crossterm::Result<Event>>,
+    input: UnboundedReceiverStream<crossterm::Result<Event>>,
+}
+
+impl TestApplication {
+    /// Opens `text` as a document served by `helix-mock-lsp` following `script`, see
+    /// `tests/mock_lsp/server.rs` for its format.
+    pub fn new(name: &str, text: &str, mut script: Value) -> anyhow::Result<Self> {
+        let dir = test_dir(name);
+        std::fs::create_dir_all(&dir)?;
+        // Don't pick up the repository as the workspace root.
+        std::env::set_current_dir(dir.parent().unwrap())?;
+
+        let file = dir.join("test.mock");
+        std::fs::write(&file, text)?;
+
+        script["log"] = json!(dir.join("messages.jsonl"));
+        let script_path = dir.join("script.json");
+        std::fs::write(&script_path, script.to_string())?;
+
+        let mut config = Config::default();
+        config.editor.idle_timeout = Duration::from_millis(20);
+
+        let args = Args {
+            files: vec![file],
+            ..Args::default()
+        };
+
+        let app = Application::new(args, config, language_config(&script_path))?;
+        let (input_tx, input_rx) = unbounded_channel();
+
+        Ok(Self {
+            app,
+            dir,
+            input_tx,
+            input: UnboundedReceiverStream::new(input_rx),
+        })
+    }
+
+    pub fn send_keys(&self, keys: &str) -> anyhow::Result<()> {
+        for key in parse_keys(keys)? {
+            self.input_tx
+                .send(Ok(Event::Key(key.into())))
+                .map_err(|_| anyhow!("input stream closed"))?;
+        }
+        Ok(())
+    }
+
+    /// Runs the event loop until `done` holds, checking it every time the editor goes idle.
+    pub async fn run_until(
+        &mut self,
+        mut done: impl FnMut(&Application) -> bool,
+    ) -> anyhow::Result<()> {
+        let app = &mut self.app;
+        let input = &mut self.input;
+        let run = async {
+            while !done(app) {
+                app.editor.reset_idle_timer();
+                if !app.event_loop_until_idle(input).await {
+                    bail!("the editor closed");
+                }
+            }
+            Ok(())
+        };
+        tokio::time::timeout(TIMEOUT, run).await?
+    }
+
+    /// Waits until the language server of the document finished initializing.
+    pub async fn wait_for_language_server(&mut self) -> anyhow::Result<()> {
+        self.run_until(|app| doc!(app.editor).language_server().is_some())
+            .await
+    }
+
+    pub fn text(&self) -> String {
+        doc!(self.app.editor).text().to_string()
+    }
+
+    pub fn status(&self) -> Option<&str> {
+        self.app
+            .editor
+            .status_msg
+            .as_ref()
+            .map(|(msg, _)| msg.as_str())
+    }
+
+    /// The messages the language server received so far.
+    pub fn received(&self) -> Vec<Value> {
+        read_messages(&self.dir.join("messages.jsonl"))
+    }
+
+    /// Waits until the language server received a message for `method` and returns it.
+    pub async fn wait_for_message(&mut self, method: &str) -> anyhow::Result<Value> {
+        let log = self.dir.join("messages.jsonl");
+        let message = |log: &Path| {
+            read_messages(log)
+                .into_iter()
+                .find(|message| message["method"] == method)
+        };
+        self.run_until(|_| message(&log).is_some()).await?;
+        Ok(message(&log).unwrap())
+    }
+
+    /// Waits for the editor to answer the server's request `id` and returns the result.
+    pub async fn response(&mut self, id: u64) -> anyhow::Result<Value> {
+        let log = self.dir.join("messages.jsonl");
+        let response = |log: &Path| {
+            read_messages(log)
+                .into_iter()
+                .find(|message| message.get("method").is_none() && message["id"] == id)
+                .map(|message| message["result"].clone())
+        };
+        self.run_until(|_| response(&log).is_some()).await?;
+        Ok(response(&log).unwrap())
+    }
+}
+
+impl Drop for TestApplication {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The rendered screen, one line per row.
+pub fn screen(app: &Application) -> String {
+    let buffer = app.compositor.buffer();
+    let mut screen = String::new();
+    for y in 0..buffer.area.height {
+        for x in 0..buffer.area.width {
+            screen.push_str(&buffer.get(x, y).symbol);
+        }
+        screen.push('\n');
+    }
+    screen
+}
+
+fn read_messages(log: &Path) -> Vec<Value> {
+    let log = std::fs::read_to_string(log).unwrap_or_default();
+    log.lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+fn language_config(script: &Path) -> syntax::Configuration {
+    let config = json!({
+        "language": [{
+            "name": "mock",
+            "scope": "source.mock",
+            "file-types": ["mock"],
+            "roots": [],
+            "indent": { "tab-width": 4, "unit": "    " },
+            "language-server": {
+                "command": env!("CARGO_BIN_EXE_helix-mock-lsp"),
+                "args": [script],
+            },
+        }],
+    });
+    toml::Value::try_from(config)
+        .expect("invalid language config")
+        .try_into()
+        .expect("invalid language config")
+}
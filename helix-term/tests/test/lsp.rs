@@ -0,0 +1,502 @@
+use helix_view::{doc, graphics::Modifier};
+use serde_json::json;
+
+use helix_lsp::Url;
+
+use helix_term::application::Application;
+
+use super::helpers::{screen, test_dir, TestApplication};
+
+fn range(start: (u32, u32), end: (u32, u32)) -> serde_json::Value {
+    json!({
+        "start": { "line": start.0, "character": start.1 },
+        "end": { "line": end.0, "character": end.1 },
+    })
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publishes_diagnostics() -> anyhow::Result<()> {
+    let script = json!({
+        "notifications": {
+            "textDocument/didOpen": [{
+                "method": "textDocument/publishDiagnostics",
+                "params": {
+                    "uri": "$uri",
+                    "diagnostics": [{
+                        "range": range((0, 4), (0, 7)),
+                        "severity": 2,
+                        "message": "unused variable `foo`",
+                    }, {
+                        "range": range((0, 10), (0, 11)),
+                        "severity": 5,
+                        "message": "unknown severity",
+                    }],
+                },
+            }],
+        },
+    });
+    let mut test = TestApplication::new("diagnostics", "let foo = 1;\n", script)?;
+
+    test.run_until(|app| !doc!(app.editor).diagnostics().is_empty())
+        .await?;
+
+    let diagnostics = doc!(test.app.editor).diagnostics();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].message, "unused variable `foo`");
+    assert_eq!(diagnostics[0].range.start, 4);
+    assert_eq!(diagnostics[1].severity, None);
+
+    // the workspace counts agree with the document's, so they aren't shown
+    assert_eq!(test.app.editor.workspace_diagnostic_counts(), (1, 1));
+    assert!(!screen(&test.app).contains(" W "));
+
+    let did_open = test
+        .received()
+        .into_iter()
+        .find(|message| message["method"] == "textDocument/didOpen")
+        .expect("the document wasn't opened");
+    assert_eq!(did_open["params"]["textDocument"]["languageId"], "mock");
+    assert_eq!(did_open["params"]["textDocument"]["text"], "let foo = 1;\n");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shows_completion_menu() -> anyhow::Result<()> {
+    let script = json!({
+        "capabilities": { "completionProvider": {} },
+        "responses": {
+            "textDocument/completion": [
+                { "label": "foobar" },
+                { "label": "foobaz" },
+            ],
+        },
+    });
+    let mut test = TestApplication::new("completion", "foo\n", script)?;
+    test.wait_for_language_server().await?;
+
+    test.send_keys("A<C-x>")?;
+    test.run_until(|app| screen(app).contains("foobar")).await?;
+
+    assert!(screen(&test.app).contains("foobaz"));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn renames_symbol() -> anyhow::Result<()> {
+    let script = json!({
+        "capabilities": { "renameProvider": true },
+        "responses": {
+            "textDocument/rename": {
+                "changes": {
+                    "$uri": [
+                        { "range": range((0, 4), (0, 7)), "newText": "bar" },
+                        { "range": range((1, 0), (1, 3)), "newText": "bar" },
+                    ],
+                },
+            },
+        },
+    });
+    let mut test = TestApplication::new("rename", "let foo = 1;\nfoo\n", script)?;
+    test.wait_for_language_server().await?;
+
+    test.send_keys("4l<space>rbar<ret>")?;
+    test.run_until(|app| doc!(app.editor).text() == "let bar = 1;\nbar\n")
+        .await?;
+
+    let rename = test
+        .received()
+        .into_iter()
+        .find(|message| message["method"] == "textDocument/rename")
+        .expect("no rename request");
+    assert_eq!(rename["params"]["newName"], "bar");
+    assert_eq!(
+        rename["params"]["position"],
+        json!({ "line": 0, "character": 4 })
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn prepares_rename() -> anyhow::Result<()> {
+    let other = test_dir("prepare-rename").join("other.mock");
+    let script = json!({
+        "capabilities": { "renameProvider": { "prepareProvider": true } },
+        "responses": {
+            "textDocument/prepareRename": { "range": range((0, 4), (0, 7)), "placeholder": "foo" },
+            "textDocument/rename": {
+                "changes": {
+                    "$uri": [
+                        { "range": range((0, 4), (0, 7)), "newText": "bar" },
+                        { "range": range((1, 0), (1, 3)), "newText": "bar" },
+                    ],
+                    Url::from_file_path(&other).unwrap().to_string(): [
+                        { "range": range((0, 4), (0, 7)), "newText": "bar" },
+                    ],
+                },
+            },
+        },
+    });
+    let mut test = TestApplication::new("prepare-rename", "let foo = 1;\nfoo\n", script)?;
+    std::fs::write(&other, "use foo;\n")?;
+    test.wait_for_language_server().await?;
+
+    test.send_keys("4l<space>r")?;
+    test.run_until(|app| screen(app).contains("rename-to:foo"))
+        .await?;
+
+    test.send_keys("<C-w>bar<ret>")?;
+    test.run_until(|app| doc!(app.editor).text() == "let bar = 1;\nbar\n")
+        .await?;
+
+    // the other file isn't opened but edited on disk
+    assert_eq!(std::fs::read_to_string(&other)?, "use bar;\n");
+    assert_eq!(test.app.editor.documents().count(), 1);
+    assert_eq!(
+        test.status(),
+        Some("Renamed 3 occurrences in 2 files (1 written to disk)")
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rejects_invalid_rename_position() -> anyhow::Result<()> {
+    let script = json!({
+        "capabilities": { "renameProvider": { "prepareProvider": true } },
+    });
+    let mut test = TestApplication::new("invalid-rename", "let foo = 1;\n", script)?;
+    test.wait_for_language_server().await?;
+    test.app.editor.clear_status();
+
+    test.send_keys("<space>r")?;
+    test.run_until(|app| app.editor.status_msg.is_some())
+        .await?;
+
+    assert_eq!(test.status(), Some("No symbol to rename at the cursor"));
+    assert!(!screen(&test.app).contains("rename-to:"));
+    assert!(!test
+        .received()
+        .iter()
+        .any(|message| message["method"] == "textDocument/rename"));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn answers_message_requests() -> anyhow::Result<()> {
+    let script = json!({
+        "notifications": {
+            "initialized": [{
+                "id": 1,
+                "method": "window/showMessageRequest",
+                "params": {
+                    "type": 3,
+                    "message": "Reload the workspace?",
+                    "actions": [{ "title": "Yes" }, { "title": "No", "reason": "later" }],
+                },
+            }],
+        },
+    });
+    let mut test = TestApplication::new("message-request", "foo\n", script)?;
+
+    test.run_until(|app| screen(app).contains("Yes")).await?;
+    assert_eq!(test.status(), Some("Reload the workspace?"));
+
+    test.send_keys("<down><ret>")?;
+    let response = test.response(1).await?;
+    assert_eq!(response, json!({ "title": "No", "reason": "later" }));
+    assert!(!screen(&test.app).contains("Yes"));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dismissing_message_request_answers_null() -> anyhow::Result<()> {
+    let script = json!({
+        "notifications": {
+            "initialized": [{
+                "id": 1,
+                "method": "window/showMessageRequest",
+                "params": { "type": 1, "message": "Build failed", "actions": [{ "title": "Retry" }] },
+            }],
+        },
+    });
+    let mut test = TestApplication::new("dismiss-message-request", "foo\n", script)?;
+
+    test.run_until(|app| screen(app).contains("Retry")).await?;
+    test.send_keys("<esc>")?;
+    assert_eq!(test.response(1).await?, json!(null));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shows_documents() -> anyhow::Result<()> {
+    let other = test_dir("show-document").join("other.mock");
+    let script = json!({
+        "notifications": {
+            "initialized": [
+                {
+                    "id": 1,
+                    "method": "window/showDocument",
+                    "params": {
+                        "uri": Url::from_file_path(&other).unwrap().to_string(),
+                        "takeFocus": true,
+                        "selection": range((1, 4), (1, 7)),
+                    },
+                },
+                {
+                    "id": 2,
+                    "method": "window/showDocument",
+                    "params": { "uri": "https://helix-editor.com/", "external": true },
+                },
+            ],
+        },
+    });
+    let mut test = TestApplication::new("show-document", "foo\n", script)?;
+    std::fs::write(&other, "fn main() {}\nlet bar = 1;\n")?;
+
+    assert_eq!(test.response(1).await?, json!({ "success": true }));
+    assert_eq!(test.response(2).await?, json!({ "success": false }));
+
+    let (view, doc) = helix_view::current_ref!(test.app.editor);
+    assert_eq!(doc.path(), Some(&other.canonicalize()?));
+    let range = doc.selection(view.id).primary();
+    assert_eq!((range.from(), range.to()), (17, 20));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn formats_document() -> anyhow::Result<()> {
+    let script = json!({
+        "capabilities": { "documentFormattingProvider": true },
+        "responses": {
+            "textDocument/formatting": [
+                { "range": range((0, 0), (0, 11)), "newText": "let foo = 1;" },
+            ],
+        },
+    });
+    let mut test = TestApplication::new("formatting", "let  foo=1;\n", script)?;
+    test.wait_for_language_server().await?;
+
+    test.send_keys(":format<ret>")?;
+    test.run_until(|app| doc!(app.editor).text() == "let foo = 1;\n")
+        .await?;
+
+    assert_eq!(test.text(), "let foo = 1;\n");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shows_and_runs_code_lenses() -> anyhow::Result<()> {
+    let script = json!({
+        "capabilities": {
+            "codeLensProvider": { "resolveProvider": true },
+            "executeCommandProvider": { "commands": ["test.run"] },
+        },
+        "responses": {
+            "textDocument/codeLens": [
+                {
+                    "range": range((1, 0), (1, 11)),
+                    "command": { "title": "Run test", "command": "test.run", "arguments": ["it_works"] },
+                },
+                { "range": range((1, 0), (1, 11)), "data": 1 },
+            ],
+            "codeLens/resolve": {
+                "range": range((1, 0), (1, 11)),
+                "command": { "title": "3 references", "command": "" },
+            },
+        },
+    });
+    let text = "#[test]\nfn it_works() {}\n";
+    let mut test = TestApplication::new("code-lens", text, script)?;
+
+    test.run_until(|app| screen(app).contains("Run test | 3 references"))
+        .await?;
+    let screen = screen(&test.app);
+    let lines: Vec<_> = screen.lines().collect();
+    let lens = lines
+        .iter()
+        .position(|line| line.contains("Run test"))
+        .unwrap();
+    assert!(lines[lens - 1].contains("#[test]"));
+    assert!(lines[lens + 1].contains("fn it_works() {}"));
+
+    test.send_keys("j<space>lRun<ret>")?;
+    let command = test.wait_for_message("workspace/executeCommand").await?;
+    assert_eq!(
+        command["params"],
+        json!({ "command": "test.run", "arguments": ["it_works"] })
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn follows_document_links() -> anyhow::Result<()> {
+    let lib = test_dir("document-link").join("lib.mock");
+    let script = json!({
+        "capabilities": { "documentLinkProvider": { "resolveProvider": true } },
+        "responses": {
+            "textDocument/documentLink": [{ "range": range((0, 5), (0, 10)) }],
+            "documentLink/resolve": {
+                "range": range((0, 5), (0, 10)),
+                "target": Url::from_file_path(&lib).unwrap().to_string(),
+            },
+        },
+    });
+    let mut test = TestApplication::new("document-link", "use \"./lib\";\n", script)?;
+    std::fs::write(&lib, "fn lib() {}\n")?;
+
+    test.run_until(|app| !doc!(app.editor).document_links().is_empty())
+        .await?;
+
+    // the link is underlined
+    let buffer = test.app.compositor.buffer();
+    let underlined: String = (0..buffer.area.width)
+        .map(|x| buffer.get(x, 0))
+        .filter(|cell| cell.modifier.contains(Modifier::UNDERLINED))
+        .map(|cell| cell.symbol.as_str())
+        .collect();
+    assert_eq!(underlined, "./lib");
+
+    let doc_id = doc!(test.app.editor).id();
+    let lib = lib.canonicalize()?;
+    test.send_keys("5lgf")?;
+    test.run_until(|app| doc!(app.editor).path() == Some(&lib))
+        .await?;
+
+    // the request is forgotten once the document is closed
+    assert!(test.app.editor.document_link_requests.contains_key(&doc_id));
+    test.app.editor.close_document(doc_id, false)?;
+    assert!(!test.app.editor.document_link_requests.contains_key(&doc_id));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn expands_selection_ranges() -> anyhow::Result<()> {
+    let script = json!({
+        "capabilities": { "selectionRangeProvider": true },
+        "responses": {
+            "textDocument/selectionRange": [{
+                "range": range((0, 4), (0, 7)),
+                "parent": { "range": range((0, 0), (0, 12)) },
+            }],
+        },
+    });
+    let mut test = TestApplication::new("selection-range", "let foo = 1;\n", script)?;
+    test.wait_for_language_server().await?;
+
+    let selection = |app: &Application| {
+        let (view, doc) = helix_view::current_ref!(app.editor);
+        let range = doc.selection(view.id).primary();
+        (range.from(), range.to())
+    };
+
+    test.send_keys("4l<A-o>")?;
+    test.run_until(|app| selection(app) == (4, 7)).await?;
+    test.send_keys("<A-o>")?;
+    test.run_until(|app| selection(app) == (0, 12)).await?;
+    test.send_keys("<A-i><A-i>")?;
+    test.run_until(|app| selection(app) == (4, 5)).await?;
+
+    // the chain was only requested once
+    let requests = test
+        .received()
+        .into_iter()
+        .filter(|message| message["method"] == "textDocument/selectionRange")
+        .count();
+    assert_eq!(requests, 1);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mirrors_linked_edits() -> anyhow::Result<()> {
+    let script = json!({
+        "capabilities": { "linkedEditingRangeProvider": true },
+        "responses": {
+            "textDocument/linkedEditingRange": {
+                "ranges": [range((0, 1), (0, 4)), range((0, 8), (0, 11))],
+                "wordPattern": "[a-z]+",
+            },
+        },
+    });
+    let mut test = TestApplication::new("linked-editing", "<div>a</div>\n", script)?;
+
+    test.run_until(|app| doc!(app.editor).linked_ranges().is_some())
+        .await?;
+    test.send_keys("4lis<esc>")?;
+    test.run_until(|app| doc!(app.editor).text() == "<divs>a</divs>\n")
+        .await?;
+
+    // a single undo step reverts both tags
+    test.send_keys("u")?;
+    test.run_until(|app| doc!(app.editor).text() == "<div>a</div>\n")
+        .await?;
+
+    // edits that don't match the word pattern aren't mirrored
+    test.send_keys("gh4li1<esc>")?;
+    test.run_until(|app| doc!(app.editor).text() == "<div1>a</div>\n")
+        .await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sets_language_of_scratch_buffer() -> anyhow::Result<()> {
+    let mut test = TestApplication::new("scratch-language", "foo\n", json!({}))?;
+    test.wait_for_language_server().await?;
+
+    // a buffer without a path can't be opened in the language server
+    test.send_keys(":new<ret>:lang<space>mock<ret>")?;
+    test.run_until(|app| {
+        let doc = doc!(app.editor);
+        doc.path().is_none() && doc.language() == Some("source.mock")
+    })
+    .await?;
+
+    let doc = doc!(test.app.editor);
+    assert!(doc.language_server().is_none());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restarts_server_that_fails_to_initialize() -> anyhow::Result<()> {
+    use helix_view::editor::LanguageServerStatus;
+
+    let script = json!({
+        "errors": { "initialize": { "code": -32603, "message": "broken" } },
+    });
+    let mut test = TestApplication::new("failed-initialize", "foo\n", script)?;
+
+    // the server is dropped from the registry and restarted like one that exited
+    test.run_until(|app| {
+        app.editor.language_server_status.get("source.mock")
+            == Some(&LanguageServerStatus::Restarting(1))
+    })
+    .await?;
+    assert!(test
+        .app
+        .editor
+        .language_servers
+        .iter_clients()
+        .next()
+        .is_none());
+
+    // the restarted server failed as well
+    test.run_until(|app| {
+        app.editor.language_server_status.get("source.mock")
+            == Some(&LanguageServerStatus::Restarting(2))
+    })
+    .await?;
+
+    Ok(())
+}
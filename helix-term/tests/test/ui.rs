@@ -0,0 +1,27 @@
+use helix_view::view;
+use serde_json::json;
+
+use super::helpers::{screen, TestApplication};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn views_leave_room_for_syntax_tree() -> anyhow::Result<()> {
+    let mut test = TestApplication::new("syntax-tree", "foo\n", json!({}))?;
+    let width = test.app.compositor.buffer().area.width;
+    let view_width = |app: &helix_term::application::Application| view!(app.editor).area.width;
+
+    test.send_keys(":tree<minus>sitter<minus>subtree<ret>")?;
+    test.run_until(|app| screen(app).contains(" syntax tree "))
+        .await?;
+    assert_eq!(
+        view_width(&test.app),
+        width - helix_term::ui::SyntaxTree::width(test.app.compositor.buffer().area)
+    );
+
+    // the views get the whole width back once the panel is closed
+    test.send_keys(":tree<minus>sitter<minus>subtree<ret>")?;
+    test.run_until(|app| !screen(app).contains(" syntax tree "))
+        .await?;
+    assert_eq!(view_width(&test.app), width);
+
+    Ok(())
+}
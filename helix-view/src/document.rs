@@ -1,4 +1,5 @@
 use anyhow::{anyhow, Context, Error};
+use futures_util::future::{BoxFuture, FutureExt};
 use serde::de::{self, Deserialize, Deserializer};
 use std::cell::Cell;
 use std::collections::HashMap;
@@ -7,14 +8,16 @@ use std::future::Future;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use tokio::sync::Notify;
 
 use helix_core::{
     history::History,
     indent::{auto_detect_indent_style, IndentStyle},
     line_ending::auto_detect_line_ending,
-    syntax::{self, LanguageConfiguration},
-    ChangeSet, Diagnostic, LineEnding, Rope, RopeBuilder, Selection, State, Syntax, Transaction,
-    DEFAULT_LINE_ENDING,
+    linked_editing::{self, LinkedRanges},
+    syntax::{self, FormatterConfiguration, LanguageConfiguration},
+    ChangeSet, Diagnostic, LineEnding, Range, Rope, RopeBuilder, Selection, State, Syntax,
+    Transaction, DEFAULT_LINE_ENDING,
 };
 use helix_lsp::util::LspFormatting;
 
@@ -27,6 +30,23 @@ const DEFAULT_INDENT: IndentStyle = IndentStyle::Spaces(4);
 
 pub const SCRATCH_BUFFER_NAME: &str = "[scratch]";
 
+/// A code lens, rendered on a virtual line above the line it belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeLens {
+    /// Char position of the start of the lens range, mapped through edits.
+    pub pos: usize,
+    pub command: helix_lsp::lsp::Command,
+}
+
+/// A link reported by the language server, e.g. an import or a URL in a comment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentLink {
+    /// Char range of the link text, mapped through edits.
+    pub start: usize,
+    pub end: usize,
+    pub target: helix_lsp::Url,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Mode {
     Normal,
@@ -87,8 +107,15 @@ pub struct Document {
     pub line_ending: LineEnding,
 
     syntax: Option<Syntax>,
+    /// Woken when a background parse of the document is over, see [`Document::poll_syntax`].
+    syntax_parsed: Option<Arc<Notify>>,
     // /// Corresponding language scope name. Usually `source.<lang>`.
     pub(crate) language: Option<Arc<LanguageConfiguration>>,
+    /// Set by `:set-language`, the language isn't detected again until it's cleared.
+    pub(crate) language_overridden: bool,
+    /// Whether the file was too large to be opened with syntax highlighting and a language
+    /// server, see [`crate::editor::LargeFileConfig`].
+    pub(crate) large_file: bool,
 
     /// Pending changes since last history commit.
     changes: ChangeSet,
@@ -106,6 +133,15 @@ pub struct Document {
     version: i32, // should be usize?
 
     diagnostics: Vec<Diagnostic>,
+    /// Sorted by position.
+    code_lenses: Vec<CodeLens>,
+    /// Sorted by position.
+    document_links: Vec<DocumentLink>,
+    /// For each view, the ranges every range of the selection can be expanded through. Cleared
+    /// when the text changes.
+    selection_ranges: HashMap<ViewId, Vec<Vec<Range>>>,
+    /// Ranges linked to the one at the cursor, reported by the language server.
+    linked_ranges: Option<LinkedRanges>,
     language_server: Option<Arc<helix_lsp::Client>>,
 }
 
@@ -122,12 +158,15 @@ impl fmt::Debug for Document {
             .field("restore_cursor", &self.restore_cursor)
             .field("syntax", &self.syntax)
             .field("language", &self.language)
+            .field("language_overridden", &self.language_overridden)
             .field("changes", &self.changes)
             .field("old_state", &self.old_state)
             // .field("history", &self.history)
             .field("last_saved_revision", &self.last_saved_revision)
             .field("version", &self.version)
             .field("diagnostics", &self.diagnostics)
+            .field("code_lenses", &self.code_lenses)
+            .field("document_links", &self.document_links)
             // .field("language_server", &self.language_server)
             .finish()
     }
@@ -308,6 +347,122 @@ pub async fn to_writer<'a, W: tokio::io::AsyncWriteExt + Unpin + ?Sized>(
     Ok(())
 }
 
+/// Formatting changes for a document, either as edits from a language server or as the full
+/// output of an external formatter. These are cheap to share across threads and are only turned
+/// into a [`Transaction`] once they're applied.
+#[derive(Debug, Clone)]
+pub enum Formatting {
+    Lsp(LspFormatting),
+    Replace { doc: Rope, formatted: Rope },
+}
+
+impl From<Formatting> for Transaction {
+    fn from(fmt: Formatting) -> Transaction {
+        match fmt {
+            Formatting::Lsp(fmt) => fmt.into(),
+            Formatting::Replace { doc, formatted } => {
+                helix_core::diff::compare_ropes(&doc, &formatted)
+            }
+        }
+    }
+}
+
+/// Errors that can occur while formatting a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatterError {
+    SpawningFailed {
+        command: String,
+        error: std::io::ErrorKind,
+    },
+    TimedOut(String),
+    /// The formatter exited unsuccessfully, with its stderr output if any.
+    NonZeroExitStatus(Option<String>),
+    InvalidUtf8Output,
+    WaitForOutputFailed,
+}
+
+impl Display for FormatterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SpawningFailed { command, error } => {
+                write!(f, "Failed to spawn formatter {}: {:?}", command, error)
+            }
+            Self::TimedOut(command) => write!(f, "Formatter {} timed out", command),
+            Self::NonZeroExitStatus(Some(stderr)) => {
+                write!(f, "Formatter error: {}", stderr.trim_end())
+            }
+            Self::NonZeroExitStatus(None) => {
+                write!(f, "Formatter exited with non zero exit status")
+            }
+            Self::InvalidUtf8Output => write!(f, "Invalid UTF-8 formatter output"),
+            Self::WaitForOutputFailed => write!(f, "Waiting for formatter output failed"),
+        }
+    }
+}
+
+impl std::error::Error for FormatterError {}
+
+/// Pipes `text` through the external `formatter` and returns the changes between its input
+/// and output. The process is killed if it doesn't finish within the configured timeout.
+async fn external_format(
+    formatter: FormatterConfiguration,
+    text: Rope,
+) -> Result<Formatting, FormatterError> {
+    use std::process::Stdio;
+    use std::time::Duration;
+
+    let mut process = tokio::process::Command::new(&formatter.command)
+        .args(&formatter.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| FormatterError::SpawningFailed {
+            command: formatter.command.clone(),
+            error: err.kind(),
+        })?;
+
+    // Feed stdin from a separate task so that a formatter that streams its output doesn't
+    // deadlock on a full stdout pipe.
+    if let Some(mut stdin) = process.stdin.take() {
+        let text = text.clone();
+        tokio::spawn(async move {
+            if let Err(err) = to_writer(&mut stdin, encoding_rs::UTF_8, &text).await {
+                log::warn!("failed to write to formatter stdin: {}", err);
+            }
+        });
+    }
+
+    let output = tokio::time::timeout(
+        Duration::from_secs(formatter.timeout),
+        process.wait_with_output(),
+    )
+    .await
+    .map_err(|_| FormatterError::TimedOut(formatter.command.clone()))?
+    .map_err(|_| FormatterError::WaitForOutputFailed)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(FormatterError::NonZeroExitStatus(
+            (!stderr.trim().is_empty()).then(|| stderr.into_owned()),
+        ));
+    } else if !output.stderr.is_empty() {
+        log::debug!(
+            "formatter printed to stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let formatted =
+        std::str::from_utf8(&output.stdout).map_err(|_| FormatterError::InvalidUtf8Output)?;
+
+    Ok(Formatting::Replace {
+        doc: text,
+        formatted: Rope::from(formatted),
+    })
+}
+
 fn take_with<T, F>(mut_ref: &mut T, f: F)
 where
     T: Default,
@@ -336,10 +491,17 @@ impl Document {
             mode: Mode::Normal,
             restore_cursor: false,
             syntax: None,
+            syntax_parsed: None,
             language: None,
+            language_overridden: false,
+            large_file: false,
             changes,
             old_state,
             diagnostics: Vec::new(),
+            code_lenses: Vec::new(),
+            document_links: Vec::new(),
+            selection_ranges: HashMap::default(),
+            linked_ranges: None,
             version: 0,
             history: Cell::new(History::default()),
             savepoint: None,
@@ -382,7 +544,7 @@ impl Document {
 
     /// The same as [`format`], but only returns formatting changes if auto-formatting
     /// is configured.
-    pub fn auto_format(&self) -> Option<impl Future<Output = LspFormatting> + 'static> {
+    pub fn auto_format(&self) -> Option<BoxFuture<'static, Result<Formatting, FormatterError>>> {
         if self.language_config().map(|c| c.auto_format) == Some(true) {
             self.format()
         } else {
@@ -391,8 +553,13 @@ impl Document {
     }
 
     /// If supported, returns the changes that should be applied to this document in order
-    /// to format it nicely.
-    pub fn format(&self) -> Option<impl Future<Output = LspFormatting> + 'static> {
+    /// to format it nicely. An external formatter configured for the language takes
+    /// precedence over the language server.
+    pub fn format(&self) -> Option<BoxFuture<'static, Result<Formatting, FormatterError>>> {
+        if let Some(formatter) = self.language_config().and_then(|c| c.formatter.clone()) {
+            return Some(external_format(formatter, self.text.clone()).boxed());
+        }
+
         if let Some(language_server) = self.language_server() {
             let text = self.text.clone();
             let offset_encoding = language_server.offset_encoding();
@@ -407,13 +574,13 @@ impl Document {
                     log::warn!("LSP formatting failed: {}", e);
                     Default::default()
                 });
-                LspFormatting {
+                Ok(Formatting::Lsp(LspFormatting {
                     doc: text,
                     edits,
                     offset_encoding,
-                }
+                }))
             };
-            Some(fut)
+            Some(fut.boxed())
         } else {
             None
         }
@@ -425,7 +592,7 @@ impl Document {
 
     pub fn format_and_save(
         &mut self,
-        formatting: Option<impl Future<Output = LspFormatting>>,
+        formatting: Option<impl Future<Output = Result<Formatting, FormatterError>>>,
     ) -> impl Future<Output = anyhow::Result<()>> {
         self.save_impl(formatting)
     }
@@ -436,7 +603,7 @@ impl Document {
     /// at its `path()`.
     ///
     /// If `formatting` is present, it supplies some changes that we apply to the text before saving.
-    fn save_impl<F: Future<Output = LspFormatting>>(
+    fn save_impl<F: Future<Output = Result<Formatting, FormatterError>>>(
         &mut self,
         formatting: Option<F>,
     ) -> impl Future<Output = Result<(), anyhow::Error>> {
@@ -467,11 +634,17 @@ impl Document {
             }
 
             if let Some(fmt) = formatting {
-                let success = Transaction::from(fmt.await).changes().apply(&mut text);
-                if !success {
-                    // This shouldn't happen, because the transaction changes were generated
-                    // from the same text we're saving.
-                    log::error!("failed to apply format changes before saving");
+                match fmt.await {
+                    Ok(fmt) => {
+                        let success = Transaction::from(fmt).changes().apply(&mut text);
+                        if !success {
+                            // This shouldn't happen, because the transaction changes were generated
+                            // from the same text we're saving.
+                            log::error!("failed to apply format changes before saving");
+                        }
+                    }
+                    // the error is reported by the format callback, save the text as is
+                    Err(err) => log::error!("formatting failed, saving unformatted: {}", err),
                 }
             }
 
@@ -493,13 +666,28 @@ impl Document {
         }
     }
 
-    /// Detect the programming language based on the file type.
+    /// Detect the programming language based on the file type and the contents, see
+    /// [`syntax::Loader::language_config_for_document`]. A language set with `:set-language` is
+    /// kept.
     pub fn detect_language(&mut self, theme: Option<&Theme>, config_loader: &syntax::Loader) {
-        if let Some(path) = &self.path {
-            let language_config = config_loader
-                .language_config_for_file_name(path)
-                .or_else(|| config_loader.language_config_for_shebang(self.text()));
+        if self.language_overridden {
+            return;
+        }
+        let language_config =
+            config_loader.language_config_for_document(self.path.as_deref(), self.text());
+        self.set_language(theme, language_config);
+    }
+
+    /// Sets the language again after the language configuration was replaced, detecting it
+    /// unless it was set with `:set-language` or the document has no path.
+    pub fn refresh_language(&mut self, theme: Option<&Theme>, config_loader: &syntax::Loader) {
+        if self.language_overridden || self.path.is_none() {
+            let language_config = self.language.as_ref().and_then(|language| {
+                config_loader.language_config_for_language_name(&language.language_id)
+            });
             self.set_language(theme, language_config);
+        } else {
+            self.detect_language(theme, config_loader);
         }
     }
 
@@ -529,18 +717,22 @@ impl Document {
 
         let mut file = std::fs::File::open(path.unwrap())?;
         let (rope, ..) = from_reader(&mut file, Some(encoding))?;
+        self.reload_from(view_id, &rope);
+
+        Ok(())
+    }
 
+    /// Replaces the text with `rope`, the contents of the file read again.
+    pub fn reload_from(&mut self, view_id: ViewId, rope: &Rope) {
         // Calculate the difference between the buffer and source text, and apply it.
         // This is not considered a modification of the contents of the file regardless
         // of the encoding.
-        let transaction = helix_core::diff::compare_ropes(self.text(), &rope);
+        let transaction = helix_core::diff::compare_ropes(self.text(), rope);
         self.apply(&transaction, view_id);
         self.append_changes_to_history(view_id);
         self.reset_modified();
 
         self.detect_indent_and_line_ending();
-
-        Ok(())
     }
 
     /// Sets the [`Document`]'s encoding with the encoding correspondent to `label`.
@@ -578,8 +770,14 @@ impl Document {
     ) {
         if let Some(language_config) = language_config {
             let scopes = theme.map(|theme| theme.scopes()).unwrap_or(&[]);
-            if let Some(highlight_config) = language_config.highlight_config(scopes) {
-                let syntax = Syntax::new(&self.text, highlight_config);
+            if self.large_file {
+                self.syntax = None;
+            } else if let Some(highlight_config) = language_config.highlight_config(scopes) {
+                let mut syntax = Syntax::new(&self.text, highlight_config);
+                if let Some(notify) = &self.syntax_parsed {
+                    let notify = notify.clone();
+                    syntax.set_parse_callback(move || notify.notify_one());
+                }
                 self.syntax = Some(syntax);
                 // TODO: config.configure(scopes) is now delayed, is that ok?
             }
@@ -669,6 +867,31 @@ impl Document {
                 diagnostic.line = self.text.char_to_line(diagnostic.range.start);
             }
 
+            for code_lens in &mut self.code_lenses {
+                code_lens.pos = transaction
+                    .changes()
+                    .map_pos(code_lens.pos, helix_core::Assoc::After);
+            }
+
+            self.selection_ranges.clear();
+
+            if let Some(linked) = &mut self.linked_ranges {
+                use helix_core::Assoc;
+                let changes = transaction.changes();
+                for (start, end) in &mut linked.ranges {
+                    // grow when typing at either end
+                    *start = changes.map_pos(*start, Assoc::Before);
+                    *end = changes.map_pos(*end, Assoc::After);
+                }
+            }
+
+            for link in &mut self.document_links {
+                use helix_core::Assoc;
+                let changes = transaction.changes();
+                link.start = changes.map_pos(link.start, Assoc::After);
+                link.end = changes.map_pos(link.end, Assoc::Before);
+            }
+
             // emit lsp notification
             if let Some(language_server) = self.language_server() {
                 let notify = language_server.text_document_did_change(
@@ -708,6 +931,35 @@ impl Document {
         success
     }
 
+    /// Apply a [`Transaction`] made while typing. If its changes lie in a range linked to others,
+    /// like the name of an opening tag, they're mirrored into the linked ranges within the same
+    /// transaction. The ranges come from the language server or the `linked-editing.scm` query.
+    pub fn apply_linked(&mut self, transaction: &Transaction, view_id: ViewId) -> bool {
+        let mirrored = self
+            .linked_ranges
+            .as_ref()
+            .and_then(|linked| {
+                linked_editing::mirror(
+                    transaction,
+                    &self.text,
+                    &linked.ranges,
+                    linked.word_pattern.as_ref(),
+                )
+            })
+            .or_else(|| {
+                let (pos, _, _) = transaction.changes_iter().next()?;
+                let query = self.language.as_ref()?.linked_editing_query()?;
+                let root = self.syntax.as_ref()?.tree().root_node();
+                let ranges = query.linked_ranges(root, self.text.slice(..), pos)?;
+                linked_editing::mirror(transaction, &self.text, &ranges, None)
+            });
+
+        match mirrored {
+            Some(transaction) => self.apply(&transaction, view_id),
+            None => self.apply(transaction, view_id),
+        }
+    }
+
     /// Undo the last modification to the [`Document`]. Returns whether the undo was successful.
     pub fn undo(&mut self, view_id: ViewId) -> bool {
         let mut history = self.history.take();
@@ -867,6 +1119,27 @@ impl Document {
         self.syntax.as_ref()
     }
 
+    /// Wakes `notify` whenever a background parse of the document is over.
+    pub fn set_syntax_parsed(&mut self, notify: Arc<Notify>) {
+        if let Some(syntax) = &mut self.syntax {
+            let notify = notify.clone();
+            syntax.set_parse_callback(move || notify.notify_one());
+        }
+        self.syntax_parsed = Some(notify);
+    }
+
+    /// Picks up the result of a background parse, returning whether the syntax tree changed.
+    pub fn poll_syntax(&mut self) -> bool {
+        match &mut self.syntax {
+            Some(syntax) => syntax.poll(&self.text),
+            None => false,
+        }
+    }
+
+    pub fn is_large_file(&self) -> bool {
+        self.large_file
+    }
+
     /// Tab size in columns.
     pub fn tab_width(&self) -> usize {
         self.language
@@ -942,6 +1215,85 @@ impl Document {
         self.diagnostics
             .sort_unstable_by_key(|diagnostic| diagnostic.range);
     }
+
+    #[inline]
+    pub fn code_lenses(&self) -> &[CodeLens] {
+        &self.code_lenses
+    }
+
+    pub fn set_code_lenses(&mut self, mut code_lenses: Vec<CodeLens>) {
+        code_lenses.sort_by_key(|code_lens| code_lens.pos);
+        self.code_lenses = code_lenses;
+    }
+
+    /// The code lenses shown above `line`.
+    pub fn code_lenses_at_line(&self, line: usize) -> &[CodeLens] {
+        if self.code_lenses.is_empty() || line >= self.text.len_lines() {
+            return &[];
+        }
+        let start = self.text.line_to_char(line);
+        let end = self.text.line_to_char(line + 1);
+        let from = self.code_lenses.partition_point(|lens| lens.pos < start);
+        let to = self.code_lenses.partition_point(|lens| lens.pos < end);
+        &self.code_lenses[from..to]
+    }
+
+    #[inline]
+    pub fn document_links(&self) -> &[DocumentLink] {
+        &self.document_links
+    }
+
+    pub fn set_document_links(&mut self, mut document_links: Vec<DocumentLink>) {
+        document_links.sort_by_key(|link| link.start);
+        self.document_links = document_links;
+    }
+
+    /// The document link that contains `pos`.
+    pub fn document_link_at(&self, pos: usize) -> Option<&DocumentLink> {
+        self.document_links
+            .iter()
+            .find(|link| link.start <= pos && pos < link.end)
+    }
+
+    /// The cached chains of ranges the selection of `view_id` expands through, one per range, if
+    /// the selection hasn't moved off them.
+    pub fn selection_ranges(&self, view_id: ViewId) -> Option<&[Vec<Range>]> {
+        let chains = self.selection_ranges.get(&view_id)?;
+        let selection = self.selections.get(&view_id)?;
+        let valid = chains.len() == selection.len()
+            && selection.iter().zip(chains).all(|(range, chain)| {
+                chain
+                    .iter()
+                    .any(|r| (r.from(), r.to()) == (range.from(), range.to()))
+            });
+        if valid {
+            Some(chains)
+        } else {
+            None
+        }
+    }
+
+    pub fn set_selection_ranges(&mut self, view_id: ViewId, chains: Vec<Vec<Range>>) {
+        self.selection_ranges.insert(view_id, chains);
+    }
+
+    #[inline]
+    pub fn linked_ranges(&self) -> Option<&LinkedRanges> {
+        self.linked_ranges.as_ref()
+    }
+
+    pub fn set_linked_ranges(&mut self, linked_ranges: Option<LinkedRanges>) {
+        self.linked_ranges = linked_ranges;
+    }
+
+    /// Number of virtual lines rendered above `line`, they don't belong to the text.
+    pub fn virtual_lines_above(&self, line: usize) -> usize {
+        if self.code_lenses_at_line(line).is_empty() {
+            0
+        } else {
+            1
+        }
+    }
 }
 
 impl Default for Document {
@@ -955,6 +1307,26 @@ impl Default for Document {
 mod test {
     use super::*;
 
+    #[test]
+    fn detects_language_without_path() {
+        let config: syntax::Configuration = toml::from_str(
+            r#"
+            [[language]]
+            name = "mock"
+            scope = "source.mock"
+            file-types = ["mock"]
+            roots = []
+            "#,
+        )
+        .unwrap();
+        let loader = syntax::Loader::new(config);
+
+        // a scratch buffer is detected from its contents
+        let mut doc = Document::from(Rope::from("# vim: set ft=mock :\n"), None);
+        doc.detect_language(None, &loader);
+        assert_eq!(doc.language(), Some("source.mock"));
+    }
+
     #[test]
     fn changeset_to_changes_ignore_line_endings() {
         use helix_lsp::{lsp, Client, OffsetEncoding};
@@ -1111,6 +1483,49 @@ mod test {
         );
     }
 
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn external_formatter() {
+        let formatter = FormatterConfiguration {
+            command: "tr".into(),
+            args: vec!["a-z".into(), "A-Z".into()],
+            timeout: 5,
+        };
+        let text = Rope::from("hello\nworld\n");
+        let fmt = external_format(formatter, text.clone()).await.unwrap();
+
+        let mut formatted = text;
+        assert!(Transaction::from(fmt).changes().apply(&mut formatted));
+        assert_eq!(formatted, "HELLO\nWORLD\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn external_formatter_failure() {
+        let formatter = FormatterConfiguration {
+            command: "sh".into(),
+            args: vec!["-c".into(), "echo 'syntax error' >&2; exit 1".into()],
+            timeout: 5,
+        };
+        let err = external_format(formatter, Rope::from("hello"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err,
+            FormatterError::NonZeroExitStatus(Some("syntax error\n".into()))
+        );
+
+        let formatter = FormatterConfiguration {
+            command: "sleep".into(),
+            args: vec!["10".into()],
+            timeout: 0,
+        };
+        let err = external_format(formatter, Rope::from("hello"))
+            .await
+            .unwrap_err();
+        assert_eq!(err, FormatterError::TimedOut("sleep".into()));
+    }
+
     macro_rules! test_decode {
         ($label:expr, $label_override:expr) => {
             let encoding = encoding_rs::Encoding::for_label($label_override.as_bytes()).unwrap();
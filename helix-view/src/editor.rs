@@ -1,7 +1,9 @@
 use crate::{
     clipboard::{get_clipboard_provider, ClipboardProvider},
     document::SCRATCH_BUFFER_NAME,
+    file_watcher::{FileChange, FileEvent, FileWatcher},
     graphics::{CursorKind, Rect},
+    tag_index::TagIndex,
     theme::{self, Theme},
     tree::{self, Tree},
     Document, DocumentId, View, ViewId,
@@ -9,7 +11,7 @@ use crate::{
 
 use futures_util::future;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap, HashSet},
     io::stdin,
     num::NonZeroUsize,
     path::{Path, PathBuf},
@@ -17,6 +19,10 @@ use std::{
     sync::Arc,
 };
 
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    Notify,
+};
 use tokio::time::{sleep, Duration, Instant, Sleep};
 
 use anyhow::{bail, Context, Error};
@@ -24,7 +30,8 @@ use anyhow::{bail, Context, Error};
 pub use helix_core::diagnostic::Severity;
 pub use helix_core::register::Registers;
 use helix_core::syntax;
-use helix_core::{Position, Selection};
+use helix_core::{Diagnostic, Position, Rope, Selection};
+use helix_lsp::{lsp, pull_diagnostics::DocumentDiagnosticReport};
 
 use serde::Deserialize;
 
@@ -76,6 +83,85 @@ impl Default for FilePickerConfig {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct StickyContextConfig {
+    /// Show the lines of the enclosing functions, impl blocks, loops, etc. at the top of views
+    /// once they're scrolled out of sight. Defaults to false.
+    pub enable: bool,
+    /// The most lines to show, the innermost ones are kept. Defaults to 5.
+    pub max_lines: u16,
+}
+
+impl StickyContextConfig {
+    /// The most lines of context views show, 0 when it's disabled.
+    pub fn lines(&self) -> usize {
+        if self.enable {
+            self.max_lines as usize
+        } else {
+            0
+        }
+    }
+}
+
+impl Default for StickyContextConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            max_lines: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct LargeFileConfig {
+    /// Open large files without syntax highlighting, language server and diagnostics gutter.
+    /// Defaults to true.
+    pub enable: bool,
+    /// Files larger than this many bytes are large. Defaults to 10 MiB.
+    pub max_size: usize,
+    /// Files with a line longer than this many bytes are large, e.g. minified ones. Defaults to
+    /// 20000.
+    pub max_line_length: usize,
+}
+
+impl Default for LargeFileConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            max_size: 10 * 1024 * 1024,
+            max_line_length: 20_000,
+        }
+    }
+}
+
+impl LargeFileConfig {
+    /// Whether `text` is too large to be opened with the usual features.
+    pub fn is_large(&self, text: &Rope) -> bool {
+        if !self.enable {
+            return false;
+        }
+        if text.len_bytes() > self.max_size {
+            return true;
+        }
+
+        // lines are measured across chunks, going through `lines()` is much slower
+        let mut line_length = 0;
+        for chunk in text.chunks() {
+            let mut lines = chunk.split('\n');
+            line_length += lines.next().map_or(0, str::len);
+            for line in lines {
+                if line_length > self.max_line_length {
+                    return true;
+                }
+                line_length = line.len();
+            }
+        }
+        line_length > self.max_line_length
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 #[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
 pub struct Config {
@@ -104,6 +190,26 @@ pub struct Config {
     /// Whether to display infoboxes. Defaults to true.
     pub auto_info: bool,
     pub file_picker: FilePickerConfig,
+    /// How to apply workspace edits (e.g. renames) to files that aren't open. Defaults to `write`.
+    pub unopened_file_edits: UnopenedFileEdits,
+    /// Reload the configuration, the theme and the queries when their files change. Defaults to
+    /// false.
+    pub auto_reload_config: bool,
+    pub sticky_context: StickyContextConfig,
+    /// Colour brackets by their nesting depth with the `ui.rainbow.N` theme scopes. Defaults to
+    /// false.
+    pub rainbow_brackets: bool,
+    pub large_file: LargeFileConfig,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnopenedFileEdits {
+    /// Apply the edits directly to the file on disk
+    Write,
+
+    /// Open the file and leave the edits unsaved
+    Open,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
@@ -136,6 +242,11 @@ impl Default for Config {
             completion_trigger_len: 2,
             auto_info: true,
             file_picker: FilePickerConfig::default(),
+            unopened_file_edits: UnopenedFileEdits::Write,
+            auto_reload_config: false,
+            sticky_context: StickyContextConfig::default(),
+            rainbow_brackets: false,
+            large_file: LargeFileConfig::default(),
         }
     }
 }
@@ -152,6 +263,15 @@ impl std::fmt::Debug for Motion {
     }
 }
 
+/// Requests for the application, which owns the configuration.
+#[derive(Debug)]
+pub enum ConfigEvent {
+    /// Reload `config.toml`, `languages.toml`, the theme and the queries from disk.
+    Refresh,
+    /// Reload `languages.toml` and the queries from disk.
+    RefreshLanguages,
+}
+
 #[derive(Debug)]
 pub struct Editor {
     pub tree: Tree,
@@ -161,15 +281,35 @@ pub struct Editor {
     pub selected_register: Option<char>,
     pub registers: Registers,
     pub theme: Theme,
+    /// The name of the theme, `None` for the default one.
+    pub theme_name: Option<String>,
     pub language_servers: helix_lsp::Registry,
+    /// Diagnostics published by the language servers for every file in the workspace, including
+    /// the ones that aren't open, along with the id of the server that published them.
+    pub diagnostics: BTreeMap<lsp::Url, Vec<(lsp::Diagnostic, usize)>>,
+    pub diagnostic_pulls: DiagnosticPulls,
+    /// The language server and document version code lenses were last requested for.
+    pub code_lens_requests: HashMap<DocumentId, (usize, i32)>,
+    /// The language server and document version document links were last requested for.
+    pub document_link_requests: HashMap<DocumentId, (usize, i32)>,
+    /// The document version and cursor position linked editing ranges were last requested for.
+    pub linked_editing_requests: HashMap<DocumentId, (i32, usize)>,
+    /// The status of language servers that were stopped or exited, by language scope.
+    pub language_server_status: HashMap<String, LanguageServerStatus>,
+    pub file_watcher: FileWatcher,
+    /// The definitions in the workspace, for symbol navigation without a language server.
+    pub tag_index: Arc<TagIndex>,
     pub clipboard_provider: Box<dyn ClipboardProvider>,
 
     pub syn_loader: Arc<syntax::Loader>,
+    /// Woken when a background parse of a document is over, see [`Editor::poll_syntax`].
+    pub syntax_parsed: Arc<Notify>,
     pub theme_loader: Arc<theme::Loader>,
 
     pub status_msg: Option<(String, Severity)>,
 
     pub config: Config,
+    pub config_events: (UnboundedSender<ConfigEvent>, UnboundedReceiver<ConfigEvent>),
 
     pub idle_timer: Pin<Box<Sleep>>,
     pub last_motion: Option<Motion>,
@@ -177,6 +317,41 @@ pub struct Editor {
     pub exit_code: i32,
 }
 
+/// Bookkeeping for the language servers that only offer pull diagnostics.
+#[derive(Debug, Default)]
+pub struct DiagnosticPulls {
+    /// The version of each document and whether it was modified when its diagnostics were last
+    /// requested. They're requested again once it changes or is saved.
+    pub documents: HashMap<DocumentId, (i32, bool)>,
+    /// The `resultId` of the last report by server and document, the server can answer that
+    /// nothing changed since.
+    pub result_ids: HashMap<(usize, lsp::Url), String>,
+    /// Servers to request workspace diagnostics from, after they started or a document was saved.
+    pub workspace_pending: HashSet<usize>,
+    /// Servers that are still answering a `workspace/diagnostic` request.
+    pub workspace_in_flight: HashSet<usize>,
+}
+
+/// The status of a language server that didn't stay up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LanguageServerStatus {
+    /// Stopped with `:lsp-stop`, it isn't started again until `:lsp-restart`.
+    Stopped,
+    /// The server exited unexpectedly and the given restart attempt is scheduled.
+    Restarting(u32),
+    /// The server is running again after the given number of restarts.
+    Restarted(u32),
+    /// The server exited too many times and won't be restarted automatically.
+    Crashed,
+}
+
+impl LanguageServerStatus {
+    /// Whether the server should be left alone instead of being started for new documents.
+    pub fn is_down(&self) -> bool {
+        !matches!(self, Self::Restarted(_))
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum Action {
     Load,
@@ -204,8 +379,18 @@ impl Editor {
             count: None,
             selected_register: None,
             theme: theme_loader.default(),
+            theme_name: None,
             language_servers,
+            diagnostics: BTreeMap::new(),
+            diagnostic_pulls: DiagnosticPulls::default(),
+            code_lens_requests: HashMap::new(),
+            document_link_requests: HashMap::new(),
+            linked_editing_requests: HashMap::new(),
+            language_server_status: HashMap::new(),
+            file_watcher: FileWatcher::new(),
+            tag_index: Arc::default(),
             syn_loader,
+            syntax_parsed: Arc::new(Notify::new()),
             theme_loader,
             registers: Registers::default(),
             clipboard_provider: get_clipboard_provider(),
@@ -213,6 +398,7 @@ impl Editor {
             idle_timer: Box::pin(sleep(config.idle_timeout)),
             last_motion: None,
             config,
+            config_events: unbounded_channel(),
             exit_code: 0,
         }
     }
@@ -262,12 +448,13 @@ impl Editor {
         self._refresh();
     }
 
-    pub fn set_theme_from_name(&mut self, theme: &str) -> anyhow::Result<()> {
+    pub fn set_theme_from_name(&mut self, name: &str) -> anyhow::Result<()> {
         let theme = self
             .theme_loader
-            .load(theme.as_ref())
-            .with_context(|| format!("failed setting theme `{}`", theme))?;
+            .load(name)
+            .with_context(|| format!("failed setting theme `{}`", name))?;
         self.set_theme(theme);
+        self.theme_name = Some(name.to_string());
         Ok(())
     }
 
@@ -275,13 +462,29 @@ impl Editor {
     pub fn refresh_language_server(&mut self, doc_id: DocumentId) -> Option<()> {
         let doc = self.documents.get_mut(&doc_id)?;
         doc.detect_language(Some(&self.theme), &self.syn_loader);
-        Self::launch_language_server(&mut self.language_servers, doc)
+        Self::launch_language_server(
+            &mut self.language_servers,
+            &self.language_server_status,
+            doc,
+        )
     }
 
     /// Launch a language server for a given document
-    fn launch_language_server(ls: &mut helix_lsp::Registry, doc: &mut Document) -> Option<()> {
+    fn launch_language_server(
+        ls: &mut helix_lsp::Registry,
+        status: &HashMap<String, LanguageServerStatus>,
+        doc: &mut Document,
+    ) -> Option<()> {
+        // language servers only know documents by their path
+        if doc.is_large_file() || doc.path().is_none() {
+            return None;
+        }
+
         // try to find a language server based on the language name
         let language_server = doc.language.as_ref().and_then(|language| {
+            if matches!(status.get(&language.scope), Some(status) if status.is_down()) {
+                return None;
+            }
             ls.get(language)
                 .map_err(|e| {
                     log::error!(
@@ -314,14 +517,337 @@ impl Editor {
 
                 doc.set_language_server(Some(language_server));
             }
+        } else if let Some(language_server) = doc.language_server() {
+            // the document changed to a language without a language server
+            tokio::spawn(language_server.text_document_did_close(doc.identifier()));
+            doc.set_language_server(None);
         }
         Some(())
     }
 
+    /// Sets the language of a document regardless of the detected one, or detects it again if
+    /// `language_config` is `None`, and moves the document to the language server of its new
+    /// language.
+    pub fn set_doc_language(
+        &mut self,
+        doc_id: DocumentId,
+        language_config: Option<Arc<syntax::LanguageConfiguration>>,
+    ) -> Option<()> {
+        let doc = self.documents.get_mut(&doc_id)?;
+        doc.language_overridden = language_config.is_some();
+        match language_config {
+            Some(language_config) => doc.set_language(Some(&self.theme), Some(language_config)),
+            None => doc.detect_language(Some(&self.theme), &self.syn_loader),
+        }
+        Self::launch_language_server(
+            &mut self.language_servers,
+            &self.language_server_status,
+            doc,
+        )
+    }
+
+    /// Removes the language server started for the language `scope`, detaching it from the
+    /// documents using it and dropping the diagnostics it published.
+    pub fn remove_language_server(&mut self, scope: &str) -> Option<Arc<helix_lsp::Client>> {
+        let language_server = self.language_servers.remove(scope)?;
+        let server_id = language_server.id();
+        self.file_watcher.remove_language_server(server_id);
+
+        let pulls = &mut self.diagnostic_pulls;
+        pulls.result_ids.retain(|(id, _), _| *id != server_id);
+        pulls.workspace_pending.remove(&server_id);
+        pulls.workspace_in_flight.remove(&server_id);
+        // a new server has to be asked for the diagnostics of all documents
+        pulls.documents.clear();
+
+        for diagnostics in self.diagnostics.values_mut() {
+            diagnostics.retain(|(_, id)| *id != server_id);
+        }
+        self.diagnostics
+            .retain(|_, diagnostics| !diagnostics.is_empty());
+
+        for doc in self.documents.values_mut() {
+            if doc.language() == Some(scope) {
+                doc.set_language_server(None);
+                let diagnostics =
+                    Self::doc_diagnostics(&self.language_servers, &self.diagnostics, doc);
+                doc.set_diagnostics(diagnostics);
+            }
+        }
+
+        Some(language_server)
+    }
+
+    /// Restarts the language server for the language `scope` and re-sends `didOpen` for all
+    /// the documents of that language.
+    pub fn restart_language_server(&mut self, scope: &str) {
+        if let Some(language_server) = self.remove_language_server(scope) {
+            tokio::spawn(async move { language_server.force_shutdown().await });
+        }
+        self.language_server_status.remove(scope);
+
+        // the documents keep their languages and syntax trees, only the server is new
+        for doc in self.documents.values_mut() {
+            if doc.language() == Some(scope) {
+                Self::launch_language_server(
+                    &mut self.language_servers,
+                    &self.language_server_status,
+                    doc,
+                );
+            }
+        }
+    }
+
+    /// Shuts down the language server for the language `scope`. It isn't started again until
+    /// [`Editor::restart_language_server`] is called.
+    pub fn stop_language_server(&mut self, scope: &str) {
+        if let Some(language_server) = self.remove_language_server(scope) {
+            tokio::spawn(async move { language_server.force_shutdown().await });
+        }
+        self.language_server_status
+            .insert(scope.to_owned(), LanguageServerStatus::Stopped);
+    }
+
+    /// Picks up the results of background parses, returning whether any syntax tree changed.
+    pub fn poll_syntax(&mut self) -> bool {
+        let mut changed = false;
+        for doc in self.documents.values_mut() {
+            changed |= doc.poll_syntax();
+        }
+        changed
+    }
+
+    /// Forwards changes on disk to the language servers watching them, returning the paths and
+    /// encodings of the open documents that changed. They're read in the background and passed
+    /// to [`Editor::document_changed_on_disk`].
+    pub fn handle_file_events(
+        &mut self,
+        events: Vec<FileEvent>,
+    ) -> Vec<(PathBuf, &'static encoding_rs::Encoding)> {
+        if self.tag_index.is_built() {
+            let tag_index = self.tag_index.clone();
+            let loader = self.syn_loader.clone();
+            let events = events.clone();
+            tokio::task::spawn_blocking(move || tag_index.handle_file_events(&loader, &events));
+        }
+
+        for (server_id, changes) in self.file_watcher.lsp_file_events(&events) {
+            if let Some(language_server) = self.language_servers.get_by_id(server_id) {
+                tokio::spawn(language_server.did_change_watched_files(changes));
+            }
+        }
+
+        events
+            .into_iter()
+            .filter(|event| event.change != FileChange::Deleted)
+            .filter_map(|event| {
+                let doc = self
+                    .documents
+                    .values()
+                    .find(|doc| doc.path() == Some(&event.path))?;
+                Some((event.path, doc.encoding()))
+            })
+            .collect()
+    }
+
+    /// Reloads the document at `path` with `text`, the contents it was changed to on disk,
+    /// unless it has unsaved changes.
+    pub fn document_changed_on_disk(&mut self, path: &Path, text: Result<Rope, Error>) {
+        let doc = match self
+            .documents
+            .values_mut()
+            .find(|doc| doc.path().map(|p| p.as_path()) == Some(path))
+        {
+            Some(doc) => doc,
+            None => return,
+        };
+
+        // saving the document shows up as a change as well
+        let text = match text {
+            Ok(text) if text == *doc.text() => return,
+            Ok(text) => text,
+            Err(err) => {
+                log::warn!("failed to read {:?} after it changed: {}", path, err);
+                return;
+            }
+        };
+
+        let name = doc
+            .relative_path()
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let view_id = doc.selections().keys().next().copied();
+        match view_id {
+            Some(view_id) if !doc.is_modified() => {
+                doc.reload_from(view_id, &text);
+                self.set_status(format!("{} changed on disk, reloaded", name));
+            }
+            _ => self.set_error(format!(
+                "{} changed on disk, use :reload to discard your changes",
+                name
+            )),
+        }
+    }
+
+    /// The `settings` of the language the language server with `server_id` was started for.
+    pub fn language_server_settings(&self, server_id: usize) -> Option<serde_json::Value> {
+        let scope = self.language_servers.scope_by_id(server_id)?;
+        self.syn_loader
+            .language_config_for_scope(scope)?
+            .settings
+            .clone()
+    }
+
+    /// Replaces the language configuration, sending `workspace/didChangeConfiguration` to the
+    /// running language servers whose settings changed. The open documents keep their languages
+    /// until [`Editor::refresh_languages`].
+    pub fn set_syn_loader(&mut self, syn_loader: Arc<syntax::Loader>) {
+        let old_syn_loader = std::mem::replace(&mut self.syn_loader, syn_loader);
+
+        for language_server in self.language_servers.iter_clients() {
+            let scope = match self.language_servers.scope_by_id(language_server.id()) {
+                Some(scope) => scope,
+                None => continue,
+            };
+            let old_settings = old_syn_loader
+                .language_config_for_scope(scope)
+                .and_then(|config| config.settings.clone());
+            let settings = self.language_server_settings(language_server.id());
+            if settings == old_settings || !language_server.is_initialized() {
+                continue;
+            }
+            tokio::spawn(language_server.did_change_configuration(settings.unwrap_or_default()));
+        }
+    }
+
+    /// Sets the languages of the open documents again after the language configuration or the
+    /// theme was replaced. They're parsed again with the new queries, and the language servers
+    /// of new languages are started.
+    pub fn refresh_languages(&mut self) {
+        let doc_ids: Vec<_> = self.documents.keys().copied().collect();
+        for doc_id in doc_ids {
+            let doc = self.documents.get_mut(&doc_id).unwrap();
+            doc.refresh_language(Some(&self.theme), &self.syn_loader);
+            Self::launch_language_server(
+                &mut self.language_servers,
+                &self.language_server_status,
+                doc,
+            );
+        }
+    }
+
+    /// Replaces the diagnostics of the file `uri` that were published by the language server
+    /// `server_id`, keeping the ones from other servers.
+    pub fn set_language_server_diagnostics(
+        &mut self,
+        server_id: usize,
+        uri: lsp::Url,
+        diagnostics: Vec<lsp::Diagnostic>,
+    ) {
+        let stored = self.diagnostics.entry(uri.clone()).or_default();
+        stored.retain(|(_, id)| *id != server_id);
+        stored.extend(
+            diagnostics
+                .into_iter()
+                .map(|diagnostic| (diagnostic, server_id)),
+        );
+        if stored.is_empty() {
+            self.diagnostics.remove(&uri);
+        }
+
+        let path = match uri.to_file_path() {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        let doc = self
+            .documents
+            .values_mut()
+            .find(|doc| doc.path().map(|p| p == &path).unwrap_or(false));
+
+        if let Some(doc) = doc {
+            let diagnostics = Self::doc_diagnostics(&self.language_servers, &self.diagnostics, doc);
+            doc.set_diagnostics(diagnostics);
+        }
+    }
+
+    /// Stores the diagnostics of a `textDocument/diagnostic` or `workspace/diagnostic` report.
+    pub fn apply_diagnostic_report(
+        &mut self,
+        server_id: usize,
+        uri: lsp::Url,
+        report: DocumentDiagnosticReport,
+    ) {
+        if let Some(result_id) = report.result_id() {
+            self.diagnostic_pulls
+                .result_ids
+                .insert((server_id, uri.clone()), result_id.to_owned());
+        }
+        match report {
+            DocumentDiagnosticReport::Full(report) => {
+                self.set_language_server_diagnostics(server_id, uri, report.items);
+                for (uri, report) in report.related_documents {
+                    self.apply_diagnostic_report(server_id, uri, report);
+                }
+            }
+            // the diagnostics that are stored already are still correct
+            DocumentDiagnosticReport::Unchanged(_) => (),
+        }
+    }
+
+    /// Converts the workspace diagnostics stored for the document's url into diagnostics
+    /// positioned in the document's text.
+    pub fn doc_diagnostics(
+        language_servers: &helix_lsp::Registry,
+        diagnostics: &BTreeMap<lsp::Url, Vec<(lsp::Diagnostic, usize)>>,
+        doc: &Document,
+    ) -> Vec<Diagnostic> {
+        let diagnostics = match doc.url().and_then(|url| diagnostics.get(&url)) {
+            Some(diagnostics) => diagnostics,
+            None => return Vec::new(),
+        };
+
+        diagnostics
+            .iter()
+            .filter_map(|(diagnostic, server_id)| {
+                let language_server = language_servers.get_by_id(*server_id)?;
+                helix_lsp::util::lsp_diagnostic_to_diagnostic(
+                    doc.text(),
+                    diagnostic,
+                    language_server.offset_encoding(),
+                )
+            })
+            .collect()
+    }
+
+    /// Counts the (warnings, errors) across all files in the workspace, the same way as the
+    /// diagnostics of a document: those without a known severity count as errors.
+    pub fn workspace_diagnostic_counts(&self) -> (usize, usize) {
+        use helix_core::diagnostic::Severity;
+
+        self.diagnostics
+            .values()
+            .flatten()
+            .fold((0, 0), |mut counts, (diagnostic, _)| {
+                let severity = diagnostic
+                    .severity
+                    .and_then(helix_lsp::util::lsp_severity_to_severity);
+                match severity {
+                    Some(Severity::Warning) => counts.0 += 1,
+                    Some(Severity::Error) | None => counts.1 += 1,
+                    _ => {}
+                }
+                counts
+            })
+    }
+
     fn _refresh(&mut self) {
         for (view, _) in self.tree.views_mut() {
             let doc = &self.documents[&view.doc];
-            view.ensure_cursor_in_view(doc, self.config.scrolloff)
+            view.ensure_cursor_in_view(
+                doc,
+                self.config.scrolloff,
+                self.config.sticky_context.lines(),
+            )
         }
     }
 
@@ -370,18 +896,19 @@ impl Editor {
                         .traverse()
                         .any(|(_, v)| v.doc == doc.id && v.id != view.id);
                 let view = view_mut!(self);
+                let view_id = view.id;
                 if remove_empty_scratch {
                     // Copy `doc.id` into a variable before calling `self.documents.remove`, which requires a mutable
                     // borrow, invalidating direct access to `doc.id`.
                     let id = doc.id;
                     self.documents.remove(&id);
+                    self.forget_requests(id);
                 } else {
                     let jump = (view.doc, doc.selection(view.id).clone());
                     view.jumps.push(jump);
                     view.last_accessed_doc = Some(view.doc);
                 }
 
-                let view_id = view.id;
                 self.replace_document_in_view(view_id, id);
 
                 return;
@@ -420,11 +947,12 @@ impl Editor {
         self.next_document_id =
             DocumentId(unsafe { NonZeroUsize::new_unchecked(self.next_document_id.0.get() + 1) });
         doc.id = id;
+        doc.set_syntax_parsed(self.syntax_parsed.clone());
         self.documents.insert(id, doc);
         id
     }
 
-    fn new_file_from_document(&mut self, action: Action, doc: Document) -> DocumentId {
+    pub fn new_file_from_document(&mut self, action: Action, doc: Document) -> DocumentId {
         let id = self.new_document(doc);
         self.switch(id, action);
         id
@@ -446,10 +974,24 @@ impl Editor {
         let id = if let Some(id) = id {
             id
         } else {
-            let mut doc = Document::open(&path, None, Some(&self.theme), Some(&self.syn_loader))?;
+            let mut doc = Document::open(&path, None, Some(&self.theme), None)?;
+            // checked before the language is set, so that large files are never parsed
+            doc.large_file = self.config.large_file.is_large(doc.text());
+            doc.detect_language(Some(&self.theme), &self.syn_loader);
+            doc.detect_indent_and_line_ending();
+
+            let _ = Self::launch_language_server(
+                &mut self.language_servers,
+                &self.language_server_status,
+                &mut doc,
+            );
 
-            let _ = Self::launch_language_server(&mut self.language_servers, &mut doc);
+            // restore diagnostics that were published before the document was opened
+            let diagnostics =
+                Self::doc_diagnostics(&self.language_servers, &self.diagnostics, &doc);
+            doc.set_diagnostics(diagnostics);
 
+            self.file_watcher.watch_file(&path);
             self.new_document(doc)
         };
 
@@ -506,6 +1048,7 @@ impl Editor {
         }
 
         self.documents.remove(&doc_id);
+        self.forget_requests(doc_id);
 
         // If the document we removed was visible in all views, we will have no more views. We don't
         // want to close the editor just for a simple buffer close, so we need to create a new view
@@ -528,6 +1071,14 @@ impl Editor {
         Ok(())
     }
 
+    /// Forgets what was requested from the language servers for a closed document.
+    fn forget_requests(&mut self, doc_id: DocumentId) {
+        self.code_lens_requests.remove(&doc_id);
+        self.document_link_requests.remove(&doc_id);
+        self.linked_editing_requests.remove(&doc_id);
+        self.diagnostic_pulls.documents.remove(&doc_id);
+    }
+
     pub fn resize(&mut self, area: Rect) {
         if self.tree.resize(area) {
             self._refresh();
@@ -561,7 +1112,11 @@ impl Editor {
     pub fn ensure_cursor_in_view(&mut self, id: ViewId) {
         let view = self.tree.get_mut(id);
         let doc = &self.documents[&view.doc];
-        view.ensure_cursor_in_view(doc, self.config.scrolloff)
+        view.ensure_cursor_in_view(
+            doc,
+            self.config.scrolloff,
+            self.config.sticky_context.lines(),
+        )
     }
 
     #[inline]
@@ -628,3 +1183,37 @@ impl Editor {
         .map(|_| ())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn large_files() {
+        let config = LargeFileConfig {
+            enable: true,
+            max_size: 100,
+            max_line_length: 10,
+        };
+        assert!(!config.is_large(&Rope::from("short\nlines\n")));
+        assert!(config.is_large(&Rope::from("short\na much longer line\n")));
+        assert!(config.is_large(&Rope::from("a much longer last line")));
+        assert!(config.is_large(&Rope::from("line\n".repeat(30))));
+
+        // lines spanning several chunks of the rope
+        let lines = format!("{}\n", "x".repeat(5000)).repeat(3);
+        let config = LargeFileConfig {
+            max_size: usize::MAX,
+            max_line_length: 5000,
+            ..config
+        };
+        assert!(!config.is_large(&Rope::from(lines.as_str())));
+        let long = Rope::from(format!("{}{}\n", lines, "x".repeat(5001)).as_str());
+        assert!(config.is_large(&long));
+        assert!(!LargeFileConfig {
+            enable: false,
+            ..config
+        }
+        .is_large(&long));
+    }
+}
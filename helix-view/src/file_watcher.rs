@@ -0,0 +1,570 @@
+//! Watches the workspace for changes made outside of the editor. The changes are used to reload
+//! open documents and are forwarded to the language servers that registered watchers for them.
+//!
+//! Watching uses the platform's native mechanism (inotify on Linux) and falls back to polling
+//! when that isn't available, for example when the inotify watch limit is exhausted. Directories
+//! are watched one by one, skipping hidden ones and the ones ignored by `.gitignore` and `.ignore`
+//! files, like `.git` or `target`.
+
+use globset::{GlobBuilder, GlobMatcher};
+use helix_lsp::lsp;
+use ignore::WalkBuilder;
+use notify::{
+    event::{MetadataKind, ModifyKind, RenameMode},
+    EventKind, PollWatcher, RecursiveMode, Watcher,
+};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// How often the polling fallback scans the watched paths.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FileChange {
+    Created,
+    Changed,
+    Deleted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileEvent {
+    pub path: PathBuf,
+    pub change: FileChange,
+}
+
+#[derive(Debug)]
+enum Request {
+    Watch(PathBuf, RecursiveMode),
+    /// A path that was created, to be watched as well if it's a directory below a recursively
+    /// watched one.
+    Created(PathBuf),
+}
+
+/// A glob registered by a language server for `workspace/didChangeWatchedFiles`.
+#[derive(Debug)]
+struct LspWatcher {
+    glob: GlobMatcher,
+    kind: lsp::WatchKind,
+}
+
+#[derive(Debug)]
+pub struct FileWatcher {
+    /// Paths to watch, handled by the watcher thread which is started with the first one.
+    requests: Option<mpsc::Sender<Request>>,
+    events_tx: UnboundedSender<FileEvent>,
+    events: UnboundedReceiver<FileEvent>,
+    /// Directories that are watched recursively.
+    roots: Vec<PathBuf>,
+    /// Directories of files, watched without their subdirectories.
+    dirs: Vec<PathBuf>,
+    /// Directories outside of the workspace that are watched recursively, like the runtime
+    /// directory.
+    trees: Vec<PathBuf>,
+    /// Watchers registered by the language servers, by server id and registration id.
+    lsp_watchers: HashMap<usize, HashMap<String, Vec<LspWatcher>>>,
+}
+
+impl Default for FileWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileWatcher {
+    pub fn new() -> Self {
+        let (events_tx, events) = unbounded_channel();
+        Self {
+            requests: None,
+            events_tx,
+            events,
+            roots: Vec::new(),
+            dirs: Vec::new(),
+            trees: Vec::new(),
+            lsp_watchers: HashMap::new(),
+        }
+    }
+
+    /// Watches the directory `root` and everything below it that isn't ignored.
+    pub fn watch_root(&mut self, root: &Path) {
+        if self.roots.iter().any(|watched| root.starts_with(watched)) {
+            return;
+        }
+        self.roots.push(root.to_path_buf());
+        self.request(Request::Watch(root.to_path_buf(), RecursiveMode::Recursive));
+    }
+
+    /// Watches the directory `dir` and everything below it, without making it part of the
+    /// workspace: language server globs aren't matched relative to it.
+    pub fn watch_dir(&mut self, dir: &Path) {
+        if self.is_watched(dir) {
+            return;
+        }
+        self.trees.push(dir.to_path_buf());
+        self.request(Request::Watch(dir.to_path_buf(), RecursiveMode::Recursive));
+    }
+
+    /// Watches a single file, which may be in an ignored directory of a root.
+    pub fn watch_file(&mut self, path: &Path) {
+        // the directory is watched rather than the file itself, files that are saved by renaming
+        // a new file over them would stop being watched otherwise
+        let dir = match path.parent() {
+            Some(dir) => dir,
+            None => return,
+        };
+        if self.dirs.iter().any(|watched| watched == dir) {
+            return;
+        }
+        self.dirs.push(dir.to_path_buf());
+        self.request(Request::Watch(
+            dir.to_path_buf(),
+            RecursiveMode::NonRecursive,
+        ));
+    }
+
+    fn is_watched(&self, path: &Path) -> bool {
+        self.roots
+            .iter()
+            .chain(&self.trees)
+            .any(|root| path.starts_with(root))
+    }
+
+    fn request(&mut self, request: Request) {
+        let events = self.events_tx.clone();
+        let requests = self.requests.get_or_insert_with(|| {
+            let (tx, rx) = mpsc::channel();
+            std::thread::spawn(move || watch(rx, events));
+            tx
+        });
+        let _ = requests.send(request);
+    }
+
+    /// Waits for changes and returns them along with all the other changes that are ready.
+    pub async fn next(&mut self) -> Option<Vec<FileEvent>> {
+        let mut events = vec![self.events.recv().await?];
+        while let Ok(event) = self.events.try_recv() {
+            if !events.contains(&event) {
+                events.push(event);
+            }
+        }
+        // new directories aren't picked up by the watches of their parents
+        for event in &events {
+            if event.change == FileChange::Created && self.is_watched(&event.path) {
+                self.request(Request::Created(event.path.clone()));
+            }
+        }
+        Some(events)
+    }
+
+    /// Registers the watchers of a `workspace/didChangeWatchedFiles` registration.
+    pub fn register_lsp_watchers(
+        &mut self,
+        server_id: usize,
+        registration_id: String,
+        watchers: Vec<lsp::FileSystemWatcher>,
+    ) {
+        let watchers = watchers
+            .into_iter()
+            .filter_map(|watcher| {
+                let glob = GlobBuilder::new(&watcher.glob_pattern)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|err| log::warn!("invalid glob pattern from language server: {}", err))
+                    .ok()?
+                    .compile_matcher();
+                let kind = watcher.kind.unwrap_or_else(lsp::WatchKind::all);
+                Some(LspWatcher { glob, kind })
+            })
+            .collect();
+        self.lsp_watchers
+            .entry(server_id)
+            .or_default()
+            .insert(registration_id, watchers);
+    }
+
+    pub fn unregister_lsp_watchers(&mut self, server_id: usize, registration_id: &str) {
+        if let Some(registrations) = self.lsp_watchers.get_mut(&server_id) {
+            registrations.remove(registration_id);
+        }
+    }
+
+    /// Drops all the registrations of a language server, used once it exited.
+    pub fn remove_language_server(&mut self, server_id: usize) {
+        self.lsp_watchers.remove(&server_id);
+    }
+
+    /// The changes each language server asked to be notified about.
+    pub fn lsp_file_events(&self, events: &[FileEvent]) -> Vec<(usize, Vec<lsp::FileEvent>)> {
+        self.lsp_watchers
+            .iter()
+            .filter_map(|(server_id, registrations)| {
+                let changes: Vec<_> = events
+                    .iter()
+                    .filter(|event| {
+                        let kind = match event.change {
+                            FileChange::Created => lsp::WatchKind::Create,
+                            FileChange::Changed => lsp::WatchKind::Change,
+                            FileChange::Deleted => lsp::WatchKind::Delete,
+                        };
+                        registrations.values().flatten().any(|watcher| {
+                            watcher.kind.contains(kind) && self.matches(&watcher.glob, &event.path)
+                        })
+                    })
+                    .filter_map(|event| {
+                        let typ = match event.change {
+                            FileChange::Created => lsp::FileChangeType::CREATED,
+                            FileChange::Changed => lsp::FileChangeType::CHANGED,
+                            FileChange::Deleted => lsp::FileChangeType::DELETED,
+                        };
+                        let uri = lsp::Url::from_file_path(&event.path).ok()?;
+                        Some(lsp::FileEvent { uri, typ })
+                    })
+                    .collect();
+                if changes.is_empty() {
+                    None
+                } else {
+                    Some((*server_id, changes))
+                }
+            })
+            .collect()
+    }
+
+    /// Patterns are matched against the absolute path and the path relative to the roots, so both
+    /// `**/*.rs` and `src/*.rs` work.
+    fn matches(&self, glob: &GlobMatcher, path: &Path) -> bool {
+        glob.is_match(path)
+            || self
+                .roots
+                .iter()
+                .filter_map(|root| path.strip_prefix(root).ok())
+                .any(|path| glob.is_match(path))
+    }
+}
+
+/// Runs on the watcher thread until the [`FileWatcher`] is dropped.
+fn watch(requests: mpsc::Receiver<Request>, events: UnboundedSender<FileEvent>) {
+    let handler = || {
+        let events = events.clone();
+        move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                for event in file_events(event) {
+                    let _ = events.send(event);
+                }
+            }
+            Err(err) => log::warn!("file watcher error: {}", err),
+        }
+    };
+    let poll_watcher = || -> Option<Box<dyn Watcher>> {
+        let config = notify::Config::default().with_poll_interval(POLL_INTERVAL);
+        match PollWatcher::new(handler(), config) {
+            Ok(watcher) => Some(Box::new(watcher)),
+            Err(err) => {
+                log::error!("failed to start the file watcher: {}", err);
+                None
+            }
+        }
+    };
+
+    let (mut watcher, mut polling): (Box<dyn Watcher>, bool) =
+        match notify::recommended_watcher(handler()) {
+            Ok(watcher) => (Box::new(watcher), false),
+            Err(err) => {
+                log::warn!("falling back to polling for file changes: {}", err);
+                match poll_watcher() {
+                    Some(watcher) => (watcher, true),
+                    None => return,
+                }
+            }
+        };
+
+    let mut watched = HashSet::new();
+    let mut recursive: Vec<PathBuf> = Vec::new();
+    for request in requests {
+        let dirs = match request {
+            Request::Watch(path, RecursiveMode::Recursive) => {
+                recursive.push(path.clone());
+                unignored_dirs(&path, &path)
+            }
+            Request::Watch(path, RecursiveMode::NonRecursive) => vec![path],
+            Request::Created(path) => match path.parent() {
+                Some(parent)
+                    if path.is_dir() && recursive.iter().any(|dir| path.starts_with(dir)) =>
+                {
+                    unignored_dirs(parent, &path)
+                }
+                _ => continue,
+            },
+        };
+
+        for dir in dirs {
+            if watched.contains(&dir) {
+                continue;
+            }
+            if let Err(err) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                if polling {
+                    log::warn!("failed to watch {:?}: {}", dir, err);
+                    continue;
+                }
+                log::warn!(
+                    "failed to watch {:?}, falling back to polling for file changes: {}",
+                    dir,
+                    err
+                );
+                watcher = match poll_watcher() {
+                    Some(watcher) => watcher,
+                    None => return,
+                };
+                polling = true;
+                for dir in watched.iter().chain(std::iter::once(&dir)) {
+                    if let Err(err) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                        log::warn!("failed to watch {:?}: {}", dir, err);
+                    }
+                }
+            }
+            watched.insert(dir);
+        }
+    }
+}
+
+/// The directories at and below `dir` that aren't hidden or ignored. The walk starts at
+/// `walk_root`, which is `dir` or its parent, so that `dir` itself can be ignored.
+fn unignored_dirs(walk_root: &Path, dir: &Path) -> Vec<PathBuf> {
+    let (root, below) = (walk_root.to_path_buf(), dir.to_path_buf());
+    WalkBuilder::new(walk_root)
+        .filter_entry(move |entry| entry.path() == root || entry.path().starts_with(&below))
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| matches!(entry.file_type(), Some(file_type) if file_type.is_dir()))
+        .map(|entry| entry.into_path())
+        .filter(|path| path.starts_with(dir))
+        .collect()
+}
+
+fn file_events(event: notify::Event) -> Vec<FileEvent> {
+    let events = |change| {
+        event
+            .paths
+            .iter()
+            .map(|path| FileEvent {
+                path: path.clone(),
+                change,
+            })
+            .collect()
+    };
+
+    match event.kind {
+        EventKind::Create(_) => events(FileChange::Created),
+        EventKind::Remove(_) => events(FileChange::Deleted),
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => events(FileChange::Deleted),
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => events(FileChange::Created),
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => event
+            .paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| FileEvent {
+                path: path.clone(),
+                change: if i == 0 {
+                    FileChange::Deleted
+                } else {
+                    FileChange::Created
+                },
+            })
+            .collect(),
+        // permission and access time changes don't change the contents
+        EventKind::Modify(ModifyKind::Metadata(kind))
+            if !matches!(kind, MetadataKind::WriteTime | MetadataKind::Any) =>
+        {
+            Vec::new()
+        }
+        EventKind::Modify(_) => events(FileChange::Changed),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use notify::event::{CreateKind, DataChange};
+
+    #[test]
+    fn maps_notify_events() {
+        let event = |kind, paths: &[&str]| {
+            let mut event = notify::Event::new(kind);
+            for path in paths {
+                event = event.add_path(PathBuf::from(path));
+            }
+            file_events(event)
+        };
+        let file_event = |path: &str, change| FileEvent {
+            path: PathBuf::from(path),
+            change,
+        };
+
+        assert_eq!(
+            event(EventKind::Create(CreateKind::File), &["/a"]),
+            vec![file_event("/a", FileChange::Created)]
+        );
+        assert_eq!(
+            event(
+                EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+                &["/a"]
+            ),
+            vec![file_event("/a", FileChange::Changed)]
+        );
+        assert_eq!(
+            event(
+                EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime)),
+                &["/a"]
+            ),
+            vec![file_event("/a", FileChange::Changed)]
+        );
+        assert_eq!(
+            event(
+                EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+                &["/a"]
+            ),
+            vec![]
+        );
+        // editors that save by renaming a temporary file over the original
+        assert_eq!(
+            event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &["/a.tmp", "/a"]
+            ),
+            vec![
+                file_event("/a.tmp", FileChange::Deleted),
+                file_event("/a", FileChange::Created)
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_lsp_watchers() {
+        let mut watcher = FileWatcher::new();
+        watcher.roots.push(PathBuf::from("/project"));
+        watcher.register_lsp_watchers(
+            0,
+            "rust".into(),
+            vec![
+                lsp::FileSystemWatcher {
+                    glob_pattern: "**/*.rs".into(),
+                    kind: None,
+                },
+                lsp::FileSystemWatcher {
+                    glob_pattern: "Cargo.{toml,lock}".into(),
+                    kind: Some(lsp::WatchKind::Change),
+                },
+            ],
+        );
+
+        let events = [
+            FileEvent {
+                path: PathBuf::from("/project/src/main.rs"),
+                change: FileChange::Created,
+            },
+            FileEvent {
+                path: PathBuf::from("/project/Cargo.toml"),
+                change: FileChange::Changed,
+            },
+            FileEvent {
+                path: PathBuf::from("/project/Cargo.lock"),
+                change: FileChange::Deleted,
+            },
+            FileEvent {
+                path: PathBuf::from("/project/src/Cargo.toml"),
+                change: FileChange::Changed,
+            },
+        ];
+        let changes = watcher.lsp_file_events(&events);
+        assert_eq!(changes.len(), 1);
+        let (server_id, changes) = &changes[0];
+        assert_eq!(*server_id, 0);
+        let paths: Vec<_> = changes
+            .iter()
+            .map(|change| (change.uri.path(), change.typ))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("/project/src/main.rs", lsp::FileChangeType::CREATED),
+                ("/project/Cargo.toml", lsp::FileChangeType::CHANGED),
+            ]
+        );
+
+        watcher.unregister_lsp_watchers(0, "rust");
+        assert!(watcher.lsp_file_events(&events).is_empty());
+    }
+
+    #[test]
+    fn watches_dirs_outside_of_workspace() {
+        let mut watcher = FileWatcher::new();
+        watcher.watch_dir(Path::new("/runtime"));
+        assert!(watcher.is_watched(Path::new("/runtime/queries/rust/highlights.scm")));
+        assert!(watcher.roots.is_empty());
+
+        // a workspace inside of it is still a root for the language server globs
+        watcher.watch_root(Path::new("/runtime/project"));
+        assert_eq!(watcher.roots, [PathBuf::from("/runtime/project")]);
+    }
+
+    #[test]
+    fn skips_ignored_dirs() {
+        let dir = std::env::temp_dir().join(format!("helix-ignored-dirs-{}", std::process::id()));
+        for subdir in [".git", "src/bin", "target/debug"] {
+            std::fs::create_dir_all(dir.join(subdir)).unwrap();
+        }
+        std::fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+
+        let mut dirs = unignored_dirs(&dir, &dir);
+        dirs.sort();
+        assert_eq!(dirs, [dir.clone(), dir.join("src"), dir.join("src/bin")]);
+        // directories created later are checked against the ignore files above them
+        assert!(unignored_dirs(&dir, &dir.join("target")).is_empty());
+        assert_eq!(unignored_dirs(&dir, &dir.join("src")).len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Waits up to 10 seconds for a change to `path`.
+    async fn wait_for(watcher: &mut FileWatcher, path: &Path) -> bool {
+        let found = tokio::time::timeout(Duration::from_secs(10), async {
+            while let Some(events) = watcher.next().await {
+                if events.iter().any(|event| event.path == path) {
+                    return true;
+                }
+            }
+            false
+        });
+        found.await.unwrap_or(false)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reports_changes_on_disk() {
+        let dir = std::env::temp_dir().join(format!("helix-file-watcher-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        let mut watcher = FileWatcher::new();
+        watcher.watch_root(&dir);
+        // give the watcher thread time to set up the watch
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let path = dir.join("file.txt");
+        std::fs::write(&path, "hello").unwrap();
+        assert!(wait_for(&mut watcher, &path).await);
+
+        // new directories are watched as well
+        let subdir = dir.join("subdir");
+        std::fs::create_dir(&subdir).unwrap();
+        assert!(wait_for(&mut watcher, &subdir).await);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let path = subdir.join("file.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let found = wait_for(&mut watcher, &path).await;
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(found);
+    }
+}
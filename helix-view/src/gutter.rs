@@ -14,6 +14,10 @@ pub fn diagnostic<'doc>(
     _is_focused: bool,
     _width: usize,
 ) -> GutterFn<'doc> {
+    if doc.is_large_file() {
+        return Box::new(|_, _, _| None);
+    }
+
     let warning = theme.get("warning");
     let error = theme.get("error");
     let info = theme.get("info");
@@ -4,11 +4,13 @@ pub mod macros;
 pub mod clipboard;
 pub mod document;
 pub mod editor;
+pub mod file_watcher;
 pub mod graphics;
 pub mod gutter;
 pub mod info;
 pub mod input;
 pub mod keyboard;
+pub mod tag_index;
 pub mod theme;
 pub mod tree;
 pub mod view;
@@ -0,0 +1,167 @@
+//! An index of the definitions in the files of the workspace, found with the `tags.scm` queries
+//! of their languages. It backs symbol navigation when no language server is running.
+//!
+//! The index is built in the background when the editor starts, and kept up to date from the
+//! events of the file watcher.
+
+use crate::file_watcher::{FileChange, FileEvent};
+use helix_core::syntax::{Loader, Tag};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/// Larger files are skipped, they are mostly generated or minified.
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Default)]
+pub struct TagIndex {
+    files: RwLock<HashMap<PathBuf, Vec<Tag>>>,
+    /// Whether the workspace was walked, so that changes should be indexed as well.
+    built: AtomicBool,
+}
+
+impl TagIndex {
+    /// Indexes `files`, e.g. all the files of the workspace. This blocks for a while.
+    pub fn build(&self, loader: &Loader, files: impl Iterator<Item = PathBuf>) {
+        for path in files {
+            self.index_file(loader, &path);
+        }
+        self.built.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_built(&self) -> bool {
+        self.built.load(Ordering::Relaxed)
+    }
+
+    /// Re-indexes the file at `path`, dropping it from the index if it can't be read or its
+    /// language has no tags query.
+    pub fn index_file(&self, loader: &Loader, path: &Path) {
+        let tags = Self::read_tags(loader, path).unwrap_or_default();
+        let mut files = self.files.write().unwrap();
+        if tags.is_empty() {
+            files.remove(path);
+        } else {
+            files.insert(path.to_path_buf(), tags);
+        }
+    }
+
+    fn read_tags(loader: &Loader, path: &Path) -> Option<Vec<Tag>> {
+        if std::fs::metadata(path).ok()?.len() > MAX_FILE_SIZE {
+            return None;
+        }
+        let mut file = std::fs::File::open(path).ok()?;
+        let (text, _) = crate::document::from_reader(&mut file, None).ok()?;
+        let language = loader.language_config_for_document(Some(path), &text)?;
+        Some(language.tags_query()?.parse_definitions(&text))
+    }
+
+    /// Updates the index with the changes reported by the file watcher. Files are indexed if
+    /// they already were, or if they are new and next to indexed ones, which keeps build
+    /// artifacts and other ignored directories out.
+    pub fn handle_file_events(&self, loader: &Loader, events: &[FileEvent]) {
+        for event in events {
+            let indexed = {
+                let files = self.files.read().unwrap();
+                files.contains_key(&event.path)
+                    || (event.change == FileChange::Created
+                        && files
+                            .keys()
+                            .any(|path| path.parent() == event.path.parent()))
+            };
+            match event.change {
+                FileChange::Deleted => self.remove_file(&event.path),
+                _ if indexed => self.index_file(loader, &event.path),
+                _ => (),
+            }
+        }
+    }
+
+    pub fn remove_file(&self, path: &Path) {
+        self.files.write().unwrap().remove(path);
+    }
+
+    /// The definitions named `name` in all the indexed files.
+    pub fn definitions(&self, name: &str) -> Vec<(PathBuf, Tag)> {
+        let files = self.files.read().unwrap();
+        let mut definitions: Vec<_> = files
+            .iter()
+            .flat_map(|(path, tags)| {
+                tags.iter()
+                    .filter(|tag| tag.name == name)
+                    .map(move |tag| (path.clone(), tag.clone()))
+            })
+            .collect();
+        definitions.sort_by(|(a, _), (b, _)| a.cmp(b));
+        definitions
+    }
+
+    /// All the definitions of the indexed files, ordered by file.
+    pub fn all_definitions(&self) -> Vec<(PathBuf, Tag)> {
+        let files = self.files.read().unwrap();
+        let mut definitions: Vec<_> = files
+            .iter()
+            .flat_map(|(path, tags)| tags.iter().map(move |tag| (path.clone(), tag.clone())))
+            .collect();
+        definitions.sort_by(|(a, _), (b, _)| a.cmp(b));
+        definitions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helix_core::syntax::Configuration;
+
+    #[test]
+    fn indexes_definitions() {
+        let config: Configuration = toml::from_str(
+            r#"
+            [[language]]
+            name = "rust"
+            scope = "source.rust"
+            file-types = ["rs"]
+            roots = []
+            "#,
+        )
+        .unwrap();
+        let loader = Loader::new(config);
+
+        let dir = std::env::temp_dir().join(format!("helix-tag-index-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lib = dir.join("lib.rs");
+        std::fs::write(&lib, "fn first() {}\nstruct Second;\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "fn first() {}\n").unwrap();
+
+        let index = TagIndex::default();
+        index.build(&loader, [lib.clone(), dir.join("notes.txt")].into_iter());
+        assert!(index.is_built());
+        let names: Vec<_> = index
+            .all_definitions()
+            .into_iter()
+            .map(|(path, tag)| (path, tag.name, tag.kind))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (lib.clone(), "first".to_string(), "function".to_string()),
+                (lib.clone(), "Second".to_string(), "struct".to_string()),
+            ]
+        );
+
+        // new files next to indexed ones are picked up, deleted ones dropped
+        let main = dir.join("main.rs");
+        std::fs::write(&main, "fn first() {}\n").unwrap();
+        let event = |path: &Path, change| FileEvent {
+            path: path.to_path_buf(),
+            change,
+        };
+        index.handle_file_events(&loader, &[event(&main, FileChange::Created)]);
+        assert_eq!(index.definitions("first").len(), 2);
+        index.handle_file_events(&loader, &[event(&lib, FileChange::Deleted)]);
+        assert_eq!(index.definitions("first")[0].0, main);
+        assert!(index.definitions("Second").is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
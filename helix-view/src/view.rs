@@ -6,6 +6,7 @@ use crate::{
     Document, DocumentId, ViewId,
 };
 use helix_core::{
+    context,
     graphemes::{grapheme_width, RopeGraphemes},
     line_ending::line_end_char_index,
     visual_coords_at_pos, Position, RopeSlice, Selection,
@@ -104,11 +105,47 @@ impl View {
         self.area.clip_left(offset).clip_bottom(1) // -1 for statusline
     }
 
-    //
+    /// The lines shown at the top of the view because the syntax nodes they start are scrolled
+    /// out of sight, outermost first. At most `max_lines` of them are kept, the innermost ones.
+    pub fn sticky_context(&self, doc: &Document, max_lines: usize) -> Vec<usize> {
+        self.sticky_context_at(doc, self.offset.row, max_lines)
+    }
+
+    /// The sticky context of the view if it started at line `first_line`. The context covers the
+    /// first rows, so it's the context of the first line left uncovered.
+    fn sticky_context_at(&self, doc: &Document, first_line: usize, max_lines: usize) -> Vec<usize> {
+        let syntax = match doc.syntax() {
+            Some(syntax) if max_lines > 0 && first_line > 0 => syntax,
+            _ => return Vec::new(),
+        };
+
+        let query = doc
+            .language_config()
+            .and_then(|config| config.context_query());
+        let text = doc.text().slice(..);
+        // leave at least half of the view to the text
+        let max_lines = max_lines.min(self.inner_area().height as usize / 2);
+
+        let mut lines = Vec::new();
+        let mut covered = 0;
+        while first_line + covered < text.len_lines() {
+            lines = context::context_lines(syntax, query, text, first_line + covered);
+            if lines.len() > max_lines {
+                lines.drain(..lines.len() - max_lines);
+            }
+            if lines.len() <= covered {
+                break;
+            }
+            covered = lines.len();
+        }
+        lines
+    }
+
     pub fn offset_coords_to_in_view(
         &self,
         doc: &Document,
         scrolloff: usize,
+        context_lines: usize,
     ) -> Option<(usize, usize)> {
         let cursor = doc
             .selection(self.id)
@@ -128,16 +165,29 @@ impl View {
 
         let last_col = self.offset.col + inner_area.width.saturating_sub(1) as usize;
 
-        let row = if line > last_line.saturating_sub(scrolloff) {
+        // the cursor stays below the sticky context covering the first rows, and the scrolloff
+        let top_at = |row: usize| self.sticky_context_at(doc, row, context_lines).len() + scrolloff;
+
+        let mut row = if line > last_line.saturating_sub(scrolloff) {
             // scroll down
             self.offset.row + line - (last_line.saturating_sub(scrolloff))
-        } else if line < self.offset.row + scrolloff {
+        } else if line < self.offset.row + top_at(self.offset.row) {
             // scroll up
-            line.saturating_sub(scrolloff)
+            let mut row = line.saturating_sub(scrolloff);
+            while row > 0 && row + top_at(row) > line {
+                row -= 1;
+            }
+            row
         } else {
             self.offset.row
         };
 
+        // virtual lines take up rows as well, keep scrolling until the cursor line fits
+        let target = (line + scrolloff).min(doc.text().len_lines().saturating_sub(1));
+        while row < line && self.last_line_at(doc, row) < target {
+            row += 1;
+        }
+
         let col = if col > last_col.saturating_sub(scrolloff) {
             // scroll right
             self.offset.col + col - (last_col.saturating_sub(scrolloff))
@@ -154,26 +204,85 @@ impl View {
         }
     }
 
-    pub fn ensure_cursor_in_view(&mut self, doc: &Document, scrolloff: usize) {
-        if let Some((row, col)) = self.offset_coords_to_in_view(doc, scrolloff) {
+    pub fn ensure_cursor_in_view(
+        &mut self,
+        doc: &Document,
+        scrolloff: usize,
+        context_lines: usize,
+    ) {
+        if let Some((row, col)) = self.offset_coords_to_in_view(doc, scrolloff, context_lines) {
             self.offset.row = row;
             self.offset.col = col;
         }
     }
 
-    pub fn is_cursor_in_view(&mut self, doc: &Document, scrolloff: usize) -> bool {
-        self.offset_coords_to_in_view(doc, scrolloff).is_none()
+    pub fn is_cursor_in_view(
+        &mut self,
+        doc: &Document,
+        scrolloff: usize,
+        context_lines: usize,
+    ) -> bool {
+        self.offset_coords_to_in_view(doc, scrolloff, context_lines)
+            .is_none()
     }
 
     /// Calculates the last visible line on screen
     #[inline]
     pub fn last_line(&self, doc: &Document) -> usize {
-        let height = self.inner_area().height;
-        std::cmp::min(
-            // Saturating subs to make it inclusive zero indexing.
-            (self.offset.row + height as usize).saturating_sub(1),
-            doc.text().len_lines().saturating_sub(1),
-        )
+        self.last_line_at(doc, self.offset.row)
+    }
+
+    /// Calculates the last visible line on screen if the view started at line `first_line`.
+    fn last_line_at(&self, doc: &Document, first_line: usize) -> usize {
+        let height = self.inner_area().height as usize;
+        let last_doc_line = doc.text().len_lines().saturating_sub(1);
+
+        if doc.code_lenses().is_empty() {
+            return std::cmp::min(
+                // Saturating subs to make it inclusive zero indexing.
+                (first_line + height).saturating_sub(1),
+                last_doc_line,
+            );
+        }
+
+        let mut line = first_line;
+        let mut row = doc.virtual_lines_above(line);
+        while line < last_doc_line {
+            let next_row = row + 1 + doc.virtual_lines_above(line + 1);
+            if next_row >= height {
+                break;
+            }
+            row = next_row;
+            line += 1;
+        }
+        line
+    }
+
+    /// The screen row of `line` relative to the top of the view, counting virtual lines.
+    pub fn screen_row(&self, doc: &Document, line: usize) -> usize {
+        let row = line.saturating_sub(self.offset.row);
+        if doc.code_lenses().is_empty() {
+            return row;
+        }
+        (self.offset.row..=line).fold(row, |row, line| row + doc.virtual_lines_above(line))
+    }
+
+    /// The line shown on screen row `row` relative to the top of the view, `None` for virtual
+    /// lines.
+    pub fn line_at_screen_row(&self, doc: &Document, row: usize) -> Option<usize> {
+        let mut line = self.offset.row;
+        let mut current = 0;
+        loop {
+            current += doc.virtual_lines_above(line);
+            if current > row {
+                return None;
+            }
+            if current == row || line >= doc.text().len_lines().saturating_sub(1) {
+                return Some(line + (row - current));
+            }
+            current += 1;
+            line += 1;
+        }
     }
 
     /// Translates a document position to an absolute position in the terminal.
@@ -207,7 +316,7 @@ impl View {
         }
 
         // It is possible for underflow to occur if the buffer length is larger than the terminal width.
-        let row = line.saturating_sub(self.offset.row);
+        let row = self.screen_row(doc, line);
         let col = col.saturating_sub(self.offset.col);
 
         Some(Position::new(row, col))
@@ -262,6 +371,14 @@ impl View {
     /// Translates a screen position to position in the text document.
     /// Returns a usize typed position in bounds of the text if found in this view, None if out of view.
     pub fn pos_at_screen_coords(&self, doc: &Document, row: u16, column: u16) -> Option<usize> {
+        let inner = self.inner_area();
+        let row = if doc.code_lenses().is_empty() || row < inner.top() {
+            row
+        } else {
+            // skip over the virtual lines
+            let line = self.line_at_screen_row(doc, (row - inner.y) as usize)?;
+            inner.y + (line - self.offset.row) as u16
+        };
         self.text_pos_at_screen_coords(&doc.text().slice(..), row, column, doc.tab_width())
     }
     // pub fn traverse<F>(&self, text: RopeSlice, start: usize, end: usize, fun: F)
@@ -404,4 +521,35 @@ mod tests {
             Some(7)
         );
     }
+
+    #[test]
+    fn test_virtual_lines() {
+        use crate::document::CodeLens;
+        use helix_lsp::lsp;
+
+        let mut view = View::new(DocumentId::default());
+        view.area = Rect::new(40, 40, 40, 5);
+        let mut doc = Document::from(Rope::from_str("a\nb\nc\nd\ne\nf\n"), None);
+
+        // 4 rows of text, the last one is the statusline
+        assert_eq!(view.last_line(&doc), 3);
+
+        let lens = |pos| CodeLens {
+            pos,
+            command: lsp::Command::new("run".into(), "run".into(), None),
+        };
+        // above "b" and "d"
+        doc.set_code_lenses(vec![lens(6), lens(2)]);
+        assert_eq!(doc.virtual_lines_above(1), 1);
+        assert_eq!(doc.virtual_lines_above(2), 0);
+
+        assert_eq!(view.last_line(&doc), 2);
+        assert_eq!(view.screen_row(&doc, 0), 0);
+        assert_eq!(view.screen_row(&doc, 1), 2);
+        assert_eq!(view.line_at_screen_row(&doc, 1), None);
+        assert_eq!(view.line_at_screen_row(&doc, 2), Some(1));
+        assert_eq!(view.line_at_screen_row(&doc, 3), Some(2));
+        assert_eq!(view.pos_at_screen_coords(&doc, 41, 40 + OFFSET), None);
+        assert_eq!(view.pos_at_screen_coords(&doc, 42, 40 + OFFSET), Some(2));
+    }
 }
@@ -0,0 +1,11 @@
+(mod_item) @context
+(impl_item) @context
+(trait_item) @context
+(function_item) @context
+(closure_expression) @context
+(if_expression) @context
+(match_expression) @context
+(match_arm) @context
+(for_expression) @context
+(while_expression) @context
+(loop_expression) @context
@@ -0,0 +1,7 @@
+[
+  (type_arguments)
+  (type_parameters)
+] @rainbow.scope
+
+(type_arguments ["<" ">"] @rainbow.bracket)
+(type_parameters ["<" ">"] @rainbow.bracket)
@@ -0,0 +1,54 @@
+; ADT definitions
+
+(struct_item
+  name: (type_identifier) @name) @definition.struct
+
+(enum_item
+  name: (type_identifier) @name) @definition.enum
+
+(union_item
+  name: (type_identifier) @name) @definition.struct
+
+(type_item
+  name: (type_identifier) @name) @definition.type
+
+; Methods and functions
+
+(declaration_list
+  (function_item
+    name: (identifier) @name) @definition.method)
+
+(function_item
+  name: (identifier) @name) @definition.function
+
+(function_signature_item
+  name: (identifier) @name) @definition.method
+
+; Traits, modules and macros
+
+(trait_item
+  name: (type_identifier) @name) @definition.interface
+
+(mod_item
+  name: (identifier) @name) @definition.module
+
+(macro_definition
+  name: (identifier) @name) @definition.macro
+
+(const_item
+  name: (identifier) @name) @definition.constant
+
+(static_item
+  name: (identifier) @name) @definition.constant
+
+; References
+
+(call_expression
+  function: (identifier) @name) @reference.call
+
+(call_expression
+  function: (field_expression
+    field: (field_identifier) @name)) @reference.call
+
+(macro_invocation
+  macro: (identifier) @name) @reference.call
@@ -0,0 +1 @@
+; inherits: html